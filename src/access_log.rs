@@ -0,0 +1,40 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// HTTP access log of the kvm-rs web server
+
+use std::net::SocketAddr;
+use std::time::Instant;
+use axum::{
+    extract::{ConnectInfo, Request},
+    middleware::Next,
+    response::Response,
+};
+use tracing::info;
+use crate::auth::Principal;
+
+/// Log one line per request with its peer, method, path, status, duration and
+/// user. WebSocket upgrades are logged when the upgrade is answered, the
+/// session itself by its own connect and disconnect messages
+pub async fn log_requests(ConnectInfo(addr): ConnectInfo<SocketAddr>, request: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let method = request.method().clone();
+    // The query is left out, it can carry session and resume tokens
+    let path = request.uri().path().to_string();
+    // A client certificate names the user before any session does
+    let certificate_user = request.extensions().get::<Principal>().cloned();
+    let response = next.run(request).await;
+    let user = response.extensions().get::<Principal>().or(certificate_user.as_ref())
+        .map(|principal| principal.0.as_str())
+        .unwrap_or("-");
+    info!(
+        target: "access",
+        peer = %addr,
+        method = %method,
+        path = ?path,
+        status = response.status().as_u16(),
+        duration_ms = started.elapsed().as_millis() as u64,
+        user = ?user,
+        "access",
+    );
+    response
+}
@@ -0,0 +1,413 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// REST control API for kvm-rs
+
+use axum::{
+    extract::{ConnectInfo, Path, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{self, KeepAlive, Sse},
+        Response,
+    },
+    routing::{get, post, put},
+    Json, Router,
+};
+use futures_util::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{convert::Infallible, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::sync::broadcast;
+use tracing::info;
+use crate::{
+    audit::{InputAudit, InputClass},
+    bandwidth::BandwidthStatus,
+    display::{CaptureStatus, DisplayHub},
+    frame_budget::BudgetStatus,
+    hid::{HidError, HidManager},
+    hid_stats::HidStatsSnapshot,
+    keyboard::{self, KeyCombo},
+    macros::MacroStep,
+    pointer::{PointerSettings, PointerSettingsUpdate},
+    security_audit::SecurityEvent,
+    sessions::SessionInfo,
+    vnc::{InputState, VncHandler},
+};
+
+/// How long buttons stay pressed for a click
+const CLICK_DURATION: Duration = Duration::from_millis(50);
+
+/// Shared state of the control API handlers
+#[derive(Clone)]
+pub struct ApiState {
+    pub hid_manager: HidManager,
+    pub hub: Arc<DisplayHub>,
+    pub vnc: VncHandler,
+    pub audit: InputAudit,
+    /// Bearer token required by the input injection endpoints, which are
+    /// disabled without one
+    pub input_token: Option<Arc<str>>,
+    /// Video and HID devices missing at startup, served without (--missing-devices degrade)
+    pub missing_devices: Arc<[String]>,
+    /// Pointer state of the API's clients, which move one pointer together
+    pub pointer: Arc<tokio::sync::Mutex<InputState>>,
+}
+
+/// Routes of the control API under /api/v1
+pub fn router(state: ApiState) -> Router {
+    // Defining and recording macros decides what replaying them types, so
+    // both need the input token as well
+    let input = Router::new()
+        .route("/api/v1/input/keys", post(post_input_keys))
+        .route("/api/v1/input/text", post(post_input_text))
+        .route("/api/v1/input/pointer", post(post_input_pointer))
+        .route("/api/v1/input/macros/{name}", post(play_macro))
+        .route("/api/v1/macros/{name}", put(put_macro).delete(delete_macro))
+        .route("/api/v1/macros/{name}/record", post(start_macro_recording))
+        .route("/api/v1/macros/record/stop", post(stop_macro_recording))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_input_token));
+
+    Router::new()
+        .merge(input)
+        .route("/api/v1/status", get(get_status))
+        .route("/api/v1/events", get(get_events))
+        .route("/api/v1/sessions", get(list_sessions))
+        .route("/api/v1/sessions/{id}", get(get_session))
+        .route("/api/v1/hid/stats", get(get_hid_stats))
+        .route("/api/v1/pointer", get(get_pointer_settings).put(put_pointer_settings))
+        .route("/api/v1/input-lock", get(get_input_lock).put(put_input_lock))
+        .route("/api/v1/macros", get(list_macros))
+        .route("/api/v1/macros/{name}", get(get_macro))
+        .with_state(state)
+}
+
+/// Reject input injection requests without the configured bearer token
+async fn require_input_token(
+    State(state): State<ApiState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, (StatusCode, String)> {
+    let Some(ref token) = state.input_token else {
+        return Err((StatusCode::FORBIDDEN, "Input injection is disabled, see --api-token-file".to_string()));
+    };
+    let provided = request.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match provided {
+        Some(provided) if constant_time_eq(provided.as_bytes(), token.as_bytes()) => Ok(next.run(request).await),
+        _ => Err((StatusCode::UNAUTHORIZED, "Missing or invalid bearer token".to_string())),
+    }
+}
+
+/// Compare secrets without returning early on the first differing byte
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Key combinations to type, e.g. ["ctrl+alt+delete", "enter"]
+#[derive(Debug, Deserialize)]
+pub struct KeysInput {
+    pub keys: Vec<String>,
+}
+
+/// ASCII text to type on a US layout
+#[derive(Debug, Deserialize)]
+pub struct TextInput {
+    pub text: String,
+}
+
+/// Absolute pointer event in framebuffer pixels
+#[derive(Debug, Deserialize)]
+pub struct PointerInput {
+    pub x: u16,
+    pub y: u16,
+    /// Bit 0 left, bit 1 middle, bit 2 right
+    #[serde(default)]
+    pub buttons: u8,
+    /// Release the buttons again afterwards
+    #[serde(default)]
+    pub click: bool,
+}
+
+/// POST /api/v1/input/keys - press and release each key combination in turn
+async fn post_input_keys(
+    State(state): State<ApiState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(input): Json<KeysInput>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let combos = input.keys.iter()
+        .map(|combo| KeyCombo::parse(combo))
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    for combo in &combos {
+        state.audit.record("api", &addr.to_string(), InputClass::Key, || format!("combo={}", combo.text()));
+        state.hid_manager.tap_keys(&combo.usages()).await.map_err(hid_error)?;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// POST /api/v1/input/text - type text, returning when it has been sent
+async fn post_input_text(
+    State(state): State<ApiState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(input): Json<TextInput>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if let Some(c) = input.text.chars().find(|c| keyboard::usage_for_char(*c).is_none()) {
+        return Err((StatusCode::BAD_REQUEST, format!("Cannot type character {:?}", c)));
+    }
+    state.audit.record("api", &addr.to_string(), InputClass::Key, || format!("text={:?}", input.text));
+    state.hid_manager.type_text(&input.text).await.map_err(hid_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// POST /api/v1/input/pointer - move the pointer and set or click buttons
+async fn post_input_pointer(
+    State(state): State<ApiState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(input): Json<PointerInput>,
+) -> StatusCode {
+    let buttons = input.buttons & 0x07;
+    let mut pointer = state.pointer.lock().await;
+    state.vnc.pointer_event(&mut pointer, buttons, input.x, input.y, addr, "api").await;
+    if input.click && buttons != 0 {
+        tokio::time::sleep(CLICK_DURATION).await;
+        state.vnc.pointer_event(&mut pointer, 0, input.x, input.y, addr, "api").await;
+    }
+    StatusCode::NO_CONTENT
+}
+
+/// Input lock state
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InputLock {
+    pub locked: bool,
+}
+
+/// Overall console state
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub capture: CaptureStatus,
+    /// Frame memory budget, shared by every console
+    pub memory: BudgetStatus,
+    /// Egress of the console clients and its caps
+    pub bandwidth: BandwidthStatus,
+    pub resolution: Resolution,
+    pub clients: Vec<SessionInfo>,
+    pub hid: HidStatus,
+    /// Devices the server started without; empty unless degraded
+    pub missing_devices: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Resolution {
+    pub width: u16,
+    pub height: u16,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HidStatus {
+    /// No device's last write failed and the host has configured the gadget (if its UDC is known)
+    pub healthy: bool,
+    pub udc_state: Option<String>,
+    pub input_locked: bool,
+    pub devices: Vec<HidStatsSnapshot>,
+}
+
+/// GET /api/v1/status - capture, clients and HID health in one document
+async fn get_status(State(state): State<ApiState>) -> Json<Status> {
+    let (width, height) = state.vnc.resolution().await;
+    let devices = state.hid_manager.stats();
+    let udc_state = state.hid_manager.udc_state();
+    Json(Status {
+        capture: state.hub.status(),
+        memory: state.hub.budget().status(),
+        bandwidth: state.vnc.sessions().bandwidth().status(),
+        resolution: Resolution { width, height },
+        clients: state.vnc.sessions().list(),
+        hid: HidStatus {
+            healthy: !devices.iter().any(|device| device.failing)
+                && udc_state.as_deref().is_none_or(|udc| udc == "configured"),
+            udc_state,
+            input_locked: state.hid_manager.is_input_locked(),
+            devices,
+        },
+        missing_devices: state.missing_devices.to_vec(),
+    })
+}
+
+/// GET /api/v1/sessions - connected clients with their counters, oldest first
+async fn list_sessions(State(state): State<ApiState>) -> Json<Vec<SessionInfo>> {
+    Json(state.vnc.sessions().list())
+}
+
+/// GET /api/v1/sessions/{id} - live counters of one session
+async fn get_session(
+    State(state): State<ApiState>,
+    Path(id): Path<u64>,
+) -> Result<Json<SessionInfo>, (StatusCode, String)> {
+    state.vnc.sessions().get(id)
+        .map(Json)
+        .ok_or((StatusCode::NOT_FOUND, format!("Unknown session {}", id)))
+}
+
+/// GET /api/v1/events - server-sent events as they happen, one JSON object each
+async fn get_events(State(state): State<ApiState>) -> Sse<impl Stream<Item = Result<sse::Event, Infallible>>> {
+    let rx = state.vnc.events().subscribe();
+    let events = futures_util::stream::unfold(rx, |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(event) => {
+                    let message = sse::Event::default().json_data(&event).ok()?;
+                    return Some((Ok(message), rx));
+                }
+                // Missed events are gone, the client catches up with the next one
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+    // Open streams would hold up a graceful shutdown
+    Sse::new(events.take_until(state.vnc.shutdown().wait())).keep_alive(KeepAlive::default())
+}
+
+/// GET /api/v1/hid/stats - per-device report and error counters
+async fn get_hid_stats(State(state): State<ApiState>) -> Json<Vec<HidStatsSnapshot>> {
+    Json(state.hid_manager.stats())
+}
+
+/// GET /api/v1/pointer - current pointer scaling and calibration
+async fn get_pointer_settings(State(state): State<ApiState>) -> Json<PointerSettings> {
+    Json(state.hid_manager.pointer_settings())
+}
+
+/// PUT /api/v1/pointer - update some or all pointer settings
+async fn put_pointer_settings(
+    State(state): State<ApiState>,
+    Json(update): Json<PointerSettingsUpdate>,
+) -> Result<Json<PointerSettings>, (StatusCode, String)> {
+    let settings = state.hid_manager.pointer_settings().updated(&update);
+    settings.validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    state.hid_manager.set_pointer_settings(settings);
+    info!("Pointer settings updated: {:?}", settings);
+    Ok(Json(settings))
+}
+
+/// GET /api/v1/input-lock - whether input forwarding is blocked
+async fn get_input_lock(State(state): State<ApiState>) -> Json<InputLock> {
+    Json(InputLock {
+        locked: state.hid_manager.is_input_locked(),
+    })
+}
+
+/// PUT /api/v1/input-lock - block or resume input forwarding to the host
+async fn put_input_lock(
+    State(state): State<ApiState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(lock): Json<InputLock>,
+) -> Json<InputLock> {
+    state.hid_manager.set_input_locked(lock.locked).await;
+    state.vnc.sessions().security_audit().log(SecurityEvent::InputLock {
+        client: addr,
+        locked: lock.locked,
+    });
+    Json(InputLock {
+        locked: state.hid_manager.is_input_locked(),
+    })
+}
+
+/// Macro names and recording state
+#[derive(Debug, Serialize)]
+pub struct MacroList {
+    pub macros: Vec<String>,
+    pub recording: Option<String>,
+}
+
+/// Result of finishing a recording
+#[derive(Debug, Serialize)]
+pub struct RecordedMacro {
+    pub name: String,
+    pub steps: usize,
+}
+
+/// GET /api/v1/macros - list stored macros
+async fn list_macros(State(state): State<ApiState>) -> Json<MacroList> {
+    let macros = state.hid_manager.macros();
+    Json(MacroList {
+        macros: macros.names(),
+        recording: macros.recording(),
+    })
+}
+
+/// GET /api/v1/macros/{name} - steps of one macro
+async fn get_macro(
+    State(state): State<ApiState>,
+    Path(name): Path<String>,
+) -> Result<Json<Vec<MacroStep>>, (StatusCode, String)> {
+    state.hid_manager.macros().get(&name)
+        .map(Json)
+        .ok_or((StatusCode::NOT_FOUND, format!("Unknown macro '{}'", name)))
+}
+
+/// PUT /api/v1/macros/{name} - define a macro from explicit steps
+async fn put_macro(
+    State(state): State<ApiState>,
+    Path(name): Path<String>,
+    Json(steps): Json<Vec<MacroStep>>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    state.hid_manager.macros().insert(&name, steps)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// DELETE /api/v1/macros/{name}
+async fn delete_macro(
+    State(state): State<ApiState>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    match state.hid_manager.macros().remove(&name) {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err((StatusCode::NOT_FOUND, format!("Unknown macro '{}'", name))),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+/// POST /api/v1/macros/{name}/record - record subsequent key events into a macro
+async fn start_macro_recording(
+    State(state): State<ApiState>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    state.hid_manager.macros().start_recording(&name)
+        .map_err(|e| (StatusCode::CONFLICT, e.to_string()))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// POST /api/v1/macros/record/stop - finish and store the current recording
+async fn stop_macro_recording(
+    State(state): State<ApiState>,
+) -> Result<Json<RecordedMacro>, (StatusCode, String)> {
+    let (name, steps) = state.hid_manager.macros().stop_recording()
+        .map_err(|e| (StatusCode::CONFLICT, e.to_string()))?;
+    Ok(Json(RecordedMacro { name, steps }))
+}
+
+/// POST /api/v1/input/macros/{name} - replay a macro, returning when it has finished
+async fn play_macro(
+    State(state): State<ApiState>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    state.hid_manager.play_macro(&name).await.map_err(hid_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Status for input the HID devices did not take: refused input is the
+/// request's fault, a powered-off host nobody's, anything else the device's
+fn hid_error(e: HidError) -> (StatusCode, String) {
+    let status = match e {
+        HidError::UnknownMacro(_) => StatusCode::NOT_FOUND,
+        HidError::HostOff => StatusCode::CONFLICT,
+        ref e if e.is_rejected() => StatusCode::BAD_REQUEST,
+        _ => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, e.to_string())
+}
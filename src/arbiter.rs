@@ -0,0 +1,210 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Console WebSocket session limits and input arbitration for kvm-rs
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use clap::ValueEnum;
+use serde::Serialize;
+use tokio::sync::watch;
+
+/// How concurrent WebSocket clients of one console share it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SessionPolicy {
+    /// Every client may send input; clients over the limit are refused
+    Shared,
+    /// The oldest client controls, the others view until it leaves
+    SingleController,
+    /// Every client may send input; at the limit the oldest client is disconnected
+    PreemptOldest,
+}
+
+/// What a client may do, reported to kvm-rs clients as a "role" control message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    Controller,
+    Viewer,
+    /// Disconnected to make room for a newer client
+    Preempted,
+}
+
+impl Role {
+    /// Name as in the "role" control message
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Controller => "controller",
+            Role::Viewer => "viewer",
+            Role::Preempted => "preempted",
+        }
+    }
+}
+
+/// Admits the WebSocket clients of one console and assigns their roles
+#[derive(Clone)]
+pub struct Arbiter {
+    policy: SessionPolicy,
+    max_sessions: Option<usize>,
+    /// How long the seat of a dropped connection is held for its resume token
+    resume_grace: Option<Duration>,
+    inner: Arc<Mutex<Seats>>,
+}
+
+#[derive(Default)]
+struct Seats {
+    next_id: u64,
+    /// Role of every admitted client, oldest first
+    roles: BTreeMap<u64, watch::Sender<Role>>,
+    /// Clients whose user may not send input; they stay viewers
+    observers: HashSet<u64>,
+    /// Seats of dropped connections by resume token
+    parked: HashMap<String, u64>,
+}
+
+impl Arbiter {
+    /// A max_sessions of 0 admits any number of clients
+    pub fn new(policy: SessionPolicy, max_sessions: usize) -> Self {
+        Self {
+            policy,
+            max_sessions: (max_sessions > 0).then_some(max_sessions),
+            resume_grace: None,
+            inner: Arc::default(),
+        }
+    }
+
+    /// Hold the seat of a client whose connection dropped for `grace`, so
+    /// it can reconnect with its resume token and keep its place and role
+    pub fn with_resume_grace(mut self, grace: Duration) -> Self {
+        self.resume_grace = (!grace.is_zero()).then_some(grace);
+        self
+    }
+
+    pub fn resume_grace(&self) -> Option<Duration> {
+        self.resume_grace
+    }
+
+    /// Admit a client, None if the console is full. Clients that may not
+    /// control are viewers whatever the policy, and never promoted
+    pub fn join(&self, may_control: bool) -> Option<Seat> {
+        let mut seats = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        if self.max_sessions.is_some_and(|max| seats.roles.len() >= max) {
+            if self.policy != SessionPolicy::PreemptOldest {
+                return None;
+            }
+            let (oldest_id, oldest) = seats.roles.pop_first()?;
+            seats.observers.remove(&oldest_id);
+            oldest.send_replace(Role::Preempted);
+        }
+
+        let controlled = seats.roles.values().any(|role| *role.borrow() == Role::Controller);
+        let role = if !may_control || (self.policy == SessionPolicy::SingleController && controlled) {
+            Role::Viewer
+        } else {
+            Role::Controller
+        };
+        let (role_tx, role) = watch::channel(role);
+        seats.next_id += 1;
+        let id = seats.next_id;
+        seats.roles.insert(id, role_tx);
+        if !may_control {
+            seats.observers.insert(id);
+        }
+        Some(self.seat(id, role))
+    }
+
+    /// Resume the seat of `resume_token` if it is still parked, else join anew
+    pub fn admit(&self, resume_token: Option<&str>, may_control: bool) -> Option<Seat> {
+        resume_token.and_then(|token| self.resume(token, may_control)).or_else(|| self.join(may_control))
+    }
+
+    /// Take back the seat parked under `token`, None if it has expired or was
+    /// preempted, or may control while the resuming client may not
+    pub fn resume(&self, token: &str, may_control: bool) -> Option<Seat> {
+        let mut seats = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let id = *seats.parked.get(token)?;
+        if !may_control && !seats.observers.contains(&id) {
+            return None;
+        }
+        seats.parked.remove(token);
+        let role = seats.roles.get(&id)?.subscribe();
+        Some(self.seat(id, role))
+    }
+
+    fn seat(&self, id: u64, role: watch::Receiver<Role>) -> Seat {
+        // Without a random source the client just cannot resume
+        let resume_token = self.resume_grace.and_then(|_| crate::credentials::random_token().ok());
+        Seat {
+            arbiter: self.clone(),
+            id,
+            role,
+            resume_token,
+            user: None,
+            parked: false,
+        }
+    }
+
+    fn leave(&self, id: u64) {
+        let mut seats = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(role) = seats.roles.remove(&id) else {
+            return;
+        };
+        seats.observers.remove(&id);
+        // Hand control to the oldest viewer that may control
+        if *role.borrow() == Role::Controller && self.policy == SessionPolicy::SingleController {
+            let next = seats.roles.iter().find(|(id, _)| !seats.observers.contains(id));
+            if let Some((_, next)) = next {
+                next.send_replace(Role::Controller);
+            }
+        }
+    }
+}
+
+/// An admitted client; its place is freed when dropped
+pub struct Seat {
+    arbiter: Arbiter,
+    id: u64,
+    /// Changes when the client is promoted or preempted
+    pub role: watch::Receiver<Role>,
+    /// Token the client reconnects with to resume this seat, if resuming is enabled
+    pub resume_token: Option<String>,
+    /// Account the client authenticated as, set by the transport
+    pub user: Option<String>,
+    /// Parked seats are freed by the grace timer, not on drop
+    parked: bool,
+}
+
+impl Seat {
+    /// Keep the seat for its resume token after the connection dropped;
+    /// it is freed when the grace period ends without a reconnect
+    pub fn park(mut self) {
+        let (Some(grace), Some(token)) = (self.arbiter.resume_grace, self.resume_token.take()) else {
+            return;
+        };
+        if *self.role.borrow() == Role::Preempted {
+            return;
+        }
+        let arbiter = self.arbiter.clone();
+        let id = self.id;
+        arbiter.inner.lock().unwrap_or_else(|e| e.into_inner()).parked.insert(token.clone(), id);
+        self.parked = true;
+        tokio::spawn(async move {
+            tokio::time::sleep(grace).await;
+            let expired = {
+                let mut seats = arbiter.inner.lock().unwrap_or_else(|e| e.into_inner());
+                seats.parked.remove(&token).is_some()
+            };
+            if expired {
+                arbiter.leave(id);
+            }
+        });
+    }
+}
+
+impl Drop for Seat {
+    fn drop(&mut self) {
+        if !self.parked {
+            self.arbiter.leave(self.id);
+        }
+    }
+}
@@ -2,13 +2,48 @@
 //
 // Command line argument parsing for kvm-rs
 
-use clap::Parser;
+use std::ffi::OsString;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use anyhow::Context;
+use clap::{parser::ValueSource, ArgAction, CommandFactory, Parser, Subcommand};
+use crate::arbiter::SessionPolicy;
+use crate::auth::{AuthMode, UserRole};
+use crate::credentials::Credential;
+use crate::display::ChromaSubsampling;
+use crate::ip_filter::Cidr;
+use crate::ldap::{LdapSource, RoleMapping};
+use crate::platform::Platform;
+use crate::hid::PowerOffInput;
+use crate::hid_descriptor::ReportValidation;
+use crate::keyboard::{KeyRepeatPolicy, KeyboardLayout, KeyboardProtocol};
+use crate::logging::LogTarget;
+use crate::obmc_ikvm;
+use crate::services::Service;
+use crate::targets::ConsoleSpec;
+
+/// Where distributions install noVNC
+const DEFAULT_NOVNC_DIR: &str = "/usr/share/novnc";
 
 /// KVM-RS: Minimal KVM-IP server for OpenBMC
 #[derive(Parser, Debug)]
 #[command(name = "kvm-rs")]
 #[command(about = "Minimal KVM-IP server for OpenBMC")]
+#[command(args_override_self = true)]
 pub struct Args {
+    /// File of further options, one `name = value` (or `name` for flags) per line; command line options take precedence
+    #[arg(long = "config")]
+    pub config: Option<String>,
+
+    /// Preset options of a BMC platform: ast2500, ast2600, npcm845 or qemu-dev; the --config file and command line override them
+    #[arg(long = "platform", value_enum)]
+    pub platform: Option<Platform>,
+
+    /// What to run; options go before it
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Video device path (V4L2 video device or framebuffer)
     #[arg(short = 'v', long = "video", default_value = "/dev/video0")]
     pub video_device: String,
@@ -17,6 +52,14 @@ pub struct Args {
     #[arg(long = "force-framebuffer")]
     pub force_framebuffer: bool,
 
+    /// Frame rate asked of V4L2 capture devices; by default the device's own
+    #[arg(long = "frame-rate", value_name = "FPS", value_parser = clap::value_parser!(u32).range(1..))]
+    pub frame_rate: Option<u32>,
+
+    /// JPEG chroma subsampling asked of V4L2 capture devices that encode frames: 444 or 420
+    #[arg(long = "jpeg-subsampling", value_enum)]
+    pub jpeg_subsampling: Option<ChromaSubsampling>,
+
     /// HID gadget device for keyboard input
     #[arg(short = 'k', long = "keyboard-hid", default_value = "/dev/hidg0")]
     pub keyboard_hid: String,
@@ -25,72 +68,1129 @@ pub struct Args {
     #[arg(short = 'm', long = "mouse-hid", default_value = "/dev/hidg1")]
     pub mouse_hid: String,
 
+    /// HID gadget device for single-touch touchscreen input (used for pointer positioning when set)
+    #[arg(long = "touchscreen-hid")]
+    pub touchscreen_hid: Option<String>,
+
+    /// Bind the gadget of --keyboard-hid to this UDC at startup unless it is bound; `auto` picks the first free one
+    #[arg(long = "bind-udc", value_name = "UDC")]
+    pub bind_udc: Option<String>,
+
+    /// Build the composite USB gadget NAME in configfs at startup, with keyboard, mouse and --mass-storage functions, and use its HID devices in place of --keyboard-hid and --mouse-hid; bound to --bind-udc or the first free UDC
+    #[arg(long = "create-gadget", value_name = "NAME")]
+    pub create_gadget: Option<String>,
+
+    /// Add a touchscreen function to the --create-gadget gadget, used as --touchscreen-hid
+    #[arg(long = "gadget-touchscreen", requires = "create_gadget")]
+    pub gadget_touchscreen: bool,
+
+    /// CDC-ACM function of the keyboard's USB gadget (e.g. acm.agent) an agent in the host OS talks to kvm-rs over, as a serial port of the host; created if missing
+    #[arg(long = "host-agent", value_name = "FUNCTION")]
+    pub host_agent: Option<String>,
+
+    /// Serve the host's serial console, the obmc-console that IPMI SOL attaches to, at the /sol WebSocket and the /serial page; CONSOLE is its ID, `default` for the console without one
+    #[arg(long = "sol", value_name = "CONSOLE")]
+    pub sol: Option<String>,
+
+    /// Keyboard HID report descriptor file (defaults to the gadget's configfs report_desc)
+    #[arg(long = "keyboard-report-desc")]
+    pub keyboard_report_desc: Option<String>,
+
+    /// Mouse HID report descriptor file (defaults to the gadget's configfs report_desc)
+    #[arg(long = "mouse-report-desc")]
+    pub mouse_report_desc: Option<String>,
+
+    /// Accept raw HID reports (input messages 0x81-0x83) from kvm-rs WebSocket clients, written to the gadgets after --report-validation
+    #[arg(long = "ws-raw-reports")]
+    pub ws_raw_reports: bool,
+
+    /// How raw HID reports from WebSocket clients are validated
+    #[arg(long = "report-validation", value_enum, default_value = "sanitize")]
+    pub report_validation: ReportValidation,
+
+    /// Record HID reports in memory and log them instead of writing to gadget devices
+    #[arg(long = "mock-hid")]
+    pub mock_hid: bool,
+
+    /// Play a moving test pattern instead of capturing from the video devices
+    #[arg(long = "mock-video")]
+    pub mock_video: bool,
+
+    /// Play the frames of a mock capture script instead of capturing from the video devices
+    #[arg(long = "mock-video-script")]
+    pub mock_video_script: Option<PathBuf>,
+
+    /// What to do when a video or HID device does not exist at startup: fail, wait for it, or degrade (serve without it, listed in the status API)
+    #[arg(long = "missing-devices", value_enum, default_value = "degrade")]
+    pub missing_devices: MissingDevicePolicy,
+
+    /// Seconds --missing-devices wait waits for the devices before failing (0 = no limit)
+    #[arg(long = "device-wait-timeout", default_value = "0")]
+    pub device_wait_timeout: u64,
+
+    /// Touchscreen HID report descriptor file (defaults to the gadget's configfs report_desc)
+    #[arg(long = "touchscreen-report-desc")]
+    pub touchscreen_report_desc: Option<String>,
+
+    /// Keyboard protocol reports are built for: report, boot or auto (follow the host)
+    #[arg(long = "keyboard-protocol", value_enum, default_value = "report")]
+    pub keyboard_protocol: KeyboardProtocol,
+
+    /// Physical layout of the host's keyboard: us, or iso to type < and > with
+    /// the extra key of 102-key keyboards
+    #[arg(long = "keyboard-layout", value_enum, default_value = "us")]
+    pub keyboard_layout: KeyboardLayout,
+
+    /// Key repeat policy for held keys
+    #[arg(long = "key-repeat", value_enum, default_value = "passthrough")]
+    pub key_repeat: KeyRepeatPolicy,
+
+    /// Delay in milliseconds before server-side key repeat starts
+    #[arg(long = "key-repeat-delay", default_value = "500")]
+    pub key_repeat_delay: u64,
+
+    /// Server-side key repeat rate in repeats per second
+    #[arg(long = "key-repeat-rate", default_value = "20")]
+    pub key_repeat_rate: u32,
+
+    /// Horizontal scaling factor for relative mouse motion
+    #[arg(long = "mouse-scale-x", default_value = "1.0")]
+    pub mouse_scale_x: f32,
+
+    /// Vertical scaling factor for relative mouse motion
+    #[arg(long = "mouse-scale-y", default_value = "1.0")]
+    pub mouse_scale_y: f32,
+
+    /// Acceleration exponent for relative mouse motion (0 = linear)
+    #[arg(long = "mouse-acceleration", default_value = "0.0")]
+    pub mouse_acceleration: f32,
+
+    /// Horizontal calibration offset in pixels for absolute pointer positioning
+    #[arg(long = "mouse-offset-x", default_value = "0", allow_negative_numbers = true)]
+    pub mouse_offset_x: i32,
+
+    /// Vertical calibration offset in pixels for absolute pointer positioning
+    #[arg(long = "mouse-offset-y", default_value = "0", allow_negative_numbers = true)]
+    pub mouse_offset_y: i32,
+
+    /// Key combination never forwarded to the host, e.g. "Alt+SysRq" (repeatable or comma-separated)
+    #[arg(long = "block-keys", value_name = "COMBO", value_delimiter = ',')]
+    pub block_keys: Vec<String>,
+
+    /// JSON file keyboard macros are loaded from and saved to
+    #[arg(long = "macro-file")]
+    pub macro_file: Option<String>,
+
+    /// Directory virtual media images are uploaded to; uploads are disabled without it
+    #[arg(long = "media-dir")]
+    pub media_dir: Option<String>,
+
+    /// Largest virtual media image accepted for upload, in MiB
+    #[arg(long = "media-max-size", default_value = "4096")]
+    pub media_max_size: u64,
+
+    /// Mass-storage function of the keyboard's USB gadget (e.g. mass_storage.kvm) uploaded images are inserted in; created if missing
+    #[arg(long = "mass-storage", value_name = "FUNCTION", requires = "media_dir")]
+    pub mass_storage: Option<String>,
+
+    /// Offer the virtual media drive to bmcweb's Redfish VirtualMedia as the xyz.openbmc_project.VirtualMedia service, in place of the virtual-media daemon
+    #[arg(long = "redfish-virtual-media", requires = "mass_storage")]
+    pub redfish_virtual_media: bool,
+
+    /// CA certificates (PEM) https virtual media servers are verified against; required for https image URLs
+    #[arg(long = "remote-media-ca", value_name = "FILE")]
+    pub remote_media_ca: Option<PathBuf>,
+
+    /// NBD device http and https virtual media images are served on
+    #[arg(long = "nbd-device", value_name = "PATH", default_value = "/dev/nbd0")]
+    pub nbd_device: PathBuf,
+
+    /// Memory for caching blocks of http and https virtual media images, in MiB
+    #[arg(long = "remote-media-cache", value_name = "MIB", default_value = "16")]
+    pub remote_media_cache: u64,
+
+    /// Let operators turn host 0 on and off, power cycle and reset it through the REST API and console clients (needs D-Bus)
+    #[arg(long = "power-control")]
+    pub power_control: bool,
+
+    /// What happens to console 0 input while host 0 is powered off: reject it, or queue it until the host is on
+    #[arg(long = "power-off-input", value_enum, default_value = "reject")]
+    pub power_off_input: PowerOffInput,
+
+    /// JPEG image shown on console 0 while host 0 is powered off, instead of a dark screen
+    #[arg(long = "power-off-image", value_name = "FILE")]
+    pub power_off_image: Option<PathBuf>,
+
     /// Port to listen on
     #[arg(short = 'p', long = "port", default_value = "8443")]
     pub port: u16,
 
+    /// Addresses the web server listens on instead of --bind and --port, e.g. [::]:8443,0.0.0.0:8443 (repeatable or comma-separated)
+    #[arg(long = "listen", value_name = "ADDR:PORT", value_delimiter = ',')]
+    pub listen: Vec<SocketAddr>,
+
+    /// Unix socket path the web server listens on instead of --port, for a local reverse proxy
+    #[arg(long = "unix-socket")]
+    pub unix_socket: Option<String>,
+
     /// VNC server port
     #[arg(long = "vnc-port", default_value = "5900")]
     pub vnc_port: u16,
 
+    /// Addresses the VNC server listens on instead of --bind and --vnc-port (repeatable or comma-separated)
+    #[arg(long = "vnc-listen", value_name = "ADDR:PORT", value_delimiter = ',')]
+    pub vnc_listen: Vec<SocketAddr>,
+
+    /// Console of a further host, as ID:video=PATH,keyboard=PATH,mouse=PATH[,touchscreen=PATH][,vnc=ADDR:PORT] (repeatable)
+    #[arg(long = "console", value_name = "ID:SETTINGS")]
+    pub consoles: Vec<ConsoleSpec>,
+
     /// Enable TLS encryption for VNC server
     #[arg(long = "vnc-tls")]
     pub vnc_tls: bool,
 
-    /// TLS certificate file path (PEM format)
+    /// TLS certificate file path (PEM format), for VNC and HTTPS
     #[arg(long = "vnc-cert")]
     pub vnc_cert: Option<String>,
 
-    /// TLS private key file path (PEM format)
+    /// TLS private key file path (PEM format), for VNC and HTTPS
     #[arg(long = "vnc-key")]
     pub vnc_key: Option<String>,
 
-    /// Bind address
+    /// Use the HTTPS certificate installed through Redfish CertificateService (xyz.openbmc_project.Certs) instead of --vnc-cert/--vnc-key
+    #[arg(long = "openbmc-certs", conflicts_with_all = ["vnc_cert", "vnc_key"])]
+    pub openbmc_certs: bool,
+
+    /// Encrypted store for secrets such as the VNC password, instead of plaintext files
+    #[arg(long = "credential-store")]
+    pub credential_store: Option<String>,
+
+    /// File with the device secret the credential store key is derived from
+    #[arg(long = "device-secret", default_value = "/etc/machine-id")]
+    pub device_secret: String,
+
+    /// Open the devices and load the files the server would use, print a JSON report and exit, non-zero if anything fails
+    #[arg(long = "check")]
+    pub check: bool,
+
+    /// Print the effective options and where each came from, as toml (the default) or json, and exit
+    #[arg(long = "dump-config", value_name = "FORMAT", value_enum, num_args = 0..=1, require_equals = true, default_missing_value = "toml")]
+    pub dump_config: Option<ConfigFormat>,
+
+    /// Read a secret from stdin, save it in the credential store and exit
+    #[arg(long = "set-credential", value_enum, requires = "credential_store")]
+    pub set_credential: Option<Credential>,
+
+    /// Certificate for a server name clients ask for by SNI, as NAME=CERT,KEY (PEM; repeatable)
+    #[arg(long = "sni-cert", value_name = "NAME=CERT,KEY")]
+    pub sni_certs: Vec<SniCert>,
+
+    /// CA bundle (PEM) client certificates of TLS connections must be issued by; clients without one are refused
+    #[arg(long = "client-ca")]
+    pub client_ca: Option<String>,
+
+    /// Password VNC port clients must give: a vncpasswd file, or a file with a crypt(3) hash (--auth none only)
+    #[arg(long = "vnc-password-file")]
+    pub vnc_password_file: Option<String>,
+
+    /// Serve the web console, WebSocket and REST API over HTTPS
+    #[arg(long = "https")]
+    pub https: bool,
+
+    /// Plain HTTP port that redirects to the HTTPS port (plain HTTP is refused otherwise)
+    #[arg(long = "http-redirect-port", requires = "https")]
+    pub http_redirect_port: Option<u16>,
+
+    /// Seconds between WebSocket pings; a client that misses a pong is disconnected
+    #[arg(long = "ws-ping-interval", default_value = "30", value_parser = clap::value_parser!(u64).range(1..))]
+    pub ws_ping_interval: u64,
+
+    /// Seconds without client messages before a WebSocket is closed (0 = never)
+    #[arg(long = "ws-idle-timeout", default_value = "0")]
+    pub ws_idle_timeout: u64,
+
+    /// Memory for captured frames and client update buffers, in MiB; frames and clients that do not fit are dropped (0 = no limit)
+    #[arg(long = "frame-memory-budget", value_name = "MIB", default_value = "64")]
+    pub frame_memory_budget: usize,
+
+    /// Bytes per second sent to each console client, in KiB; clients over it skip frames (0 = no limit)
+    #[arg(long = "client-bandwidth-limit", value_name = "KIB", default_value = "0")]
+    pub client_bandwidth_limit: u64,
+
+    /// Bytes per second sent to all console clients together, in KiB; clients skip frames while it is reached (0 = no limit)
+    #[arg(long = "bandwidth-limit", value_name = "KIB", default_value = "0")]
+    pub bandwidth_limit: u64,
+
+    /// Directory captured frames are dumped to for debugging, when asked for at /api/v1/debug/frame-dump or over D-Bus
+    #[arg(long = "frame-dump-dir", value_name = "DIR", default_value = "/tmp")]
+    pub frame_dump_dir: PathBuf,
+
+    /// JPEG quality (1-100) of kvm-rs WebSocket subprotocol and MJPEG stream frames
+    #[arg(long = "ws-jpeg-quality", default_value = "80", value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub ws_jpeg_quality: u8,
+
+    /// How concurrent WebSocket clients of a console share it
+    #[arg(long = "ws-policy", value_enum, default_value = "shared")]
+    pub ws_policy: SessionPolicy,
+
+    /// Maximum WebSocket clients per console (0 = unlimited)
+    #[arg(long = "ws-max-sessions", default_value = "0")]
+    pub ws_max_sessions: usize,
+
+    /// Seconds a dropped kvm-rs session's seat is held for its resume token (0 = no resuming)
+    #[arg(long = "ws-resume-grace", default_value = "30")]
+    pub ws_resume_grace: u64,
+
+    /// Let kvm-rs subprotocol clients move video and input to WebRTC data channels
+    #[arg(long = "webrtc")]
+    pub webrtc: bool,
+
+    /// STUN/TURN server URLs for WebRTC (e.g. stun:stun.example.com:3478)
+    #[arg(long = "webrtc-ice-servers", value_name = "URL", value_delimiter = ',')]
+    pub webrtc_ice_servers: Vec<String>,
+
+    /// Username for the TURN servers
+    #[arg(long = "webrtc-ice-username")]
+    pub webrtc_ice_username: Option<String>,
+
+    /// Credential for the TURN servers
+    #[arg(long = "webrtc-ice-credential")]
+    pub webrtc_ice_credential: Option<String>,
+
+    /// UDP port of the WebTransport (HTTP/3) console endpoint; uses the TLS certificate
+    #[arg(long = "webtransport-port")]
+    pub webtransport_port: Option<u16>,
+
+    /// Directory of a noVNC installation the web console uses instead of its built-in viewer, served under /novnc
+    #[arg(long = "novnc-dir", default_value = DEFAULT_NOVNC_DIR)]
+    pub novnc_dir: String,
+
+    /// Path prefix all web routes are served under, e.g. /kvm-rs for a reverse proxy that does not rewrite paths
+    #[arg(long = "base-path", default_value = "/", value_parser = parse_base_path)]
+    pub base_path: String,
+
+    /// Authentication of console, stream and (with local) web UI and REST connections
+    #[arg(long = "auth", value_enum, default_value = "none")]
+    pub auth: AuthMode,
+
+    /// Base URL of the Redfish service (bmcweb) session tokens are checked against; https:// unless it is on a loopback address
+    #[arg(long = "redfish-url", default_value = "https://127.0.0.1")]
+    pub redfish_url: String,
+
+    /// CA certificate (PEM) for the Redfish service; required unless it is on a loopback address
+    #[arg(long = "redfish-ca")]
+    pub redfish_ca: Option<String>,
+
+    /// Role of bmcweb session holders (--auth redfish) whose account is not known, e.g. LDAP users
+    #[arg(long = "redfish-role", value_enum, default_value = "observer")]
+    pub redfish_role: UserRole,
+
+    /// Failed logins in a row after which the source address or account is locked out (0 = never)
+    #[arg(long = "auth-max-failures", default_value = "5")]
+    pub auth_max_failures: u32,
+
+    /// Seconds a source address or account stays locked out
+    #[arg(long = "auth-lockout", default_value = "300")]
+    pub auth_lockout: u64,
+
+    /// Seconds a login page session may stay unused before it expires (--auth local)
+    #[arg(long = "login-timeout", default_value = "1800")]
+    pub login_timeout: u64,
+
+    /// Also accept LDAP or Active Directory accounts at local logins, configured by: openbmc (phosphor-ldap-config over D-Bus) or direct (the --ldap-* options)
+    #[arg(long = "ldap", value_enum)]
+    pub ldap: Option<LdapSource>,
+
+    /// LDAP server, ldap://HOST[:PORT] or ldaps://HOST[:PORT]
+    #[arg(long = "ldap-url", value_name = "URL")]
+    pub ldap_url: Option<String>,
+
+    /// Base DN user entries and groups are searched under
+    #[arg(long = "ldap-base-dn", value_name = "DN")]
+    pub ldap_base_dn: Option<String>,
+
+    /// DN user entries are looked up as; anonymous without one
+    #[arg(long = "ldap-bind-dn", value_name = "DN")]
+    pub ldap_bind_dn: Option<String>,
+
+    /// File holding the password of --ldap-bind-dn (defaults to the credential store)
+    #[arg(long = "ldap-bind-password-file", value_name = "FILE")]
+    pub ldap_bind_password_file: Option<PathBuf>,
+
+    /// Attribute of user entries holding the login name, e.g. sAMAccountName for Active Directory
+    #[arg(long = "ldap-user-attribute", default_value = "uid")]
+    pub ldap_user_attribute: String,
+
+    /// Attribute of user entries naming their groups
+    #[arg(long = "ldap-group-attribute", default_value = "memberOf")]
+    pub ldap_group_attribute: String,
+
+    /// Role of the members of an LDAP group, GROUP=ROLE with ROLE observer, operator or admin (repeatable)
+    #[arg(long = "ldap-role-map", value_name = "GROUP=ROLE")]
+    pub ldap_role_map: Vec<RoleMapping>,
+
+    /// CA certificates ldaps:// servers are verified against (defaults to the BMC's LDAP CA certificates with --ldap openbmc)
+    #[arg(long = "ldap-ca", value_name = "FILE")]
+    pub ldap_ca: Option<PathBuf>,
+
+    /// Extra origins (or host names) whose pages may open /kvm/0; "*" allows any
+    #[arg(long = "allowed-origins", value_name = "ORIGIN", value_delimiter = ',')]
+    pub allowed_origins: Vec<String>,
+
+    /// Extra host names the console may be reached by, besides IP addresses and the BMC's hostname
+    #[arg(long = "allowed-hosts", value_name = "HOST", value_delimiter = ',')]
+    pub allowed_hosts: Vec<String>,
+
+    /// Origins whose pages may call the REST API and read /stream.mjpg cross-origin; "*" allows any
+    #[arg(long = "cors-origins", value_name = "ORIGIN", value_delimiter = ',')]
+    pub cors_origins: Vec<String>,
+
+    /// HTTP methods allowed in cross-origin requests
+    #[arg(long = "cors-methods", value_name = "METHOD", value_delimiter = ',', default_value = "GET,HEAD")]
+    pub cors_methods: Vec<String>,
+
+    /// File with the bearer token for the /api/v1/input endpoints (disabled without it)
+    #[arg(long = "api-token-file")]
+    pub api_token_file: Option<String>,
+
+    /// Input audit trail destination: a file path (rotated by size) or "journald"
+    #[arg(long = "input-audit")]
+    pub input_audit: Option<String>,
+
+    /// Record key and pointer contents in the input audit trail, not only event classes
+    #[arg(long = "input-audit-full")]
+    pub input_audit_full: bool,
+
+    /// Maximum input audit file size in bytes before rotation
+    #[arg(long = "input-audit-max-size", default_value = "1048576")]
+    pub input_audit_max_size: u64,
+
+    /// Messages to log: a level (error, warn, info, debug, trace), optionally with per-module levels, e.g. "info,kvm_rs::vnc=debug"
+    #[arg(long = "log-level", default_value = "info")]
+    pub log_level: String,
+
+    /// Where log messages go: stdout, or journald entries with SYSLOG_IDENTIFIER kvm-rs and SUBSYSTEM, SESSION_ID and PEER fields
+    #[arg(long = "log-target", value_enum, default_value = "stdout")]
+    pub log_target: LogTarget,
+
+    /// Send security events (connections, logins, lockouts, sessions, role changes) to journald
+    #[arg(long = "security-audit")]
+    pub security_audit: bool,
+
+    /// URLs console events are POSTed to as JSON: sessions started and ended, authentication failures, video lost and restored
+    #[arg(long = "webhook", value_name = "URL", value_delimiter = ',')]
+    pub webhooks: Vec<String>,
+
+    /// CA certificates (PEM) https webhooks are verified against; required for https URLs
+    #[arg(long = "webhook-ca", value_name = "FILE")]
+    pub webhook_ca: Option<PathBuf>,
+
+    /// Further attempts to deliver an event to a webhook that failed, with doubling delays from 2s
+    #[arg(long = "webhook-retries", default_value = "3")]
+    pub webhook_retries: u32,
+
+    /// Do not log web server requests (method, path, status, duration, peer and user)
+    #[arg(long = "no-access-log")]
+    pub no_access_log: bool,
+
+    /// Seconds after which VNC and WebSocket sessions are closed, whatever their activity (0 = no limit)
+    #[arg(long = "max-session-duration", default_value = "0")]
+    pub max_session_duration: u64,
+
+    /// Seconds before the maximum session duration that clients are warned
+    #[arg(long = "session-expiry-warning", default_value = "60")]
+    pub session_expiry_warning: u64,
+
+    /// Seconds to wait for clients to disconnect on SIGTERM before exiting
+    #[arg(long = "shutdown-timeout", default_value = "10")]
+    pub shutdown_timeout: u64,
+
+    /// Open web server connections allowed per client address (0 = unlimited)
+    #[arg(long = "ip-max-connections", default_value = "32")]
+    pub ip_max_connections: u32,
+
+    /// Sustained HTTP requests per second allowed per client address (0 = unlimited)
+    #[arg(long = "ip-request-rate", default_value = "20")]
+    pub ip_request_rate: f64,
+
+    /// HTTP requests a client address may send in a burst above the rate
+    #[arg(long = "ip-request-burst", default_value = "100")]
+    pub ip_request_burst: u32,
+
+    /// Only accept web and VNC connections from these addresses (CIDR, e.g. 10.0.0.0/24; repeatable or comma-separated)
+    #[arg(long = "allow-ip", value_name = "CIDR", value_delimiter = ',')]
+    pub allow_ip: Vec<Cidr>,
+
+    /// Refuse web and VNC connections from these addresses (CIDR; repeatable or comma-separated)
+    #[arg(long = "deny-ip", value_name = "CIDR", value_delimiter = ',')]
+    pub deny_ip: Vec<Cidr>,
+
+    /// Services to start turned off: vnc, websocket, screenshot (repeatable or comma-separated; turned on at runtime over the API or D-Bus)
+    #[arg(long = "disable-service", value_name = "SERVICE", value_enum, value_delimiter = ',')]
+    pub disable_service: Vec<Service>,
+
+    /// Advertise the VNC ports and the web console over mDNS/DNS-SD through Avahi, named after the BMC's hostname
+    #[arg(long = "mdns")]
+    pub mdns: bool,
+
+    /// User to switch to once the listeners are bound, with its groups, giving up root and every capability; kvm-rs must be started as root
+    #[arg(long = "user")]
+    pub user: Option<String>,
+
+    /// Group to switch to with --user, instead of the user's own
+    #[arg(long = "group", requires = "user")]
+    pub group: Option<String>,
+
+    /// Once initialized, refuse system calls such as execve, ptrace, mount and module loading (needs the seccomp feature)
+    #[arg(long = "seccomp")]
+    pub seccomp: bool,
+
+    /// Bind address, IPv4 or IPv6
     #[arg(short = 'b', long = "bind", default_value = "0.0.0.0")]
-    pub bind_address: String,
+    pub bind_address: IpAddr,
+}
+
+/// What happens when a device named by the options does not exist at startup
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissingDevicePolicy {
+    /// Exit with an error
+    Fail,
+    /// Wait for the devices to appear, e.g. a gadget configured after the service starts
+    Wait,
+    /// Serve without them: mock video, input dropped by the missing gadgets
+    Degrade,
+}
+
+/// Output of --dump-config
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Json,
+}
+
+/// Where the effective value of an option came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptionSource {
+    Default,
+    ConfigFile,
+    Platform,
+    Environment,
+    CommandLine,
+}
+
+impl OptionSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OptionSource::Default => "default",
+            OptionSource::ConfigFile => "config file",
+            OptionSource::Platform => "platform preset",
+            OptionSource::Environment => "environment",
+            OptionSource::CommandLine => "command line",
+        }
+    }
+}
+
+/// Effective value of one option, as listed by --dump-config
+#[derive(Debug)]
+pub struct EffectiveOption {
+    /// Long name, as used in the --config file
+    pub name: String,
+    pub values: Vec<String>,
+    /// Whether the option takes no value
+    pub flag: bool,
+    /// Whether the option can be given several times
+    pub repeatable: bool,
+    pub source: OptionSource,
+}
+
+/// Certificate served to clients asking for a server name by SNI, given as
+/// `NAME=CERT,KEY`; a name of `*.example.com` matches one label
+#[derive(Debug, Clone)]
+pub struct SniCert {
+    pub name: String,
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+impl FromStr for SniCert {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (name, files) = spec.split_once('=').ok_or("expected NAME=CERT,KEY")?;
+        let (cert_path, key_path) = files.split_once(',').ok_or("expected NAME=CERT,KEY")?;
+        if name.is_empty() || cert_path.is_empty() || key_path.is_empty() {
+            return Err("expected NAME=CERT,KEY".to_string());
+        }
+        Ok(Self {
+            name: name.to_ascii_lowercase(),
+            cert_path: cert_path.to_string(),
+            key_path: key_path.to_string(),
+        })
+    }
+}
+
+/// Commands of the binary; without one the server runs
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Run the KVM server
+    Serve,
+    /// Capture one frame of --video and save it as JPEG
+    Screenshot {
+        file: PathBuf,
+        /// JPEG quality for frames not captured as JPEG
+        #[arg(long = "quality", default_value = "85", value_parser = clap::value_parser!(u8).range(1..=100))]
+        quality: u8,
+    },
+    /// Type text on the keyboard gadget
+    InjectKeys {
+        /// Text to type; with --combo, key combinations such as ctrl+alt+delete pressed in turn
+        #[arg(required = true)]
+        text: Vec<String>,
+        #[arg(long = "combo")]
+        combo: bool,
+    },
+    /// Print a JSON report of the capture devices, HID gadgets and codecs found
+    Probe,
+    /// Check the options and the files they name, then exit
+    CheckConfig,
 }
 
 impl Args {
-    /// Validate that the specified device paths exist
-    pub fn validate_devices(&self) {
-        if !std::path::Path::new(&self.video_device).exists() {
-            eprintln!("Warning: Video device {} does not exist", self.video_device);
+    /// Addresses of the web server: --listen, or --bind with --port
+    pub fn listen_addrs(&self) -> Vec<SocketAddr> {
+        if self.listen.is_empty() {
+            vec![SocketAddr::new(self.bind_address, self.port)]
+        } else {
+            self.listen.clone()
         }
-        if !std::path::Path::new(&self.keyboard_hid).exists() {
-            eprintln!("Warning: Keyboard HID device {} does not exist", self.keyboard_hid);
+    }
+
+    /// The web server's addresses with `port`, for the listeners beside it:
+    /// WebTransport and the plain HTTP redirect
+    pub fn listen_addrs_on(&self, port: u16) -> Vec<SocketAddr> {
+        let mut addrs = Vec::new();
+        for addr in self.listen_addrs() {
+            let addr = SocketAddr::new(addr.ip(), port);
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+        addrs
+    }
+
+    /// Whether mock frames are played instead of capturing from the video devices
+    pub fn uses_mock_video(&self) -> bool {
+        self.mock_video || self.mock_video_script.is_some()
+    }
+
+    /// Addresses of the VNC server: --vnc-listen, or --bind with --vnc-port
+    pub fn vnc_listen_addrs(&self) -> Vec<SocketAddr> {
+        if self.vnc_listen.is_empty() {
+            vec![SocketAddr::new(self.bind_address, self.vnc_port)]
+        } else {
+            self.vnc_listen.clone()
         }
-        if !std::path::Path::new(&self.mouse_hid).exists() {
-            eprintln!("Warning: Mouse HID device {} does not exist", self.mouse_hid);
+    }
+
+    /// Warn about files the web console needs that do not exist; missing
+    /// devices are handled by the server, per --missing-devices
+    pub fn validate_files(&self) {
+        if cfg!(feature = "web") && !self.novnc_installed() && self.novnc_dir != DEFAULT_NOVNC_DIR {
+            eprintln!("Warning: noVNC not found in {}, the web console uses its built-in viewer", self.novnc_dir);
         }
     }
 
+    /// Whether --novnc-dir holds noVNC, which the web console then uses
+    fn novnc_installed(&self) -> bool {
+        Path::new(&self.novnc_dir).join("core/rfb.js").exists()
+    }
+
+    /// Video devices and HID gadgets of every console that do not exist;
+    /// `video` and `hid` select which are looked for
+    pub fn absent_devices(&self, video: bool, hid: bool) -> Vec<String> {
+        self.devices(video, hid).into_iter()
+            .filter(|device| !Path::new(device).exists())
+            .collect()
+    }
+
+    /// Video devices and HID gadgets of every console; `video` and `hid`
+    /// select which are listed
+    pub fn devices(&self, video: bool, hid: bool) -> Vec<String> {
+        let mut devices = Vec::new();
+        if video {
+            devices.push(self.video_device.as_str());
+            devices.extend(self.consoles.iter().map(|console| console.video_device.as_str()));
+        }
+        if hid {
+            devices.extend([self.keyboard_hid.as_str(), self.mouse_hid.as_str()]);
+            devices.extend(self.touchscreen_hid.as_deref());
+            for console in &self.consoles {
+                devices.extend([console.keyboard_hid.as_str(), console.mouse_hid.as_str()]);
+                devices.extend(console.touchscreen_hid.as_deref());
+            }
+        }
+        devices.into_iter().map(String::from).collect()
+    }
+
     /// Print configuration summary
     pub fn print_config(&self) {
         println!("KVM‑RS starting with:");
+        if let Some(ref config) = self.config {
+            println!("  Config file: {}", config);
+        }
+        if let Some(platform) = self.platform {
+            println!("  Platform preset: {}", platform.as_str());
+        }
+        println!("  Log level: {}", self.log_level);
+        println!("  Log target: {}", self.log_target.as_str());
         println!("  Video device: {}", self.video_device);
-        if self.force_framebuffer {
+        if let Some(ref script) = self.mock_video_script {
+            println!("  Video mode: mock, playing {}", script.display());
+        } else if self.mock_video {
+            println!("  Video mode: mock test pattern");
+        } else if self.force_framebuffer {
             println!("  Video mode: Framebuffer (forced)");
         } else {
             println!("  Video mode: Auto-detect (V4L2 preferred, framebuffer fallback)");
         }
-        println!("  Keyboard HID: {}", self.keyboard_hid);
-        println!("  Mouse HID: {}", self.mouse_hid);
-        println!("  WebSocket listening on: {}:{}", self.bind_address, self.port);
+        if let Some(frame_rate) = self.frame_rate {
+            println!("  Capture frame rate: {} fps", frame_rate);
+        }
+        if let Some(subsampling) = self.jpeg_subsampling {
+            println!("  JPEG chroma subsampling: {}", subsampling.as_str());
+        }
+        if self.mock_hid {
+            println!("  HID: mock (reports are logged, not written)");
+        } else {
+            println!("  Keyboard HID: {}", self.keyboard_hid);
+            println!("  Mouse HID: {}", self.mouse_hid);
+        }
+        if let Some(ref touchscreen) = self.touchscreen_hid {
+            println!("  Touchscreen HID: {} (pointer positioning)", touchscreen);
+        }
+        if let Some(ref name) = self.create_gadget {
+            println!("  Composite USB gadget: {} (keyboard, mouse{}{}{})", name,
+                if self.gadget_touchscreen { ", touchscreen" } else { "" },
+                if self.host_agent.is_some() { ", host agent serial port" } else { "" },
+                if self.mass_storage.is_some() { ", virtual media drive while an image is inserted" } else { "" });
+        }
+        if let Some(ref function) = self.host_agent {
+            println!("  Host agent channel: {}", function);
+        }
+        if let Some(ref console) = self.sol {
+            println!("  Serial console: obmc-console {} at /sol", console);
+        }
+        if let Some(ref udc) = self.bind_udc {
+            println!("  HID gadget bound to: {}", if udc == "auto" { "first free UDC" } else { udc });
+        }
+        match self.missing_devices {
+            MissingDevicePolicy::Fail => println!("  Missing devices: fail"),
+            MissingDevicePolicy::Wait if self.device_wait_timeout > 0 => println!("  Missing devices: wait up to {}s", self.device_wait_timeout),
+            MissingDevicePolicy::Wait => println!("  Missing devices: wait"),
+            MissingDevicePolicy::Degrade => println!("  Missing devices: degrade (reported in the status)"),
+        }
+        println!("  Pointer: scale {}x{}, acceleration {}, offset {:+},{:+}",
+            self.mouse_scale_x, self.mouse_scale_y, self.mouse_acceleration, self.mouse_offset_x, self.mouse_offset_y);
+        match (self.ws_raw_reports, self.report_validation) {
+            (false, _) => println!("  Raw WebSocket reports: refused"),
+            (true, ReportValidation::Strict) => println!("  Raw report validation: strict (invalid reports rejected)"),
+            (true, ReportValidation::Sanitize) => println!("  Raw report validation: sanitize"),
+            (true, ReportValidation::Permissive) => println!("  Raw report validation: permissive (length only)"),
+        }
+        if !self.block_keys.is_empty() {
+            println!("  Blocked key combinations: {}", self.block_keys.join(", "));
+        }
+        if let Some(ref macro_file) = self.macro_file {
+            println!("  Macro file: {}", macro_file);
+        }
+        if let Some(ref media_dir) = self.media_dir {
+            println!("  Virtual media images: {} (up to {} MiB)", media_dir, self.media_max_size);
+            if let Some(ref function) = self.mass_storage {
+                println!("  Virtual media drive: {} (remote images on {}, {} MiB cache)", function, self.nbd_device.display(), self.remote_media_cache);
+                if self.redfish_virtual_media {
+                    println!("    Offered to Redfish VirtualMedia over D-Bus");
+                }
+            }
+        }
+        if self.power_control {
+            println!("  Host power control: enabled");
+        }
+        match self.power_off_input {
+            PowerOffInput::Reject => println!("  Input while the host is off: rejected"),
+            PowerOffInput::Queue => println!("  Input while the host is off: queued"),
+        }
+        if let Some(ref image) = self.power_off_image {
+            println!("  Power-off image: {}", image.display());
+        }
+        match self.keyboard_protocol {
+            KeyboardProtocol::Report => println!("  Keyboard protocol: report"),
+            KeyboardProtocol::Boot => println!("  Keyboard protocol: boot (8-byte reports)"),
+            KeyboardProtocol::Auto => println!("  Keyboard protocol: auto (follows the host)"),
+        }
+        if self.keyboard_layout == KeyboardLayout::Iso {
+            println!("  Keyboard layout: ISO 102-key (< and > on the extra key)");
+        }
+        match self.key_repeat {
+            KeyRepeatPolicy::Passthrough => println!("  Key repeat: passthrough"),
+            KeyRepeatPolicy::Host => println!("  Key repeat: host typematic (duplicate key-downs dropped)"),
+            KeyRepeatPolicy::Server => println!("  Key repeat: server-side, {} ms delay, {}/s",
+                self.key_repeat_delay, self.key_repeat_rate),
+        }
+        if let Some(ref desc) = self.keyboard_report_desc {
+            println!("    Keyboard report descriptor: {}", desc);
+        }
+        if let Some(ref desc) = self.mouse_report_desc {
+            println!("    Mouse report descriptor: {}", desc);
+        }
+        if let Some(ref desc) = self.touchscreen_report_desc {
+            println!("    Touchscreen report descriptor: {}", desc);
+        }
+        let scheme = if self.https { "https" } else { "http" };
+        if let Some(ref path) = self.unix_socket {
+            println!("  WebSocket listening on: unix socket {}{}", path, if self.https { " (TLS encrypted)" } else { "" });
+        } else if self.https {
+            println!("  WebSocket listening on: {} (TLS encrypted)", join_addrs(&self.listen_addrs()));
+            if let Some(redirect_port) = self.http_redirect_port {
+                println!("    Plain HTTP on {} redirects to HTTPS", join_addrs(&self.listen_addrs_on(redirect_port)));
+            }
+        } else {
+            println!("  WebSocket listening on: {}", join_addrs(&self.listen_addrs()));
+        }
+        if self.ws_idle_timeout > 0 {
+            println!("  WebSocket keepalive: ping every {}s, idle timeout {}s", self.ws_ping_interval, self.ws_idle_timeout);
+        } else {
+            println!("  WebSocket keepalive: ping every {}s, no idle timeout", self.ws_ping_interval);
+        }
+        println!("  WebSocket JPEG quality: {}", self.ws_jpeg_quality);
+        match self.frame_memory_budget {
+            0 => println!("  Frame memory budget: unlimited"),
+            mib => println!("  Frame memory budget: {} MiB", mib),
+        }
+        if self.client_bandwidth_limit > 0 {
+            println!("  Bandwidth limit per client: {} KiB/s", self.client_bandwidth_limit);
+        }
+        if self.bandwidth_limit > 0 {
+            println!("  Bandwidth limit of all clients: {} KiB/s", self.bandwidth_limit);
+        }
+        if self.webrtc {
+            if self.webrtc_ice_servers.is_empty() {
+                println!("  WebRTC: enabled, host candidates only");
+            } else {
+                println!("  WebRTC: enabled, ICE servers {}", self.webrtc_ice_servers.join(", "));
+            }
+        }
+        if let Some(port) = self.webtransport_port {
+            println!("  WebTransport on: {} (UDP)", join_addrs(&self.listen_addrs_on(port)));
+        }
+        if self.ws_max_sessions > 0 {
+            println!("  WebSocket sessions: {:?}, at most {} per console", self.ws_policy, self.ws_max_sessions);
+        } else {
+            println!("  WebSocket sessions: {:?}, unlimited", self.ws_policy);
+        }
+        if self.ws_resume_grace > 0 {
+            println!("  Dropped kvm-rs sessions resumable for {}s", self.ws_resume_grace);
+        }
+        if !cfg!(feature = "web") {
+            println!("  Web console: not built");
+        } else if self.novnc_installed() {
+            println!("  Web console: {}://{}{}/ (noVNC from {})", scheme, self.listen_addrs()[0], self.base_path, self.novnc_dir);
+        } else {
+            println!("  Web console: {}://{}{}/ (built-in viewer)", scheme, self.listen_addrs()[0], self.base_path);
+        }
         
-        if self.vnc_tls {
-            println!("  VNC listening on: {}:{} (TLS encrypted)", self.bind_address, self.vnc_port);
-            if let Some(ref cert) = self.vnc_cert {
-                println!("    TLS certificate: {}", cert);
+        if self.vnc_tls || self.https || self.webtransport_port.is_some() {
+            if self.openbmc_certs {
+                println!("  TLS certificate: OpenBMC certificate manager (HTTPS certificate)");
+            } else if let Some(ref cert) = self.vnc_cert {
+                println!("  TLS certificate: {}", cert);
             } else {
-                println!("    TLS certificate: Self-signed (auto-generated)");
+                println!("  TLS certificate: Self-signed (auto-generated)");
             }
-            if let Some(ref key) = self.vnc_key {
-                println!("    TLS private key: {}", key);
+            if self.openbmc_certs {
+                println!("  TLS private key: /etc/ssl/certs/https/server.pem");
+            } else if let Some(ref key) = self.vnc_key {
+                println!("  TLS private key: {}", key);
             } else {
-                println!("    TLS private key: Auto-generated");
+                println!("  TLS private key: Auto-generated");
+            }
+            for sni in &self.sni_certs {
+                println!("  TLS certificate for {}: {}", sni.name, sni.cert_path);
             }
+            if let Some(ref ca) = self.client_ca {
+                println!("  TLS client certificates: required, issued by {}", ca);
+            }
+        }
+        if !cfg!(feature = "vnc") {
+            println!("  VNC port: not built");
+        } else if self.vnc_tls {
+            println!("  VNC listening on: {} (TLS encrypted)", join_addrs(&self.vnc_listen_addrs()));
         } else {
-            println!("  VNC listening on: {}:{} (unencrypted)", self.bind_address, self.vnc_port);
+            println!("  VNC listening on: {} (unencrypted)", join_addrs(&self.vnc_listen_addrs()));
         }
+        for console in &self.consoles {
+            let vnc = if console.vnc_listen.is_empty() {
+                "no VNC port".to_string()
+            } else {
+                format!("VNC on {}", join_addrs(&console.vnc_listen))
+            };
+            println!("  Console {}: {}, keyboard {}, mouse {}, {}",
+                console.id, console.video_device, console.keyboard_hid, console.mouse_hid, vnc);
+        }
+        if let Some(ref path) = self.vnc_password_file {
+            println!("  VNC password: {}", path);
+        }
+        if let Some(ref path) = self.credential_store {
+            println!("  Credential store: {} (key from {})", path, self.device_secret);
+        }
+
+        match self.auth {
+            AuthMode::None => println!("  Console authentication: none"),
+            AuthMode::Redfish => println!(
+                "  Console authentication: Redfish sessions at {} (unknown accounts: {})",
+                self.redfish_url,
+                self.redfish_role.as_str()
+            ),
+            AuthMode::Local => println!("  Console authentication: login page, sessions expire after {}s unused", self.login_timeout),
+        }
+        match (self.ldap, &self.ldap_url) {
+            (Some(LdapSource::Openbmc), _) => println!("  LDAP accounts: OpenBMC LDAP/Active Directory configuration"),
+            (Some(LdapSource::Direct), Some(url)) => println!("  LDAP accounts: {} ({} group mappings)", url, self.ldap_role_map.len()),
+            _ => {}
+        }
+        if (self.auth != AuthMode::None || self.vnc_password_file.is_some()) && self.auth_max_failures > 0 {
+            println!("  Login lockout: {}s after {} failures", self.auth_lockout, self.auth_max_failures);
+        }
+        if !self.allowed_origins.is_empty() {
+            println!("  Allowed origins: {}", self.allowed_origins.join(", "));
+        }
+        if !self.allowed_hosts.is_empty() {
+            println!("  Allowed host names: {}", self.allowed_hosts.join(", "));
+        }
+        if !self.cors_origins.is_empty() {
+            println!("  CORS: {} may use {}", self.cors_origins.join(", "), self.cors_methods.join(", "));
+        }
+        match self.api_token_file {
+            Some(ref file) => println!("  Input injection API: enabled (token from {})", file),
+            None => println!("  Input injection API: disabled"),
+        }
+        if let Some(ref target) = self.input_audit {
+            let detail = if self.input_audit_full { "full" } else { "event classes only" };
+            println!("  Input audit: {} ({})", target, detail);
+        }
+        if self.security_audit {
+            println!("  Security audit: journald");
+        }
+        if !self.webhooks.is_empty() {
+            println!("  Webhooks: {} ({} retries)", self.webhooks.len(), self.webhook_retries);
+        }
+        println!("  Access log: {}", if self.no_access_log { "disabled" } else { "enabled" });
+        if self.max_session_duration > 0 {
+            println!(
+                "  Max session duration: {}s (warning {}s before)",
+                self.max_session_duration, self.session_expiry_warning
+            );
+        }
+        println!("  Shutdown timeout: {}s", self.shutdown_timeout);
+        match self.ip_max_connections {
+            0 => println!("  Connections per client: unlimited"),
+            max => println!("  Connections per client: {}", max),
+        }
+        if self.ip_request_rate > 0.0 {
+            println!("  Requests per client: {}/s, bursts of {}", self.ip_request_rate, self.ip_request_burst);
+        } else {
+            println!("  Requests per client: unlimited");
+        }
+        if !self.allow_ip.is_empty() {
+            let allow: Vec<String> = self.allow_ip.iter().map(Cidr::to_string).collect();
+            println!("  Allowed client addresses: {}", allow.join(", "));
+        }
+        if !self.deny_ip.is_empty() {
+            let deny: Vec<String> = self.deny_ip.iter().map(Cidr::to_string).collect();
+            println!("  Denied client addresses: {}", deny.join(", "));
+        }
+        if let Some(ref user) = self.user {
+            println!("  Privileges: dropped to user {}{} after startup", user,
+                self.group.as_deref().map(|group| format!(", group {}", group)).unwrap_or_default());
+        }
+        if self.seccomp {
+            println!("  seccomp: system call filter after startup");
+        }
+        if !self.disable_service.is_empty() {
+            let services: Vec<&str> = self.disable_service.iter().map(Service::as_str).collect();
+            println!("  Services turned off: {}", services.join(", "));
+        }
+        if self.mdns {
+            println!("  mDNS advertisement: enabled (Avahi)");
+        }
+    }
+}
+
+impl Args {
+    /// Options of the command line, after those of its --config file and
+    /// then those of the --platform preset
+    pub fn load() -> anyhow::Result<Self> {
+        let command_line = command_line();
+        let mut args = Self::parse_from(&command_line);
+        let mut configured = command_line.clone();
+        if let Some(ref config) = args.config {
+            configured = with_config(&command_line, config)?;
+            args = Self::try_parse_from(&configured).with_context(|| format!("Invalid option in {}", config))?;
+        }
+        let Some(platform) = args.platform else {
+            return Ok(args);
+        };
+        Self::try_parse_from(with_preset(&configured, platform))
+            .with_context(|| format!("Invalid option in the {} preset", platform.as_str()))
+    }
+
+    /// Options that have a value after merging the defaults, the --config file
+    /// and the command line, with the source each value was taken from
+    pub fn effective_options() -> anyhow::Result<Vec<EffectiveOption>> {
+        let command_line = command_line();
+        let command = Self::command();
+        let given = command.clone().try_get_matches_from(&command_line)?;
+        let (configured_line, configured) = match given.get_one::<String>("config") {
+            Some(config) => {
+                let configured_line = with_config(&command_line, config)?;
+                let configured = command.clone().try_get_matches_from(&configured_line)
+                    .with_context(|| format!("Invalid option in {}", config))?;
+                (configured_line, configured)
+            }
+            None => (command_line.clone(), given.clone()),
+        };
+        let merged = match configured.get_one::<Platform>("platform") {
+            Some(platform) => command.clone().try_get_matches_from(with_preset(&configured_line, *platform))
+                .with_context(|| format!("Invalid option in the {} preset", platform.as_str()))?,
+            None => configured.clone(),
+        };
+
+        let mut options = Vec::new();
+        for arg in command.get_arguments() {
+            let id = arg.get_id().as_str();
+            // Actions rather than settings
+            if matches!(id, "check" | "dump_config" | "set_credential") {
+                continue;
+            }
+            let (Some(name), Some(values)) = (arg.get_long(), merged.get_raw(id)) else {
+                continue;
+            };
+            let source = match merged.value_source(id) {
+                Some(ValueSource::DefaultValue) => OptionSource::Default,
+                Some(ValueSource::EnvVariable) => OptionSource::Environment,
+                _ if given.value_source(id) == Some(ValueSource::CommandLine) => OptionSource::CommandLine,
+                _ if configured.value_source(id) == Some(ValueSource::CommandLine) => OptionSource::ConfigFile,
+                _ => OptionSource::Platform,
+            };
+            options.push(EffectiveOption {
+                name: name.to_string(),
+                values: values.map(|value| value.to_string_lossy().into_owned()).collect(),
+                flag: matches!(arg.get_action(), ArgAction::SetTrue | ArgAction::SetFalse),
+                repeatable: matches!(arg.get_action(), ArgAction::Append),
+                source,
+            });
+        }
+        Ok(options)
+    }
+}
+
+/// The command line, with the options of obmc-ikvm translated when started as it
+fn command_line() -> Vec<OsString> {
+    let command_line: Vec<OsString> = std::env::args_os().collect();
+    if obmc_ikvm::invoked_as(&command_line) {
+        obmc_ikvm::translate(&command_line)
+    } else {
+        command_line
+    }
+}
+
+/// The command line with the options of its --config file in front, so that its own take precedence
+fn with_config(command_line: &[OsString], config: &str) -> anyhow::Result<Vec<OsString>> {
+    let contents = std::fs::read_to_string(config)
+        .with_context(|| format!("Failed to read config file {}", config))?;
+    Ok(with_options(command_line, parse_config(&contents)))
+}
+
+/// The command line with the options of a platform preset in front, so that
+/// the command line and its --config file take precedence
+fn with_preset(command_line: &[OsString], platform: Platform) -> Vec<OsString> {
+    with_options(command_line, parse_config(platform.preset()))
+}
+
+fn with_options(command_line: &[OsString], options: Vec<String>) -> Vec<OsString> {
+    let mut merged = command_line[..1].to_vec();
+    merged.extend(options.into_iter().map(OsString::from));
+    merged.extend(command_line[1..].iter().cloned());
+    merged
+}
+
+/// Options in the format of a --config file as command line arguments; `#` starts a comment line
+fn parse_config(contents: &str) -> Vec<String> {
+    let mut options = Vec::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((name, value)) => {
+                options.push(format!("--{}", name.trim()));
+                options.push(value.trim().trim_matches('"').to_string());
+            }
+            None => options.push(format!("--{}", line)),
+        }
+    }
+    options
+}
+
+/// Base paths start with '/' and are kept without a trailing one ("/" becomes "")
+fn parse_base_path(path: &str) -> Result<String, String> {
+    if !path.starts_with('/') {
+        return Err("must start with '/'".to_string());
+    }
+    if path.contains(['?', '#']) {
+        return Err("must be a plain path".to_string());
+    }
+    Ok(path.trim_end_matches('/').to_string())
+}
+
+fn join_addrs(addrs: &[SocketAddr]) -> String {
+    addrs.iter().map(SocketAddr::to_string).collect::<Vec<_>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Options as `--platform` applies them, with nothing else on the command line
+    fn preset_args(platform: Platform) -> Args {
+        let command_line = [OsString::from("kvm-rs")];
+        Args::try_parse_from(with_preset(&command_line, platform))
+            .unwrap_or_else(|e| panic!("Invalid option in the {} preset: {}", platform.as_str(), e))
+    }
+
+    #[test]
+    fn ast2500_preset_parses() {
+        let args = preset_args(Platform::Ast2500);
+        assert_eq!(args.frame_memory_budget, 24);
+        assert_eq!(args.ws_max_sessions, 4);
+    }
+
+    #[test]
+    fn ast2600_preset_parses() {
+        let args = preset_args(Platform::Ast2600);
+        assert_eq!(args.frame_memory_budget, 64);
+        assert_eq!(args.ws_jpeg_quality, 75);
+    }
+
+    #[test]
+    fn npcm845_preset_parses() {
+        let args = preset_args(Platform::Npcm845);
+        assert_eq!(args.frame_memory_budget, 128);
+        assert_eq!(args.ws_jpeg_quality, 85);
+    }
+
+    #[test]
+    fn qemu_dev_preset_parses() {
+        let args = preset_args(Platform::QemuDev);
+        assert!(args.mock_video && args.mock_hid);
     }
 }
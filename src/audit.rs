@@ -0,0 +1,233 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Input event audit trail for kvm-rs
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use anyhow::{Context, Result};
+use tracing::warn;
+
+/// Number of rotated audit files kept next to the active one
+const ROTATED_FILES: usize = 5;
+
+/// journald native protocol socket
+#[cfg(target_os = "linux")]
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Class of an audited input event
+#[derive(Debug, Clone, Copy)]
+pub enum InputClass {
+    Key,
+    PointerButton,
+    Touch,
+    RawKeyboard,
+    RawMouse,
+    RawTouch,
+    /// Bytes typed on the host's serial console
+    Serial,
+}
+
+impl InputClass {
+    fn as_str(&self) -> &'static str {
+        match self {
+            InputClass::Key => "key",
+            InputClass::PointerButton => "pointer-button",
+            InputClass::Touch => "touch",
+            InputClass::RawKeyboard => "raw-keyboard",
+            InputClass::RawMouse => "raw-mouse",
+            InputClass::RawTouch => "raw-touch",
+            InputClass::Serial => "serial",
+        }
+    }
+}
+
+enum AuditSink {
+    File {
+        path: PathBuf,
+        max_bytes: u64,
+        file: File,
+        size: u64,
+    },
+    #[cfg(target_os = "linux")]
+    Journald(std::os::unix::net::UnixDatagram),
+}
+
+/// Audit trail of input forwarded to the host.
+///
+/// By default only the event class is recorded; key and pointer contents are
+/// included only in full mode.
+#[derive(Clone, Default)]
+pub struct InputAudit {
+    sink: Option<Arc<Mutex<AuditSink>>>,
+    full: bool,
+}
+
+impl InputAudit {
+    /// Open an audit trail; `target` is a file path or `journald`
+    pub fn open(target: &str, max_bytes: u64, full: bool) -> Result<Self> {
+        let sink = if target == "journald" {
+            Self::journald_sink()?
+        } else {
+            let path = PathBuf::from(target);
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .with_context(|| format!("Failed to open input audit log: {}", target))?;
+            let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+            AuditSink::File { path, max_bytes, file, size }
+        };
+
+        Ok(Self {
+            sink: Some(Arc::new(Mutex::new(sink))),
+            full,
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    fn journald_sink() -> Result<AuditSink> {
+        Ok(AuditSink::Journald(connect_journald()?))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn journald_sink() -> Result<AuditSink> {
+        Err(anyhow::anyhow!("journald audit logging is only supported on Linux"))
+    }
+
+    /// Record an input event from `client` over `transport`; `detail` is only
+    /// evaluated and stored in full mode
+    pub fn record<F>(&self, transport: &str, client: &str, class: InputClass, detail: F)
+    where
+        F: FnOnce() -> String,
+    {
+        let Some(ref sink) = self.sink else {
+            return;
+        };
+        let detail = if self.full { Some(detail()) } else { None };
+
+        let mut sink = match sink.lock() {
+            Ok(sink) => sink,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let Err(e) = sink.write(transport, client, class, detail.as_deref()) {
+            warn!("Failed to write input audit record: {}", e);
+        }
+    }
+
+    /// Make sure records written so far are on disk
+    pub fn flush(&self) {
+        let Some(ref sink) = self.sink else {
+            return;
+        };
+        let sink = match sink.lock() {
+            Ok(sink) => sink,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        match *sink {
+            AuditSink::File { ref file, .. } => {
+                if let Err(e) = file.sync_data() {
+                    warn!("Failed to flush input audit log: {}", e);
+                }
+            }
+            // Datagrams are handed to journald as they are written
+            #[cfg(target_os = "linux")]
+            AuditSink::Journald(_) => {}
+        }
+    }
+}
+
+/// Datagram socket connected to journald's native protocol socket
+#[cfg(target_os = "linux")]
+pub fn connect_journald() -> Result<std::os::unix::net::UnixDatagram> {
+    let socket = std::os::unix::net::UnixDatagram::unbound()
+        .context("Failed to create journald socket")?;
+    socket.connect(JOURNALD_SOCKET)
+        .with_context(|| format!("Failed to connect to journald at {}", JOURNALD_SOCKET))?;
+    Ok(socket)
+}
+
+/// Append a field in the journald native protocol; values with newlines, such
+/// as user names sent by clients, are length-prefixed so they cannot add fields
+#[cfg(target_os = "linux")]
+pub fn push_field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+impl AuditSink {
+    fn write(&mut self, transport: &str, client: &str, class: InputClass, detail: Option<&str>) -> Result<()> {
+        match self {
+            AuditSink::File { path, max_bytes, file, size } => {
+                let mut line = format!(
+                    "{} transport={} client={} class={}",
+                    humantime::format_rfc3339_millis(SystemTime::now()),
+                    transport,
+                    client,
+                    class.as_str()
+                );
+                if let Some(detail) = detail {
+                    line.push(' ');
+                    line.push_str(detail);
+                }
+                line.push('\n');
+
+                if *size > 0 && *size + line.len() as u64 > *max_bytes {
+                    *file = Self::rotate(path)?;
+                    *size = 0;
+                }
+                file.write_all(line.as_bytes())?;
+                *size += line.len() as u64;
+            }
+            #[cfg(target_os = "linux")]
+            AuditSink::Journald(socket) => {
+                let mut entry = format!(
+                    "MESSAGE=Input {} from {} via {}\nPRIORITY=5\nSYSLOG_IDENTIFIER=kvm-rs\nKVM_TRANSPORT={}\nKVM_CLIENT={}\nKVM_INPUT_CLASS={}\n",
+                    class.as_str(),
+                    client,
+                    transport,
+                    transport,
+                    client,
+                    class.as_str()
+                );
+                if let Some(detail) = detail {
+                    entry.push_str(&format!("KVM_INPUT_DETAIL={}\n", detail));
+                }
+                socket.send(entry.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Shift `log.N` to `log.N+1`, move the active file to `log.1` and reopen it
+    fn rotate(path: &Path) -> Result<File> {
+        let rotated = |n: usize| {
+            let mut name = path.to_path_buf().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+
+        for n in (1..ROTATED_FILES).rev() {
+            let from = rotated(n);
+            if from.exists() {
+                std::fs::rename(&from, rotated(n + 1))?;
+            }
+        }
+        std::fs::rename(path, rotated(1))?;
+
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to reopen input audit log: {}", path.display()))
+    }
+}
@@ -0,0 +1,802 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Console authentication against bmcweb's Redfish sessions or local logins for kvm-rs
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(feature = "web")]
+use std::{collections::HashMap, net::SocketAddr, sync::Mutex, time::Instant};
+use anyhow::Result;
+#[cfg(feature = "web")]
+use anyhow::{anyhow, Context};
+#[cfg(feature = "web")]
+use axum::{
+    body::{Body, Bytes},
+    extract::{ConnectInfo, Query, Request, State},
+    http::{header, HeaderMap, Method, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::post,
+    Extension, Json, Router,
+};
+#[cfg(feature = "web")]
+use hyper_util::rt::TokioIo;
+#[cfg(feature = "web")]
+use rustls::pki_types::ServerName;
+#[cfg(feature = "web")]
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use crate::ldap::LdapDirectory;
+use crate::lockout::Lockout;
+use crate::login::LocalSessions;
+use crate::security_audit::{SecurityAudit, SecurityEvent};
+use crate::user_manager::UserManager;
+
+/// How long a token bmcweb accepted is trusted without asking again
+#[cfg(feature = "web")]
+const AUTH_CACHE_TTL: Duration = Duration::from_secs(10);
+/// How often open connections check that their session is still valid
+const SESSION_RECHECK: Duration = Duration::from_secs(30);
+/// Time limit for one validation request to bmcweb
+#[cfg(feature = "web")]
+const REDFISH_TIMEOUT: Duration = Duration::from_secs(5);
+/// Redfish accounts any logged-in user may list; bmcweb lists only their own
+/// account to users without the ConfigureUsers privilege
+#[cfg(feature = "web")]
+const ACCOUNTS_PATH: &str = "/redfish/v1/AccountService/Accounts";
+/// Largest accounts collection read from bmcweb
+#[cfg(feature = "web")]
+const MAX_ACCOUNTS_BODY: usize = 256 * 1024;
+/// How long a handoff token may wait to be redeemed
+#[cfg(feature = "web")]
+const HANDOFF_TTL: Duration = Duration::from_secs(30);
+/// Session cookies set by bmcweb (current and older releases)
+#[cfg(feature = "web")]
+const SESSION_COOKIES: [&str; 2] = ["BMCWEB-SESSION", "SESSION"];
+
+/// How console connections are authenticated
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMode {
+    /// No authentication, for development or when a proxy authenticates
+    None,
+    /// Require a bmcweb session token (X-Auth-Token header or session cookie)
+    Redfish,
+    /// Require a login with an OpenBMC account on kvm-rs's own login page
+    Local,
+}
+
+/// User a request was authenticated as, attached to its response for the access log
+#[derive(Debug, Clone)]
+pub struct Principal(pub String);
+
+/// What an authenticated user may do, from least to most
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum UserRole {
+    /// View the console only
+    Observer,
+    /// Also send keyboard, pointer and clipboard input
+    Operator,
+    /// Also change settings through the REST API
+    Admin,
+}
+
+impl UserRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserRole::Observer => "observer",
+            UserRole::Operator => "operator",
+            UserRole::Admin => "admin",
+        }
+    }
+
+    pub fn may_send_input(&self) -> bool {
+        *self >= UserRole::Operator
+    }
+}
+
+/// Who a request comes from and what they may do; handlers behind
+/// `require_session` find it in the request extensions
+#[derive(Debug, Clone)]
+pub struct Access {
+    /// User, when known (bmcweb lists no account to LDAP users, and every
+    /// account to administrators)
+    pub principal: Option<Principal>,
+    pub role: UserRole,
+    /// Session token whose end closes connections opened with this access
+    pub token: Option<String>,
+}
+
+impl Access {
+    /// Access of every client when authentication is disabled
+    fn unrestricted() -> Self {
+        Self {
+            principal: None,
+            role: UserRole::Admin,
+            token: None,
+        }
+    }
+}
+
+/// Why a login or a session credential was refused
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    #[error("Missing session token")]
+    MissingToken,
+    #[error("Invalid or expired session")]
+    InvalidSession,
+    #[error("Invalid, expired or used handoff token")]
+    InvalidHandoff,
+    #[error("Invalid user name or password")]
+    InvalidCredentials,
+    #[error("Too many failed logins, try again in {}s", .0.as_secs().max(1))]
+    LockedOut(Duration),
+    /// bmcweb could not be asked whether a token is valid
+    #[error("Session validation failed")]
+    Unavailable,
+    /// The account could not be checked, e.g. PAM or the user manager failed
+    #[error("Login failed")]
+    Failed,
+}
+
+impl AuthError {
+    #[cfg(feature = "web")]
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AuthError::LockedOut(_) => StatusCode::TOO_MANY_REQUESTS,
+            AuthError::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            AuthError::Failed => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    /// Whether the same credential may be accepted later: the lockout ends or
+    /// the service checking it comes back. Otherwise the credential is wrong
+    pub fn is_recoverable(&self) -> bool {
+        matches!(self, AuthError::LockedOut(_) | AuthError::Unavailable | AuthError::Failed)
+    }
+}
+
+#[cfg(feature = "web")]
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        (self.status(), self.to_string()).into_response()
+    }
+}
+
+/// Single-use token standing in for a session when opening a console
+#[cfg(feature = "web")]
+struct Handoff {
+    access: Access,
+    issued: Instant,
+}
+
+/// Checks the session credential of console requests
+#[derive(Clone)]
+pub struct Authenticator {
+    #[cfg(feature = "web")]
+    redfish: Option<Arc<RedfishSessions>>,
+    local: Option<Arc<LocalSessions>>,
+    /// Failed logins with credentials, by source address and account
+    lockout: Arc<Lockout>,
+    audit: SecurityAudit,
+    /// Unredeemed handoff tokens
+    #[cfg(feature = "web")]
+    handoffs: Arc<Mutex<HashMap<String, Handoff>>>,
+}
+
+impl Authenticator {
+    /// Accept every request
+    pub fn disabled() -> Self {
+        Self {
+            #[cfg(feature = "web")]
+            redfish: None,
+            local: None,
+            lockout: Arc::default(),
+            audit: SecurityAudit::default(),
+            #[cfg(feature = "web")]
+            handoffs: Arc::default(),
+        }
+    }
+
+    /// Validate session tokens against the Redfish service at `base_url`; `users`
+    /// resolves the role of the token's account, and holders whose account is
+    /// not known get `fallback_role`
+    #[cfg(feature = "web")]
+    pub fn redfish(base_url: &str, ca_file: Option<&str>, users: Option<UserManager>, fallback_role: UserRole) -> Result<Self> {
+        Ok(Self {
+            redfish: Some(Arc::new(RedfishSessions::new(base_url, ca_file, users, fallback_role)?)),
+            local: None,
+            lockout: Arc::default(),
+            audit: SecurityAudit::default(),
+            handoffs: Arc::default(),
+        })
+    }
+
+    /// Accept the session cookies issued by the login page, expiring after
+    /// `timeout` unused; `users` resolves account privileges and state, and
+    /// `ldap` checks accounts that are not local
+    pub fn local(timeout: Duration, users: Option<UserManager>, ldap: Option<LdapDirectory>) -> Self {
+        Self {
+            local: Some(Arc::new(LocalSessions::new(timeout, users, ldap))),
+            ..Self::disabled()
+        }
+    }
+
+    /// Lock out a source address or account for `duration` after `max_failures`
+    /// failed logins in a row (0 never locks out; failures are always delayed)
+    pub fn with_lockout(mut self, max_failures: u32, duration: Duration) -> Self {
+        self.lockout = Arc::new(Lockout::new(max_failures, duration));
+        self
+    }
+
+    /// Record logins, lockouts and refused session tokens in the security audit trail
+    pub fn with_security_audit(mut self, audit: SecurityAudit) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    /// Sessions of the login page, in local mode
+    #[cfg(feature = "web")]
+    pub fn local_sessions(&self) -> Option<&Arc<LocalSessions>> {
+        self.local.as_ref()
+    }
+
+    /// Whether connections need a session at all
+    pub fn is_enabled(&self) -> bool {
+        #[cfg(feature = "web")]
+        if self.redfish.is_some() {
+            return true;
+        }
+        self.local.is_some()
+    }
+
+    /// Why a request with these headers must be refused, if it must, else who
+    /// it comes from and what they may do
+    #[cfg(feature = "web")]
+    pub async fn check(&self, headers: &HeaderMap) -> Result<Access, AuthError> {
+        if !self.is_enabled() {
+            return Ok(Access::unrestricted());
+        }
+        let Some(token) = self.token(headers) else {
+            return Err(AuthError::MissingToken);
+        };
+        self.check_token(&token).await
+    }
+
+    /// Session token a request carries
+    #[cfg(feature = "web")]
+    fn token(&self, headers: &HeaderMap) -> Option<String> {
+        if self.local.is_some() {
+            // The header form serves WebTransport, which passes the token in the URL
+            return crate::login::session_cookie(headers)
+                .or_else(|| headers.get("x-auth-token")?.to_str().ok().map(|token| token.trim().to_string()));
+        }
+        session_token(headers)
+    }
+
+    /// Why a session token must be refused, if it must, else who it belongs to
+    pub async fn check_token(&self, token: &str) -> Result<Access, AuthError> {
+        if let Some(ref local) = self.local {
+            return match local.validate(token) {
+                Some((username, role)) => Ok(Access {
+                    principal: Some(Principal(username)),
+                    role,
+                    token: Some(token.to_string()),
+                }),
+                None => Err(AuthError::InvalidSession),
+            };
+        }
+        #[cfg(feature = "web")]
+        if let Some(ref redfish) = self.redfish {
+            return match redfish.validate(token).await {
+                Ok(Some(holder)) => Ok(Access {
+                    principal: holder.username.map(Principal),
+                    role: holder.role,
+                    token: Some(token.to_string()),
+                }),
+                Ok(None) => Err(AuthError::InvalidSession),
+                Err(e) => {
+                    warn!("Session validation failed: {:#}", e);
+                    Err(AuthError::Unavailable)
+                }
+            };
+        }
+        Ok(Access::unrestricted())
+    }
+
+    /// New single-use token that opens one connection with `access` within 30 seconds
+    #[cfg(feature = "web")]
+    pub fn handoff(&self, access: Access) -> Result<String> {
+        let token = crate::credentials::random_token()?;
+        let mut handoffs = self.handoffs.lock().unwrap_or_else(|e| e.into_inner());
+        handoffs.retain(|_, handoff| handoff.issued.elapsed() < HANDOFF_TTL);
+        handoffs.insert(token.clone(), Handoff {
+            access,
+            issued: Instant::now(),
+        });
+        Ok(token)
+    }
+
+    /// Access a handoff token was minted for; the token is used up
+    #[cfg(feature = "web")]
+    pub fn redeem(&self, token: &str) -> Option<Access> {
+        let mut handoffs = self.handoffs.lock().unwrap_or_else(|e| e.into_inner());
+        handoffs.retain(|_, handoff| handoff.issued.elapsed() < HANDOFF_TTL);
+        handoffs.remove(token).map(|handoff| handoff.access)
+    }
+
+    /// Open a session with user name and password credentials from `ip`, as
+    /// VNC clients and the login page send them: an OpenBMC account in local
+    /// mode, or a bmcweb session token as the password in Redfish mode.
+    /// Failures are answered late and can lock the address or account out.
+    /// `transport` names the client in the security audit trail. Returns the
+    /// session token and the user's role
+    pub async fn login(
+        &self,
+        username: &str,
+        password: &str,
+        ip: IpAddr,
+        transport: &str,
+    ) -> Result<(String, UserRole), AuthError> {
+        let audit_failure = |reason: &str| {
+            self.audit.log(SecurityEvent::Login {
+                transport,
+                client: ip,
+                user: username,
+                failure: Some(reason),
+            });
+        };
+        if let Some(remaining) = self.lockout.locked(ip, Some(username)) {
+            audit_failure("locked out");
+            return Err(AuthError::LockedOut(remaining));
+        }
+        match self.open_session(username, password).await {
+            Ok(session) => {
+                self.lockout.succeeded(ip, Some(username));
+                self.audit.log(SecurityEvent::Login {
+                    transport,
+                    client: ip,
+                    user: username,
+                    failure: None,
+                });
+                Ok(session)
+            }
+            Err(e) if !e.is_recoverable() => {
+                warn!("Failed login for {} from {}", username, ip);
+                audit_failure(&e.to_string());
+                let delay = self.lockout.failed(ip, Some(username));
+                if let Some(duration) = self.lockout.locked(ip, Some(username)) {
+                    self.audit.log(SecurityEvent::Lockout {
+                        client: ip,
+                        user: username,
+                        duration,
+                    });
+                }
+                tokio::time::sleep(delay).await;
+                Err(e)
+            }
+            Err(e) => {
+                audit_failure(&e.to_string());
+                Err(e)
+            }
+        }
+    }
+
+    async fn open_session(&self, username: &str, password: &str) -> Result<(String, UserRole), AuthError> {
+        if let Some(ref local) = self.local {
+            return match local.login(username.to_string(), password.to_string()).await {
+                Ok(Some(session)) => Ok(session),
+                Ok(None) => Err(AuthError::InvalidCredentials),
+                Err(e) => {
+                    warn!("Login failed: {:#}", e);
+                    Err(AuthError::Failed)
+                }
+            };
+        }
+        let access = self.check_token(password).await?;
+        Ok((password.to_string(), access.role))
+    }
+
+    /// End a session opened with `login`; bmcweb sessions belong to bmcweb and stay open
+    pub fn logout(&self, token: &str) {
+        if let Some(ref local) = self.local {
+            local.logout(token);
+        }
+    }
+
+    /// Completes once the session of `token` has expired or was logged out, so
+    /// connections opened with it can be closed. Never completes without a token
+    pub async fn session_ended(&self, token: Option<String>) {
+        let Some(token) = token.filter(|_| self.is_enabled()) else {
+            return std::future::pending().await;
+        };
+        loop {
+            tokio::time::sleep(SESSION_RECHECK).await;
+            // An unreachable Redfish service does not end sessions
+            if self.check_token(&token).await.is_err_and(|e| !e.is_recoverable()) {
+                return;
+            }
+        }
+    }
+}
+
+/// Query of requests opened with a handoff token
+#[cfg(feature = "web")]
+#[derive(Deserialize)]
+struct HandoffQuery {
+    handoff: Option<String>,
+}
+
+/// Reject requests without a valid session or handoff token, before e.g. a
+/// WebSocket upgrade. Handlers find the `Access` in the request extensions
+#[cfg(feature = "web")]
+pub async fn require_session(
+    State(auth): State<Authenticator>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, AuthError> {
+    let handoff = Query::<HandoffQuery>::try_from_uri(request.uri()).ok().and_then(|query| query.0.handoff);
+    let access = match handoff {
+        Some(handoff) => auth.redeem(&handoff).ok_or(AuthError::InvalidHandoff),
+        None => auth.check(request.headers()).await,
+    };
+    let access = access.inspect_err(|e| {
+        auth.audit.log(SecurityEvent::TokenRefused {
+            client: addr,
+            reason: &e.to_string(),
+        });
+    })?;
+    let principal = access.principal.clone();
+    request.extensions_mut().insert(access);
+    let mut response = next.run(request).await;
+    if let Some(principal) = principal {
+        response.extensions_mut().insert(principal);
+    }
+    Ok(response)
+}
+
+/// Answer of POST /api/v1/handoff
+#[cfg(feature = "web")]
+#[derive(Serialize)]
+struct HandoffToken {
+    token: String,
+    expires_in_secs: u64,
+}
+
+/// Route minting handoff tokens; it needs `require_session` like the consoles
+#[cfg(feature = "web")]
+pub fn handoff_router(auth: Authenticator) -> Router {
+    Router::new()
+        .route("/api/v1/handoff", post(mint_handoff))
+        .with_state(auth)
+}
+
+/// POST /api/v1/handoff - single-use token for opening /kvm/{id}?handoff=<token>,
+/// so the session credential stays out of WebSocket URLs
+#[cfg(feature = "web")]
+async fn mint_handoff(
+    State(auth): State<Authenticator>,
+    Extension(access): Extension<Access>,
+) -> Result<Json<HandoffToken>, (StatusCode, String)> {
+    let token = auth.handoff(access).map_err(|e| {
+        warn!("Failed to mint handoff token: {:#}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, "Failed to mint handoff token".to_string())
+    })?;
+    Ok(Json(HandoffToken {
+        token,
+        expires_in_secs: HANDOFF_TTL.as_secs(),
+    }))
+}
+
+/// Refuse REST requests the user's role does not allow: observers may only
+/// read, operators may also send input, admins may change settings too.
+/// Requests without a role passed no session check and are not restricted
+#[cfg(feature = "web")]
+pub async fn require_role(request: Request, next: Next) -> Result<Response, (StatusCode, String)> {
+    let role = request.extensions().get::<Access>().map(|access| access.role).unwrap_or(UserRole::Admin);
+    let required = required_role(request.method(), request.uri().path());
+    if role < required {
+        return Err((StatusCode::FORBIDDEN, format!("Requires the {} role", required.as_str())));
+    }
+    Ok(next.run(request).await)
+}
+
+/// Least role a REST request needs
+#[cfg(feature = "web")]
+fn required_role(method: &Method, path: &str) -> UserRole {
+    if method == Method::GET || method == Method::HEAD {
+        UserRole::Observer
+    } else if path.starts_with("/api/v1/input/")
+        || path.starts_with("/api/v1/media/drive/")
+        || path.starts_with("/api/v1/power/")
+    {
+        UserRole::Operator
+    } else {
+        UserRole::Admin
+    }
+}
+
+/// Token from the X-Auth-Token header or a bmcweb session cookie
+#[cfg(feature = "web")]
+fn session_token(headers: &HeaderMap) -> Option<String> {
+    if let Some(token) = headers.get("x-auth-token").and_then(|value| value.to_str().ok()) {
+        return Some(token.trim().to_string());
+    }
+    headers.get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| SESSION_COOKIES.contains(name))
+        .map(|(_, value)| value.to_string())
+}
+
+/// Holder of a session token bmcweb accepted
+#[cfg(feature = "web")]
+#[derive(Debug, Clone)]
+struct TokenHolder {
+    username: Option<String>,
+    role: UserRole,
+}
+
+/// Accounts collection of bmcweb, as far as it is read
+#[cfg(feature = "web")]
+#[derive(Deserialize)]
+struct AccountCollection {
+    #[serde(rename = "Members", default)]
+    members: Vec<AccountLink>,
+}
+
+#[cfg(feature = "web")]
+#[derive(Deserialize)]
+struct AccountLink {
+    #[serde(rename = "@odata.id")]
+    id: String,
+}
+
+/// Session validation by asking bmcweb to list the accounts a token may see,
+/// which also tells whose token it is
+#[cfg(feature = "web")]
+struct RedfishSessions {
+    uri: Uri,
+    host: String,
+    port: u16,
+    tls: Option<tokio_rustls::TlsConnector>,
+    users: Option<UserManager>,
+    /// Role of holders whose account is not known
+    fallback_role: UserRole,
+    /// Accepted tokens, when they were checked and whose they are
+    accepted: Mutex<HashMap<String, (Instant, TokenHolder)>>,
+}
+
+#[cfg(feature = "web")]
+impl RedfishSessions {
+    fn new(base_url: &str, ca_file: Option<&str>, users: Option<UserManager>, fallback_role: UserRole) -> Result<Self> {
+        let uri: Uri = format!("{}{}", base_url.trim_end_matches('/'), ACCOUNTS_PATH)
+            .parse()
+            .with_context(|| format!("Invalid Redfish URL {}", base_url))?;
+        let https = match uri.scheme_str() {
+            Some("https") => true,
+            Some("http") => false,
+            _ => return Err(anyhow!("Redfish URL {} must start with http:// or https://", base_url)),
+        };
+        let host = uri.host()
+            .ok_or_else(|| anyhow!("Redfish URL {} has no host", base_url))?
+            .trim_matches(|c| c == '[' || c == ']')
+            .to_string();
+        let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+        if !https && !is_loopback(&host) {
+            // Tokens would cross the network in the clear to a peer nobody verified
+            return Err(anyhow!("Redfish URL {} must use https:// for a service that is not on a loopback address", base_url));
+        }
+
+        let tls = if https {
+            Some(tokio_rustls::TlsConnector::from(Arc::new(client_config(&host, ca_file)?)))
+        } else {
+            None
+        };
+        Ok(Self {
+            uri,
+            host,
+            port,
+            tls,
+            users,
+            fallback_role,
+            accepted: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Holder of `token`, or None if bmcweb does not accept it or the holder's
+    /// account may not use the console
+    async fn validate(&self, token: &str) -> Result<Option<TokenHolder>> {
+        {
+            let mut accepted = self.accepted.lock().unwrap_or_else(|e| e.into_inner());
+            accepted.retain(|_, (checked, _)| checked.elapsed() < AUTH_CACHE_TTL);
+            if let Some((_, holder)) = accepted.get(token) {
+                return Ok(Some(holder.clone()));
+            }
+        }
+
+        let (status, body) = tokio::time::timeout(REDFISH_TIMEOUT, self.query(token))
+            .await
+            .map_err(|_| anyhow!("Redfish service did not answer within {:?}", REDFISH_TIMEOUT))??;
+        let accounts = match status {
+            StatusCode::OK => serde_json::from_slice::<AccountCollection>(&body)
+                .context("Invalid Redfish accounts collection")?
+                .members,
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => return Ok(None),
+            status => return Err(anyhow!("Unexpected Redfish response {}", status)),
+        };
+        let Some(holder) = self.holder(&accounts).await else {
+            return Ok(None);
+        };
+        self.accepted.lock().unwrap_or_else(|e| e.into_inner())
+            .insert(token.to_string(), (Instant::now(), holder.clone()));
+        Ok(Some(holder))
+    }
+
+    /// Holder of a token that may list `accounts`: bmcweb lists a user's own
+    /// account only, unless they have the ConfigureUsers privilege of
+    /// administrators. None if the account may not use the console
+    async fn holder(&self, accounts: &[AccountLink]) -> Option<TokenHolder> {
+        let username = match accounts {
+            [account] => account.id.rsplit('/').next().filter(|name| !name.is_empty()),
+            [] => None,
+            _ => {
+                return Some(TokenHolder {
+                    username: None,
+                    role: UserRole::Admin,
+                })
+            }
+        };
+        let Some(username) = username else {
+            return Some(TokenHolder {
+                username: None,
+                role: self.fallback_role,
+            });
+        };
+        let role = match self.users {
+            Some(ref users) => match users.console_role(username).await {
+                Ok(role) => role?,
+                Err(e) => {
+                    warn!("Failed to look up the role of {}, assuming {}: {:#}", username, self.fallback_role.as_str(), e);
+                    self.fallback_role
+                }
+            },
+            None => self.fallback_role,
+        };
+        Some(TokenHolder {
+            username: Some(username.to_string()),
+            role,
+        })
+    }
+
+    /// GET the accounts collection with the token, returning the status code and body
+    async fn query(&self, token: &str) -> Result<(StatusCode, Bytes)> {
+        let stream = tokio::net::TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .with_context(|| format!("Failed to connect to Redfish service {}:{}", self.host, self.port))?;
+        let request = axum::http::Request::get(self.uri.path())
+            .header(header::HOST, self.uri.authority().map(|a| a.as_str()).unwrap_or(&self.host))
+            .header("X-Auth-Token", token)
+            .body(Body::empty())?;
+
+        match self.tls {
+            Some(ref connector) => {
+                let server_name = ServerName::try_from(self.host.clone())?;
+                let stream = connector.connect(server_name, stream).await
+                    .context("TLS handshake with Redfish service failed")?;
+                read_response(TokioIo::new(stream), request).await
+            }
+            None => read_response(TokioIo::new(stream), request).await,
+        }
+    }
+}
+
+/// Send `request` over `io` with HTTP/1.1, returning the status code
+#[cfg(feature = "web")]
+pub async fn send_request<I>(io: I, request: axum::http::Request<Body>) -> Result<StatusCode>
+where
+    I: hyper::rt::Read + hyper::rt::Write + Unpin + Send + 'static,
+{
+    Ok(exchange(io, request).await?.status())
+}
+
+/// Send `request` over `io` with HTTP/1.1, returning the status code and the
+/// body of a Redfish response
+#[cfg(feature = "web")]
+async fn read_response<I>(io: I, request: axum::http::Request<Body>) -> Result<(StatusCode, Bytes)>
+where
+    I: hyper::rt::Read + hyper::rt::Write + Unpin + Send + 'static,
+{
+    let response = exchange(io, request).await?;
+    let status = response.status();
+    let body = axum::body::to_bytes(Body::new(response.into_body()), MAX_ACCOUNTS_BODY).await?;
+    Ok((status, body))
+}
+
+#[cfg(feature = "web")]
+async fn exchange<I>(io: I, request: axum::http::Request<Body>) -> Result<axum::http::Response<hyper::body::Incoming>>
+where
+    I: hyper::rt::Read + hyper::rt::Write + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(io).await?;
+    tokio::spawn(async move {
+        let _ = connection.await;
+    });
+    Ok(sender.send_request(request).await?)
+}
+
+/// TLS client settings for bmcweb: verified against `ca_file`, or not at all for
+/// a loopback address, where bmcweb typically uses a self-signed certificate
+#[cfg(feature = "web")]
+fn client_config(host: &str, ca_file: Option<&str>) -> Result<rustls::ClientConfig> {
+    if let Some(ca_file) = ca_file {
+        let pem = std::fs::read(ca_file)
+            .with_context(|| format!("Failed to read Redfish CA file {}", ca_file))?;
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut std::io::Cursor::new(&pem)) {
+            roots.add(cert.context("Failed to parse Redfish CA file")?)?;
+        }
+        return Ok(rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth());
+    }
+
+    if !is_loopback(host) {
+        return Err(anyhow!("--redfish-ca is required to verify the Redfish service at {}", host));
+    }
+    Ok(rustls::ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(LoopbackVerifier(Arc::new(rustls::crypto::aws_lc_rs::default_provider()))))
+        .with_no_client_auth())
+}
+
+/// Whether `host` names this machine, so connections to it never leave the BMC
+#[cfg(feature = "web")]
+fn is_loopback(host: &str) -> bool {
+    host == "localhost" || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// Accepts any certificate; only used for connections that never leave the BMC
+#[cfg(feature = "web")]
+#[derive(Debug)]
+struct LoopbackVerifier(Arc<rustls::crypto::CryptoProvider>);
+
+#[cfg(feature = "web")]
+impl rustls::client::danger::ServerCertVerifier for LoopbackVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::pki_types::CertificateDer<'_>,
+        _intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::danger::ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
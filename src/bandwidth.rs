@@ -0,0 +1,159 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Egress accounting and caps of console clients for kvm-rs
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use serde::Serialize;
+
+/// Window the byte rate is measured over
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Caps the bytes per second sent to each client and to all of them, so a
+/// console session cannot starve the management traffic sharing the BMC's
+/// NIC. Clients over a cap skip frames until their bytes have been paid off,
+/// which lowers their frame rate rather than queueing data
+#[derive(Clone, Default)]
+pub struct Bandwidth {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Bytes per second, None for no limit
+    client_limit: Option<u64>,
+    limit: Option<u64>,
+    /// Bytes sent to every client
+    pacer: Mutex<Pacer>,
+    rate: Mutex<ByteRate>,
+    /// Frames clients skipped because a cap was reached
+    throttled_frames: AtomicU64,
+}
+
+/// Egress of one client against its own cap and the shared one
+#[derive(Default)]
+pub struct ClientBandwidth {
+    bandwidth: Bandwidth,
+    pacer: Mutex<Pacer>,
+    rate: Mutex<ByteRate>,
+}
+
+/// Caps and egress as reported by the status API
+#[derive(Debug, Clone, Serialize)]
+pub struct BandwidthStatus {
+    /// Bytes per second sent to all clients over the last second
+    pub bytes_per_sec: u64,
+    pub limit: Option<u64>,
+    pub client_limit: Option<u64>,
+    pub throttled_frames: u64,
+}
+
+/// When the bytes sent so far have been paid off at a given rate
+#[derive(Default)]
+struct Pacer {
+    next: Option<Instant>,
+}
+
+#[derive(Default)]
+struct ByteRate {
+    window_start: Option<Instant>,
+    window_bytes: u64,
+    bytes_per_sec: u64,
+    last_sent: Option<Instant>,
+}
+
+impl Bandwidth {
+    /// At most `client_limit` bytes per second to each client and `limit` to
+    /// all of them; 0 for no limit
+    pub fn new(client_limit: u64, limit: u64) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                client_limit: (client_limit > 0).then_some(client_limit),
+                limit: (limit > 0).then_some(limit),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Accounting of a new client
+    pub fn client(&self) -> ClientBandwidth {
+        ClientBandwidth {
+            bandwidth: self.clone(),
+            ..Default::default()
+        }
+    }
+
+    pub fn status(&self) -> BandwidthStatus {
+        BandwidthStatus {
+            bytes_per_sec: self.inner.rate.lock().unwrap_or_else(|e| e.into_inner()).current(),
+            limit: self.inner.limit,
+            client_limit: self.inner.client_limit,
+            throttled_frames: self.inner.throttled_frames.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl ClientBandwidth {
+    /// Count `bytes` sent to the client against both caps
+    pub fn sent(&self, bytes: usize) {
+        let bytes = bytes as u64;
+        let inner = &self.bandwidth.inner;
+        self.rate.lock().unwrap_or_else(|e| e.into_inner()).add(bytes);
+        inner.rate.lock().unwrap_or_else(|e| e.into_inner()).add(bytes);
+        if let Some(limit) = inner.client_limit {
+            self.pacer.lock().unwrap_or_else(|e| e.into_inner()).add(bytes, limit);
+        }
+        if let Some(limit) = inner.limit {
+            inner.pacer.lock().unwrap_or_else(|e| e.into_inner()).add(bytes, limit);
+        }
+    }
+
+    /// When the next frame fits under both caps; None if it may be sent now.
+    /// Asking counts a throttled frame, so ask once per frame
+    pub fn throttled_until(&self) -> Option<Instant> {
+        let now = Instant::now();
+        let client = self.pacer.lock().unwrap_or_else(|e| e.into_inner()).next;
+        let shared = self.bandwidth.inner.pacer.lock().unwrap_or_else(|e| e.into_inner()).next;
+        let until = client.max(shared).filter(|until| *until > now)?;
+        self.bandwidth.inner.throttled_frames.fetch_add(1, Ordering::Relaxed);
+        Some(until)
+    }
+
+    /// Bytes per second sent to the client over the last second
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.rate.lock().unwrap_or_else(|e| e.into_inner()).current()
+    }
+}
+
+impl Pacer {
+    /// Bytes sent while earlier ones are still being paid off queue up behind them
+    fn add(&mut self, bytes: u64, limit: u64) {
+        let now = Instant::now();
+        let start = self.next.filter(|next| *next > now).unwrap_or(now);
+        self.next = Some(start + Duration::from_secs_f64(bytes as f64 / limit as f64));
+    }
+}
+
+impl ByteRate {
+    fn add(&mut self, bytes: u64) {
+        let now = Instant::now();
+        self.last_sent = Some(now);
+        self.window_bytes += bytes;
+        let window_start = *self.window_start.get_or_insert(now);
+        let elapsed = now.duration_since(window_start);
+        if elapsed >= RATE_WINDOW {
+            self.bytes_per_sec = (self.window_bytes as f64 / elapsed.as_secs_f64()) as u64;
+            self.window_start = Some(now);
+            self.window_bytes = 0;
+        }
+    }
+
+    /// Rate over the last window; 0 when nothing was sent for a whole window
+    fn current(&self) -> u64 {
+        if self.last_sent.is_none_or(|last| last.elapsed() >= RATE_WINDOW) {
+            return 0;
+        }
+        self.bytes_per_sec
+    }
+}
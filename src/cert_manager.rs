@@ -0,0 +1,73 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// HTTPS certificate from the OpenBMC certificate manager for kvm-rs
+
+use std::io::Cursor;
+use anyhow::{Context, Result};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tracing::{info, warn};
+use crate::tls::TlsIdentity;
+
+const CERTS_SERVICE: &str = "xyz.openbmc_project.Certs.Manager.Server.Https";
+/// Server certificate that Redfish CertificateService installs and replaces
+const CERTIFICATE_PATH: &str = "/xyz/openbmc_project/certs/server/https/1";
+const CERTIFICATE_INTERFACE: &str = "xyz.openbmc_project.Certs.Certificate";
+/// Where phosphor-certificate-manager installs the certificate with its
+/// private key, which D-Bus does not carry
+const SERVER_PEM: &str = "/etc/ssl/certs/https/server.pem";
+
+/// The BMC's HTTPS server certificate in xyz.openbmc_project.Certs
+pub struct CertManager {
+    proxy: zbus::Proxy<'static>,
+}
+
+impl CertManager {
+    pub async fn new(dbus: &zbus::Connection) -> Result<Self> {
+        let proxy = zbus::Proxy::new(dbus, CERTS_SERVICE, CERTIFICATE_PATH, CERTIFICATE_INTERFACE)
+            .await
+            .context("OpenBMC certificate manager unavailable")?;
+        Ok(Self { proxy })
+    }
+
+    /// Installed certificate chain and its private key
+    pub async fn server_certificate(&self) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+        let pem: String = self.proxy.get_property("CertificateString").await
+            .context("Failed to read the installed HTTPS certificate")?;
+        certificate_with_key(&pem).await
+    }
+
+    /// Present each certificate installed through Redfish from now on
+    pub async fn watch(self, identity: TlsIdentity) {
+        use futures_util::StreamExt;
+
+        let mut changes = self.proxy.receive_property_changed::<String>("CertificateString").await;
+        while let Some(change) = changes.next().await {
+            let installed = match change.get().await {
+                Ok(pem) => certificate_with_key(&pem).await,
+                Err(e) => Err(e.into()),
+            };
+            match installed.and_then(|(cert_chain, key)| identity.replace(cert_chain, key)) {
+                Ok(()) => info!("Presenting the HTTPS certificate replaced through the certificate manager"),
+                Err(e) => warn!("Keeping the current TLS certificate: {:#}", e),
+            }
+        }
+    }
+}
+
+/// Chain of the certificate manager's PEM and the key installed with it
+async fn certificate_with_key(pem: &str) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let cert_chain = rustls_pemfile::certs(&mut Cursor::new(pem.as_bytes()))
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to parse the installed HTTPS certificate")?;
+    let installed = tokio::fs::read(SERVER_PEM).await
+        .with_context(|| format!("Failed to read {}", SERVER_PEM))?;
+    // The file is rewritten before the property changes; make sure it holds this certificate
+    let installed_leaf = rustls_pemfile::certs(&mut Cursor::new(&installed)).next().transpose()?;
+    if installed_leaf.is_none() || installed_leaf.as_ref() != cert_chain.first() {
+        return Err(anyhow::anyhow!("{} does not hold the installed HTTPS certificate", SERVER_PEM));
+    }
+    let key = rustls_pemfile::private_key(&mut Cursor::new(&installed))
+        .with_context(|| format!("Failed to parse the private key in {}", SERVER_PEM))?
+        .ok_or_else(|| anyhow::anyhow!("No private key found in {}", SERVER_PEM))?;
+    Ok((cert_chain, key))
+}
@@ -0,0 +1,65 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Clipboard shared by the console clients of kvm-rs
+
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Longest clipboard text accepted from a client, in bytes
+pub const MAX_CLIPBOARD_TEXT: usize = 1024 * 1024;
+
+/// Text copied in one client, offered to every other client, like a desktop clipboard
+#[derive(Clone)]
+pub struct Clipboard {
+    tx: Arc<watch::Sender<ClipboardText>>,
+}
+
+/// Current clipboard contents
+#[derive(Debug, Clone, Default)]
+pub struct ClipboardText {
+    pub text: Arc<str>,
+    /// Session that copied it, which does not need it back; 0 if none
+    pub source: u64,
+}
+
+impl Default for Clipboard {
+    fn default() -> Self {
+        Self {
+            tx: Arc::new(watch::channel(ClipboardText::default()).0),
+        }
+    }
+}
+
+impl Clipboard {
+    /// Replace the contents with text copied in session `source`
+    pub fn set(&self, text: &str, source: u64) {
+        self.tx.send_if_modified(|current| {
+            if *current.text == *text {
+                return false;
+            }
+            *current = ClipboardText {
+                text: Arc::from(text),
+                source,
+            };
+            true
+        });
+    }
+
+    pub fn text(&self) -> Arc<str> {
+        self.tx.borrow().text.clone()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<ClipboardText> {
+        self.tx.subscribe()
+    }
+}
+
+/// RFB cut text is ISO 8859-1
+pub fn from_latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
+
+/// Text as ISO 8859-1 for RFB clients; other characters become '?'
+pub fn to_latin1(text: &str) -> Vec<u8> {
+    text.chars().map(|c| u8::try_from(u32::from(c)).unwrap_or(b'?')).collect()
+}
@@ -0,0 +1,301 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// One-shot debugging commands for kvm-rs
+
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use tokio::sync::broadcast;
+use crate::{
+    args::{Args, ConfigFormat, EffectiveOption},
+    display::{CaptureSettings, DisplayHub},
+    hid::HidManager,
+    hid_backend::MockHidBackend,
+    hid_descriptor,
+    keyboard::{self, KeyBlocklist, KeyCombo},
+    macros::MacroStore,
+    mock_capture::MockScript,
+    pointer::PointerSettings,
+    probe::{self, HardwareReport},
+    vnc::VncHandler,
+    vnc_password::VncPassword,
+};
+#[cfg(feature = "web")]
+use crate::cors;
+#[cfg(feature = "tls")]
+use crate::tls::TlsIdentity;
+
+/// How long `screenshot` waits for capture to deliver a frame
+const FRAME_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Save the next captured frame of --video as JPEG
+pub async fn screenshot(args: &Args, file: &Path, quality: u8) -> Result<()> {
+    let hub = DisplayHub::new();
+    let mut frames = hub.tx.subscribe();
+    let mut capture = tokio::spawn(hub.clone().spawn(args.video_device.clone(), CaptureSettings::from_args(args)?));
+    let frame_data = tokio::select! {
+        frame_data = async {
+            loop {
+                match frames.recv().await {
+                    Ok(frame_data) => return Some(frame_data),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        } => frame_data.ok_or_else(|| anyhow::anyhow!("Capture stopped"))?,
+        result = &mut capture => return match result {
+            Ok(Err(e)) => Err(e.into()),
+            _ => Err(anyhow::anyhow!("Capture stopped without a frame")),
+        },
+        _ = tokio::time::sleep(FRAME_TIMEOUT) => {
+            return Err(anyhow::anyhow!("No frame from {} within {}s", args.video_device, FRAME_TIMEOUT.as_secs()));
+        }
+    };
+    capture.abort();
+
+    // Only used to convert the frame; no input is sent
+    let hid_manager = HidManager::from_backends(
+        Arc::new(MockHidBackend::new("mock-keyboard")),
+        Arc::new(MockHidBackend::new("mock-mouse")),
+        None,
+        None,
+    );
+    let vnc = VncHandler::new(hub, hid_manager);
+    let jpeg = vnc.frame_jpeg(&frame_data, quality, false).await
+        .ok_or_else(|| anyhow::anyhow!("Failed to encode the captured frame"))?;
+    std::fs::write(file, &jpeg.data).with_context(|| format!("Failed to write {}", file.display()))?;
+    println!("Saved a {}x{} screenshot of {} to {}", jpeg.width, jpeg.height, args.video_device, file.display());
+    Ok(())
+}
+
+/// Type text, or press key combinations, on the keyboard gadget
+pub async fn inject_keys(hid_manager: &HidManager, text: &[String], combo: bool) -> Result<()> {
+    if combo {
+        let combos = text.iter()
+            .map(|combo| KeyCombo::parse(combo))
+            .collect::<Result<Vec<_>>>()?;
+        for combo in &combos {
+            hid_manager.tap_keys(&combo.usages()).await?;
+        }
+    } else {
+        let text = text.join(" ");
+        if let Some(c) = text.chars().find(|c| keyboard::usage_for_char(*c).is_none()) {
+            return Err(anyhow::anyhow!("Cannot type character {:?}", c));
+        }
+        hid_manager.type_text(&text).await?;
+    }
+    hid_manager.close_devices().await;
+    Ok(())
+}
+
+/// Print a JSON report of the capture devices, HID gadgets and codecs found
+pub fn probe(args: &Args) -> Result<()> {
+    let report = HardwareReport::collect(args);
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+/// Outcome of one check, as reported by --check
+#[derive(Debug, Serialize)]
+pub struct CheckResult {
+    pub check: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Report of --check
+#[derive(Debug, Default, Serialize)]
+pub struct CheckReport {
+    pub ok: bool,
+    pub checks: Vec<CheckResult>,
+}
+
+impl CheckReport {
+    fn record(&mut self, check: impl Into<String>, result: Result<()>) {
+        self.checks.push(CheckResult {
+            check: check.into(),
+            ok: result.is_ok(),
+            error: result.err().map(|e| format!("{:#}", e)),
+        });
+    }
+
+    fn failed(&self) -> impl Iterator<Item = &CheckResult> {
+        self.checks.iter().filter(|result| !result.ok)
+    }
+}
+
+/// Check the options and the files they name, printing every problem found
+pub async fn check_config(args: &Args) -> Result<()> {
+    let mut report = CheckReport::default();
+    check_options(args, &mut report).await;
+
+    let failed = report.failed().count();
+    if failed == 0 {
+        println!("Configuration OK");
+        return Ok(());
+    }
+    for result in report.failed() {
+        println!("{}: {}", result.check, result.error.as_deref().unwrap_or_default());
+    }
+    Err(anyhow::anyhow!("Found {} configuration problems", failed))
+}
+
+/// Open every device and load every file the server would use, and print a
+/// JSON report of what would fail
+pub async fn check(args: &Args) -> Result<()> {
+    let mut report = CheckReport::default();
+    check_options(args, &mut report).await;
+
+    let mut videos = vec![args.video_device.as_str()];
+    let mut gadgets = vec![
+        (args.keyboard_hid.as_str(), args.keyboard_report_desc.as_deref()),
+        (args.mouse_hid.as_str(), args.mouse_report_desc.as_deref()),
+    ];
+    if let Some(ref touchscreen) = args.touchscreen_hid {
+        gadgets.push((touchscreen.as_str(), args.touchscreen_report_desc.as_deref()));
+    }
+    for console in &args.consoles {
+        videos.push(&console.video_device);
+        gadgets.push((console.keyboard_hid.as_str(), args.keyboard_report_desc.as_deref()));
+        gadgets.push((console.mouse_hid.as_str(), args.mouse_report_desc.as_deref()));
+        if let Some(ref touchscreen) = console.touchscreen_hid {
+            gadgets.push((touchscreen.as_str(), args.touchscreen_report_desc.as_deref()));
+        }
+    }
+    if !args.uses_mock_video() {
+        for video in videos {
+            report.record(format!("video {}", video), check_video(video));
+        }
+    }
+    if !args.mock_hid {
+        for (device, descriptor_path) in gadgets {
+            report.record(format!("HID gadget {}", device), check_gadget(device, descriptor_path));
+        }
+    }
+    let novnc = Path::new(&args.novnc_dir).join("core/rfb.js");
+    report.record("noVNC", if novnc.exists() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!("{} not found, the web console will not load", novnc.display()))
+    });
+
+    let ok = report.failed().next().is_none();
+    report.ok = ok;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    if !report.ok {
+        return Err(anyhow::anyhow!("{} checks failed", report.failed().count()));
+    }
+    Ok(())
+}
+
+/// Print the effective options with the source of each, for support requests
+pub fn dump_config(format: ConfigFormat) -> Result<()> {
+    let options = Args::effective_options()?;
+    match format {
+        ConfigFormat::Json => {
+            let dump: serde_json::Map<String, serde_json::Value> = options.iter()
+                .map(|option| (option.name.clone(), serde_json::json!({
+                    "value": option_value(option),
+                    "source": option.source.as_str(),
+                })))
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&dump)?);
+        }
+        ConfigFormat::Toml => {
+            for option in &options {
+                // JSON strings, numbers, booleans and arrays of them are valid TOML values
+                println!("{} = {}  # {}", option.name, option_value(option), option.source.as_str());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Value of an option as a boolean for flags, an array for repeatable
+/// options, and a number or string otherwise
+fn option_value(option: &EffectiveOption) -> serde_json::Value {
+    let scalar = |value: &String| {
+        if option.flag {
+            if let Ok(flag) = value.parse::<bool>() {
+                return serde_json::Value::Bool(flag);
+            }
+        }
+        // Keep values such as 0600 or 1.0 as they were written
+        match value.parse::<i64>() {
+            Ok(number) if number.to_string() == *value => serde_json::Value::from(number),
+            _ => serde_json::Value::from(value.as_str()),
+        }
+    };
+    if option.repeatable {
+        serde_json::Value::Array(option.values.iter().map(scalar).collect())
+    } else {
+        option.values.first().map(scalar).unwrap_or(serde_json::Value::Null)
+    }
+}
+
+/// Open a capture device and query its formats
+fn check_video(path: &str) -> Result<()> {
+    if path.starts_with("/dev/fb") {
+        probe::framebuffer(Path::new(path))?;
+    } else {
+        probe::video_device(path)?;
+    }
+    std::fs::File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    Ok(())
+}
+
+/// Open a HID gadget read-only and parse its report descriptor
+fn check_gadget(device: &str, descriptor_path: Option<&str>) -> Result<()> {
+    std::fs::File::open(device).with_context(|| format!("Failed to open {}", device))?;
+    if let Some(descriptor) = hid_descriptor::load_descriptor(device, descriptor_path)? {
+        hid_descriptor::parse(&descriptor)?;
+    }
+    Ok(())
+}
+
+/// Checks of the options and the files they name
+async fn check_options(args: &Args, report: &mut CheckReport) {
+    report.record("pointer settings", PointerSettings::from_args(args).validate());
+    report.record("--block-keys", KeyBlocklist::parse(&args.block_keys).map(drop));
+    report.record("--macro-file", MacroStore::load(args.macro_file.clone()).map(drop));
+    #[cfg(feature = "web")]
+    report.record("CORS", cors::layer(&args.cors_origins, &args.cors_methods).map(drop));
+    if let Some(ref path) = args.mock_video_script {
+        report.record("--mock-video-script", MockScript::load(path).map(drop));
+    }
+    let mut ids = vec![0];
+    for console in &args.consoles {
+        if ids.contains(&console.id) {
+            report.record("--console", Err(anyhow::anyhow!("console {} is configured more than once", console.id)));
+        }
+        ids.push(console.id);
+    }
+    if let Some(ref path) = args.vnc_password_file {
+        report.record("--vnc-password-file", VncPassword::load(path.as_ref()).map(drop));
+    }
+    if let Some(ref path) = args.api_token_file {
+        let token = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path));
+        report.record("--api-token-file", token.and_then(|token| {
+            if token.trim().is_empty() {
+                return Err(anyhow::anyhow!("{} is empty", path));
+            }
+            Ok(())
+        }));
+    }
+    #[cfg(feature = "tls")]
+    if args.vnc_cert.is_some() || args.vnc_key.is_some() {
+        let identity = TlsIdentity::from_paths(args.vnc_cert.as_deref(), args.vnc_key.as_deref(), &[]).await;
+        let identity = match identity {
+            Ok(identity) => identity.with_sni_certs(&args.sni_certs).await,
+            Err(e) => Err(e),
+        };
+        let identity = match (identity, &args.client_ca) {
+            (Ok(identity), Some(ca)) => identity.with_client_ca(ca),
+            (identity, _) => identity,
+        };
+        report.record("TLS", identity.map(drop));
+    }
+}
@@ -0,0 +1,296 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Control of the service and its consoles over D-Bus for kvm-rs
+
+use std::collections::HashMap;
+use std::time::Duration;
+use anyhow::Result;
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+use zbus::{fdo, SignalContext};
+use std::sync::Arc;
+use crate::{events::{Event, EventBus}, frame_dump::FrameDump, reload::Reloader, services::{Service, Services}, sessions::{SessionInfo, SessionRegistry}, targets::{Target, TargetRegistry}, virtual_media::{MediaError, VirtualMedia}};
+
+/// Well-known D-Bus name kvm-rs takes for its control interface
+pub const DBUS_NAME: &str = "xyz.openbmc_project.KvmRs";
+pub const DBUS_PATH: &str = "/xyz/openbmc_project/kvm_rs";
+
+/// How long Screenshot waits for the next captured frame
+const SCREENSHOT_TIMEOUT: Duration = Duration::from_secs(5);
+/// JPEG quality of screenshots encoded from raw frames
+const SCREENSHOT_QUALITY: u8 = 85;
+
+/// A session as GetSessions and GetSession report it: id, transport, address,
+/// user, role, connect time, frames, frames per second, bytes sent and input events
+type SessionStats = (u64, String, String, String, String, String, u64, f64, u64, u64);
+
+/// Offer the Control interface, and a Console object under
+/// `/xyz/openbmc_project/kvm_rs/console/{id}` for each console, on the system bus.
+/// Session starts, ends and role changes on `events` are signalled on Control
+#[allow(clippy::too_many_arguments)]
+pub async fn serve_dbus(
+    dbus: &zbus::Connection,
+    reloader: Reloader,
+    sessions: SessionRegistry,
+    services: Services,
+    targets: &TargetRegistry,
+    events: &EventBus,
+    frame_dump: FrameDump,
+    virtual_media: Option<Arc<VirtualMedia>>,
+) -> Result<()> {
+    let object_server = dbus.object_server();
+    object_server.at(DBUS_PATH, Control { reloader, sessions, services: services.clone(), frame_dump, virtual_media }).await?;
+    let control = object_server.interface::<_, Control>(DBUS_PATH).await?;
+    tokio::spawn(signal_sessions(control.signal_context().clone(), events.subscribe()));
+    for id in targets.ids() {
+        if let Some(target) = targets.get(id) {
+            let path = format!("{}/console/{}", DBUS_PATH, id);
+            object_server.at(path, Console { target: target.clone(), services: services.clone() }).await?;
+        }
+    }
+    dbus.request_name(DBUS_NAME).await?;
+    Ok(())
+}
+
+/// xyz.openbmc_project.KvmRs.Control D-Bus interface
+struct Control {
+    reloader: Reloader,
+    sessions: SessionRegistry,
+    services: Services,
+    frame_dump: FrameDump,
+    virtual_media: Option<Arc<VirtualMedia>>,
+}
+
+#[zbus::interface(name = "xyz.openbmc_project.KvmRs.Control")]
+impl Control {
+    /// Reload the configuration, as SIGHUP does
+    async fn reload(&self) -> fdo::Result<()> {
+        self.reloader.reload().map_err(|e| {
+            warn!("Configuration reload failed, keeping the current settings: {:#}", e);
+            fdo::Error::Failed(format!("{:#}", e))
+        })
+    }
+
+    /// Close the session `id` of any console and transport
+    async fn disconnect_session(&self, id: u64) -> fdo::Result<()> {
+        if !self.sessions.disconnect(id) {
+            return Err(fdo::Error::InvalidArgs(format!("No session {}", id)));
+        }
+        info!("Disconnecting session {} on request over D-Bus", id);
+        Ok(())
+    }
+
+    /// Connected clients with their counters, oldest first; user and role are
+    /// empty when not known
+    async fn get_sessions(&self) -> Vec<SessionStats> {
+        self.sessions.list().into_iter().map(session_stats).collect()
+    }
+
+    /// Live counters of the session `id`
+    async fn get_session(&self, id: u64) -> fdo::Result<SessionStats> {
+        self.sessions.get(id)
+            .map(session_stats)
+            .ok_or_else(|| fdo::Error::InvalidArgs(format!("No session {}", id)))
+    }
+
+    /// Turn the service "vnc", "websocket" or "screenshot" on or off; turning
+    /// it off closes its sessions
+    async fn set_service_enabled(&self, service: &str, enabled: bool) -> fdo::Result<()> {
+        let service = <Service as clap::ValueEnum>::from_str(service, true)
+            .map_err(|_| fdo::Error::InvalidArgs(format!("No service {}", service)))?;
+        self.services.set(service, enabled);
+        Ok(())
+    }
+
+    /// Write the next `frames` captured frames and their RGB conversions to
+    /// the frame dump directory, which is returned; 0 stops a dump
+    async fn dump_frames(&self, frames: u32) -> fdo::Result<String> {
+        let status = self.frame_dump.start(frames).map_err(|e| fdo::Error::InvalidArgs(format!("{:#}", e)))?;
+        Ok(status.dir)
+    }
+
+    /// Insert the uploaded image `image` in the virtual media drive
+    async fn insert_media(&self, image: &str, read_only: bool, removable: bool) -> fdo::Result<()> {
+        self.drive()?.insert(image, read_only, removable).map_err(media_error)?;
+        Ok(())
+    }
+
+    /// Insert the image at `url` in the virtual media drive, read-only; the
+    /// user name and password, if not empty, log in to an SMB share
+    async fn insert_remote_media(&self, url: &str, removable: bool, username: &str, password: &str) -> fdo::Result<()> {
+        let credentials = (!username.is_empty()).then_some((username, password));
+        self.drive()?.insert_url(url, removable, credentials).await.map_err(media_error)?;
+        Ok(())
+    }
+
+    /// Eject the image in the virtual media drive; false if it was empty
+    async fn eject_media(&self) -> fdo::Result<bool> {
+        Ok(self.drive()?.eject().map_err(media_error)?.is_some())
+    }
+
+    /// Image in the virtual media drive, empty if none or without a drive
+    #[zbus(property)]
+    async fn inserted_media(&self) -> String {
+        self.virtual_media.as_ref()
+            .and_then(|drive| drive.status().inserted)
+            .map(|medium| medium.image)
+            .unwrap_or_default()
+    }
+
+    /// Whether each service is on, by name
+    #[zbus(property)]
+    async fn services(&self) -> HashMap<String, bool> {
+        let state = self.services.state();
+        Service::ALL.into_iter()
+            .map(|service| (service.as_str().to_string(), state.enabled(service)))
+            .collect()
+    }
+
+    /// A client connected
+    #[zbus(signal)]
+    async fn session_started(ctxt: &SignalContext<'_>, id: u64, transport: &str, address: &str) -> zbus::Result<()>;
+
+    /// A client disconnected
+    #[zbus(signal)]
+    async fn session_ended(ctxt: &SignalContext<'_>, id: u64, transport: &str, address: &str) -> zbus::Result<()>;
+
+    /// A client got its role or a new one; user is empty when not known
+    #[zbus(signal)]
+    async fn role_changed(ctxt: &SignalContext<'_>, id: u64, user: &str, role: &str) -> zbus::Result<()>;
+
+    /// Connected clients as (id, transport, address, seconds connected), oldest first
+    #[zbus(property)]
+    async fn active_sessions(&self) -> Vec<(u64, String, String, u64)> {
+        self.sessions.list().into_iter()
+            .map(|session| (session.id, session.transport.to_string(), session.address, session.duration_secs))
+            .collect()
+    }
+}
+
+impl Control {
+    fn drive(&self) -> fdo::Result<&VirtualMedia> {
+        self.virtual_media.as_deref()
+            .ok_or_else(|| fdo::Error::NotSupported("No virtual media drive, see --mass-storage".to_string()))
+    }
+}
+
+fn media_error(e: MediaError) -> fdo::Error {
+    match e {
+        MediaError::Busy(_) | MediaError::UnknownImage(_) | MediaError::InvalidUrl(_) => fdo::Error::InvalidArgs(e.to_string()),
+        MediaError::Remote(_) | MediaError::Gadget(_) => fdo::Error::Failed(e.to_string()),
+    }
+}
+
+/// Emit the Control signals of session events until the bus closes
+async fn signal_sessions(ctxt: SignalContext<'static>, mut events: broadcast::Receiver<Event>) {
+    loop {
+        let result = match events.recv().await {
+            Ok(Event::ClientConnected { id, transport, address }) => Control::session_started(&ctxt, id, transport, &address).await,
+            Ok(Event::ClientDisconnected { id, transport, address }) => Control::session_ended(&ctxt, id, transport, &address).await,
+            Ok(Event::RoleChanged { id, user, role }) => {
+                Control::role_changed(&ctxt, id, user.as_deref().unwrap_or_default(), role.as_str()).await
+            }
+            Ok(_) => continue,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!("{} session events were not signalled on D-Bus", missed);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        if let Err(e) = result {
+            warn!("Failed to emit a D-Bus session signal: {}", e);
+        }
+    }
+}
+
+fn session_stats(session: SessionInfo) -> SessionStats {
+    (
+        session.id,
+        session.transport.to_string(),
+        session.address,
+        session.user.unwrap_or_default(),
+        session.role.map(|role| role.as_str().to_string()).unwrap_or_default(),
+        session.connected_at,
+        session.frames,
+        session.fps,
+        session.bytes_sent,
+        session.input_events,
+    )
+}
+
+/// xyz.openbmc_project.KvmRs.Console D-Bus interface of one console
+struct Console {
+    target: Target,
+    services: Services,
+}
+
+#[zbus::interface(name = "xyz.openbmc_project.KvmRs.Console")]
+impl Console {
+    /// Forward client input to the host, or block it as the input lock does
+    async fn enable_input(&self, enable: bool) {
+        self.target.hid_manager.set_input_locked(!enable).await;
+    }
+
+    /// Show clients a black screen instead of the host's
+    async fn set_privacy_mode(&self, enabled: bool) {
+        self.target.vnc.set_privacy_mode(enabled);
+    }
+
+    /// The next captured frame as JPEG
+    async fn screenshot(&self) -> fdo::Result<Vec<u8>> {
+        if !self.services.is_enabled(Service::Screenshot) {
+            return Err(fdo::Error::AccessDenied("Screenshot service is turned off".to_string()));
+        }
+        let mut frames = self.target.hub.tx.subscribe();
+        let frame_data = tokio::time::timeout(SCREENSHOT_TIMEOUT, async {
+            loop {
+                match frames.recv().await {
+                    Ok(frame_data) => return Some(frame_data),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+        .await
+        .ok()
+        .flatten()
+        .ok_or_else(|| fdo::Error::Failed("Capture is not delivering frames".to_string()))?;
+        let jpeg = self.target.vnc.frame_jpeg(&frame_data, SCREENSHOT_QUALITY, false).await
+            .ok_or_else(|| fdo::Error::Failed("Failed to encode the frame".to_string()))?;
+        Ok(jpeg.data.clone())
+    }
+
+    /// Width and height of the host's screen
+    #[zbus(property)]
+    async fn resolution(&self) -> (u16, u16) {
+        self.target.vnc.resolution().await
+    }
+
+    /// "Starting" until the first frame, then "Running", "Stalled" while no
+    /// frames arrive, or "Failed" once capture has stopped
+    #[zbus(property)]
+    async fn capture_state(&self) -> String {
+        let status = self.target.hub.status();
+        let state = if status.error.is_some() {
+            "Failed"
+        } else if status.frames == 0 {
+            "Starting"
+        } else if status.fps == 0.0 {
+            "Stalled"
+        } else {
+            "Running"
+        };
+        state.to_string()
+    }
+
+    /// Whether client input is forwarded to the host
+    #[zbus(property)]
+    async fn input_enabled(&self) -> bool {
+        !self.target.hid_manager.is_input_locked()
+    }
+
+    #[zbus(property)]
+    async fn privacy_mode(&self) -> bool {
+        self.target.vnc.privacy_mode()
+    }
+}
@@ -0,0 +1,182 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Encrypted credential store for kvm-rs
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use aws_lc_rs::{aead, hkdf, rand};
+use serde::{Deserialize, Serialize};
+
+/// Context the store key is derived for, so the device secret yields other keys elsewhere
+const KEY_INFO: &[u8] = b"kvm-rs credential store v1";
+const SALT_LEN: usize = 16;
+
+/// Secrets kept in the credential store
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Credential {
+    /// Password of the VNC port (VNC Authentication)
+    VncPassword,
+    /// Password of --ldap-bind-dn
+    LdapBindPassword,
+}
+
+impl Credential {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Credential::VncPassword => "vnc-password",
+            Credential::LdapBindPassword => "ldap-bind-password",
+        }
+    }
+}
+
+/// Layout of the store file; values are hex encoded
+#[derive(Serialize, Deserialize)]
+struct StoreFile {
+    salt: String,
+    /// Nonce followed by the AES-256-GCM ciphertext and tag, by credential name
+    secrets: BTreeMap<String, String>,
+}
+
+/// Secrets encrypted with AES-256-GCM under a key derived (HKDF-SHA256) from
+/// a device secret, so the file alone does not reveal them
+pub struct CredentialStore {
+    path: PathBuf,
+    salt: Vec<u8>,
+    key: aead::LessSafeKey,
+    secrets: BTreeMap<String, Vec<u8>>,
+}
+
+impl CredentialStore {
+    /// Open the store at `path`, or start an empty one if it does not exist yet
+    pub fn open(path: &Path, device_secret: &Path) -> Result<Self> {
+        let secret = std::fs::read(device_secret)
+            .with_context(|| format!("Failed to read device secret {}", device_secret.display()))?;
+        if secret.iter().all(u8::is_ascii_whitespace) {
+            return Err(anyhow::anyhow!("Device secret {} is empty", device_secret.display()));
+        }
+        let (salt, encoded) = match std::fs::read(path) {
+            Ok(contents) => {
+                let file: StoreFile = serde_json::from_slice(&contents)
+                    .with_context(|| format!("Invalid credential store {}", path.display()))?;
+                (from_hex(&file.salt)?, file.secrets)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let mut salt = vec![0u8; SALT_LEN];
+                random_bytes(&mut salt)?;
+                (salt, BTreeMap::new())
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to read credential store {}", path.display())),
+        };
+        let secrets = encoded.iter()
+            .map(|(name, value)| Ok((name.clone(), from_hex(value)?)))
+            .collect::<Result<_>>()?;
+
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, &salt).extract(&secret);
+        let okm = prk.expand(&[KEY_INFO], &aead::AES_256_GCM)
+            .map_err(|_| anyhow::anyhow!("Failed to derive the credential store key"))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            salt,
+            key: aead::LessSafeKey::new(aead::UnboundKey::from(okm)),
+            secrets,
+        })
+    }
+
+    /// Decrypted value of a credential, if the store has one
+    pub fn get(&self, credential: Credential) -> Result<Option<String>> {
+        let Some(sealed) = self.secrets.get(credential.as_str()) else {
+            return Ok(None);
+        };
+        if sealed.len() < aead::NONCE_LEN {
+            return Err(anyhow::anyhow!("Stored {} is truncated", credential.as_str()));
+        }
+        let (nonce, ciphertext) = sealed.split_at(aead::NONCE_LEN);
+        let nonce = aead::Nonce::try_assume_unique_for_key(nonce)
+            .map_err(|_| anyhow::anyhow!("Invalid nonce of stored {}", credential.as_str()))?;
+        let mut in_out = ciphertext.to_vec();
+        let plaintext = self.key
+            .open_in_place(nonce, aead::Aad::from(credential.as_str().as_bytes()), &mut in_out)
+            .map_err(|_| anyhow::anyhow!(
+                "Cannot decrypt stored {}: the store was written on another device or with another secret",
+                credential.as_str()
+            ))?;
+        let value = String::from_utf8(plaintext.to_vec())
+            .with_context(|| format!("Stored {} is not text", credential.as_str()))?;
+        Ok(Some(value))
+    }
+
+    /// Encrypt and save a credential, replacing the stored value
+    pub fn set(&mut self, credential: Credential, value: &str) -> Result<()> {
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        random_bytes(&mut nonce)?;
+        let mut in_out = value.as_bytes().to_vec();
+        self.key
+            .seal_in_place_append_tag(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::from(credential.as_str().as_bytes()),
+                &mut in_out,
+            )
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt {}", credential.as_str()))?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&in_out);
+        self.secrets.insert(credential.as_str().to_string(), sealed);
+        self.save()
+    }
+
+    /// Write the store readable by its owner only, replacing the file atomically
+    fn save(&self) -> Result<()> {
+        use std::io::Write;
+
+        let file = StoreFile {
+            salt: to_hex(&self.salt),
+            secrets: self.secrets.iter().map(|(name, sealed)| (name.clone(), to_hex(sealed))).collect(),
+        };
+        let contents = serde_json::to_vec_pretty(&file)?;
+        let temp = self.path.with_extension("tmp");
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut out = options.open(&temp)
+            .with_context(|| format!("Failed to write {}", temp.display()))?;
+        out.write_all(&contents)?;
+        out.sync_all()?;
+        std::fs::rename(&temp, &self.path)
+            .with_context(|| format!("Failed to replace credential store {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+/// Random secret for session and resume tokens, hex encoded
+pub fn random_token() -> Result<String> {
+    let mut bytes = [0u8; 32];
+    random_bytes(&mut bytes)?;
+    Ok(to_hex(&bytes))
+}
+
+/// Fill `bytes` from the system's secure random source
+pub fn random_bytes(bytes: &mut [u8]) -> Result<()> {
+    rand::fill(bytes).map_err(|_| anyhow::anyhow!("No secure random source"))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(anyhow::anyhow!("Invalid hex in credential store"));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| anyhow::anyhow!("Invalid hex in credential store"))
+        })
+        .collect()
+}
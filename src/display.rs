@@ -2,12 +2,43 @@
 //
 // Display hub with V4L2 and framebuffer support for kvm-rs
 
-use std::sync::Arc;
-use tokio::sync::broadcast;
-use anyhow::Result;
+use std::collections::HashMap;
+use std::future::Future;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, OnceCell};
+use serde::Serialize;
+use tracing::{debug, error, info, warn};
+use crate::{
+    args::Args,
+    frame_budget::{FrameBudget, Reservation},
+    mock_capture::{FrameSource, MockScript, Step},
+    power::HostState,
+};
+
+/// Window the frame rate is averaged over
+const FPS_WINDOW: Duration = Duration::from_secs(2);
+
+/// Frames kept for subscribers that have not received them yet; every
+/// subscriber only wants the newest, and each one held counts against the budget
+const FRAME_QUEUE: usize = 2;
+
+/// How often the placeholder is sent while the host is off, for clients connecting meanwhile
+const PLACEHOLDER_INTERVAL: Duration = Duration::from_secs(1);
+/// Placeholder shown while the host is off when no image is given: dark grey at 640x480
+const PLACEHOLDER_COLOR: [u8; 3] = [0x20, 0x20, 0x20];
+/// V4L2_CID_JPEG_CHROMA_SUBSAMPLING, set on video engines that encode JPEG
+#[cfg(target_os = "linux")]
+const V4L2_CID_JPEG_CHROMA_SUBSAMPLING: u32 = 0x009d_0901;
+/// V4L2_CID_JPEG_COMPRESSION_QUALITY, set on video engines that encode JPEG
+#[cfg(target_os = "linux")]
+const V4L2_CID_JPEG_COMPRESSION_QUALITY: u32 = 0x009d_0903;
 
 /// Video capture mode detected or forced
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
 #[allow(dead_code)] // Used on Linux only
 pub enum CaptureMode {
     V4L2,
@@ -15,15 +46,305 @@ pub enum CaptureMode {
     Mock,
 }
 
+/// Why video capture stopped
+#[derive(Debug, thiserror::Error)]
+pub enum CaptureError {
+    #[error("Failed to open capture device {device}: {source}")]
+    Open { device: String, source: std::io::Error },
+    #[error("Failed to query the capture device: {0}")]
+    Query(std::io::Error),
+    #[error("Failed to create mmap stream: {0}")]
+    Stream(std::io::Error),
+}
+
+impl CaptureError {
+    /// Whether the device is missing or inaccessible, so capture cannot
+    /// succeed until it is fixed; otherwise e.g. another process holds it
+    pub fn is_fatal(&self) -> bool {
+        let (CaptureError::Open { source, .. } | CaptureError::Query(source) | CaptureError::Stream(source)) = self;
+        matches!(source.kind(), std::io::ErrorKind::NotFound | std::io::ErrorKind::PermissionDenied)
+    }
+}
+
+/// How consoles capture video
+#[derive(Clone, Default)]
+pub struct CaptureSettings {
+    /// Read the framebuffer even where a V4L2 device exists
+    pub force_framebuffer: bool,
+    /// Frames played instead of capturing from the video devices
+    pub mock: Option<Arc<MockScript>>,
+    /// Host whose video this is, whose power-off pauses capture
+    pub host: Option<HostPause>,
+    /// Frame rate asked of V4L2 devices
+    pub frame_rate: Option<u32>,
+    /// Chroma subsampling asked of V4L2 devices that encode JPEG
+    pub subsampling: Option<ChromaSubsampling>,
+    /// JPEG quality asked of V4L2 devices that encode JPEG, so their frames
+    /// can be forwarded instead of encoded again in software
+    pub jpeg_quality: Option<u8>,
+}
+
+/// JPEG chroma subsampling of the video engine: full colour resolution, or a
+/// quarter of it for smaller frames
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChromaSubsampling {
+    #[value(name = "444")]
+    Yuv444,
+    #[value(name = "420")]
+    Yuv420,
+}
+
+impl ChromaSubsampling {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChromaSubsampling::Yuv444 => "4:4:4",
+            ChromaSubsampling::Yuv420 => "4:2:0",
+        }
+    }
+
+    /// Value of V4L2_CID_JPEG_CHROMA_SUBSAMPLING
+    #[cfg(target_os = "linux")]
+    fn control_value(&self) -> i64 {
+        match self {
+            ChromaSubsampling::Yuv444 => 0,
+            ChromaSubsampling::Yuv420 => 2,
+        }
+    }
+}
+
+/// Capture of a host's video is paused while the host is off, with a
+/// placeholder shown instead of whatever the capture device makes of no signal
+#[derive(Clone)]
+pub struct HostPause {
+    state: HostState,
+    placeholder: Arc<FrameSource>,
+}
+
+impl HostPause {
+    /// Show the JPEG `image`, or a dark screen, while `state` says the host is off
+    pub fn new(state: HostState, image: Option<&Path>) -> anyhow::Result<Self> {
+        let placeholder = match image {
+            Some(path) => {
+                let data = std::fs::read(path)
+                    .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+                if !data.starts_with(&[0xFF, 0xD8]) {
+                    anyhow::bail!("{} is not a JPEG file", path.display());
+                }
+                FrameSource::Jpeg(data)
+            }
+            None => FrameSource::Solid { width: 640, height: 480, color: PLACEHOLDER_COLOR },
+        };
+        Ok(Self {
+            state,
+            placeholder: Arc::new(placeholder),
+        })
+    }
+}
+
+impl CaptureSettings {
+    pub fn from_args(args: &Args) -> anyhow::Result<Self> {
+        let mock = match args.mock_video_script {
+            Some(ref path) => Some(Arc::new(MockScript::load(path)?)),
+            None if args.mock_video => Some(Arc::new(MockScript::default())),
+            None => None,
+        };
+        Ok(Self {
+            force_framebuffer: args.force_framebuffer,
+            mock,
+            host: None,
+            frame_rate: args.frame_rate,
+            subsampling: args.jpeg_subsampling,
+            jpeg_quality: Some(args.ws_jpeg_quality),
+        })
+    }
+}
+
+/// A captured frame, shared by the subscribers of a `DisplayHub` and counted
+/// against the frame budget until the last of them drops it
+pub struct Frame {
+    data: Vec<u8>,
+    _reservation: Reservation,
+    /// JPEG encodings of the frame made so far, shared by every client asking
+    /// for the same one
+    jpeg: Mutex<HashMap<JpegKey, Arc<JpegSlot>>>,
+}
+
+/// One encoding of a frame, empty until its first client has encoded it
+type JpegSlot = OnceCell<Option<Arc<Jpeg>>>;
+
+/// Which JPEG encoding of a frame a client is sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct JpegKey {
+    /// None for a frame captured as JPEG and forwarded as it is
+    pub quality: Option<u8>,
+    /// Blacked out, in privacy mode
+    pub blank: bool,
+}
+
+/// A frame encoded as JPEG
+pub struct Jpeg {
+    pub width: u16,
+    pub height: u16,
+    pub data: Vec<u8>,
+}
+
+impl Frame {
+    /// The `key` encoding of the frame; the first client asking for it
+    /// encodes it with `encode`, the others wait for that and share it
+    pub async fn jpeg<F, Fut>(&self, key: JpegKey, encode: F) -> Option<Arc<Jpeg>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Option<Jpeg>>,
+    {
+        let cell = self.jpeg.lock().unwrap_or_else(|e| e.into_inner()).entry(key).or_default().clone();
+        cell.get_or_init(|| async { encode().await.map(Arc::new) }).await.clone()
+    }
+}
+
+impl Deref for Frame {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
 /// Shared video frame broadcaster
 pub struct DisplayHub {
-    pub tx: broadcast::Sender<Vec<u8>>,
+    pub tx: broadcast::Sender<Arc<Frame>>,
+    budget: FrameBudget,
+    stats: Mutex<CaptureStats>,
+}
+
+#[derive(Default)]
+struct CaptureStats {
+    mode: Option<CaptureMode>,
+    frames: u64,
+    window_start: Option<Instant>,
+    window_frames: u32,
+    fps: f64,
+    last_frame: Option<Instant>,
+    error: Option<String>,
+    fatal: bool,
+    paused: bool,
+}
+
+/// Capture state as reported by the status API
+#[derive(Debug, Clone, Serialize)]
+pub struct CaptureStatus {
+    /// None until capture has started
+    pub mode: Option<CaptureMode>,
+    /// Frames broadcast since startup
+    pub frames: u64,
+    pub fps: f64,
+    /// Why capture stopped, None while it runs
+    pub error: Option<String>,
+    /// Whether capture stopped because the device is missing or inaccessible
+    pub fatal: bool,
+    /// Whether capture is paused while the host is powered off
+    pub paused: bool,
 }
 
 impl DisplayHub {
     pub fn new() -> Arc<Self> {
-        let (tx, _rx) = broadcast::channel(16);
-        Arc::new(Self { tx })
+        Self::with_budget(FrameBudget::default())
+    }
+
+    /// A hub whose frames count against `budget`
+    pub fn with_budget(budget: FrameBudget) -> Arc<Self> {
+        let (tx, _rx) = broadcast::channel(FRAME_QUEUE);
+        Arc::new(Self {
+            tx,
+            budget,
+            stats: Mutex::new(CaptureStats::default()),
+        })
+    }
+
+    /// Budget of this hub's frames and its clients' buffers
+    pub fn budget(&self) -> &FrameBudget {
+        &self.budget
+    }
+
+    /// Broadcast a frame to all subscribers and count it for the frame rate;
+    /// a frame that does not fit the budget is dropped and reaches no one
+    fn publish(&self, frame_data: Vec<u8>) -> Result<usize, broadcast::error::SendError<Arc<Frame>>> {
+        {
+            let mut stats = self.stats.lock().unwrap_or_else(|e| e.into_inner());
+            let now = Instant::now();
+            stats.frames += 1;
+            stats.last_frame = Some(now);
+            // A restarted capture runs again
+            stats.error = None;
+            stats.fatal = false;
+            stats.window_frames += 1;
+            let window_start = *stats.window_start.get_or_insert(now);
+            let elapsed = now.duration_since(window_start);
+            if elapsed >= FPS_WINDOW {
+                stats.fps = stats.window_frames as f64 / elapsed.as_secs_f64();
+                stats.window_start = Some(now);
+                stats.window_frames = 0;
+            }
+        }
+        self.send(frame_data)
+    }
+
+    /// Broadcast a frame without counting it, as the placeholder
+    fn send(&self, frame_data: Vec<u8>) -> Result<usize, broadcast::error::SendError<Arc<Frame>>> {
+        let Some(reservation) = self.budget.reserve_frame(frame_data.len()) else {
+            debug!("Dropped a {}-byte frame, the frame memory budget is used up", frame_data.len());
+            return Ok(0);
+        };
+        self.tx.send(Arc::new(Frame { data: frame_data, _reservation: reservation, jpeg: Mutex::default() }))
+    }
+
+    fn set_mode(&self, mode: CaptureMode) {
+        self.stats.lock().unwrap_or_else(|e| e.into_inner()).mode = Some(mode);
+    }
+
+    pub fn status(&self) -> CaptureStatus {
+        let stats = self.stats.lock().unwrap_or_else(|e| e.into_inner());
+        // No frames for a whole window means capture has stalled
+        let stalled = stats.last_frame.is_none_or(|last| last.elapsed() >= FPS_WINDOW);
+        CaptureStatus {
+            mode: stats.mode,
+            frames: stats.frames,
+            fps: if stalled { 0.0 } else { (stats.fps * 10.0).round() / 10.0 },
+            error: stats.error.clone(),
+            fatal: stats.fatal,
+            paused: stats.paused,
+        }
+    }
+
+    /// While the host `pause` follows is powered off, send its placeholder
+    /// instead of capturing. Returns whether capture was paused, so a stream
+    /// that stalled with the host's video can be started over
+    async fn pause_while_off(&self, pause: Option<&HostPause>) -> bool {
+        let Some(pause) = pause else {
+            return false;
+        };
+        let mut off = pause.state.subscribe();
+        if !*off.borrow_and_update() {
+            return false;
+        }
+        info!("Host is powered off, video capture paused");
+        self.stats.lock().unwrap_or_else(|e| e.into_inner()).paused = true;
+        let placeholder = pause.placeholder.render(0);
+        let mut ticker = tokio::time::interval(PLACEHOLDER_INTERVAL);
+        while *off.borrow_and_update() {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    let _ = self.send(placeholder.clone());
+                }
+                changed = off.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+        info!("Host is powered on, video capture resumed");
+        self.stats.lock().unwrap_or_else(|e| e.into_inner()).paused = false;
+        true
     }
 
     #[cfg(target_os = "linux")]
@@ -39,34 +360,52 @@ impl DisplayHub {
         }
     }
 
-    pub async fn spawn(self: Arc<Self>, video_device_path: String, force_framebuffer: bool) -> Result<()> {
+    /// Capture frames until capture fails; the error is kept for the status API
+    pub async fn spawn(self: Arc<Self>, video_device_path: String, settings: CaptureSettings) -> Result<(), CaptureError> {
+        let result = self.clone().capture(video_device_path, settings).await;
+        if let Err(ref e) = result {
+            error!("Video capture stopped: {}", e);
+            let mut stats = self.stats.lock().unwrap_or_else(|e| e.into_inner());
+            stats.error = Some(e.to_string());
+            stats.fatal = e.is_fatal();
+        }
+        result
+    }
+
+    async fn capture(self: Arc<Self>, video_device_path: String, settings: CaptureSettings) -> Result<(), CaptureError> {
+        let pause = settings.host.as_ref();
+        if let Some(script) = settings.mock {
+            info!("Playing mock capture instead of {}", video_device_path);
+            self.set_mode(CaptureMode::Mock);
+            return self.play_mock(&script, &video_device_path, pause).await;
+        }
         #[cfg(target_os = "linux")]
         {
-            let mode = if force_framebuffer {
+            let mode = if settings.force_framebuffer {
                 CaptureMode::Framebuffer
             } else {
                 self.detect_capture_mode(&video_device_path).await
             };
             
-            println!("Using capture mode: {:?}", mode);
+            info!("Using capture mode: {:?}", mode);
+            self.set_mode(mode);
             
             match mode {
-                CaptureMode::V4L2 => self.spawn_v4l2_capture(video_device_path).await,
-                CaptureMode::Framebuffer => self.spawn_framebuffer_capture(video_device_path).await,
-                CaptureMode::Mock => self.spawn_mock_capture(video_device_path).await,
+                CaptureMode::V4L2 => self.spawn_v4l2_capture(video_device_path, &settings).await,
+                CaptureMode::Framebuffer => self.spawn_framebuffer_capture(video_device_path, pause).await,
+                CaptureMode::Mock => self.play_mock(&MockScript::default(), &video_device_path, pause).await,
             }
         }
         #[cfg(not(target_os = "linux"))]
         {
-            let _ = force_framebuffer; // Suppress unused warning
-            self.spawn_mock_capture(video_device_path).await
+            warn!("V4L2 and framebuffer capture only work on Linux, using mock capture");
+            self.set_mode(CaptureMode::Mock);
+            self.play_mock(&MockScript::default(), &video_device_path, pause).await
         }
     }
 
     #[cfg(target_os = "linux")]
     async fn detect_capture_mode(&self, video_device_path: &str) -> CaptureMode {
-        use std::path::Path;
-        
         // Check if it's a V4L2 device first
         if video_device_path.starts_with("/dev/video") {
             if Path::new(video_device_path).exists() {
@@ -76,12 +415,12 @@ impl DisplayHub {
                     return CaptureMode::V4L2;
                 }
             }
-            println!("Warning: V4L2 device {} not available, trying framebuffer fallback", video_device_path);
+            warn!("V4L2 device {} not available, trying framebuffer fallback", video_device_path);
             
             // Fallback to common framebuffer devices
             for fb_path in ["/dev/fb0", "/dev/fb1"] {
                 if Path::new(fb_path).exists() {
-                    println!("Found framebuffer device: {}", fb_path);
+                    info!("Found framebuffer device: {}", fb_path);
                     return CaptureMode::Framebuffer;
                 }
             }
@@ -111,75 +450,120 @@ impl DisplayHub {
             return CaptureMode::Framebuffer;
         }
         
-        println!("Warning: No video device found, using mock capture");
+        warn!("No video device found, using mock capture");
         CaptureMode::Mock
     }
 
     #[cfg(target_os = "linux")]
-    async fn spawn_v4l2_capture(self: Arc<Self>, video_device_path: String) -> Result<()> {
+    async fn spawn_v4l2_capture(self: Arc<Self>, video_device_path: String, settings: &CaptureSettings) -> Result<(), CaptureError> {
         use v4l::Device;
-        use anyhow::Context;
 
-        println!("Starting V4L2 capture from: {}", video_device_path);
+        let pause = settings.host.as_ref();
+
+        info!("Starting V4L2 capture from: {}", video_device_path);
 
         // Open V4L2 device
         let device_index = Self::get_device_index_from_path(&video_device_path);
         let dev = Device::new(device_index)
-            .with_context(|| format!("Failed to open V4L2 device: {} (index: {})", video_device_path, device_index))?;
+            .map_err(|source| CaptureError::Open {
+                device: format!("{} (index: {})", video_device_path, device_index),
+                source,
+            })?;
 
-        println!("Opened V4L2 device: {}", video_device_path);
+        info!("Opened V4L2 device: {}", video_device_path);
+        Self::tune_v4l2_device(&dev, settings);
 
         // Get device capabilities
         let caps = dev.query_caps()
-            .context("Failed to query device capabilities")?;
+            .map_err(CaptureError::Query)?;
         
-        println!("Device capabilities: {}", caps);
+        debug!("Device capabilities: {}", caps);
 
         if caps.to_string().contains("Thumbnail") {
-            println!("Detected thumbnail/snapshot device, using read-based capture");
-            self.spawn_v4l2_read_capture(dev, video_device_path).await
+            info!("Detected thumbnail/snapshot device, using read-based capture");
+            self.spawn_v4l2_read_capture(dev, video_device_path, pause).await
         } else {
-            println!("Getting current format for streaming device...");
+            debug!("Getting current format for streaming device...");
             
             // Get current format for streaming devices
             let fmt = match v4l::video::Capture::format(&dev) {
                 Ok(current_fmt) => {
-                    println!("Current format: {:?} {}x{}", 
+                    debug!("Current format: {:?} {}x{}", 
                         std::str::from_utf8(&current_fmt.fourcc.repr).unwrap_or("unknown"),
                         current_fmt.width, current_fmt.height);
                     current_fmt
                 }
                 Err(e) => {
-                    println!("Failed to get current format: {}", e);
-                    return Err(anyhow::anyhow!("Cannot get format from device: {}", e));
+                    warn!("Failed to get current format: {}", e);
+                    return Err(CaptureError::Query(e));
                 }
             };
 
-            println!("Using format: {:?} {}x{}", 
+            info!("Using format: {:?} {}x{}", 
                 std::str::from_utf8(&fmt.fourcc.repr).unwrap_or("unknown"),
                 fmt.width, fmt.height);
             
-            println!("Detected streaming device, invoking streaming capture method");
-            self.spawn_v4l2_streaming_capture(dev, fmt).await
+            info!("Detected streaming device, invoking streaming capture method");
+            self.spawn_v4l2_streaming_capture(dev, fmt, pause).await
+        }
+    }
+
+    /// Ask the device for the frame rate, chroma subsampling and JPEG quality of `settings`;
+    /// devices that do not support them keep their own
+    #[cfg(target_os = "linux")]
+    fn tune_v4l2_device(dev: &v4l::Device, settings: &CaptureSettings) {
+        if let Some(fps) = settings.frame_rate {
+            match v4l::video::Capture::set_params(dev, &v4l::video::capture::Parameters::with_fps(fps)) {
+                Ok(params) => info!("Frame rate set to {} fps (interval {})", fps, params.interval),
+                Err(e) => warn!("Failed to set the frame rate to {} fps: {}", fps, e),
+            }
+        }
+        if let Some(subsampling) = settings.subsampling {
+            let control = v4l::control::Control {
+                id: V4L2_CID_JPEG_CHROMA_SUBSAMPLING,
+                value: v4l::control::Value::Integer(subsampling.control_value()),
+            };
+            match dev.set_control(control) {
+                Ok(()) => info!("JPEG chroma subsampling set to {}", subsampling.as_str()),
+                Err(e) => warn!("Failed to set the JPEG chroma subsampling to {}: {}", subsampling.as_str(), e),
+            }
+        }
+        if let Some(quality) = settings.jpeg_quality {
+            let control = v4l::control::Control {
+                id: V4L2_CID_JPEG_COMPRESSION_QUALITY,
+                value: v4l::control::Value::Integer(quality as i64),
+            };
+            // Only devices that encode JPEG have the control
+            match dev.set_control(control) {
+                Ok(()) => info!("JPEG quality of the video engine set to {}", quality),
+                Err(e) => debug!("Device JPEG quality not set: {}", e),
+            }
         }
     }
 
     #[cfg(target_os = "linux")]
-    async fn spawn_v4l2_streaming_capture(self: Arc<Self>, dev: v4l::Device, fmt: v4l::Format) -> Result<()> {
+    async fn spawn_v4l2_streaming_capture(self: Arc<Self>, dev: v4l::Device, fmt: v4l::Format, pause: Option<&HostPause>) -> Result<(), CaptureError> {
         use v4l::{buffer::Type, io::traits::CaptureStream};
         use v4l::prelude::MmapStream;
-        use anyhow::Context;
 
         // Create capture stream
         let mut stream = MmapStream::with_buffers(&dev, Type::VideoCapture, 4)
-            .context("Failed to create mmap stream")?;
+            .map_err(CaptureError::Stream)?;
 
-        println!("Started V4L2 streaming capture");
+        info!("Started V4L2 streaming capture");
 
         let mut frame_counter = 0u32;
         let mut last_successful_frame: Option<Vec<u8>> = None;
 
         loop {
+            if pause.is_some_and(|pause| pause.state.is_off()) {
+                // Stop streaming while there is no signal, and start over once it is back
+                drop(stream);
+                self.pause_while_off(pause).await;
+                stream = MmapStream::with_buffers(&dev, Type::VideoCapture, 4)
+                    .map_err(CaptureError::Stream)?;
+                last_successful_frame = None;
+            }
             match stream.next() {
                 Ok((buf, meta)) => {
                     // Convert frame data to Vec<u8> for broadcasting
@@ -203,19 +587,19 @@ impl DisplayHub {
                     last_successful_frame = Some(frame_data.clone());
 
                     // Broadcast frame to all subscribers
-                    let _ = self.tx.send(frame_data);
+                    let _ = self.publish(frame_data);
 
                     frame_counter += 1;
                     if frame_counter % 30 == 0 { // Every second at 30fps
-                        println!("V4L2: Captured frame {}, size: {} bytes", meta.sequence, buf.len());
+                        debug!("V4L2: Captured frame {}, size: {} bytes", meta.sequence, buf.len());
                     }
                 }
                 Err(e) => {
-                    println!("V4L2 capture error: {}, retrying in 100ms...", e);
+                    warn!("V4L2 capture error: {}, retrying in 100ms...", e);
                     
                     // If we have a last successful frame, broadcast it to keep the stream alive
                     if let Some(ref frame_data) = last_successful_frame {
-                        let _ = self.tx.send(frame_data.clone());
+                        let _ = self.publish(frame_data.clone());
                     }
                     
                     // Wait before retrying
@@ -225,10 +609,10 @@ impl DisplayHub {
                     match MmapStream::with_buffers(&dev, Type::VideoCapture, 4) {
                         Ok(new_stream) => {
                             stream = new_stream;
-                            println!("V4L2: Successfully recreated stream");
+                            info!("V4L2: Successfully recreated stream");
                         }
                         Err(stream_err) => {
-                            println!("V4L2: Failed to recreate stream: {}", stream_err);
+                            warn!("V4L2: Failed to recreate stream: {}", stream_err);
                             // Continue with the old stream and try again next iteration
                         }
                     }
@@ -241,61 +625,64 @@ impl DisplayHub {
     }
 
     #[cfg(target_os = "linux")]
-    async fn spawn_v4l2_read_capture(self: Arc<Self>, dev: v4l::Device, video_device_path: String) -> Result<()> {
+    async fn spawn_v4l2_read_capture(self: Arc<Self>, dev: v4l::Device, video_device_path: String, pause: Option<&HostPause>) -> Result<(), CaptureError> {
         use v4l::{buffer::Type, io::traits::CaptureStream};
         use v4l::prelude::MmapStream;
 
-        println!("Started V4L2 read-based capture for snapshot device: {}", video_device_path);
+        info!("Started V4L2 read-based capture for snapshot device: {}", video_device_path);
 
         // Try to get device format, but don't fail if we can't
         if let Ok(fmt) = v4l::video::Capture::format(&dev) {
-            println!("Snapshot device format: {:?} {}x{}", 
+            info!("Snapshot device format: {:?} {}x{}", 
                 std::str::from_utf8(&fmt.fourcc.repr).unwrap_or("unknown"),
                 fmt.width, fmt.height);
         } else {
-            println!("Warning: Could not get format from snapshot device, proceeding anyway");
+            warn!("Could not get format from snapshot device, proceeding anyway");
         }
 
         let mut frame_counter = 0u32;
         let mut last_successful_frame: Option<Vec<u8>> = None;
 
         loop {
+            if self.pause_while_off(pause).await {
+                last_successful_frame = None;
+            }
             // For snapshot devices, create a new stream for each capture attempt
             match MmapStream::with_buffers(&dev, Type::VideoCapture, 1) {
                 Ok(mut stream) => {
                     match stream.next() {
                         Ok((buf, meta)) => {
-                            println!("Snapshot: Captured frame {}, size: {} bytes", meta.sequence, buf.len());
+                            debug!("Snapshot: Captured frame {}, size: {} bytes", meta.sequence, buf.len());
                             
                             // Just use the raw buffer data
                             let frame_data = buf.to_vec();
 
                             // Store and broadcast the frame
                             last_successful_frame = Some(frame_data.clone());
-                            match self.tx.send(frame_data) {
+                            match self.publish(frame_data) {
                                 Ok(_) => {
                                     frame_counter += 1;
                                     if frame_counter % 10 == 0 {
-                                        println!("Snapshot: Successfully captured and broadcasted frame {}", frame_counter);
+                                        debug!("Snapshot: Successfully captured and broadcasted frame {}", frame_counter);
                                     }
                                 }
-                                Err(e) => println!("Error broadcasting frame: {}", e),
+                                Err(e) => debug!("Error broadcasting frame: {}", e),
                             }
                         }
                         Err(e) => {
-                            println!("V4L2 snapshot capture error: {}", e);
+                            warn!("V4L2 snapshot capture error: {}", e);
                             // Broadcast last successful frame if available
                             if let Some(ref frame_data) = last_successful_frame {
-                                let _ = self.tx.send(frame_data.clone());
+                                let _ = self.publish(frame_data.clone());
                             }
                         }
                     }
                 }
                 Err(e) => {
-                    println!("Error creating snapshot stream: {}", e);
+                    warn!("Error creating snapshot stream: {}", e);
                     // Broadcast last successful frame if available
                     if let Some(ref frame_data) = last_successful_frame {
-                        let _ = self.tx.send(frame_data.clone());
+                        let _ = self.publish(frame_data.clone());
                     }
                 }
             }
@@ -306,43 +693,43 @@ impl DisplayHub {
     }
 
     #[cfg(target_os = "linux")]
-    async fn spawn_framebuffer_capture(self: Arc<Self>, video_device_path: String) -> Result<()> {
+    async fn spawn_framebuffer_capture(self: Arc<Self>, video_device_path: String, pause: Option<&HostPause>) -> Result<(), CaptureError> {
         use tokio::{fs::File, io::AsyncReadExt};
-        use anyhow::Context;
 
-        println!("Starting framebuffer capture from: {}", video_device_path);
+        info!("Starting framebuffer capture from: {}", video_device_path);
 
         // Try to determine framebuffer properties
         let (width, height, bpp) = self.get_framebuffer_info(&video_device_path).await
             .unwrap_or((1920, 1080, 4)); // Default to 1080p RGBA
 
-        println!("Framebuffer: {}x{} @ {} bytes per pixel", width, height, bpp);
+        info!("Framebuffer: {}x{} @ {} bytes per pixel", width, height, bpp);
         
         let mut file = File::open(&video_device_path).await
-            .with_context(|| format!("Failed to open framebuffer device: {}", video_device_path))?;
+            .map_err(|source| CaptureError::Open { device: video_device_path.clone(), source })?;
         
         let mut buf = vec![0u8; width * height * bpp];
         let mut frame_counter = 0u32;
 
         loop {
+            self.pause_while_off(pause).await;
             // Read framebuffer data
             match file.read_exact(&mut buf).await {
                 Ok(_) => {
                     // Broadcast frame to all subscribers
-                    let _ = self.tx.send(buf.clone());
+                    let _ = self.publish(buf.clone());
                     
                     frame_counter += 1;
                     if frame_counter % 300 == 0 { // Every 10 seconds at 30fps
-                        println!("Framebuffer: Read frame {}, size: {} bytes", frame_counter, buf.len());
+                        debug!("Framebuffer: Read frame {}, size: {} bytes", frame_counter, buf.len());
                     }
                 }
                 Err(e) => {
-                    println!("Framebuffer read error: {}, retrying...", e);
+                    warn!("Framebuffer read error: {}, retrying...", e);
                     // Try to reopen the file
                     match File::open(&video_device_path).await {
                         Ok(new_file) => file = new_file,
                         Err(reopen_err) => {
-                            println!("Failed to reopen framebuffer: {}", reopen_err);
+                            warn!("Failed to reopen framebuffer: {}", reopen_err);
                             tokio::time::sleep(std::time::Duration::from_secs(1)).await;
                             continue;
                         }
@@ -378,122 +765,67 @@ impl DisplayHub {
                     bpp_str.trim().parse::<usize>()
                 ) {
                     let bytes_per_pixel = (bpp + 7) / 8; // Round up to nearest byte
-                    println!("Detected framebuffer: {}x{} @ {} bpp ({} bytes/pixel)", 
+                    info!("Detected framebuffer: {}x{} @ {} bpp ({} bytes/pixel)", 
                             width, height, bpp, bytes_per_pixel);
                     return Some((width, height, bytes_per_pixel));
                 }
             }
         }
         
-        println!("Could not detect framebuffer properties, using defaults");
+        warn!("Could not detect framebuffer properties, using defaults");
         None
     }
 
-    #[cfg(target_os = "linux")]
-    async fn spawn_mock_capture(self: Arc<Self>, video_device_path: String) -> Result<()> {
-        println!("Mock video capture on Linux (device: {})", video_device_path);
-        println!("Note: Neither V4L2 nor framebuffer devices were available. Using mock implementation.");
-        
-        // Generate mock video data
-        let mut frame_counter = 0u32;
+    /// Publish the frames of a mock capture script
+    async fn play_mock(&self, script: &MockScript, video_device_path: &str, pause: Option<&HostPause>) -> Result<(), CaptureError> {
         loop {
-            // Create a simple test pattern - alternating colors
-            let color = match (frame_counter / 30) % 3 {
-                0 => [255u8, 0, 0], // Red
-                1 => [0u8, 255, 0], // Green
-                _ => [0u8, 0, 255], // Blue
-            };
-            
-            // Create a 640x480 RGB24 test frame
-            let width = 640;
-            let height = 480;
-            let mut frame_data = Vec::with_capacity(width * height * 3);
-            
-            for y in 0..height {
-                for x in 0..width {
-                    // Create a simple pattern with the base color
-                    let intensity = ((x + y + frame_counter as usize) % 256) as u8;
-                    frame_data.push((color[0] as u16 * intensity as u16 / 255) as u8);
-                    frame_data.push((color[1] as u16 * intensity as u16 / 255) as u8);
-                    frame_data.push((color[2] as u16 * intensity as u16 / 255) as u8);
-                }
-            }
-
-            // Broadcast mock frame
-            let _ = self.tx.send(frame_data);
-
-            frame_counter += 1;
-            if frame_counter % 300 == 0 {
-                println!("Mock capture: Generated frame {}", frame_counter);
-            }
-
-            // 30 FPS
-            tokio::time::sleep(std::time::Duration::from_millis(33)).await;
-        }
-    }
-
-    #[cfg(not(target_os = "linux"))]
-    async fn spawn_mock_capture(self: Arc<Self>, video_device_path: String) -> Result<()> {
-        println!("Mock video capture for development (device: {})", video_device_path);
-        println!("Note: V4L2/Framebuffer capture only works on Linux. This is a mock implementation for development.");
-        
-        // Generate mock video data for development/testing
-        let mut frame_counter = 0u32;
-        loop {
-            // Create a simple test pattern - alternating colors
-            let color = match (frame_counter / 30) % 3 {
-                0 => [255u8, 0, 0], // Red
-                1 => [0u8, 255, 0], // Green
-                _ => [0u8, 0, 255], // Blue
-            };
-            
-            // Create a 640x480 RGB24 test frame
-            let width = 640;
-            let height = 480;
-            let mut frame_data = Vec::with_capacity(width * height * 3);
-            
-            for y in 0..height {
-                for x in 0..width {
-                    // Create a simple pattern with the base color
-                    let intensity = ((x + y + frame_counter as usize) % 256) as u8;
-                    frame_data.push((color[0] as u16 * intensity as u16 / 255) as u8);
-                    frame_data.push((color[1] as u16 * intensity as u16 / 255) as u8);
-                    frame_data.push((color[2] as u16 * intensity as u16 / 255) as u8);
+            for step in &script.steps {
+                match step {
+                    Step::Frames { source, duration, fps } => {
+                        let count = (duration.as_secs_f64() * *fps as f64).round().max(1.0) as u32;
+                        let mut ticks = tokio::time::interval(Duration::from_secs(1) / *fps);
+                        debug!("Mock capture: {} frames at {} fps", count, fps);
+                        for n in 0..count {
+                            self.pause_while_off(pause).await;
+                            ticks.tick().await;
+                            let _ = self.publish(source.render(n));
+                        }
+                    }
+                    Step::Stall(duration) => tokio::time::sleep(*duration).await,
+                    Step::Fail => {
+                        return Err(CaptureError::Open {
+                            device: video_device_path.to_string(),
+                            source: std::io::Error::new(std::io::ErrorKind::NotFound, "failed by the mock capture script"),
+                        });
+                    }
                 }
             }
-
-            // Broadcast mock frame
-            let _ = self.tx.send(frame_data);
-
-            frame_counter += 1;
-            if frame_counter % 300 == 0 {
-                println!("Mock capture: Generated frame {}", frame_counter);
+            if !script.repeat {
+                info!("Mock capture script finished");
+                return std::future::pending().await;
             }
-
-            // 30 FPS
-            tokio::time::sleep(std::time::Duration::from_millis(33)).await;
         }
     }
 
     #[cfg(target_os = "linux")]
     #[allow(dead_code)] // May be used for different types of thumbnail devices
-    async fn spawn_v4l2_snapshot_capture(self: Arc<Self>, dev: v4l::Device, fmt: v4l::Format) -> Result<()> {
+    async fn spawn_v4l2_snapshot_capture(self: Arc<Self>, dev: v4l::Device, fmt: v4l::Format) -> Result<(), CaptureError> {
         use v4l::{buffer::Type, io::traits::CaptureStream};
         use v4l::prelude::MmapStream;
 
-        println!("Started V4L2 snapshot capture for thumbnail device");
+        info!("Started V4L2 snapshot capture for thumbnail device");
 
         let mut frame_counter = 0u32;
         let mut last_successful_frame: Option<Vec<u8>> = None;
 
         loop {
-            println!("Attempting to create a new stream for snapshot capture...");
+            debug!("Attempting to create a new stream for snapshot capture...");
             match MmapStream::with_buffers(&dev, Type::VideoCapture, 1) {
                 Ok(mut stream) => {
-                    println!("Stream created successfully, attempting to capture frame...");
+                    debug!("Stream created successfully, attempting to capture frame...");
                     match stream.next() {
                         Ok((buf, _meta)) => {
-                            println!("Frame captured successfully, size: {} bytes", buf.len());
+                            debug!("Frame captured successfully, size: {} bytes", buf.len());
                             let frame_data = match &fmt.fourcc.repr {
                                 b"MJPG" => buf.to_vec(),
                                 b"YUYV" => buf.to_vec(),
@@ -501,31 +833,31 @@ impl DisplayHub {
                             };
 
                             last_successful_frame = Some(frame_data.clone());
-                            let broadcast_result = self.tx.send(frame_data);
+                            let broadcast_result = self.publish(frame_data);
                             match broadcast_result {
-                                Ok(_) => println!("Frame broadcasted successfully"),
-                                Err(e) => println!("Error broadcasting frame: {}", e),
+                                Ok(_) => debug!("Frame broadcasted successfully"),
+                                Err(e) => debug!("Error broadcasting frame: {}", e),
                             }
                             frame_counter += 1;
                             if frame_counter % 30 == 0 {
-                                println!("Snapshot: Captured frame {}, size: {} bytes", frame_counter, buf.len());
+                                debug!("Snapshot: Captured frame {}, size: {} bytes", frame_counter, buf.len());
                             }
                         }
                         Err(e) => {
-                            println!("Error capturing frame: {}", e);
+                            warn!("Error capturing frame: {}", e);
                             if let Some(ref frame_data) = last_successful_frame {
-                                let broadcast_result = self.tx.send(frame_data.clone());
+                                let broadcast_result = self.publish(frame_data.clone());
                                 match broadcast_result {
-                                    Ok(_) => println!("Last successful frame broadcasted successfully"),
-                                    Err(e) => println!("Error broadcasting last successful frame: {}", e),
+                                    Ok(_) => debug!("Last successful frame broadcasted successfully"),
+                                    Err(e) => debug!("Error broadcasting last successful frame: {}", e),
                                 }
                             }
                         }
                     }
                 }
                 Err(e) => {
-                    println!("Error creating stream: {}", e);
-                    println!("Device may have stopped. Retrying in 1 second...");
+                    warn!("Error creating stream: {}", e);
+                    warn!("Device may have stopped. Retrying in 1 second...");
                     tokio::time::sleep(std::time::Duration::from_secs(1)).await;
                     continue;
                 }
@@ -0,0 +1,203 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Console event notifications for kvm-rs
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use tokio::sync::{broadcast, watch};
+use tracing::{info, warn};
+use crate::arbiter::Role;
+use crate::display::DisplayHub;
+#[cfg(target_os = "linux")]
+use crate::power::HostState;
+
+/// Events kept for slow subscribers before they start missing some
+const EVENT_QUEUE: usize = 64;
+/// How often capture is checked for lost video
+const CAPTURE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How long capture may deliver no frames before the video counts as lost
+const VIDEO_LOST_AFTER: Duration = Duration::from_secs(5);
+
+/// Something web UIs show changed
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Event {
+    ClientConnected {
+        id: u64,
+        transport: &'static str,
+        address: String,
+    },
+    ClientDisconnected {
+        id: u64,
+        transport: &'static str,
+        address: String,
+    },
+    /// A client's role was set or changed, e.g. it took control of the console
+    RoleChanged {
+        id: u64,
+        user: Option<String>,
+        role: Role,
+    },
+    ResolutionChanged {
+        width: u16,
+        height: u16,
+    },
+    /// Capture of a console failed, or delivered no frames for a while
+    VideoLost {
+        console: u32,
+        reason: String,
+    },
+    /// Capture of a console delivers frames again
+    VideoRestored {
+        console: u32,
+    },
+    /// CurrentHostState of the OpenBMC host state manager, e.g. "Running" or "Off"
+    HostPower {
+        state: String,
+    },
+    InputLock {
+        locked: bool,
+    },
+    /// An image was inserted in the virtual media drive
+    MediaInserted {
+        image: String,
+        read_only: bool,
+    },
+    MediaEjected {
+        image: String,
+    },
+    /// An agent in the host OS said hello over the host agent channel
+    HostAgentConnected {
+        agent: String,
+    },
+    HostAgentDisconnected,
+    /// Resolution of the host's screen, as the host agent reports it
+    HostResolution {
+        width: u16,
+        height: u16,
+    },
+    /// Keyboard focus in the host OS, as the host agent reports it
+    HostFocus {
+        focused: bool,
+        window: String,
+    },
+}
+
+/// Publishes events to every subscriber, e.g. /api/v1/events streams
+#[derive(Clone)]
+pub struct EventBus {
+    tx: broadcast::Sender<Event>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self {
+            tx: broadcast::channel(EVENT_QUEUE).0,
+        }
+    }
+}
+
+impl EventBus {
+    pub fn publish(&self, event: Event) {
+        // Nobody listening is fine
+        let _ = self.tx.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.tx.subscribe()
+    }
+
+    /// Publish changes of the input lock
+    pub async fn forward_input_lock(self, mut input_lock: watch::Receiver<bool>) {
+        while input_lock.changed().await.is_ok() {
+            let locked = *input_lock.borrow_and_update();
+            self.publish(Event::InputLock { locked });
+        }
+    }
+
+    /// Publish when the capture of `console` stops delivering frames, and when
+    /// it recovers. Nothing is lost before the first frame, or while capture
+    /// is paused for a host that is off
+    pub async fn watch_capture(self, console: u32, hub: Arc<DisplayHub>) {
+        let mut ticker = tokio::time::interval(CAPTURE_CHECK_INTERVAL);
+        let mut stalled_since: Option<Instant> = None;
+        let mut lost = false;
+        loop {
+            ticker.tick().await;
+            let status = hub.status();
+            if status.frames == 0 && status.error.is_none() {
+                continue;
+            }
+            // No video is expected from a host that is off
+            if status.paused {
+                stalled_since = None;
+                continue;
+            }
+            let stalled = status.error.is_none() && status.fps == 0.0;
+            stalled_since = if stalled { stalled_since.or(Some(Instant::now())) } else { None };
+            let reason = match status.error {
+                Some(error) => Some(error),
+                None if stalled_since.is_some_and(|since| since.elapsed() >= VIDEO_LOST_AFTER) => {
+                    Some(format!("No frames captured for {}s", VIDEO_LOST_AFTER.as_secs()))
+                }
+                None => None,
+            };
+            match reason {
+                Some(reason) if !lost => {
+                    warn!("Video of console {} lost: {}", console, reason);
+                    lost = true;
+                    self.publish(Event::VideoLost { console, reason });
+                }
+                None if lost && !stalled => {
+                    info!("Video of console {} restored", console);
+                    lost = false;
+                    self.publish(Event::VideoRestored { console });
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Publish the host power state reported by phosphor-state-manager, and
+    /// keep `host` up to date with it
+    #[cfg(target_os = "linux")]
+    pub async fn watch_host_power(self, dbus: zbus::Connection, host: HostState) {
+        use futures_util::StreamExt;
+
+        let proxy = match zbus::Proxy::new(
+            &dbus,
+            "xyz.openbmc_project.State.Host",
+            "/xyz/openbmc_project/state/host0",
+            "xyz.openbmc_project.State.Host",
+        ).await {
+            Ok(proxy) => proxy,
+            Err(e) => {
+                warn!("Host power events unavailable: {}", e);
+                return;
+            }
+        };
+        // Changes are only signalled, the state at startup is read
+        match proxy.get_property::<String>("CurrentHostState").await {
+            Ok(state) => host.update(host_state_name(&state)),
+            Err(e) => warn!("Failed to read host power state: {}", e),
+        }
+        let mut changes = proxy.receive_property_changed::<String>("CurrentHostState").await;
+        while let Some(change) = changes.next().await {
+            match change.get().await {
+                Ok(state) => {
+                    let state = host_state_name(&state);
+                    host.update(state);
+                    self.publish(Event::HostPower { state: state.to_string() });
+                }
+                Err(e) => warn!("Failed to read host power state: {}", e),
+            }
+        }
+    }
+}
+
+/// "xyz.openbmc_project.State.Host.HostState.Running" -> "Running"
+#[cfg(target_os = "linux")]
+fn host_state_name(state: &str) -> &str {
+    state.rsplit('.').next().unwrap_or_default()
+}
@@ -0,0 +1,123 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Memory budget of captured frames and client buffers for kvm-rs
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use serde::Serialize;
+
+/// Caps the memory held in captured frames, converted frames and the
+/// per-client buffers of encoded updates, shared by every console.
+/// What does not fit is dropped: a new frame, a client's update (sent with
+/// a later frame) or a new client
+#[derive(Clone, Default)]
+pub struct FrameBudget {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// None for no limit
+    limit: Option<usize>,
+    used: AtomicUsize,
+    /// Size of the latest captured frame, the least a new client needs
+    frame_size: AtomicUsize,
+    dropped_frames: AtomicU64,
+    refused_clients: AtomicU64,
+}
+
+/// Bytes counted against the budget until dropped
+pub struct Reservation {
+    budget: FrameBudget,
+    bytes: usize,
+}
+
+/// Budget use as reported by the status API
+#[derive(Debug, Clone, Serialize)]
+pub struct BudgetStatus {
+    pub used: usize,
+    pub limit: Option<usize>,
+    /// Frames and client updates dropped because they did not fit
+    pub dropped_frames: u64,
+    pub refused_clients: u64,
+}
+
+impl FrameBudget {
+    /// At most `limit` bytes; 0 for no limit
+    pub fn new(limit: usize) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                limit: (limit > 0).then_some(limit),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Count `bytes` against the budget, or None if they do not fit
+    pub fn try_reserve(&self, bytes: usize) -> Option<Reservation> {
+        let limit = self.inner.limit.unwrap_or(usize::MAX);
+        let reserved = self.inner.used.fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+            used.checked_add(bytes).filter(|total| *total <= limit)
+        });
+        match reserved {
+            Ok(_) => Some(Reservation { budget: self.clone(), bytes }),
+            Err(_) => {
+                self.inner.dropped_frames.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Reserve a captured frame, remembering its size for admitting clients
+    pub fn reserve_frame(&self, bytes: usize) -> Option<Reservation> {
+        self.inner.frame_size.store(bytes, Ordering::Relaxed);
+        self.try_reserve(bytes)
+    }
+
+    /// Whether a new client fits: there is room for one more frame for it
+    pub fn admit_client(&self) -> bool {
+        let Some(limit) = self.inner.limit else {
+            return true;
+        };
+        let needed = self.inner.used.load(Ordering::Acquire) + self.inner.frame_size.load(Ordering::Relaxed);
+        if needed > limit {
+            self.inner.refused_clients.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+
+    pub fn status(&self) -> BudgetStatus {
+        BudgetStatus {
+            used: self.inner.used.load(Ordering::Acquire),
+            limit: self.inner.limit,
+            dropped_frames: self.inner.dropped_frames.load(Ordering::Relaxed),
+            refused_clients: self.inner.refused_clients.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Reservation {
+    /// Count `bytes` instead, e.g. for a frame that replaces the reserved
+    /// one, so only the difference has to fit; false if it does not
+    pub fn try_resize(&mut self, bytes: usize) -> bool {
+        let inner = &self.budget.inner;
+        let limit = inner.limit.unwrap_or(usize::MAX);
+        let old = self.bytes;
+        let resized = inner.used.fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+            (used - old).checked_add(bytes).filter(|total| bytes <= old || *total <= limit)
+        });
+        if resized.is_err() {
+            inner.dropped_frames.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        self.bytes = bytes;
+        true
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.inner.used.fetch_sub(self.bytes, Ordering::AcqRel);
+    }
+}
@@ -0,0 +1,198 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Dumps of captured frames for debugging capture formats and colours for kvm-rs
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use anyhow::{Context, Result};
+#[cfg(feature = "web")]
+use axum::{
+    extract::State,
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use serde::Serialize;
+#[cfg(feature = "web")]
+use serde::Deserialize;
+use tracing::{info, warn};
+use crate::display::CaptureMode;
+
+/// Most frames one request dumps; a 1080p frame and its conversion take ~10 MB
+pub const MAX_DUMP_FRAMES: u32 = 16;
+
+/// Writes the next captured buffers, as the capture delivered them, and their
+/// RGB conversions to a directory, each with a JSON file of metadata
+#[derive(Clone)]
+pub struct FrameDump {
+    dir: Arc<PathBuf>,
+    state: Arc<Mutex<DumpState>>,
+}
+
+#[derive(Default)]
+struct DumpState {
+    /// Frames still to be dumped
+    remaining: u32,
+    /// Frames dumped since startup, numbering the files
+    written: u64,
+}
+
+/// Answer of GET and PUT /api/v1/debug/frame-dump
+#[derive(Debug, Serialize)]
+pub struct DumpStatus {
+    pub dir: String,
+    pub remaining: u32,
+    pub written: u64,
+}
+
+/// Body of PUT /api/v1/debug/frame-dump; 0 frames stops a dump
+#[cfg(feature = "web")]
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DumpRequest {
+    frames: u32,
+}
+
+/// What the converter made of a captured buffer
+#[derive(Debug, Clone, Copy)]
+pub struct Conversion {
+    /// "mjpeg", "yuyv", "rgb" or "unknown"
+    pub format: &'static str,
+    pub width: u16,
+    pub height: u16,
+    pub capture_mode: Option<CaptureMode>,
+}
+
+#[derive(Serialize)]
+struct Metadata<'a> {
+    sequence: u64,
+    captured_at: String,
+    capture_mode: Option<CaptureMode>,
+    /// Format the buffer was taken for
+    format: &'static str,
+    width: u16,
+    height: u16,
+    raw_file: &'a str,
+    raw_bytes: usize,
+    rgb_file: &'a str,
+    rgb_bytes: usize,
+    version: &'static str,
+}
+
+impl FrameDump {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: Arc::new(dir.into()),
+            state: Arc::default(),
+        }
+    }
+
+    pub fn status(&self) -> DumpStatus {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        DumpStatus {
+            dir: self.dir.display().to_string(),
+            remaining: state.remaining,
+            written: state.written,
+        }
+    }
+
+    /// Dump the next `frames` captured frames, replacing a dump in progress;
+    /// 0 stops it
+    pub fn start(&self, frames: u32) -> Result<DumpStatus> {
+        if frames > MAX_DUMP_FRAMES {
+            anyhow::bail!("At most {} frames can be dumped at once", MAX_DUMP_FRAMES);
+        }
+        if frames > 0 {
+            std::fs::create_dir_all(self.dir.as_path())
+                .with_context(|| format!("Failed to create frame dump directory {}", self.dir.display()))?;
+            info!("Dumping the next {} captured frames to {}", frames, self.dir.display());
+        }
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).remaining = frames;
+        Ok(self.status())
+    }
+
+    /// Write `raw` and its conversion `rgb` if a dump is in progress. Files are
+    /// written in the background so capture is not held up
+    pub fn capture(&self, raw: &[u8], rgb: &[u8], conversion: Conversion) {
+        let sequence = {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            if state.remaining == 0 {
+                return;
+            }
+            state.remaining -= 1;
+            state.written += 1;
+            state.written
+        };
+        let (dir, raw, rgb) = (self.dir.clone(), raw.to_vec(), rgb.to_vec());
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = write_dump(&dir, sequence, &raw, &rgb, conversion) {
+                warn!("Failed to dump frame {}: {:#}", sequence, e);
+            }
+        });
+    }
+}
+
+/// Write the files of one frame: the buffer as captured, the RGB result as a
+/// PPM image when it has the frame's size, else as raw RGB24, and the metadata
+fn write_dump(dir: &Path, sequence: u64, raw: &[u8], rgb: &[u8], conversion: Conversion) -> Result<()> {
+    let stem = format!("kvm-rs-frame-{}-{:04}", std::process::id(), sequence);
+    let raw_file = format!("{}.raw", stem);
+    std::fs::write(dir.join(&raw_file), raw)?;
+
+    let (width, height) = (conversion.width as usize, conversion.height as usize);
+    let rgb_file = if rgb.len() == width * height * 3 {
+        let mut ppm = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+        ppm.extend_from_slice(rgb);
+        let file = format!("{}.ppm", stem);
+        std::fs::write(dir.join(&file), ppm)?;
+        file
+    } else {
+        let file = format!("{}.rgb", stem);
+        std::fs::write(dir.join(&file), rgb)?;
+        file
+    };
+
+    let metadata = Metadata {
+        sequence,
+        captured_at: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+        capture_mode: conversion.capture_mode,
+        format: conversion.format,
+        width: conversion.width,
+        height: conversion.height,
+        raw_file: &raw_file,
+        raw_bytes: raw.len(),
+        rgb_file: &rgb_file,
+        rgb_bytes: rgb.len(),
+        version: env!("CARGO_PKG_VERSION"),
+    };
+    let json_file = dir.join(format!("{}.json", stem));
+    std::fs::write(&json_file, serde_json::to_vec_pretty(&metadata)?)?;
+    info!("Dumped captured frame {} to {}", sequence, json_file.display());
+    Ok(())
+}
+
+/// Routes to start and follow frame dumps
+#[cfg(feature = "web")]
+pub fn router(dump: FrameDump) -> Router {
+    Router::new()
+        .route("/api/v1/debug/frame-dump", get(get_frame_dump).put(put_frame_dump))
+        .with_state(dump)
+}
+
+/// GET /api/v1/debug/frame-dump - frames still to be dumped and the directory
+#[cfg(feature = "web")]
+async fn get_frame_dump(State(dump): State<FrameDump>) -> Json<DumpStatus> {
+    Json(dump.status())
+}
+
+/// PUT /api/v1/debug/frame-dump - dump the next frames, or stop with 0
+#[cfg(feature = "web")]
+async fn put_frame_dump(
+    State(dump): State<FrameDump>,
+    Json(request): Json<DumpRequest>,
+) -> Result<Json<DumpStatus>, (StatusCode, String)> {
+    dump.start(request.frames)
+        .map(Json)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("{:#}", e)))
+}
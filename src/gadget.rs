@@ -0,0 +1,455 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// USB gadget configfs and UDC helpers for kvm-rs
+
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+
+/// Root of the USB gadget configfs tree
+#[cfg(target_os = "linux")]
+const CONFIGFS_GADGET_ROOT: &str = "/sys/kernel/config/usb_gadget";
+
+/// UDC class directory in sysfs
+const UDC_CLASS_ROOT: &str = "/sys/class/udc";
+
+/// Locate the configfs `hid.*` function whose `dev` matches the device node
+#[cfg(target_os = "linux")]
+pub fn find_hid_function(device: &str) -> Option<PathBuf> {
+    use std::os::unix::fs::MetadataExt;
+
+    let rdev = std::fs::metadata(device).ok()?.rdev();
+    let major = ((rdev >> 32) & 0xffff_f000) | ((rdev >> 8) & 0x0000_0fff);
+    let minor = ((rdev >> 12) & 0xffff_ff00) | (rdev & 0x0000_00ff);
+    let wanted = format!("{}:{}", major, minor);
+
+    for gadget in std::fs::read_dir(CONFIGFS_GADGET_ROOT).ok()?.flatten() {
+        let Ok(functions) = std::fs::read_dir(gadget.path().join("functions")) else {
+            continue;
+        };
+        for function in functions.flatten() {
+            if !function.file_name().to_string_lossy().starts_with("hid.") {
+                continue;
+            }
+            if let Ok(dev) = std::fs::read_to_string(function.path().join("dev")) {
+                if dev.trim() == wanted {
+                    return Some(function.path());
+                }
+            }
+        }
+    }
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn find_hid_function(_device: &str) -> Option<PathBuf> {
+    None
+}
+
+/// Value of an attribute (e.g. `subclass`) of the device's configfs HID function
+pub fn read_function_attribute(device: &str, name: &str) -> Option<String> {
+    let function = find_hid_function(device)?;
+    std::fs::read_to_string(function.join(name))
+        .ok()
+        .map(|value| value.trim().to_string())
+}
+
+/// configfs directory of the gadget the device's HID function belongs to
+pub fn gadget_dir(device: &str) -> Option<PathBuf> {
+    let function = find_hid_function(device)?;
+    // <gadget>/functions/hid.N
+    Some(function.parent()?.parent()?.to_path_buf())
+}
+
+/// sysfs `state` file of the UDC the device's gadget is bound to
+pub fn udc_state_path(device: &str) -> Option<PathBuf> {
+    let gadget = gadget_dir(device)?;
+    let udc = std::fs::read_to_string(gadget.join("UDC")).ok()?;
+    let udc = udc.trim();
+    if udc.is_empty() {
+        return None;
+    }
+    Some(Path::new(UDC_CLASS_ROOT).join(udc).join("state"))
+}
+
+/// Current UDC state, e.g. "configured", "not attached" or "suspended"
+pub fn read_udc_state(state_path: &Path) -> Option<String> {
+    std::fs::read_to_string(state_path)
+        .ok()
+        .map(|state| state.trim().to_string())
+}
+
+/// Bind the gadget to `udc`, or to the first UDC no gadget is bound to when it
+/// is `auto`, unless it is bound already; the UDC it is bound to
+#[cfg(target_os = "linux")]
+pub fn bind_udc(gadget: &Path, udc: &str) -> Result<String> {
+    use anyhow::Context;
+
+    let udc_file = gadget.join("UDC");
+    let bound = std::fs::read_to_string(&udc_file)
+        .with_context(|| format!("Failed to read {}", udc_file.display()))?;
+    if !bound.trim().is_empty() {
+        return Ok(bound.trim().to_string());
+    }
+    let udc = match udc {
+        "auto" => free_udc().with_context(|| format!("No free UDC in {}", UDC_CLASS_ROOT))?,
+        udc => udc.to_string(),
+    };
+    std::fs::write(&udc_file, &udc).with_context(|| format!("Failed to bind {} to {}", gadget.display(), udc))?;
+    Ok(udc)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn bind_udc(gadget: &Path, _udc: &str) -> Result<String> {
+    anyhow::bail!("Binding {} needs Linux configfs", gadget.display())
+}
+
+/// First UDC by name that no gadget is bound to, e.g. a free port of the
+/// ASPEED virtual hub
+#[cfg(target_os = "linux")]
+fn free_udc() -> Option<String> {
+    let bound: Vec<String> = std::fs::read_dir(CONFIGFS_GADGET_ROOT).ok()?
+        .flatten()
+        .filter_map(|gadget| std::fs::read_to_string(gadget.path().join("UDC")).ok())
+        .map(|udc| udc.trim().to_string())
+        .collect();
+    std::fs::read_dir(UDC_CLASS_ROOT).ok()?
+        .flatten()
+        .map(|udc| udc.file_name().to_string_lossy().into_owned())
+        .filter(|udc| !bound.contains(udc))
+        .min()
+}
+
+/// Create the function `name` (e.g. `mass_storage.kvm`) in the gadget and link
+/// it into the gadget's first configuration, unless it exists. Functions cannot
+/// be added to a bound gadget, so it is unbound from its UDC meanwhile and the
+/// host sees the USB device reconnect
+#[cfg(target_os = "linux")]
+pub fn add_function(gadget: &Path, name: &str) -> Result<PathBuf> {
+    use anyhow::Context;
+
+    let function = gadget.join("functions").join(name);
+    if function.exists() {
+        return Ok(function);
+    }
+    let config = std::fs::read_dir(gadget.join("configs"))
+        .with_context(|| format!("Failed to read the configurations of {}", gadget.display()))?
+        .flatten()
+        .map(|entry| entry.path())
+        .min()
+        .with_context(|| format!("Gadget {} has no configuration", gadget.display()))?;
+    unbound(gadget, || {
+        std::fs::create_dir(&function)
+            .and_then(|()| std::os::unix::fs::symlink(&function, config.join(name)))
+            .with_context(|| format!("Failed to add {} to {}", name, gadget.display()))
+    })?;
+    Ok(function)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn add_function(_gadget: &Path, name: &str) -> Result<PathBuf> {
+    anyhow::bail!("USB gadget function {} needs Linux configfs", name)
+}
+
+/// Serial device of a CDC-ACM function, e.g. /dev/ttyGS0 for `port_num` 0
+#[cfg(target_os = "linux")]
+pub fn acm_tty(function: &Path) -> Result<PathBuf> {
+    let port = read_attribute(function, "port_num")?;
+    Ok(PathBuf::from(format!("/dev/ttyGS{}", port)))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn acm_tty(function: &Path) -> Result<PathBuf> {
+    anyhow::bail!("USB gadget function {} needs Linux configfs", function.display())
+}
+
+/// Apply `change` to the gadget while it is unbound from its UDC, and bind it
+/// to the UDC again even if the change failed
+#[cfg(target_os = "linux")]
+fn unbound<T>(gadget: &Path, change: impl FnOnce() -> Result<T>) -> Result<T> {
+    use anyhow::Context;
+
+    let udc_file = gadget.join("UDC");
+    let udc = std::fs::read_to_string(&udc_file).unwrap_or_default().trim().to_string();
+    if !udc.is_empty() {
+        std::fs::write(&udc_file, "\n").with_context(|| format!("Failed to unbind {} from {}", gadget.display(), udc))?;
+    }
+    let changed = change();
+    if !udc.is_empty() {
+        std::fs::write(&udc_file, &udc).with_context(|| format!("Failed to bind {} to {}", gadget.display(), udc))?;
+    }
+    changed
+}
+
+/// Report descriptor of the keyboard: the 8-byte boot protocol report, with
+/// every key usage up to 0xDD, and the five LEDs as output report
+const KEYBOARD_REPORT_DESC: &[u8] = &[
+    0x05, 0x01, 0x09, 0x06, 0xa1, 0x01,
+    // Modifiers
+    0x05, 0x07, 0x19, 0xe0, 0x29, 0xe7, 0x15, 0x00, 0x25, 0x01, 0x75, 0x01, 0x95, 0x08, 0x81, 0x02,
+    // Reserved byte
+    0x95, 0x01, 0x75, 0x08, 0x81, 0x03,
+    // LEDs, padded to a byte
+    0x05, 0x08, 0x19, 0x01, 0x29, 0x05, 0x95, 0x05, 0x75, 0x01, 0x91, 0x02,
+    0x95, 0x01, 0x75, 0x03, 0x91, 0x03,
+    // Six keys
+    0x05, 0x07, 0x19, 0x00, 0x2a, 0xdd, 0x00, 0x15, 0x00, 0x26, 0xdd, 0x00, 0x95, 0x06, 0x75, 0x08, 0x81, 0x00,
+    0xc0,
+];
+
+/// Report descriptor of the mouse: three buttons, X and Y from 0 to 32767
+/// across the screen and a wheel, in 6-byte reports as obmc-ikvm's gadget
+const MOUSE_REPORT_DESC: &[u8] = &[
+    0x05, 0x01, 0x09, 0x02, 0xa1, 0x01, 0x09, 0x01, 0xa1, 0x00,
+    // Buttons, padded to a byte
+    0x05, 0x09, 0x19, 0x01, 0x29, 0x03, 0x15, 0x00, 0x25, 0x01, 0x95, 0x03, 0x75, 0x01, 0x81, 0x02,
+    0x95, 0x01, 0x75, 0x05, 0x81, 0x03,
+    // Absolute X and Y
+    0x05, 0x01, 0x09, 0x30, 0x09, 0x31, 0x15, 0x00, 0x26, 0xff, 0x7f, 0x75, 0x10, 0x95, 0x02, 0x81, 0x02,
+    // Relative wheel
+    0x09, 0x38, 0x15, 0x81, 0x25, 0x7f, 0x75, 0x08, 0x95, 0x01, 0x81, 0x06,
+    0xc0, 0xc0,
+];
+
+/// Report descriptor of the touchscreen: tip switch and in range, X and Y
+/// from 0 to 32767, in 5-byte reports
+const TOUCHSCREEN_REPORT_DESC: &[u8] = &[
+    0x05, 0x0d, 0x09, 0x04, 0xa1, 0x01, 0x09, 0x22, 0xa1, 0x02,
+    // Tip switch and in range, padded to a byte
+    0x09, 0x42, 0x09, 0x32, 0x15, 0x00, 0x25, 0x01, 0x75, 0x01, 0x95, 0x02, 0x81, 0x02,
+    0x95, 0x06, 0x81, 0x03,
+    // X and Y
+    0x05, 0x01, 0x09, 0x30, 0x09, 0x31, 0x15, 0x00, 0x26, 0xff, 0x7f, 0x75, 0x10, 0x95, 0x02, 0x81, 0x02,
+    0xc0, 0xc0,
+];
+
+/// A HID function of the composite gadget
+struct HidFunction {
+    name: &'static str,
+    subclass: u8,
+    protocol: u8,
+    report_length: usize,
+    report_desc: &'static [u8],
+}
+
+const KEYBOARD: HidFunction = HidFunction {
+    name: "hid.keyboard",
+    // Boot interface, so BIOS and UEFI setup screens take keys
+    subclass: 1,
+    protocol: 1,
+    report_length: 8,
+    report_desc: KEYBOARD_REPORT_DESC,
+};
+
+/// Absolute pointers have no boot protocol, so the mouse and touchscreen are
+/// no boot interfaces
+const MOUSE: HidFunction = HidFunction {
+    name: "hid.mouse",
+    subclass: 0,
+    protocol: 0,
+    report_length: 6,
+    report_desc: MOUSE_REPORT_DESC,
+};
+
+const TOUCHSCREEN: HidFunction = HidFunction {
+    name: "hid.touchscreen",
+    subclass: 0,
+    protocol: 0,
+    report_length: 5,
+    report_desc: TOUCHSCREEN_REPORT_DESC,
+};
+
+/// Device nodes of the HID functions of a composite gadget
+#[derive(Debug, Clone)]
+pub struct HidDevices {
+    pub keyboard: String,
+    pub mouse: String,
+    pub touchscreen: Option<String>,
+}
+
+/// One USB device with the keyboard, the mouse, optionally a touchscreen, the
+/// host agent's serial port and the virtual media drive, built by kvm-rs in
+/// configfs. The mass-storage function is linked last, so the other
+/// interfaces keep their numbers when it is linked and unlinked
+#[derive(Debug)]
+pub struct CompositeGadget {
+    dir: PathBuf,
+    touchscreen: bool,
+    /// CDC-ACM function of the host agent, e.g. `acm.agent`
+    agent: Option<String>,
+    /// Mass-storage function, e.g. `mass_storage.kvm`
+    storage: Option<String>,
+}
+
+impl CompositeGadget {
+    /// configfs directory of the gadget
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl CompositeGadget {
+    /// Build the gadget `name`, or complete one left by a previous run, and
+    /// bind it to `udc` (`auto` for the first free one) once every HID
+    /// function and the `agent` ACM function are linked. The mass-storage
+    /// function is created, but only linked by `set_storage`
+    pub fn create(name: &str, touchscreen: bool, agent: Option<&str>, storage: Option<&str>, udc: &str) -> Result<Self> {
+        use anyhow::Context;
+
+        let gadget = Self {
+            dir: Path::new(CONFIGFS_GADGET_ROOT).join(name),
+            touchscreen,
+            agent: agent.map(str::to_string),
+            storage: storage.map(str::to_string),
+        };
+        let dir = &gadget.dir;
+        std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}; is configfs mounted and libcomposite loaded?", dir.display()))?;
+        unbound(dir, || gadget.populate())?;
+        let bound = bind_udc(dir, udc)?;
+        tracing::info!("Composite USB gadget {} bound to {}", dir.display(), bound);
+        Ok(gadget)
+    }
+
+    /// The first configuration, which holds every function
+    fn config(&self) -> PathBuf {
+        self.dir.join("configs").join("c.1")
+    }
+
+    /// Descriptors, strings and functions; the gadget must be unbound
+    fn populate(&self) -> Result<()> {
+        // Linux Foundation Multifunction Composite Gadget
+        write_attribute(&self.dir, "idVendor", "0x1d6b")?;
+        write_attribute(&self.dir, "idProduct", "0x0104")?;
+        write_attribute(&self.dir, "bcdDevice", "0x0100")?;
+        write_attribute(&self.dir, "bcdUSB", "0x0200")?;
+        let strings = self.dir.join("strings").join("0x409");
+        create_dir(&strings)?;
+        write_attribute(&strings, "manufacturer", "kvm-rs")?;
+        write_attribute(&strings, "product", "Virtual Keyboard, Mouse and Media")?;
+        let config = self.config();
+        create_dir(&config.join("strings").join("0x409"))?;
+        write_attribute(&config.join("strings").join("0x409"), "configuration", "KVM")?;
+        // Bus-powered, with remote wakeup so keys wake a suspended host
+        write_attribute(&config, "bmAttributes", "0xa0")?;
+        write_attribute(&config, "MaxPower", "100")?;
+
+        let mut functions = vec![&KEYBOARD, &MOUSE];
+        if self.touchscreen {
+            functions.push(&TOUCHSCREEN);
+        } else {
+            self.link(TOUCHSCREEN.name, false)?;
+        }
+        for function in functions {
+            let dir = self.dir.join("functions").join(function.name);
+            // Attributes of a function cannot change while it is linked
+            if !config.join(function.name).exists() {
+                create_dir(&dir)?;
+                write_attribute(&dir, "subclass", &function.subclass.to_string())?;
+                write_attribute(&dir, "protocol", &function.protocol.to_string())?;
+                write_attribute(&dir, "report_length", &function.report_length.to_string())?;
+                write_bytes(&dir.join("report_desc"), function.report_desc)?;
+            }
+            self.link(function.name, true)?;
+        }
+        // ACM has two interfaces, grouped by an interface association
+        // descriptor, which hosts only look for in a Miscellaneous device
+        let (class, subclass, protocol) = if self.agent.is_some() { ("0xef", "0x02", "0x01") } else { ("0x00", "0x00", "0x00") };
+        write_attribute(&self.dir, "bDeviceClass", class)?;
+        write_attribute(&self.dir, "bDeviceSubClass", subclass)?;
+        write_attribute(&self.dir, "bDeviceProtocol", protocol)?;
+        if let Some(ref agent) = self.agent {
+            create_dir(&self.dir.join("functions").join(agent))?;
+            self.link(agent, true)?;
+        }
+        if let Some(ref storage) = self.storage {
+            create_dir(&self.dir.join("functions").join(storage))?;
+        }
+        Ok(())
+    }
+
+    /// Device nodes of the HID functions, named by the kernel after their minor
+    pub fn hid_devices(&self) -> Result<HidDevices> {
+        let device = |function: &HidFunction| -> Result<String> {
+            let dev = read_attribute(&self.dir.join("functions").join(function.name), "dev")?;
+            let minor = dev.split_once(':').map(|(_, minor)| minor.to_string()).unwrap_or(dev);
+            Ok(format!("/dev/hidg{}", minor))
+        };
+        Ok(HidDevices {
+            keyboard: device(&KEYBOARD)?,
+            mouse: device(&MOUSE)?,
+            touchscreen: if self.touchscreen { Some(device(&TOUCHSCREEN)?) } else { None },
+        })
+    }
+
+    /// Link the mass-storage function while a medium is inserted, so the host
+    /// enumerates the drive with it and firmware boot menus list it; unlink it
+    /// while the drive is empty. The gadget is unbound meanwhile and the host
+    /// sees the USB device reconnect
+    pub fn set_storage(&self, inserted: bool) -> Result<()> {
+        let Some(ref storage) = self.storage else {
+            return Ok(());
+        };
+        if self.config().join(storage).exists() == inserted {
+            return Ok(());
+        }
+        unbound(&self.dir, || self.link(storage, inserted))?;
+        tracing::info!("Virtual media drive {} the composite USB gadget", if inserted { "added to" } else { "removed from" });
+        Ok(())
+    }
+
+    fn link(&self, function: &str, linked: bool) -> Result<()> {
+        use anyhow::Context;
+
+        let link = self.config().join(function);
+        let changed = match (link.exists(), linked) {
+            (false, true) => std::os::unix::fs::symlink(self.dir.join("functions").join(function), &link),
+            (true, false) => std::fs::remove_file(&link),
+            _ => return Ok(()),
+        };
+        changed.with_context(|| format!("Failed to {} {}", if linked { "link" } else { "unlink" }, link.display()))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl CompositeGadget {
+    pub fn create(name: &str, _touchscreen: bool, _agent: Option<&str>, _storage: Option<&str>, _udc: &str) -> Result<Self> {
+        anyhow::bail!("USB gadget {} needs Linux configfs", name)
+    }
+
+    pub fn hid_devices(&self) -> Result<HidDevices> {
+        anyhow::bail!("USB gadget {} needs Linux configfs", self.dir.display())
+    }
+
+    pub fn set_storage(&self, _inserted: bool) -> Result<()> {
+        anyhow::bail!("USB gadget {} needs Linux configfs", self.dir.display())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn create_dir(dir: &Path) -> Result<()> {
+    use anyhow::Context;
+
+    std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))
+}
+
+#[cfg(target_os = "linux")]
+fn write_attribute(dir: &Path, name: &str, value: &str) -> Result<()> {
+    write_bytes(&dir.join(name), value.as_bytes())
+}
+
+#[cfg(target_os = "linux")]
+fn write_bytes(path: &Path, value: &[u8]) -> Result<()> {
+    use anyhow::Context;
+
+    std::fs::write(path, value).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(target_os = "linux")]
+fn read_attribute(dir: &Path, name: &str) -> Result<String> {
+    use anyhow::Context;
+
+    let path = dir.join(name);
+    std::fs::read_to_string(&path)
+        .map(|value| value.trim().to_string())
+        .with_context(|| format!("Failed to read {}", path.display()))
+}
@@ -2,72 +2,974 @@
 //
 // HID device management for kvm-rs
 
-/// HID device manager for keyboard and mouse input
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicU8, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{watch, Mutex},
+    task::JoinHandle,
+};
+use tracing::{debug, error, info, trace, warn};
+use crate::gadget;
+use crate::hid_backend::HidBackend;
+use crate::hid_stats::{HidStats, HidStatsSnapshot};
+use crate::hid_descriptor::{self, KeyboardLayout, MouseLayout, ParsedDescriptor, ReportValidation, TouchLayout};
+use crate::keyboard::{self, KeyBlocklist, KeyRepeat, KeyRepeatPolicy, KeyboardProtocol, KeyboardState};
+use crate::macros::MacroStore;
+use crate::pointer::PointerSettings;
+use crate::power::HostState;
+
+/// How often the UDC state is polled for host disconnects and reconnects
+const UDC_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time between the key events of injected keystrokes, so the host sees each report
+const KEY_TAP_DELAY: Duration = Duration::from_millis(10);
+
+/// Reports kept while the host is off with --power-off-input queue
+const MAX_QUEUED_REPORTS: usize = 1024;
+/// How long queued reports wait for a powered-on host to enumerate the gadget
+const HOST_ENUMERATION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What happens to input sent while the host is powered off
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerOffInput {
+    /// Refuse it; REST requests get 409 Conflict
+    Reject,
+    /// Keep it and send it once the host is on again
+    Queue,
+}
+
+/// A report kept until the host is on again
+enum QueuedReport {
+    Keyboard(Vec<u8>),
+    Mouse(Vec<u8>),
+    Touch(Vec<u8>),
+}
+
+/// Why input could not be sent to the host
+#[derive(Debug, thiserror::Error)]
+pub enum HidError {
+    #[error("Input forwarding is locked")]
+    InputLocked,
+    #[error("Host is powered off")]
+    HostOff,
+    #[error("No touchscreen HID device configured")]
+    NoTouchscreen,
+    /// A report that does not match the device's report descriptor
+    #[error("{0}")]
+    InvalidReport(String),
+    #[error("Unknown macro '{0}'")]
+    UnknownMacro(String),
+    #[error("Cannot type character {0:?}")]
+    Untypeable(char),
+    #[error("Failed to open HID device {path}: {source}")]
+    Open { path: String, source: std::io::Error },
+    #[error("Write to {path} failed, will reopen: {source}")]
+    Write { path: String, source: std::io::Error },
+    #[error("Write to {0} timed out, host is not reading reports")]
+    Timeout(String),
+    #[error("Read from {path} failed: {source}")]
+    Read { path: String, source: std::io::Error },
+    #[error("HID device {0} closed")]
+    Closed(String),
+}
+
+impl HidError {
+    /// Whether the input itself was refused, so sending it again cannot succeed
+    pub fn is_rejected(&self) -> bool {
+        matches!(
+            self,
+            HidError::NoTouchscreen | HidError::InvalidReport(_) | HidError::UnknownMacro(_) | HidError::Untypeable(_)
+        )
+    }
+
+    /// Whether the device cannot be used until it is fixed (node missing, no
+    /// permission); other device errors clear once the host reads reports again
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            HidError::Open { source, .. }
+                if matches!(source.kind(), std::io::ErrorKind::NotFound | std::io::ErrorKind::PermissionDenied)
+        )
+    }
+}
+
+/// Output backend of one device together with its write statistics
+#[derive(Clone)]
+struct HidOutput {
+    backend: Arc<dyn HidBackend>,
+    stats: Arc<HidStats>,
+}
+
+impl HidOutput {
+    fn new(backend: Arc<dyn HidBackend>) -> Self {
+        Self {
+            backend,
+            stats: Arc::new(HidStats::default()),
+        }
+    }
+
+    fn path(&self) -> &str {
+        self.backend.path()
+    }
+
+    async fn write(&self, data: &[u8]) -> Result<(), HidError> {
+        self.stats.attempt();
+        match self.backend.write(data).await {
+            Ok(()) => {
+                self.stats.success(data.len());
+                Ok(())
+            }
+            Err(e) => {
+                self.stats.failure(&e);
+                Err(e)
+            }
+        }
+    }
+
+    async fn close(&self) {
+        self.backend.close().await;
+    }
+
+    fn stats(&self) -> HidStatsSnapshot {
+        self.stats.snapshot(self.path())
+    }
+}
+
+/// Usage ID of a key being repeated, with the task repeating it
+type RepeatTask = (u8, JoinHandle<()>);
+
+/// HID device manager for keyboard, mouse and touchscreen input
 #[derive(Clone)]
 pub struct HidManager {
-    keyboard_device: String,
-    mouse_device: String,
+    keyboard_device: HidOutput,
+    mouse_device: HidOutput,
+    touch_device: Option<HidOutput>,
+    keyboard_layout: KeyboardLayout,
+    mouse_layout: MouseLayout,
+    touch_layout: TouchLayout,
+    keyboard_state: Arc<Mutex<KeyboardState>>,
+    key_repeat: KeyRepeat,
+    /// Server-side typematic task and the key it repeats
+    repeat_task: Arc<std::sync::Mutex<Option<RepeatTask>>>,
+    pointer_settings: Arc<std::sync::RwLock<PointerSettings>>,
+    /// When set, no input is forwarded to the host
+    input_lock: Arc<watch::Sender<bool>>,
+    macros: MacroStore,
+    /// Key combinations filtered out of every keyboard report
+    blocked_keys: KeyBlocklist,
+    /// Checks applied to raw reports from clients
+    report_validation: ReportValidation,
+    /// Keyboard LED state (NumLock, CapsLock, ScrollLock bits)
+    keyboard_leds: Arc<AtomicU8>,
+    /// Whether the host has sent an LED report; until then NumLock is
+    /// tracked from the key presses sent to it
+    host_leds_seen: Arc<AtomicBool>,
+    keyboard_protocol: KeyboardProtocol,
+    /// Whether keyboard reports are currently sent in boot protocol format
+    boot_protocol: Arc<AtomicBool>,
+    /// Power state of the host the gadget is plugged into
+    host: HostState,
+    power_off_input: PowerOffInput,
+    /// Reports sent while the host was off, with PowerOffInput::Queue
+    queued: Arc<std::sync::Mutex<VecDeque<QueuedReport>>>,
 }
 
 impl HidManager {
-    pub fn new(keyboard_device: String, mouse_device: String) -> Self {
+    /// Build a manager writing reports to the given backends
+    pub fn from_backends(
+        keyboard_device: Arc<dyn HidBackend>,
+        mouse_device: Arc<dyn HidBackend>,
+        keyboard_descriptor: Option<String>,
+        mouse_descriptor: Option<String>,
+    ) -> Self {
+        let keyboard_path = keyboard_device.path();
+        let keyboard_layout = Self::parsed_descriptor_for(keyboard_path, keyboard_descriptor.as_deref())
+            .keyboard
+            .inspect(|layout| {
+                info!("Keyboard {}: {}-byte reports, {} key slots", keyboard_path, layout.report_len, layout.key_count);
+            })
+            .unwrap_or_else(|| {
+                info!("Keyboard {}: assuming 8-byte boot keyboard reports", keyboard_path);
+                KeyboardLayout::default()
+            });
+
+        let mouse_path = mouse_device.path();
+        let mouse_layout = Self::parsed_descriptor_for(mouse_path, mouse_descriptor.as_deref())
+            .mouse
+            .inspect(|layout| {
+                info!("Mouse {}: {}-byte {} reports", mouse_path, layout.report_len,
+                    if layout.absolute { "absolute" } else { "relative" });
+            })
+            .unwrap_or_else(|| {
+                info!("Mouse {}: assuming 4-byte relative mouse reports", mouse_path);
+                MouseLayout::default()
+            });
+
         Self {
-            keyboard_device,
-            mouse_device,
+            keyboard_device: HidOutput::new(keyboard_device),
+            mouse_device: HidOutput::new(mouse_device),
+            touch_device: None,
+            keyboard_layout,
+            mouse_layout,
+            touch_layout: TouchLayout::default(),
+            keyboard_state: Arc::new(Mutex::new(KeyboardState::default())),
+            key_repeat: KeyRepeat::default(),
+            repeat_task: Arc::new(std::sync::Mutex::new(None)),
+            pointer_settings: Arc::new(std::sync::RwLock::new(PointerSettings::default())),
+            input_lock: Arc::new(watch::channel(false).0),
+            macros: MacroStore::default(),
+            blocked_keys: KeyBlocklist::default(),
+            report_validation: ReportValidation::Sanitize,
+            keyboard_leds: Arc::new(AtomicU8::new(0)),
+            host_leds_seen: Arc::new(AtomicBool::new(false)),
+            keyboard_protocol: KeyboardProtocol::Report,
+            boot_protocol: Arc::new(AtomicBool::new(false)),
+            host: HostState::default(),
+            power_off_input: PowerOffInput::Reject,
+            queued: Arc::default(),
+        }
+    }
+
+    /// Apply a key repeat policy to key events
+    pub fn with_key_repeat(mut self, key_repeat: KeyRepeat) -> Self {
+        self.key_repeat = key_repeat;
+        self
+    }
+
+    /// Record and replay keyboard macros from the given store
+    pub fn with_macros(mut self, macros: MacroStore) -> Self {
+        self.macros = macros;
+        self
+    }
+
+    /// Never forward the given key combinations to the host
+    pub fn with_blocked_keys(mut self, blocked_keys: KeyBlocklist) -> Self {
+        self.blocked_keys = blocked_keys;
+        self
+    }
+
+    /// Check raw client reports with the given policy
+    pub fn with_report_validation(mut self, report_validation: ReportValidation) -> Self {
+        self.report_validation = report_validation;
+        self
+    }
+
+    /// Build keyboard reports for the given protocol
+    pub fn with_keyboard_protocol(mut self, protocol: KeyboardProtocol) -> Self {
+        let path = self.keyboard_device.path();
+        match protocol {
+            KeyboardProtocol::Report => {}
+            KeyboardProtocol::Boot => {
+                info!("Keyboard {}: sending boot protocol reports", path);
+                self.boot_protocol.store(true, Ordering::Relaxed);
+            }
+            KeyboardProtocol::Auto if self.keyboard_layout.is_boot_compatible() => {
+                info!("Keyboard {}: reports are valid in both boot and report protocol", path);
+            }
+            KeyboardProtocol::Auto => {
+                match gadget::read_function_attribute(path, "subclass").as_deref() {
+                    Some("1") => info!("Keyboard {}: boot interface, detecting protocol from host LED reports", path),
+                    Some(_) => info!("Keyboard {}: gadget is not a boot interface, the host will use report protocol", path),
+                    None => info!("Keyboard {}: detecting protocol from host LED reports", path),
+                }
+            }
+        }
+        self.keyboard_protocol = protocol;
+        self
+    }
+
+    /// Keyboard macro store
+    pub fn macros(&self) -> &MacroStore {
+        &self.macros
+    }
+
+    /// Route pointer positioning through a touchscreen writing to the given backend
+    pub fn with_touch_backend(mut self, touch_device: Arc<dyn HidBackend>, touch_descriptor: Option<String>) -> Self {
+        let touch_path = touch_device.path();
+        self.touch_layout = Self::parsed_descriptor_for(touch_path, touch_descriptor.as_deref())
+            .touch
+            .inspect(|layout| {
+                info!("Touchscreen {}: {}-byte reports", touch_path, layout.report_len);
+            })
+            .unwrap_or_else(|| {
+                info!("Touchscreen {}: assuming 5-byte single-touch reports", touch_path);
+                TouchLayout::default()
+            });
+        self.touch_device = Some(HidOutput::new(touch_device));
+        self
+    }
+
+    /// Load and parse the gadget's report descriptor, empty if unavailable
+    fn parsed_descriptor_for(device: &str, descriptor_path: Option<&str>) -> ParsedDescriptor {
+        match hid_descriptor::load_descriptor(device, descriptor_path) {
+            Ok(Some(descriptor)) => match hid_descriptor::parse(&descriptor) {
+                Ok(parsed) => return parsed,
+                Err(e) => warn!("Failed to parse report descriptor for {}: {}", device, e),
+            },
+            Ok(None) => info!("No report descriptor found for {}", device),
+            Err(e) => warn!("{}", e),
+        }
+        ParsedDescriptor::default()
+    }
+
+    /// Use the given pointer scaling and calibration settings
+    pub fn with_pointer_settings(self, settings: PointerSettings) -> Self {
+        self.set_pointer_settings(settings);
+        self
+    }
+
+    /// Current pointer scaling and calibration settings
+    pub fn pointer_settings(&self) -> PointerSettings {
+        *self.pointer_settings.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Refuse or queue input by `policy` while `host` is powered off
+    pub fn with_host_state(mut self, host: HostState, policy: PowerOffInput) -> Self {
+        self.host = host;
+        self.power_off_input = policy;
+        self
+    }
+
+    /// Watch whether the host is powered off
+    pub fn subscribe_host_state(&self) -> watch::Receiver<bool> {
+        self.host.subscribe()
+    }
+
+    /// Replace the pointer settings at runtime
+    pub fn set_pointer_settings(&self, settings: PointerSettings) {
+        *self.pointer_settings.write().unwrap_or_else(|e| e.into_inner()) = settings;
+    }
+
+    /// Whether input forwarding to the host is currently blocked
+    pub fn is_input_locked(&self) -> bool {
+        *self.input_lock.borrow()
+    }
+
+    /// Watch the input lock state
+    pub fn subscribe_input_lock(&self) -> watch::Receiver<bool> {
+        self.input_lock.subscribe()
+    }
+
+    /// Block or resume input forwarding; held keys are released before locking
+    pub async fn set_input_locked(&self, locked: bool) {
+        if locked && !self.is_input_locked() {
+            if let Err(e) = self.release_all().await {
+                warn!("Failed to release input before locking: {}", e);
+            }
+        }
+        if self.input_lock.send_replace(locked) != locked {
+            info!("Input forwarding {}", if locked { "locked" } else { "unlocked" });
+        }
+    }
+
+    /// Release all held keys and mouse buttons on the host
+    pub async fn release_all(&self) -> Result<(), HidError> {
+        if let Some((_, task)) = self.repeat_task.lock().unwrap_or_else(|e| e.into_inner()).take() {
+            task.abort();
+        }
+        self.keyboard_state.lock().await.clear();
+        self.send_keyboard_input(&self.keyboard_layout.report(0, &[])).await?;
+
+        // Absolute reports always carry a position, so only relative mice are reset
+        if !self.mouse_layout.absolute {
+            self.send_mouse_input(&self.mouse_layout.report(0, 0, 0, 0)).await?;
+        }
+        Ok(())
+    }
+
+    /// Press or release a key (HID usage) and send the resulting keyboard report
+    pub async fn key_event(&self, usage: u8, down: bool) -> Result<(), HidError> {
+        if self.is_input_locked() {
+            return Err(HidError::InputLocked);
+        }
+
+        let report = {
+            let mut state = self.keyboard_state.lock().await;
+            if down {
+                if let Some(combo) = self.blocked_keys.blocked(state.modifiers(), usage) {
+                    info!("Blocked key combination {}", combo);
+                    return Ok(());
+                }
+            }
+            let changed = if down { state.press(usage) } else { state.release(usage) };
+
+            // Clients that auto-repeat send extra key-downs for a held key
+            if !changed && self.key_repeat.policy != KeyRepeatPolicy::Passthrough {
+                return Ok(());
+            }
+            self.keyboard_layout.report(state.modifiers(), state.keys())
+        };
+        self.macros.record_key(usage, down);
+
+        if usage == keyboard::NUM_LOCK_USAGE && down && !self.host_leds_seen.load(Ordering::Relaxed) {
+            self.keyboard_leds.fetch_xor(keyboard::LED_NUM_LOCK, Ordering::Relaxed);
+        }
+
+        if self.key_repeat.policy == KeyRepeatPolicy::Server && !keyboard::is_modifier(usage) {
+            if down {
+                self.start_repeat(usage);
+            } else {
+                self.stop_repeat(usage);
+            }
+        }
+
+        self.send_keyboard_input(&report).await
+    }
+
+    /// Press `usages` in order, then release them in reverse, e.g. Ctrl+Alt+Delete
+    pub async fn tap_keys(&self, usages: &[u8]) -> Result<(), HidError> {
+        for usage in usages {
+            self.key_event(*usage, true).await?;
+            tokio::time::sleep(KEY_TAP_DELAY).await;
+        }
+        for usage in usages.iter().rev() {
+            self.key_event(*usage, false).await?;
+            tokio::time::sleep(KEY_TAP_DELAY).await;
+        }
+        Ok(())
+    }
+
+    /// Type ASCII text as US layout keystrokes
+    pub async fn type_text(&self, text: &str) -> Result<(), HidError> {
+        for c in text.chars() {
+            let (usage, shift) = keyboard::usage_for_char(c)
+                .ok_or(HidError::Untypeable(c))?;
+            if shift {
+                self.tap_keys(&[keyboard::LEFT_SHIFT_USAGE, usage]).await?;
+            } else {
+                self.tap_keys(&[usage]).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replay a recorded macro; keys it leaves pressed are released at the end
+    pub async fn play_macro(&self, name: &str) -> Result<(), HidError> {
+        let steps = self.macros.get(name)
+            .ok_or_else(|| HidError::UnknownMacro(name.to_string()))?;
+        info!("Playing keyboard macro '{}' ({} steps)", name, steps.len());
+
+        let mut held = Vec::new();
+        for step in &steps {
+            if step.delay_ms > 0 {
+                tokio::time::sleep(Duration::from_millis(step.delay_ms)).await;
+            }
+            self.key_event(step.usage, step.down).await?;
+            if step.down {
+                held.push(step.usage);
+            } else {
+                held.retain(|usage| *usage != step.usage);
+            }
+        }
+        for usage in held {
+            self.key_event(usage, false).await?;
+        }
+        Ok(())
+    }
+
+    /// Repeat `usage` while it stays held, replacing any key currently repeating
+    fn start_repeat(&self, usage: u8) {
+        let manager = self.clone();
+        let task = tokio::spawn(async move {
+            tokio::time::sleep(manager.key_repeat.delay).await;
+            let mut interval = tokio::time::interval(manager.key_repeat.interval);
+            loop {
+                interval.tick().await;
+                let (released, pressed) = {
+                    let state = manager.keyboard_state.lock().await;
+                    if !state.is_pressed(usage) {
+                        break;
+                    }
+                    (
+                        manager.keyboard_layout.report(state.modifiers(), &state.keys_without(usage)),
+                        manager.keyboard_layout.report(state.modifiers(), state.keys()),
+                    )
+                };
+                // A release/press cycle is seen as a fresh keystroke by the host
+                if manager.send_keyboard_input(&released).await.is_err()
+                    || manager.send_keyboard_input(&pressed).await.is_err()
+                {
+                    break;
+                }
+            }
+        });
+
+        let mut repeat_task = self.repeat_task.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((_, previous)) = repeat_task.replace((usage, task)) {
+            previous.abort();
+        }
+    }
+
+    /// Stop repeating `usage` if it is the key currently repeating
+    fn stop_repeat(&self, usage: u8) {
+        let mut repeat_task = self.repeat_task.lock().unwrap_or_else(|e| e.into_inner());
+        if matches!(*repeat_task, Some((repeating, _)) if repeating == usage) {
+            if let Some((_, task)) = repeat_task.take() {
+                task.abort();
+            }
+        }
+    }
+
+    /// Whether NumLock is on at the host
+    pub fn num_lock(&self) -> bool {
+        self.keyboard_leds.load(Ordering::Relaxed) & keyboard::LED_NUM_LOCK != 0
+    }
+
+    /// Track the keyboard LED output reports the host sends
+    pub async fn monitor_leds(self) {
+        let mut buf = [0u8; 64];
+        let mut failing = false;
+        loop {
+            match self.keyboard_device.backend.read_output(&mut buf).await {
+                Ok(0) => continue,
+                Ok(len) => {
+                    failing = false;
+                    // In report protocol the LED bits follow the report ID; a boot
+                    // protocol host sends the single LED byte without it
+                    let boot = self.keyboard_layout.report_id.is_some() && len == 1;
+                    let leds = if self.keyboard_layout.report_id.is_some() && !boot {
+                        buf[1]
+                    } else {
+                        buf[0]
+                    };
+                    if self.keyboard_protocol == KeyboardProtocol::Auto
+                        && self.keyboard_layout.report_id.is_some()
+                        && self.boot_protocol.swap(boot, Ordering::Relaxed) != boot
+                    {
+                        info!("Host switched keyboard to {} protocol", if boot { "boot" } else { "report" });
+                    }
+                    self.host_leds_seen.store(true, Ordering::Relaxed);
+                    if self.keyboard_leds.swap(leds, Ordering::Relaxed) != leds {
+                        debug!("Host keyboard LEDs: 0x{:02x}", leds);
+                    }
+                }
+                Err(e) => {
+                    if !failing {
+                        warn!("Keyboard LED reports unavailable: {}", e);
+                        failing = true;
+                    }
+                    tokio::time::sleep(UDC_POLL_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    /// Watch the UDC the keyboard gadget is bound to and resynchronize the
+    /// devices when the host disconnects and reconnects (reboot, cable re-plug)
+    pub async fn monitor_udc(self) {
+        let Some(state_path) = gadget::udc_state_path(self.keyboard_device.path()) else {
+            info!("No UDC found for {}, gadget hotplug monitoring disabled", self.keyboard_device.path());
+            return;
+        };
+        info!("Monitoring gadget UDC state: {}", state_path.display());
+
+        let mut last_state = gadget::read_udc_state(&state_path);
+        loop {
+            tokio::time::sleep(UDC_POLL_INTERVAL).await;
+
+            let state = gadget::read_udc_state(&state_path);
+            if state == last_state {
+                continue;
+            }
+            info!("Gadget UDC state changed: {} -> {}",
+                last_state.as_deref().unwrap_or("unbound"), state.as_deref().unwrap_or("unbound"));
+
+            if state.as_deref() == Some("configured") {
+                self.reconnect().await;
+            } else {
+                self.close_devices().await;
+            }
+            last_state = state;
+        }
+    }
+
+    /// Close the gadget device handles; the next report reopens them
+    pub async fn close_devices(&self) {
+        self.keyboard_device.close().await;
+        self.mouse_device.close().await;
+        if let Some(ref touch_device) = self.touch_device {
+            touch_device.close().await;
+        }
+    }
+
+    /// Reopen the devices and reconcile key state with a freshly enumerated host
+    async fn reconnect(&self) {
+        self.close_devices().await;
+
+        // A USB reset puts the host side back into report protocol
+        if self.keyboard_protocol == KeyboardProtocol::Auto {
+            self.boot_protocol.store(false, Ordering::Relaxed);
+        }
+
+        // Keys held before the host went away are stale, the host now sees none
+        if self.is_input_locked() {
+            self.keyboard_state.lock().await.clear();
+        } else if let Err(e) = self.release_all().await {
+            warn!("Failed to resynchronize HID state after reconnect: {}", e);
+        } else {
+            info!("HID devices reopened after host reconnect");
+        }
+    }
+
+    /// State of the UDC the gadget is bound to (e.g. "configured"), None if unbound or unknown
+    pub fn udc_state(&self) -> Option<String> {
+        gadget::udc_state_path(self.keyboard_device.path())
+            .and_then(|state_path| gadget::read_udc_state(&state_path))
+    }
+
+    /// Write statistics of every configured device
+    pub fn stats(&self) -> Vec<HidStatsSnapshot> {
+        let mut stats = vec![self.keyboard_device.stats(), self.mouse_device.stats()];
+        if let Some(ref touch_device) = self.touch_device {
+            stats.push(touch_device.stats());
+        }
+        stats
+    }
+
+    /// Mouse report layout of the gadget
+    pub fn mouse_layout(&self) -> &MouseLayout {
+        &self.mouse_layout
+    }
+
+    /// Touchscreen report layout, if a touchscreen gadget is configured
+    pub fn touch_layout(&self) -> Option<&TouchLayout> {
+        self.touch_device.as_ref().map(|_| &self.touch_layout)
+    }
+
+    /// Validate a raw keyboard report from a client and send it
+    pub async fn send_raw_keyboard_input(&self, data: &[u8]) -> Result<(), HidError> {
+        let layout = &self.keyboard_layout;
+        let report = self.validate_raw_report("Keyboard", data, layout.report_id, layout.report_len, |r| layout.sanitize(r))?;
+        self.send_keyboard_input(&report).await
+    }
+
+    /// Validate a raw mouse report from a client and send it
+    pub async fn send_raw_mouse_input(&self, data: &[u8]) -> Result<(), HidError> {
+        let layout = &self.mouse_layout;
+        let report = self.validate_raw_report("Mouse", data, layout.report_id, layout.report_len, |r| layout.sanitize(r))?;
+        self.send_mouse_input(&report).await
+    }
+
+    /// Validate a raw touchscreen report from a client and send it
+    pub async fn send_raw_touch_input(&self, data: &[u8]) -> Result<(), HidError> {
+        let layout = &self.touch_layout;
+        let report = self.validate_raw_report("Touchscreen", data, layout.report_id, layout.report_len, |r| layout.sanitize(r))?;
+        self.send_touch_input(&report).await
+    }
+
+    /// Apply the report validation policy to a raw report; `sanitize` rebuilds
+    /// a report from the fields the descriptor defines
+    fn validate_raw_report<'a, F>(
+        &self,
+        kind: &str,
+        data: &'a [u8],
+        report_id: Option<u8>,
+        report_len: usize,
+        sanitize: F,
+    ) -> Result<Cow<'a, [u8]>, HidError>
+    where
+        F: FnOnce(&[u8]) -> Vec<u8>,
+    {
+        if self.report_validation == ReportValidation::Permissive {
+            return Ok(Cow::Borrowed(data));
+        }
+
+        if data.len() < report_len
+            || (data.len() > report_len && self.report_validation == ReportValidation::Strict)
+        {
+            return Err(HidError::InvalidReport(format!(
+                "{} HID report must be exactly {} bytes, got {}", kind, report_len, data.len())));
+        }
+        let data = &data[..report_len];
+        if let Some(id) = report_id {
+            if data[0] != id {
+                return Err(HidError::InvalidReport(format!(
+                    "{} HID report has report ID {}, expected {}", kind, data[0], id)));
+            }
+        }
+
+        let sanitized = sanitize(data);
+        if sanitized == data {
+            return Ok(Cow::Borrowed(data));
+        }
+        match self.report_validation {
+            ReportValidation::Strict => Err(HidError::InvalidReport(format!(
+                "{} HID report {:02x?} has invalid usages or values", kind, data))),
+            _ => {
+                warn!("Sanitized {} HID report {:02x?} to {:02x?}", kind.to_lowercase(), data, sanitized);
+                Ok(Cow::Owned(sanitized))
+            }
         }
     }
 
     /// Send keyboard input to HID gadget device
-    pub async fn send_keyboard_input(&self, data: &[u8]) -> anyhow::Result<()> {
-        use tokio::io::AsyncWriteExt;
-        
-        // TODO: In production, validate HID report format
-        if data.len() < 8 {
-            return Err(anyhow::anyhow!("Keyboard HID report must be at least 8 bytes"));
+    pub async fn send_keyboard_input(&self, data: &[u8]) -> Result<(), HidError> {
+        if self.is_input_locked() {
+            return Err(HidError::InputLocked);
+        }
+
+        if data.len() < self.keyboard_layout.report_len {
+            return Err(HidError::InvalidReport(format!("Keyboard HID report must be at least {} bytes", self.keyboard_layout.report_len)));
         }
+        // Queued as given, the host may be in another protocol when it is back
+        if let Some(result) = self.hold_while_off(|| QueuedReport::Keyboard(data.to_vec())) {
+            return result;
+        }
+        let filtered = self.filter_blocked_keys(data);
+        let mut data = Cow::Borrowed(filtered.as_deref().unwrap_or(data));
+        if self.boot_protocol.load(Ordering::Relaxed) && !self.keyboard_layout.is_boot_compatible() {
+            let (modifiers, keys) = self.keyboard_layout.decode(&data);
+            data = Cow::Owned(KeyboardLayout::BOOT.report(modifiers, &keys));
+        }
+        let data = data.as_ref();
         
-        match tokio::fs::OpenOptions::new()
-            .write(true)
-            .open(&self.keyboard_device)
-            .await
-        {
-            Ok(mut file) => {
-                file.write_all(data).await?;
-                file.flush().await?;
-                println!("Sent keyboard input to {}: {} bytes", self.keyboard_device, data.len());
+        match self.keyboard_device.write(data).await {
+            Ok(()) => {
+                trace!("Sent keyboard input to {}: {} bytes", self.keyboard_device.path(), data.len());
             }
             Err(e) => {
-                eprintln!("Failed to open keyboard device {}: {}", self.keyboard_device, e);
-                return Err(e.into());
+                error!("Keyboard device error: {}", e);
+                return Err(e);
             }
         }
         Ok(())
     }
 
+    /// While the host is off, refuse the report or queue it by the policy;
+    /// None when the host is on and the report is to be sent
+    fn hold_while_off(&self, report: impl FnOnce() -> QueuedReport) -> Option<Result<(), HidError>> {
+        if !self.host.is_off() {
+            return None;
+        }
+        if self.power_off_input == PowerOffInput::Reject {
+            return Some(Err(HidError::HostOff));
+        }
+        let mut queued = self.queued.lock().unwrap_or_else(|e| e.into_inner());
+        if queued.len() >= MAX_QUEUED_REPORTS {
+            return Some(Err(HidError::HostOff));
+        }
+        queued.push_back(report());
+        Some(Ok(()))
+    }
+
+    /// Send the reports queued while the host was off once it is on again
+    /// and has enumerated the gadget, which it does not read reports before
+    pub async fn monitor_host_state(self) {
+        let mut off = self.host.subscribe();
+        loop {
+            if off.wait_for(|off| *off).await.is_err() || off.wait_for(|off| !*off).await.is_err() {
+                break;
+            }
+            let deadline = Instant::now() + HOST_ENUMERATION_TIMEOUT;
+            while self.udc_state().is_some_and(|state| state != "configured") && Instant::now() < deadline {
+                tokio::time::sleep(UDC_POLL_INTERVAL).await;
+            }
+            let queued = std::mem::take(&mut *self.queued.lock().unwrap_or_else(|e| e.into_inner()));
+            if queued.is_empty() {
+                continue;
+            }
+            info!("Sending {} input reports queued while the host was off", queued.len());
+            for report in queued {
+                let result = match report {
+                    QueuedReport::Keyboard(data) => self.send_keyboard_input(&data).await,
+                    QueuedReport::Mouse(data) => self.send_mouse_input(&data).await,
+                    QueuedReport::Touch(data) => self.send_touch_input(&data).await,
+                };
+                if let Err(e) = result {
+                    warn!("Dropped the rest of the queued input: {}", e);
+                    break;
+                }
+                tokio::time::sleep(KEY_TAP_DELAY).await;
+            }
+        }
+    }
+
+    /// Rebuild a keyboard report without keys that form a blocked combination
+    /// with its modifiers; None if nothing is blocked
+    fn filter_blocked_keys(&self, data: &[u8]) -> Option<Vec<u8>> {
+        if self.blocked_keys.is_empty() {
+            return None;
+        }
+
+        let (modifiers, keys) = self.keyboard_layout.decode(data);
+        let mut allowed = Vec::with_capacity(keys.len());
+        for usage in &keys {
+            match self.blocked_keys.blocked(modifiers, *usage) {
+                Some(combo) => info!("Blocked key combination {}", combo),
+                None => allowed.push(*usage),
+            }
+        }
+        if allowed.len() == keys.len() {
+            return None;
+        }
+        Some(self.keyboard_layout.report(modifiers, &allowed))
+    }
+
     /// Send mouse input to HID gadget device
-    pub async fn send_mouse_input(&self, data: &[u8]) -> anyhow::Result<()> {
-        use tokio::io::AsyncWriteExt;
-        
-        // TODO: In production, validate HID report format
-        if data.len() < 4 {
-            return Err(anyhow::anyhow!("Mouse HID report must be at least 4 bytes"));
+    pub async fn send_mouse_input(&self, data: &[u8]) -> Result<(), HidError> {
+        if self.is_input_locked() {
+            return Err(HidError::InputLocked);
+        }
+
+        if data.len() < self.mouse_layout.report_len {
+            return Err(HidError::InvalidReport(format!("Mouse HID report must be at least {} bytes", self.mouse_layout.report_len)));
+        }
+        if let Some(result) = self.hold_while_off(|| QueuedReport::Mouse(data.to_vec())) {
+            return result;
         }
         
-        match tokio::fs::OpenOptions::new()
-            .write(true)
-            .open(&self.mouse_device)
-            .await
-        {
-            Ok(mut file) => {
-                file.write_all(data).await?;
-                file.flush().await?;
-                println!("Sent mouse input to {}: {} bytes", self.mouse_device, data.len());
+        match self.mouse_device.write(data).await {
+            Ok(()) => {
+                trace!("Sent mouse input to {}: {} bytes", self.mouse_device.path(), data.len());
             }
             Err(e) => {
-                eprintln!("Failed to open mouse device {}: {}", self.mouse_device, e);
-                return Err(e.into());
+                error!("Mouse device error: {}", e);
+                return Err(e);
             }
         }
         Ok(())
     }
+
+    /// Send touchscreen input to HID gadget device
+    pub async fn send_touch_input(&self, data: &[u8]) -> Result<(), HidError> {
+        let Some(ref touch_device) = self.touch_device else {
+            return Err(HidError::NoTouchscreen);
+        };
+
+        if self.is_input_locked() {
+            return Err(HidError::InputLocked);
+        }
+
+        if data.len() < self.touch_layout.report_len {
+            return Err(HidError::InvalidReport(format!("Touchscreen HID report must be at least {} bytes", self.touch_layout.report_len)));
+        }
+        if let Some(result) = self.hold_while_off(|| QueuedReport::Touch(data.to_vec())) {
+            return result;
+        }
+
+        match touch_device.write(data).await {
+            Ok(()) => {
+                trace!("Sent touchscreen input to {}: {} bytes", touch_device.path(), data.len());
+            }
+            Err(e) => {
+                error!("Touchscreen device error: {}", e);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hid_backend::MockHidBackend;
+
+    /// Manager with the default boot keyboard and relative mouse layouts
+    fn mock_manager() -> (HidManager, MockHidBackend) {
+        let keyboard = MockHidBackend::new("mock-keyboard");
+        let mouse = MockHidBackend::new("mock-mouse");
+        let manager = HidManager::from_backends(Arc::new(keyboard.clone()), Arc::new(mouse), None, None);
+        (manager, keyboard)
+    }
+
+    /// 9-byte keyboard reports behind report ID 1, not valid in boot protocol
+    const REPORT_ID_LAYOUT: KeyboardLayout = KeyboardLayout {
+        report_id: Some(1),
+        report_len: 9,
+        modifier_offset: Some(8),
+        keys_offset: 24,
+        key_size: 8,
+        key_count: 6,
+    };
+
+    #[tokio::test]
+    async fn key_events_build_boot_reports() {
+        let (manager, keyboard) = mock_manager();
+        manager.key_event(0xe1, true).await.unwrap(); // Left Shift
+        manager.key_event(0x04, true).await.unwrap(); // A
+        manager.key_event(0x04, false).await.unwrap();
+        manager.key_event(0xe1, false).await.unwrap();
+        assert_eq!(keyboard.take_reports(), [
+            vec![0x02, 0, 0, 0, 0, 0, 0, 0],
+            vec![0x02, 0, 0x04, 0, 0, 0, 0, 0],
+            vec![0x02, 0, 0, 0, 0, 0, 0, 0],
+            vec![0, 0, 0, 0, 0, 0, 0, 0],
+        ]);
+    }
+
+    #[tokio::test]
+    async fn blocked_combination_is_not_pressed() {
+        let (manager, keyboard) = mock_manager();
+        let manager = manager.with_blocked_keys(KeyBlocklist::parse(&["Ctrl+Alt+Delete".to_string()]).unwrap());
+        manager.key_event(0xe0, true).await.unwrap(); // Left Control
+        manager.key_event(0xe2, true).await.unwrap(); // Left Alt
+        manager.key_event(0x4c, true).await.unwrap(); // Delete
+        assert_eq!(keyboard.take_reports(), [
+            vec![0x01, 0, 0, 0, 0, 0, 0, 0],
+            vec![0x05, 0, 0, 0, 0, 0, 0, 0],
+        ]);
+
+        // Delete alone is still allowed
+        manager.release_all().await.unwrap();
+        keyboard.take_reports();
+        manager.key_event(0x4c, true).await.unwrap();
+        assert_eq!(keyboard.take_reports(), [vec![0, 0, 0x4c, 0, 0, 0, 0, 0]]);
+    }
+
+    #[tokio::test]
+    async fn blocked_keys_are_filtered_from_raw_reports() {
+        let (manager, keyboard) = mock_manager();
+        let manager = manager.with_blocked_keys(KeyBlocklist::parse(&["Ctrl+Alt+Delete".to_string()]).unwrap());
+        manager.send_keyboard_input(&[0x05, 0, 0x4c, 0x04, 0, 0, 0, 0]).await.unwrap();
+        manager.send_keyboard_input(&[0x01, 0, 0x4c, 0, 0, 0, 0, 0]).await.unwrap();
+        assert_eq!(keyboard.take_reports(), [
+            vec![0x05, 0, 0x04, 0, 0, 0, 0, 0],
+            vec![0x01, 0, 0x4c, 0, 0, 0, 0, 0],
+        ]);
+    }
+
+    #[tokio::test]
+    async fn num_lock_follows_key_presses_until_host_leds() {
+        let (manager, _keyboard) = mock_manager();
+        assert!(!manager.num_lock());
+        manager.key_event(keyboard::NUM_LOCK_USAGE, true).await.unwrap();
+        manager.key_event(keyboard::NUM_LOCK_USAGE, false).await.unwrap();
+        assert!(manager.num_lock());
+
+        // Once the host reports its LEDs, only those count
+        manager.host_leds_seen.store(true, Ordering::Relaxed);
+        manager.key_event(keyboard::NUM_LOCK_USAGE, true).await.unwrap();
+        assert!(manager.num_lock());
+    }
+
+    #[tokio::test]
+    async fn report_protocol_keeps_the_descriptor_layout() {
+        let (mut manager, keyboard) = mock_manager();
+        manager.keyboard_layout = REPORT_ID_LAYOUT;
+        manager.key_event(0x04, true).await.unwrap();
+        assert_eq!(keyboard.take_reports(), [vec![0x01, 0, 0, 0x04, 0, 0, 0, 0, 0]]);
+    }
+
+    #[tokio::test]
+    async fn boot_protocol_converts_reports() {
+        let (mut manager, keyboard) = mock_manager();
+        manager.keyboard_layout = REPORT_ID_LAYOUT;
+        let manager = manager.with_keyboard_protocol(KeyboardProtocol::Boot);
+        manager.key_event(0xe0, true).await.unwrap(); // Left Control
+        manager.key_event(0x06, true).await.unwrap(); // C
+        manager.send_keyboard_input(&[0x01, 0x02, 0, 0x05, 0, 0, 0, 0, 0]).await.unwrap();
+        assert_eq!(keyboard.take_reports(), [
+            vec![0x01, 0, 0, 0, 0, 0, 0, 0],
+            vec![0x01, 0, 0x06, 0, 0, 0, 0, 0],
+            vec![0x02, 0, 0x05, 0, 0, 0, 0, 0],
+        ]);
+    }
 }
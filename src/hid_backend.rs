@@ -0,0 +1,225 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// HID report output backends for kvm-rs
+
+use std::sync::Arc;
+use std::time::Duration;
+use futures_util::future::BoxFuture;
+use tokio::sync::Mutex;
+use tracing::info;
+use crate::hid::HidError;
+
+/// How long a report write may block before the host is considered gone
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Number of reports a mock backend keeps
+const MOCK_HISTORY: usize = 1024;
+
+/// Destination for the reports of one HID device
+pub trait HidBackend: Send + Sync {
+    /// Device path or name, used in log messages and for UDC lookup
+    fn path(&self) -> &str;
+
+    /// Write one report
+    fn write<'a>(&'a self, data: &'a [u8]) -> BoxFuture<'a, Result<(), HidError>>;
+
+    /// Drop any open handle so the next write starts fresh
+    fn close(&self) -> BoxFuture<'_, ()>;
+
+    /// Wait for the next output report from the host (e.g. keyboard LEDs)
+    fn read_output<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, Result<usize, HidError>>;
+}
+
+/// Gadget device of a console that a backend stands for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HidRole {
+    Keyboard,
+    Mouse,
+    Touchscreen,
+}
+
+impl HidRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HidRole::Keyboard => "keyboard",
+            HidRole::Mouse => "mouse",
+            HidRole::Touchscreen => "touchscreen",
+        }
+    }
+}
+
+/// Makes the backend of a console's gadget device from its role and path
+pub type HidBackendFactory = Arc<dyn Fn(HidRole, &str) -> Arc<dyn HidBackend> + Send + Sync>;
+
+/// Backends writing to the gadget devices
+pub fn gadget_backends() -> HidBackendFactory {
+    Arc::new(|_: HidRole, path: &str| -> Arc<dyn HidBackend> { Arc::new(GadgetDevice::new(path.to_string())) })
+}
+
+/// Backends logging the reports, named after the role they stand in for
+pub fn mock_backends() -> HidBackendFactory {
+    Arc::new(|role: HidRole, _: &str| -> Arc<dyn HidBackend> {
+        Arc::new(MockHidBackend::new(&format!("mock-{}", role.as_str())))
+    })
+}
+
+/// Persistent handle to a HID gadget device node, reopened after errors
+pub struct GadgetDevice {
+    path: String,
+    file: Mutex<Option<tokio::fs::File>>,
+    /// Separate read handle, a pending read must not block report writes
+    reader: Mutex<Option<tokio::fs::File>>,
+}
+
+impl GadgetDevice {
+    pub fn new(path: String) -> Self {
+        Self {
+            path,
+            file: Mutex::new(None),
+            reader: Mutex::new(None),
+        }
+    }
+
+    async fn read_report(&self, buf: &mut [u8]) -> Result<usize, HidError> {
+        use tokio::io::AsyncReadExt;
+
+        let mut reader = self.reader.lock().await;
+        if reader.is_none() {
+            let opened = tokio::fs::OpenOptions::new()
+                .read(true)
+                .open(&self.path)
+                .await
+                .map_err(|source| HidError::Open { path: self.path.clone(), source })?;
+            *reader = Some(opened);
+        }
+
+        let Some(handle) = reader.as_mut() else {
+            return Err(HidError::Closed(self.path.clone()));
+        };
+        match handle.read(buf).await {
+            Ok(0) => {
+                *reader = None;
+                Err(HidError::Closed(self.path.clone()))
+            }
+            Ok(n) => Ok(n),
+            Err(source) => {
+                *reader = None;
+                Err(HidError::Read { path: self.path.clone(), source })
+            }
+        }
+    }
+
+    /// Write one report, opening the device if needed and dropping the
+    /// handle on failure so the next write reopens it
+    async fn write_report(&self, data: &[u8]) -> Result<(), HidError> {
+        use tokio::io::AsyncWriteExt;
+
+        let mut file = self.file.lock().await;
+        if file.is_none() {
+            let opened = tokio::fs::OpenOptions::new()
+                .write(true)
+                .open(&self.path)
+                .await
+                .map_err(|source| HidError::Open { path: self.path.clone(), source })?;
+            *file = Some(opened);
+        }
+
+        let Some(handle) = file.as_mut() else {
+            return Err(HidError::Closed(self.path.clone()));
+        };
+        let result = tokio::time::timeout(WRITE_TIMEOUT, async {
+            handle.write_all(data).await?;
+            handle.flush().await
+        })
+        .await;
+
+        match result {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(source)) => {
+                // ESHUTDOWN/EPIPE etc. when the UDC is unbound or the host went away
+                *file = None;
+                Err(HidError::Write { path: self.path.clone(), source })
+            }
+            Err(_) => {
+                *file = None;
+                Err(HidError::Timeout(self.path.clone()))
+            }
+        }
+    }
+}
+
+impl HidBackend for GadgetDevice {
+    fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn write<'a>(&'a self, data: &'a [u8]) -> BoxFuture<'a, Result<(), HidError>> {
+        Box::pin(self.write_report(data))
+    }
+
+    fn close(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            *self.file.lock().await = None;
+        })
+    }
+
+    fn read_output<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, Result<usize, HidError>> {
+        Box::pin(self.read_report(buf))
+    }
+}
+
+/// In-memory backend that records every report instead of writing to a
+/// gadget, for running without /dev/hidg* and for exercising the input paths
+#[derive(Clone)]
+pub struct MockHidBackend {
+    name: String,
+    reports: Arc<std::sync::Mutex<Vec<Vec<u8>>>>,
+}
+
+impl MockHidBackend {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            reports: Arc::new(std::sync::Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Most recent reports written, oldest first
+    #[cfg(all(test, feature = "vnc"))]
+    pub fn reports(&self) -> Vec<Vec<u8>> {
+        self.reports.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Remove and return the recorded reports
+    #[cfg(test)]
+    pub fn take_reports(&self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut *self.reports.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+impl HidBackend for MockHidBackend {
+    fn path(&self) -> &str {
+        &self.name
+    }
+
+    fn write<'a>(&'a self, data: &'a [u8]) -> BoxFuture<'a, Result<(), HidError>> {
+        Box::pin(async move {
+            info!("Mock HID {}: {:02x?}", self.name, data);
+            let mut reports = self.reports.lock().unwrap_or_else(|e| e.into_inner());
+            if reports.len() >= MOCK_HISTORY {
+                reports.remove(0);
+            }
+            reports.push(data.to_vec());
+            Ok(())
+        })
+    }
+
+    fn close(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async {})
+    }
+
+    fn read_output<'a>(&'a self, _buf: &'a mut [u8]) -> BoxFuture<'a, Result<usize, HidError>> {
+        // There is no host to send output reports
+        Box::pin(std::future::pending())
+    }
+}
@@ -0,0 +1,624 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// HID report descriptor parsing for kvm-rs
+
+use std::collections::HashMap;
+use anyhow::{Context, Result};
+use tracing::info;
+use crate::gadget;
+use crate::keyboard;
+
+/// Highest key usage in the Keyboard/Keypad page below the modifier range
+const KEY_USAGE_MAX: u8 = 0xDD;
+
+/// How raw input reports from clients are checked before they are written
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportValidation {
+    /// Reject reports that are not exactly what the descriptor allows
+    Strict,
+    /// Rebuild reports from their decoded fields, dropping invalid usages and clamping values
+    Sanitize,
+    /// Only check the minimum length (for debugging clients)
+    Permissive,
+}
+
+/// A single input field located inside a HID report
+#[derive(Debug, Clone, Copy)]
+pub struct ReportField {
+    /// Bit offset from the start of the report (including the report ID byte)
+    pub bit_offset: usize,
+    pub bit_size: usize,
+    pub logical_min: i32,
+    pub logical_max: i32,
+}
+
+impl ReportField {
+    /// Clamp a value to the logical range and store it in the report
+    pub fn write(&self, report: &mut [u8], value: i32) {
+        let value = value.clamp(self.logical_min, self.logical_max);
+        write_bits(report, self.bit_offset, self.bit_size, value as u32);
+    }
+
+    /// Read the field from a report, sign-extended if the logical range is signed
+    pub fn read(&self, report: &[u8]) -> i32 {
+        let value = read_bits(report, self.bit_offset, self.bit_size);
+        if self.logical_min < 0 && self.bit_size > 0 && self.bit_size < 32 {
+            let shift = 32 - self.bit_size as u32;
+            ((value << shift) as i32) >> shift
+        } else {
+            value as i32
+        }
+    }
+
+    /// Map a position within `0..extent` onto the logical range (absolute axes)
+    pub fn scale(&self, position: u16, extent: u16) -> i32 {
+        if extent <= 1 {
+            return self.logical_min;
+        }
+        let span = (self.logical_max - self.logical_min) as i64;
+        let position = (position as i64).min(extent as i64 - 1);
+        self.logical_min + (position * span / (extent as i64 - 1)) as i32
+    }
+}
+
+/// Keyboard input report layout
+#[derive(Debug, Clone)]
+pub struct KeyboardLayout {
+    pub report_id: Option<u8>,
+    /// Total report length in bytes, including the report ID
+    pub report_len: usize,
+    /// Bit offset of the eight modifier bits (LeftControl..RightGUI)
+    pub modifier_offset: Option<usize>,
+    /// Bit offset of the key usage array
+    pub keys_offset: usize,
+    pub key_size: usize,
+    pub key_count: usize,
+}
+
+impl Default for KeyboardLayout {
+    fn default() -> Self {
+        Self::BOOT
+    }
+}
+
+impl KeyboardLayout {
+    /// Standard 8-byte boot protocol keyboard
+    pub const BOOT: KeyboardLayout = KeyboardLayout {
+        report_id: None,
+        report_len: 8,
+        modifier_offset: Some(0),
+        keys_offset: 16,
+        key_size: 8,
+        key_count: 6,
+    };
+
+    /// Whether reports of this layout are also valid boot protocol reports
+    pub fn is_boot_compatible(&self) -> bool {
+        self.report_id.is_none()
+            && self.report_len == Self::BOOT.report_len
+            && self.modifier_offset == Self::BOOT.modifier_offset
+            && self.keys_offset == Self::BOOT.keys_offset
+            && self.key_size == Self::BOOT.key_size
+    }
+
+    /// Build an input report with the given modifier bits and pressed key usages
+    pub fn report(&self, modifiers: u8, keys: &[u8]) -> Vec<u8> {
+        let mut report = vec![0u8; self.report_len];
+        if let Some(id) = self.report_id {
+            report[0] = id;
+        }
+        if let Some(offset) = self.modifier_offset {
+            write_bits(&mut report, offset, 8, modifiers as u32);
+        }
+        for (i, key) in keys.iter().take(self.key_count).enumerate() {
+            write_bits(&mut report, self.keys_offset + i * self.key_size, self.key_size, *key as u32);
+        }
+        report
+    }
+
+    /// Modifier bits and pressed key usages contained in a report
+    pub fn decode(&self, report: &[u8]) -> (u8, Vec<u8>) {
+        let modifiers = self.modifier_offset
+            .map(|offset| read_bits(report, offset, 8) as u8)
+            .unwrap_or(0);
+        let keys = (0..self.key_count)
+            .map(|i| read_bits(report, self.keys_offset + i * self.key_size, self.key_size) as u8)
+            // 0 is "no key", 1-3 are rollover/error codes
+            .filter(|usage| *usage > 3)
+            .collect();
+        (modifiers, keys)
+    }
+
+    /// Rebuild a report from its modifiers and valid, distinct key usages;
+    /// modifier usages in the key array are moved to the modifier bits
+    pub fn sanitize(&self, report: &[u8]) -> Vec<u8> {
+        let (mut modifiers, decoded) = self.decode(report);
+        let mut keys = Vec::with_capacity(decoded.len());
+        for usage in decoded {
+            if keyboard::is_modifier(usage) {
+                modifiers |= 1 << (usage - 0xE0);
+            } else if usage <= KEY_USAGE_MAX && !keys.contains(&usage) {
+                keys.push(usage);
+            }
+        }
+        self.report(modifiers, &keys)
+    }
+}
+
+/// Mouse (relative or absolute pointer) input report layout
+#[derive(Debug, Clone)]
+pub struct MouseLayout {
+    pub report_id: Option<u8>,
+    /// Total report length in bytes, including the report ID
+    pub report_len: usize,
+    pub buttons_offset: usize,
+    pub button_count: usize,
+    pub x: ReportField,
+    pub y: ReportField,
+    pub wheel: Option<ReportField>,
+    /// True for tablet-style absolute positioning, false for relative motion
+    pub absolute: bool,
+}
+
+impl Default for MouseLayout {
+    /// 4-byte relative mouse: buttons, dx, dy, wheel
+    fn default() -> Self {
+        let axis = |bit_offset| ReportField {
+            bit_offset,
+            bit_size: 8,
+            logical_min: -127,
+            logical_max: 127,
+        };
+        Self {
+            report_id: None,
+            report_len: 4,
+            buttons_offset: 0,
+            button_count: 3,
+            x: axis(8),
+            y: axis(16),
+            wheel: Some(axis(24)),
+            absolute: false,
+        }
+    }
+}
+
+impl MouseLayout {
+    /// Build an input report; `x`/`y` are deltas for relative layouts and
+    /// logical coordinates for absolute ones
+    pub fn report(&self, buttons: u8, x: i32, y: i32, wheel: i32) -> Vec<u8> {
+        let mut report = vec![0u8; self.report_len];
+        if let Some(id) = self.report_id {
+            report[0] = id;
+        }
+        for i in 0..self.button_count.min(8) {
+            write_bits(&mut report, self.buttons_offset + i, 1, ((buttons >> i) & 1) as u32);
+        }
+        self.x.write(&mut report, x);
+        self.y.write(&mut report, y);
+        if let Some(ref field) = self.wheel {
+            field.write(&mut report, wheel);
+        }
+        report
+    }
+
+    /// Rebuild a report from its buttons and clamped axis values
+    pub fn sanitize(&self, report: &[u8]) -> Vec<u8> {
+        let mut buttons = 0u8;
+        for i in 0..self.button_count.min(8) {
+            buttons |= (read_bits(report, self.buttons_offset + i, 1) as u8) << i;
+        }
+        let wheel = self.wheel.map(|field| field.read(report)).unwrap_or(0);
+        self.report(buttons, self.x.read(report), self.y.read(report), wheel)
+    }
+}
+
+/// Single-touch touchscreen (digitizer) input report layout
+#[derive(Debug, Clone)]
+pub struct TouchLayout {
+    pub report_id: Option<u8>,
+    /// Total report length in bytes, including the report ID
+    pub report_len: usize,
+    pub tip_switch_offset: usize,
+    pub in_range_offset: Option<usize>,
+    pub contact_id: Option<ReportField>,
+    pub contact_count: Option<ReportField>,
+    pub x: ReportField,
+    pub y: ReportField,
+}
+
+impl Default for TouchLayout {
+    /// 5-byte single-touch report: tip switch/in range bits, 16-bit X and Y
+    fn default() -> Self {
+        let axis = |bit_offset| ReportField {
+            bit_offset,
+            bit_size: 16,
+            logical_min: 0,
+            logical_max: 32767,
+        };
+        Self {
+            report_id: None,
+            report_len: 5,
+            tip_switch_offset: 0,
+            in_range_offset: Some(1),
+            contact_id: None,
+            contact_count: None,
+            x: axis(8),
+            y: axis(24),
+        }
+    }
+}
+
+impl TouchLayout {
+    /// Build an input report for a single contact at logical coordinates `x`/`y`
+    pub fn report(&self, touching: bool, x: i32, y: i32) -> Vec<u8> {
+        let mut report = vec![0u8; self.report_len];
+        if let Some(id) = self.report_id {
+            report[0] = id;
+        }
+        write_bits(&mut report, self.tip_switch_offset, 1, touching as u32);
+        if let Some(offset) = self.in_range_offset {
+            write_bits(&mut report, offset, 1, 1);
+        }
+        if let Some(ref field) = self.contact_id {
+            field.write(&mut report, 0);
+        }
+        // Hosts expect the contact to be counted on the lift-off report too
+        if let Some(ref field) = self.contact_count {
+            field.write(&mut report, 1);
+        }
+        self.x.write(&mut report, x);
+        self.y.write(&mut report, y);
+        report
+    }
+
+    /// Rebuild a report from its tip switch, in-range bit and clamped coordinates
+    pub fn sanitize(&self, report: &[u8]) -> Vec<u8> {
+        let touching = read_bits(report, self.tip_switch_offset, 1) != 0;
+        let mut sanitized = self.report(touching, self.x.read(report), self.y.read(report));
+        if let Some(offset) = self.in_range_offset {
+            write_bits(&mut sanitized, offset, 1, read_bits(report, offset, 1));
+        }
+        sanitized
+    }
+}
+
+/// Layouts found in a parsed report descriptor
+#[derive(Debug, Default)]
+pub struct ParsedDescriptor {
+    pub keyboard: Option<KeyboardLayout>,
+    pub mouse: Option<MouseLayout>,
+    pub touch: Option<TouchLayout>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct GlobalState {
+    usage_page: u32,
+    logical_min: i32,
+    logical_max: i32,
+    report_size: usize,
+    report_count: usize,
+    report_id: Option<u8>,
+}
+
+#[derive(Debug, Default)]
+struct LocalState {
+    usages: Vec<u32>,
+    usage_min: Option<u32>,
+    usage_max: Option<u32>,
+}
+
+impl LocalState {
+    /// Usage assigned to the n-th field of the current main item
+    fn usage(&self, index: usize) -> Option<u32> {
+        if let Some(usage) = self.usages.get(index).or(self.usages.last()) {
+            return Some(*usage);
+        }
+        match (self.usage_min, self.usage_max) {
+            (Some(min), Some(max)) => Some((min + index as u32).min(max)),
+            _ => None,
+        }
+    }
+
+    fn covers(&self, usage: u32) -> bool {
+        self.usages.contains(&usage)
+            || matches!((self.usage_min, self.usage_max), (Some(min), Some(max)) if (min..=max).contains(&usage))
+    }
+}
+
+#[derive(Debug, Default)]
+struct KeyboardFields {
+    report_id: Option<u8>,
+    modifier_offset: Option<usize>,
+    keys: Option<(usize, usize, usize)>,
+}
+
+#[derive(Debug, Default)]
+struct MouseFields {
+    report_id: Option<u8>,
+    buttons: Option<(usize, usize)>,
+    x: Option<ReportField>,
+    y: Option<ReportField>,
+    wheel: Option<ReportField>,
+    absolute: bool,
+}
+
+#[derive(Debug, Default)]
+struct TouchFields {
+    report_id: Option<u8>,
+    tip_switch: Option<usize>,
+    in_range: Option<usize>,
+    contact_id: Option<ReportField>,
+    contact_count: Option<ReportField>,
+    x: Option<ReportField>,
+    y: Option<ReportField>,
+}
+
+#[derive(Debug, Default)]
+struct Fields {
+    keyboard: KeyboardFields,
+    mouse: MouseFields,
+    touch: TouchFields,
+}
+
+const GENERIC_DESKTOP_PAGE: u32 = 0x01;
+const KEYBOARD_PAGE: u32 = 0x07;
+const BUTTON_PAGE: u32 = 0x09;
+const DIGITIZER_PAGE: u32 = 0x0D;
+
+/// Parse a raw HID report descriptor into keyboard and mouse layouts
+pub fn parse(descriptor: &[u8]) -> Result<ParsedDescriptor> {
+    let mut global = GlobalState::default();
+    let mut global_stack: Vec<GlobalState> = Vec::new();
+    let mut local = LocalState::default();
+    // Input bit offset per report ID
+    let mut offsets: HashMap<Option<u8>, usize> = HashMap::new();
+    let mut fields = Fields::default();
+    // Usage of the enclosing application collection
+    let mut application = 0u32;
+
+    let mut pos = 0;
+    while pos < descriptor.len() {
+        let prefix = descriptor[pos];
+
+        // Long items carry no information we need
+        if prefix == 0xFE {
+            let size = *descriptor.get(pos + 1)
+                .ok_or_else(|| anyhow::anyhow!("Truncated long item at offset {}", pos))? as usize;
+            pos += 3 + size;
+            continue;
+        }
+
+        let size = match prefix & 0x03 {
+            3 => 4,
+            n => n as usize,
+        };
+        let data = descriptor.get(pos + 1..pos + 1 + size)
+            .ok_or_else(|| anyhow::anyhow!("Truncated report descriptor item at offset {}", pos))?;
+        let unsigned = data.iter().rev().fold(0u32, |acc, b| (acc << 8) | *b as u32);
+        let signed = match size {
+            1 => data[0] as i8 as i32,
+            2 => i16::from_le_bytes([data[0], data[1]]) as i32,
+            4 => unsigned as i32,
+            _ => 0,
+        };
+
+        let item_type = (prefix >> 2) & 0x03;
+        let tag = prefix >> 4;
+
+        match (item_type, tag) {
+            // Main: Input
+            (0, 0x8) => {
+                let constant = unsigned & 0x01 != 0;
+
+                let offset = offsets.entry(global.report_id).or_insert(0);
+                let start = *offset + if global.report_id.is_some() { 8 } else { 0 };
+                *offset += global.report_size * global.report_count;
+
+                if !constant {
+                    classify_input(&global, &local, application, start, unsigned, &mut fields);
+                }
+                local = LocalState::default();
+            }
+            // Main: Collection
+            (0, 0xA) => {
+                if unsigned == 0x01 {
+                    application = local.usage(0).unwrap_or(0);
+                }
+                local = LocalState::default();
+            }
+            // Main: Output, Feature, End Collection
+            (0, _) => local = LocalState::default(),
+            // Global items
+            (1, 0x0) => global.usage_page = unsigned,
+            (1, 0x1) => global.logical_min = signed,
+            (1, 0x2) => {
+                // Descriptors commonly encode e.g. 255 as a one-byte maximum
+                global.logical_max = if signed < global.logical_min { unsigned as i32 } else { signed };
+            }
+            (1, 0x7) => global.report_size = unsigned as usize,
+            (1, 0x8) => global.report_id = Some(unsigned as u8),
+            (1, 0x9) => global.report_count = unsigned as usize,
+            (1, 0xA) => global_stack.push(global.clone()),
+            (1, 0xB) => {
+                global = global_stack.pop()
+                    .ok_or_else(|| anyhow::anyhow!("Pop without matching Push at offset {}", pos))?;
+            }
+            // Local items
+            (2, 0x0) => local.usages.push(full_usage(&global, size, unsigned)),
+            (2, 0x1) => local.usage_min = Some(full_usage(&global, size, unsigned)),
+            (2, 0x2) => local.usage_max = Some(full_usage(&global, size, unsigned)),
+            _ => {}
+        }
+
+        pos += 1 + size;
+    }
+
+    let report_len = |report_id: Option<u8>| {
+        let bits = offsets.get(&report_id).copied().unwrap_or(0);
+        bits.div_ceil(8) + if report_id.is_some() { 1 } else { 0 }
+    };
+
+    let Fields { keyboard, mouse, touch } = fields;
+
+    let keyboard = keyboard.keys.map(|(keys_offset, key_size, key_count)| KeyboardLayout {
+        report_id: keyboard.report_id,
+        report_len: report_len(keyboard.report_id),
+        modifier_offset: keyboard.modifier_offset,
+        keys_offset,
+        key_size,
+        key_count,
+    });
+
+    let mouse = match (mouse.x, mouse.y) {
+        (Some(x), Some(y)) => {
+            let (buttons_offset, button_count) = mouse.buttons.unwrap_or((0, 0));
+            Some(MouseLayout {
+                report_id: mouse.report_id,
+                report_len: report_len(mouse.report_id),
+                buttons_offset,
+                button_count,
+                x,
+                y,
+                wheel: mouse.wheel,
+                absolute: mouse.absolute,
+            })
+        }
+        _ => None,
+    };
+
+    let touch = match (touch.tip_switch, touch.x, touch.y) {
+        (Some(tip_switch_offset), Some(x), Some(y)) => Some(TouchLayout {
+            report_id: touch.report_id,
+            report_len: report_len(touch.report_id),
+            tip_switch_offset,
+            in_range_offset: touch.in_range,
+            contact_id: touch.contact_id,
+            contact_count: touch.contact_count,
+            x,
+            y,
+        }),
+        _ => None,
+    };
+
+    Ok(ParsedDescriptor { keyboard, mouse, touch })
+}
+
+/// Extended (4-byte) usages already carry their page
+fn full_usage(global: &GlobalState, size: usize, value: u32) -> u32 {
+    if size == 4 {
+        value
+    } else {
+        (global.usage_page << 16) | value
+    }
+}
+
+fn classify_input(
+    global: &GlobalState,
+    local: &LocalState,
+    application: u32,
+    start: usize,
+    flags: u32,
+    fields: &mut Fields,
+) {
+    let variable = flags & 0x02 != 0;
+    let relative = flags & 0x04 != 0;
+    let in_digitizer = application >> 16 == DIGITIZER_PAGE;
+
+    let keyboard = &mut fields.keyboard;
+    let mouse = &mut fields.mouse;
+    let touch = &mut fields.touch;
+
+    match global.usage_page {
+        KEYBOARD_PAGE if variable && global.report_size == 1 && local.covers((KEYBOARD_PAGE << 16) | 0xE0) => {
+            keyboard.report_id = global.report_id;
+            keyboard.modifier_offset = Some(start);
+        }
+        KEYBOARD_PAGE if !variable && keyboard.keys.is_none() => {
+            keyboard.report_id = global.report_id;
+            keyboard.keys = Some((start, global.report_size, global.report_count));
+        }
+        BUTTON_PAGE if variable && !in_digitizer && mouse.buttons.is_none() => {
+            mouse.buttons = Some((start, global.report_count));
+        }
+        _ if variable => {
+            for index in 0..global.report_count {
+                let Some(usage) = local.usage(index) else { break };
+                let bit_offset = start + index * global.report_size;
+                let field = ReportField {
+                    bit_offset,
+                    bit_size: global.report_size,
+                    logical_min: global.logical_min,
+                    logical_max: global.logical_max,
+                };
+                match (usage >> 16, usage & 0xFFFF, in_digitizer) {
+                    (GENERIC_DESKTOP_PAGE, 0x30, false) => {
+                        mouse.report_id = global.report_id;
+                        mouse.absolute = !relative;
+                        mouse.x = Some(field);
+                    }
+                    (GENERIC_DESKTOP_PAGE, 0x31, false) => mouse.y = Some(field),
+                    (GENERIC_DESKTOP_PAGE, 0x38, false) => mouse.wheel = Some(field),
+                    // Only the first contact of a multi-touch digitizer is used
+                    (GENERIC_DESKTOP_PAGE, 0x30, true) if touch.x.is_none() => touch.x = Some(field),
+                    (GENERIC_DESKTOP_PAGE, 0x31, true) if touch.y.is_none() => touch.y = Some(field),
+                    (DIGITIZER_PAGE, 0x42, _) if touch.tip_switch.is_none() => {
+                        touch.report_id = global.report_id;
+                        touch.tip_switch = Some(bit_offset);
+                    }
+                    (DIGITIZER_PAGE, 0x32, _) if touch.in_range.is_none() => touch.in_range = Some(bit_offset),
+                    (DIGITIZER_PAGE, 0x51, _) if touch.contact_id.is_none() => touch.contact_id = Some(field),
+                    (DIGITIZER_PAGE, 0x54, _) => touch.contact_count = Some(field),
+                    _ => {}
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Load the report descriptor for `device`, either from an explicit file or
+/// from the configfs HID function that owns the device node
+pub fn load_descriptor(device: &str, descriptor_path: Option<&str>) -> Result<Option<Vec<u8>>> {
+    if let Some(path) = descriptor_path {
+        let data = std::fs::read(path)
+            .with_context(|| format!("Failed to read report descriptor file: {}", path))?;
+        return Ok(Some(data));
+    }
+
+    match gadget::find_hid_function(device) {
+        Some(function) => {
+            info!("Found report descriptor for {} in {}", device, function.display());
+            let data = std::fs::read(function.join("report_desc"))
+                .with_context(|| format!("Failed to read {}/report_desc", function.display()))?;
+            Ok(Some(data))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Read `bit_size` bits at `bit_offset` (LSB first); bits past the end read as 0
+fn read_bits(report: &[u8], bit_offset: usize, bit_size: usize) -> u32 {
+    let mut value = 0u32;
+    for i in 0..bit_size.min(32) {
+        let bit = bit_offset + i;
+        if let Some(byte) = report.get(bit / 8) {
+            if byte & (1 << (bit % 8)) != 0 {
+                value |= 1 << i;
+            }
+        }
+    }
+    value
+}
+
+/// Write the low `bit_size` bits of `value` at `bit_offset` (LSB first, as HID does)
+fn write_bits(report: &mut [u8], bit_offset: usize, bit_size: usize, value: u32) {
+    for i in 0..bit_size.min(32) {
+        let bit = bit_offset + i;
+        let Some(byte) = report.get_mut(bit / 8) else { break };
+        if (value >> i) & 1 != 0 {
+            *byte |= 1 << (bit % 8);
+        } else {
+            *byte &= !(1 << (bit % 8));
+        }
+    }
+}
@@ -0,0 +1,84 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Per-device HID write statistics for kvm-rs
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use serde::Serialize;
+use crate::hid::HidError;
+
+/// Counters for the reports written to one HID device
+#[derive(Default)]
+pub struct HidStats {
+    reports: AtomicU64,
+    bytes: AtomicU64,
+    errors: AtomicU64,
+    retries: AtomicU64,
+    /// Whether the last write failed, so the next one is a retry
+    failing: AtomicBool,
+    /// Whether the last error needs the device fixed rather than a retry
+    fatal: AtomicBool,
+    last_error: Mutex<Option<(SystemTime, String)>>,
+}
+
+/// Point-in-time copy of a device's counters
+#[derive(Debug, Clone, Serialize)]
+pub struct HidStatsSnapshot {
+    pub device: String,
+    /// Reports written successfully
+    pub reports: u64,
+    /// Bytes written successfully
+    pub bytes: u64,
+    /// Failed writes, including timeouts
+    pub errors: u64,
+    /// Writes attempted after a failed one (the device is reopened for these)
+    pub retries: u64,
+    /// Whether the most recent write failed
+    pub failing: bool,
+    /// Whether the most recent write failed in a way retrying cannot fix,
+    /// e.g. the device node is missing
+    pub fatal: bool,
+    pub last_error: Option<String>,
+    /// RFC 3339 time of the last error
+    pub last_error_at: Option<String>,
+}
+
+impl HidStats {
+    /// Note a write attempt; counted as a retry if the previous write failed
+    pub fn attempt(&self) {
+        if self.failing.load(Ordering::Relaxed) {
+            self.retries.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn success(&self, len: usize) {
+        self.reports.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(len as u64, Ordering::Relaxed);
+        self.failing.store(false, Ordering::Relaxed);
+        self.fatal.store(false, Ordering::Relaxed);
+    }
+
+    pub fn failure(&self, error: &HidError) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        self.failing.store(true, Ordering::Relaxed);
+        self.fatal.store(error.is_fatal(), Ordering::Relaxed);
+        *self.last_error.lock().unwrap_or_else(|e| e.into_inner()) = Some((SystemTime::now(), error.to_string()));
+    }
+
+    pub fn snapshot(&self, device: &str) -> HidStatsSnapshot {
+        let last_error = self.last_error.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        HidStatsSnapshot {
+            device: device.to_string(),
+            reports: self.reports.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            failing: self.failing.load(Ordering::Relaxed),
+            fatal: self.fatal.load(Ordering::Relaxed),
+            last_error_at: last_error.as_ref()
+                .map(|(at, _)| humantime::format_rfc3339_seconds(*at).to_string()),
+            last_error: last_error.map(|(_, message)| message),
+        }
+    }
+}
@@ -0,0 +1,338 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Channel to an agent in the host OS over a USB serial port for kvm-rs
+
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::Result;
+#[cfg(feature = "web")]
+use axum::{extract::State, routing::get, Json, Router};
+use serde::Serialize;
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
+use crate::clipboard::{Clipboard, MAX_CLIPBOARD_TEXT};
+use crate::events::{Event, EventBus};
+
+/// Start of every frame, so a reader joining mid-frame finds the next one
+const MAGIC: [u8; 2] = *b"KA";
+/// Magic, type and big-endian payload length
+const HEADER_LEN: usize = 7;
+const PROTOCOL_VERSION: u8 = 1;
+/// How often a connected agent is pinged
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+/// How long an agent may send nothing, pongs included, before it counts as gone
+const AGENT_TIMEOUT: Duration = Duration::from_secs(15);
+/// How long a frame may take to be written before the agent counts as gone;
+/// the host buffers little while nothing reads the port
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Clipboard source of text copied in the host OS, which no session has
+pub const AGENT_SOURCE: u64 = u64::MAX;
+
+/// Frame types
+const HELLO: u8 = 0x01;
+const PING: u8 = 0x02;
+const PONG: u8 = 0x03;
+const RESOLUTION: u8 = 0x10;
+const FOCUS: u8 = 0x11;
+const CLIPBOARD: u8 = 0x20;
+
+/// What the agent in the host OS reported
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AgentStatus {
+    pub connected: bool,
+    /// Name and version the agent gave, e.g. "kvm-agent 1.2 (Windows 11)"
+    pub agent: Option<String>,
+    /// Resolution of the host's screen, which capture may see scaled
+    pub width: Option<u16>,
+    pub height: Option<u16>,
+    /// Whether a desktop session has keyboard focus, false e.g. on a lock screen
+    pub focused: Option<bool>,
+    /// Title of the window with keyboard focus
+    pub window: Option<String>,
+}
+
+/// The agent in the host OS, whose messages keep `AgentStatus` up to date and
+/// whose clipboard is shared with the console clients
+#[derive(Clone)]
+pub struct HostAgent {
+    status: Arc<watch::Sender<AgentStatus>>,
+    clipboard: Clipboard,
+    events: EventBus,
+}
+
+impl HostAgent {
+    pub fn new(clipboard: Clipboard, events: EventBus) -> Self {
+        Self {
+            status: Arc::new(watch::channel(AgentStatus::default()).0),
+            clipboard,
+            events,
+        }
+    }
+
+    pub fn status(&self) -> AgentStatus {
+        self.status.borrow().clone()
+    }
+
+    /// Talk to agents over the serial port `tty`, e.g. /dev/ttyGS0, until it fails
+    #[cfg(target_os = "linux")]
+    pub async fn serve(&self, tty: &Path) -> Result<()> {
+        let port = SerialPort::open(tty)?;
+        info!("Host agent channel on {}", tty.display());
+        let served = self.converse(&port).await;
+        self.disconnected();
+        served
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub async fn serve(&self, tty: &Path) -> Result<()> {
+        anyhow::bail!("Host agent channel on {} needs Linux", tty.display())
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn converse(&self, port: &SerialPort) -> Result<()> {
+        use anyhow::Context;
+        use tokio::time::Instant;
+
+        let mut frames = FrameReader::default();
+        let mut clipboard = self.clipboard.subscribe();
+        clipboard.borrow_and_update();
+        let mut pings = tokio::time::interval(PING_INTERVAL);
+        let mut buf = vec![0u8; 4096];
+        // When the connected agent was last heard of
+        let mut last_seen: Option<Instant> = None;
+        loop {
+            // Frames for the agent; nothing is sent before it said hello
+            let mut replies: Vec<Vec<u8>> = Vec::new();
+            tokio::select! {
+                read = port.read(&mut buf) => {
+                    let n = read.context("Failed to read from the host agent")?;
+                    if n == 0 {
+                        anyhow::bail!("Host agent port hung up");
+                    }
+                    frames.push(&buf[..n]);
+                    while let Some((kind, payload)) = frames.next_frame() {
+                        if kind == HELLO {
+                            replies.push(encode(HELLO, &hello_payload()));
+                            let text = self.clipboard.text();
+                            if !text.is_empty() {
+                                replies.push(encode(CLIPBOARD, text.as_bytes()));
+                            }
+                        }
+                        if kind == HELLO || last_seen.is_some() {
+                            last_seen = Some(Instant::now());
+                            self.handle(kind, &payload);
+                        }
+                    }
+                }
+                changed = clipboard.changed() => {
+                    changed?;
+                    let copied = clipboard.borrow_and_update().clone();
+                    if last_seen.is_some() && copied.source != AGENT_SOURCE {
+                        replies.push(encode(CLIPBOARD, copied.text.as_bytes()));
+                    }
+                }
+                _ = pings.tick() => match last_seen {
+                    Some(seen) if seen.elapsed() > AGENT_TIMEOUT => {
+                        warn!("Host agent stopped answering");
+                        self.disconnected();
+                        last_seen = None;
+                    }
+                    Some(_) => replies.push(encode(PING, &[])),
+                    None => {}
+                },
+            }
+            for frame in replies {
+                if last_seen.is_none() {
+                    break;
+                }
+                let written = tokio::time::timeout(WRITE_TIMEOUT, port.write_all(&frame)).await;
+                if !matches!(written, Ok(Ok(()))) {
+                    warn!("Host agent stopped reading");
+                    self.disconnected();
+                    last_seen = None;
+                }
+            }
+        }
+    }
+
+    /// Apply a message of the agent
+    fn handle(&self, kind: u8, payload: &[u8]) {
+        match (kind, payload) {
+            (HELLO, payload) => {
+                let (version, agent) = payload.split_first().unwrap_or((&0, &[]));
+                let agent = String::from_utf8_lossy(agent).into_owned();
+                info!("Host agent {} connected (protocol {})", agent, version);
+                self.status.send_modify(|status| {
+                    *status = AgentStatus {
+                        connected: true,
+                        agent: Some(agent.clone()),
+                        ..AgentStatus::default()
+                    }
+                });
+                self.events.publish(Event::HostAgentConnected { agent });
+            }
+            (RESOLUTION, [w0, w1, h0, h1]) => {
+                let (width, height) = (u16::from_be_bytes([*w0, *w1]), u16::from_be_bytes([*h0, *h1]));
+                let changed = self.status.send_if_modified(|status| {
+                    let changed = status.width != Some(width) || status.height != Some(height);
+                    (status.width, status.height) = (Some(width), Some(height));
+                    changed
+                });
+                if changed {
+                    info!("Host screen resolution {}x{}", width, height);
+                    self.events.publish(Event::HostResolution { width, height });
+                }
+            }
+            (FOCUS, [focused, window @ ..]) => {
+                let focused = *focused != 0;
+                let window = String::from_utf8_lossy(window).into_owned();
+                self.status.send_modify(|status| {
+                    status.focused = Some(focused);
+                    status.window = Some(window.clone());
+                });
+                self.events.publish(Event::HostFocus { focused, window });
+            }
+            (CLIPBOARD, text) => match std::str::from_utf8(text) {
+                Ok(text) => self.clipboard.set(text, AGENT_SOURCE),
+                Err(_) => debug!("Dropped host clipboard text that is not UTF-8"),
+            },
+            // Keeps the agent alive, which any frame does
+            (PONG, _) => {}
+            (kind, payload) => debug!("Ignored host agent frame 0x{:02x} of {} bytes", kind, payload.len()),
+        }
+    }
+
+    fn disconnected(&self) {
+        let was_connected = self.status.send_if_modified(|status| {
+            let was_connected = status.connected;
+            *status = AgentStatus::default();
+            was_connected
+        });
+        if was_connected {
+            info!("Host agent disconnected");
+            self.events.publish(Event::HostAgentDisconnected);
+        }
+    }
+}
+
+/// Protocol version and name of kvm-rs, answering the agent's hello
+fn hello_payload() -> Vec<u8> {
+    let mut payload = vec![PROTOCOL_VERSION];
+    payload.extend_from_slice(concat!("kvm-rs ", env!("CARGO_PKG_VERSION")).as_bytes());
+    payload
+}
+
+fn encode(kind: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&MAGIC);
+    frame.push(kind);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Splits the byte stream from the agent into frames, skipping bytes up to the
+/// next magic where a frame is cut off or invalid
+#[derive(Default)]
+struct FrameReader {
+    buffer: Vec<u8>,
+}
+
+impl FrameReader {
+    fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// The next complete frame, as type and payload
+    fn next_frame(&mut self) -> Option<(u8, Vec<u8>)> {
+        loop {
+            let start = self.buffer.windows(MAGIC.len()).position(|window| window == MAGIC);
+            // Keep a last byte that may begin the magic
+            let start = start.unwrap_or(self.buffer.len().saturating_sub(1));
+            self.buffer.drain(..start);
+            if self.buffer.len() < HEADER_LEN {
+                return None;
+            }
+            let len = u32::from_be_bytes([self.buffer[3], self.buffer[4], self.buffer[5], self.buffer[6]]) as usize;
+            if len > MAX_CLIPBOARD_TEXT {
+                self.buffer.drain(..MAGIC.len());
+                continue;
+            }
+            if self.buffer.len() < HEADER_LEN + len {
+                return None;
+            }
+            let kind = self.buffer[2];
+            let payload = self.buffer[HEADER_LEN..HEADER_LEN + len].to_vec();
+            self.buffer.drain(..HEADER_LEN + len);
+            return Some((kind, payload));
+        }
+    }
+}
+
+/// A tty in raw mode, read and written without blocking a thread, so writes
+/// nobody reads can time out
+#[cfg(target_os = "linux")]
+struct SerialPort {
+    fd: tokio::io::unix::AsyncFd<std::fs::File>,
+}
+
+#[cfg(target_os = "linux")]
+impl SerialPort {
+    fn open(path: &Path) -> Result<Self> {
+        use std::os::unix::fs::OpenOptionsExt;
+        use anyhow::Context;
+        use nix::sys::termios;
+
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(nix::libc::O_NONBLOCK | nix::libc::O_NOCTTY)
+            .open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        // Bytes as they are: no echo, line editing or newline translation
+        let mut attrs = termios::tcgetattr(&file).with_context(|| format!("{} is no tty", path.display()))?;
+        termios::cfmakeraw(&mut attrs);
+        termios::tcsetattr(&file, termios::SetArg::TCSANOW, &attrs)?;
+        Ok(Self {
+            fd: tokio::io::unix::AsyncFd::new(file)?,
+        })
+    }
+
+    async fn read(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        use std::io::Read;
+
+        loop {
+            let mut guard = self.fd.readable().await?;
+            if let Ok(read) = guard.try_io(|file| file.get_ref().read(buf)) {
+                return read;
+            }
+        }
+    }
+
+    async fn write_all(&self, mut data: &[u8]) -> std::io::Result<()> {
+        use std::io::Write;
+
+        while !data.is_empty() {
+            let mut guard = self.fd.writable().await?;
+            if let Ok(written) = guard.try_io(|file| file.get_ref().write(data)) {
+                data = &data[written?..];
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Routes of the host agent under /api/v1/host-agent
+#[cfg(feature = "web")]
+pub fn router(agent: HostAgent) -> Router {
+    Router::new()
+        .route("/api/v1/host-agent", get(get_agent))
+        .with_state(agent)
+}
+
+/// GET /api/v1/host-agent - whether an agent is connected and what it reported
+#[cfg(feature = "web")]
+async fn get_agent(State(agent): State<HostAgent>) -> Json<AgentStatus> {
+    Json(agent.status())
+}
@@ -0,0 +1,141 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// HTTP and HTTPS serving of the web server for kvm-rs
+
+use std::net::SocketAddr;
+use futures_util::future::BoxFuture;
+use axum::{
+    extract::{ConnectInfo, Request},
+    http::{StatusCode, Uri},
+    response::{IntoResponse, Redirect, Response},
+    serve::{Listener, ListenerExt},
+    Extension, Router,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use tokio_rustls::TlsAcceptor;
+use tracing::warn;
+use crate::{auth::Principal, listen::MultiListener, shutdown::Shutdown, tls, transport::ConsoleTransport};
+
+/// The web server, carrying the console WebSockets, as a console transport
+pub struct WebServer<L> {
+    listener: L,
+    acceptor: Option<TlsAcceptor>,
+    app: Router,
+}
+
+impl<L> WebServer<L> {
+    pub fn new(listener: L, acceptor: Option<TlsAcceptor>, app: Router) -> Self {
+        Self { listener, acceptor, app }
+    }
+}
+
+impl<L> ConsoleTransport for WebServer<L>
+where
+    L: Listener<Addr = SocketAddr>,
+{
+    fn name(&self) -> &str {
+        "Web server"
+    }
+
+    fn serve(self: Box<Self>, shutdown: Shutdown) -> BoxFuture<'static, anyhow::Result<()>> {
+        Box::pin(serve(self.listener, self.acceptor, self.app, shutdown))
+    }
+}
+
+/// Serve `app` on `listener`, over TLS if an acceptor is given, until shutdown
+pub async fn serve<L>(listener: L, acceptor: Option<TlsAcceptor>, app: Router, shutdown: Shutdown) -> anyhow::Result<()>
+where
+    L: Listener<Addr = SocketAddr>,
+{
+    match acceptor {
+        Some(acceptor) => serve_tls(listener, acceptor, app, shutdown).await,
+        None => {
+            // axum only provides the peer address of other listeners through tap_io
+            axum::serve(listener.tap_io(|_| {}), app.into_make_service_with_connect_info::<SocketAddr>())
+                .with_graceful_shutdown(shutdown.wait())
+                .await?;
+            Ok(())
+        }
+    }
+}
+
+/// Serve `app` over TLS on `listener`, one task per connection, until shutdown
+async fn serve_tls<L>(mut listener: L, acceptor: TlsAcceptor, app: Router, shutdown: Shutdown) -> anyhow::Result<()>
+where
+    L: Listener<Addr = SocketAddr>,
+{
+    let stopping = shutdown.wait();
+    tokio::pin!(stopping);
+    loop {
+        // Accept errors are retried by the listener
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut stopping => return Ok(()),
+        };
+        let acceptor = acceptor.clone();
+        let shutdown = shutdown.clone();
+        // Handlers read the peer address from ConnectInfo as with axum::serve
+        let mut app = app.clone().layer(Extension(ConnectInfo(addr)));
+        tokio::spawn(async move {
+            let tls_stream = match acceptor.accept(stream).await {
+                Ok(tls_stream) => tls_stream,
+                Err(e) => {
+                    warn!("TLS handshake failed for {}: {}", addr, e);
+                    return;
+                }
+            };
+            // Requests over a connection with a client certificate are audited as its user
+            if let Some(name) = tls::client_name(tls_stream.get_ref().1.peer_certificates()) {
+                app = app.layer(Extension(Principal(name)));
+            }
+            let service = TowerToHyperService::new(app);
+            // HTTP/2 if negotiated by ALPN; WebSockets then use extended CONNECT (RFC 8441)
+            let mut builder = auto::Builder::new(TokioExecutor::new());
+            builder.http2().enable_connect_protocol();
+            let connection = builder.serve_connection_with_upgrades(TokioIo::new(tls_stream), service);
+            tokio::pin!(connection);
+            let result = tokio::select! {
+                result = connection.as_mut() => result,
+                _ = shutdown.wait() => {
+                    // Finish the request in progress, then close
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            };
+            if let Err(e) = result {
+                warn!("HTTPS connection error for {}: {}", addr, e);
+            }
+        });
+    }
+}
+
+/// Answer every plain HTTP request with a redirect to the HTTPS port, until shutdown
+pub async fn serve_redirect(listener: MultiListener, https_port: u16, shutdown: Shutdown) -> anyhow::Result<()> {
+    let app = Router::new().fallback(move |request: Request| async move {
+        redirect_to_https(request, https_port)
+    });
+    axum::serve(listener, app).with_graceful_shutdown(shutdown.wait()).await?;
+    Ok(())
+}
+
+fn redirect_to_https(request: Request, https_port: u16) -> Response {
+    let Some(host) = crate::origin::request_host(request.uri(), request.headers()) else {
+        return (StatusCode::BAD_REQUEST, "Missing Host header").into_response();
+    };
+    // Drop the port of the plain listener
+    let host = crate::origin::strip_port(host);
+    let path = request.uri().path_and_query().map(|path| path.as_str()).unwrap_or("/");
+    let location = if https_port == 443 {
+        format!("https://{}{}", host, path)
+    } else {
+        format!("https://{}:{}{}", host, https_port, path)
+    };
+    match location.parse::<Uri>() {
+        Ok(_) => Redirect::permanent(&location).into_response(),
+        Err(_) => (StatusCode::BAD_REQUEST, "Invalid Host header").into_response(),
+    }
+}
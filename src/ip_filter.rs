@@ -0,0 +1,148 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Source address allow and deny lists for kvm-rs
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+#[cfg(feature = "web")]
+use axum::{
+    extract::State,
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "web")]
+use tracing::info;
+
+/// Address block in CIDR notation, e.g. 10.0.0.0/24; a plain address is a single host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value, None),
+        };
+        let network = address.parse::<IpAddr>()
+            .map_err(|_| format!("Invalid address in {}", value))?
+            .to_canonical();
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().ok().filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("Invalid prefix length in {}", value))?,
+            None => max,
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> Self {
+        cidr.to_string()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// Addresses clients may connect from: any in `allow` (any at all if it is
+/// empty) that is not in `deny`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FilterRules {
+    #[serde(default)]
+    pub allow: Vec<Cidr>,
+    #[serde(default)]
+    pub deny: Vec<Cidr>,
+}
+
+/// Source address filter shared by the listeners, changeable at runtime.
+/// Loopback clients, such as a reverse proxy on the BMC, are always allowed
+#[derive(Clone, Default)]
+pub struct IpFilter {
+    rules: Arc<RwLock<FilterRules>>,
+}
+
+impl IpFilter {
+    pub fn new(rules: FilterRules) -> Self {
+        Self {
+            rules: Arc::new(RwLock::new(rules)),
+        }
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        if ip.to_canonical().is_loopback() {
+            return true;
+        }
+        let rules = self.rules.read().unwrap_or_else(|e| e.into_inner());
+        (rules.allow.is_empty() || rules.allow.iter().any(|cidr| cidr.contains(ip)))
+            && !rules.deny.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    pub fn rules(&self) -> FilterRules {
+        self.rules.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn set_rules(&self, rules: FilterRules) {
+        *self.rules.write().unwrap_or_else(|e| e.into_inner()) = rules;
+    }
+}
+
+/// Routes to read and replace the filter rules
+#[cfg(feature = "web")]
+pub fn router(filter: IpFilter) -> Router {
+    Router::new()
+        .route("/api/v1/ip-filter", get(get_rules).put(put_rules))
+        .with_state(filter)
+}
+
+/// GET /api/v1/ip-filter - current allow and deny lists
+#[cfg(feature = "web")]
+async fn get_rules(State(filter): State<IpFilter>) -> Json<FilterRules> {
+    Json(filter.rules())
+}
+
+/// PUT /api/v1/ip-filter - replace both lists; new connections are checked against them
+#[cfg(feature = "web")]
+async fn put_rules(State(filter): State<IpFilter>, Json(rules): Json<FilterRules>) -> Json<FilterRules> {
+    let allow: Vec<String> = rules.allow.iter().map(Cidr::to_string).collect();
+    let deny: Vec<String> = rules.deny.iter().map(Cidr::to_string).collect();
+    info!("IP filter updated: allow [{}], deny [{}]", allow.join(", "), deny.join(", "));
+    filter.set_rules(rules.clone());
+    Json(rules)
+}
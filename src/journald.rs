@@ -0,0 +1,139 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Log entries through journald's native protocol for kvm-rs
+
+use std::fmt::{Debug, Write as _};
+use std::os::unix::net::UnixDatagram;
+use anyhow::Result;
+use tracing::field::{Field, Visit};
+use tracing::{span, Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+use crate::audit::{connect_journald, push_field};
+
+/// Identifier of log entries, e.g. `journalctl -t kvm-rs`
+const SYSLOG_IDENTIFIER: &str = "kvm-rs";
+
+/// Sends every log message to journald as one entry with its priority, the
+/// module it comes from as SUBSYSTEM, and the SESSION_ID and PEER of the
+/// connection it belongs to
+pub struct JournaldLayer {
+    socket: UnixDatagram,
+}
+
+impl JournaldLayer {
+    pub fn connect() -> Result<Self> {
+        Ok(Self { socket: connect_journald()? })
+    }
+}
+
+/// Session and peer named by a span or event; connection spans record
+/// them as `session` and `client`, the access log as `peer`
+#[derive(Default)]
+struct Connection {
+    session: Option<String>,
+    peer: Option<String>,
+}
+
+impl Visit for Connection {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        match field.name() {
+            "session" => self.session = Some(format!("{:?}", value)),
+            "client" | "peer" => self.peer = Some(format!("{:?}", value)),
+            _ => {}
+        }
+    }
+}
+
+/// Message of an event with its other fields appended, as on stdout
+#[derive(Default)]
+struct Message {
+    text: String,
+    connection: Connection,
+}
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.text, "{:?}", value);
+            return;
+        }
+        self.connection.record_debug(field, value);
+        let _ = write!(self.text, " {}={:?}", field.name(), value);
+    }
+}
+
+impl<S> Layer<S> for JournaldLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let mut connection = Connection::default();
+        attrs.record(&mut connection);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(connection);
+        }
+    }
+
+    // Sessions are recorded once registered, after the span was entered
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(connection) = span.extensions_mut().get_mut::<Connection>() {
+                values.record(connection);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut message = Message::default();
+        event.record(&mut message);
+        let mut connection = message.connection;
+        // The innermost span naming them wins over the outer ones
+        for span in ctx.event_scope(event).into_iter().flatten() {
+            if let Some(outer) = span.extensions().get::<Connection>() {
+                connection.session = connection.session.or_else(|| outer.session.clone());
+                connection.peer = connection.peer.or_else(|| outer.peer.clone());
+            }
+        }
+
+        let metadata = event.metadata();
+        let mut entry = Vec::new();
+        push_field(&mut entry, "MESSAGE", &message.text);
+        push_field(&mut entry, "PRIORITY", priority(metadata.level()));
+        push_field(&mut entry, "SYSLOG_IDENTIFIER", SYSLOG_IDENTIFIER);
+        push_field(&mut entry, "SUBSYSTEM", subsystem(metadata.target()));
+        if let Some(ref session) = connection.session {
+            push_field(&mut entry, "SESSION_ID", session);
+        }
+        if let Some(ref peer) = connection.peer {
+            push_field(&mut entry, "PEER", peer);
+        }
+        if let (Some(file), Some(line)) = (metadata.file(), metadata.line()) {
+            push_field(&mut entry, "CODE_FILE", file);
+            push_field(&mut entry, "CODE_LINE", &line.to_string());
+        }
+        // There is nowhere left to report a failure to log
+        let _ = self.socket.send(&entry);
+    }
+}
+
+/// syslog priority of a level
+fn priority(level: &Level) -> &'static str {
+    match *level {
+        Level::ERROR => "3",
+        Level::WARN => "4",
+        Level::INFO => "6",
+        Level::DEBUG | Level::TRACE => "7",
+    }
+}
+
+/// Module of kvm-rs a message comes from, e.g. "vnc" for kvm_rs::vnc, or the
+/// first part of other targets, e.g. "access" or "zbus"
+fn subsystem(target: &str) -> &str {
+    match target.strip_prefix("kvm_rs::") {
+        Some(module) => module.split("::").next().unwrap_or(module),
+        None if target == "kvm_rs" => "main",
+        None => target.split("::").next().unwrap_or(target),
+    }
+}
@@ -0,0 +1,349 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Keyboard state tracking and key repeat policy for kvm-rs
+
+use std::time::Duration;
+
+/// First HID usage of the modifier range (LeftControl)
+const MODIFIER_USAGE_MIN: u8 = 0xE0;
+/// Last HID usage of the modifier range (RightGUI)
+const MODIFIER_USAGE_MAX: u8 = 0xE7;
+
+/// HID usage of the Num Lock key
+pub const NUM_LOCK_USAGE: u8 = 0x53;
+
+/// HID usage of the extra key of ISO 102-key keyboards (Keyboard Non-US \ and |)
+pub const NON_US_BACKSLASH_USAGE: u8 = 0x64;
+
+/// Num Lock bit of the keyboard LED output report
+pub const LED_NUM_LOCK: u8 = 0x01;
+
+/// How held keys are repeated on the host
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyRepeatPolicy {
+    /// Forward every client key-down as-is
+    Passthrough,
+    /// Drop duplicate key-downs and let the host's own typematic repeat held keys
+    Host,
+    /// Drop duplicate key-downs and generate repeats server-side while a key is held
+    Server,
+}
+
+/// Which keyboard protocol reports are built for
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyboardProtocol {
+    /// Always use the report descriptor's layout
+    Report,
+    /// Always send 8-byte boot protocol reports
+    Boot,
+    /// Switch to boot reports when the host selects the boot protocol
+    Auto,
+}
+
+/// Physical layout of the host's keyboard, for keys that only some layouts have
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyboardLayout {
+    /// ANSI 101-key: < and > are typed with Shift and the comma and period keys
+    #[default]
+    Us,
+    /// ISO 102-key: < and > are the extra key between left Shift and Z
+    /// (Keyboard Non-US \ and |)
+    Iso,
+}
+
+/// Key repeat settings
+#[derive(Debug, Clone, Copy)]
+pub struct KeyRepeat {
+    pub policy: KeyRepeatPolicy,
+    /// Delay before the first repeat
+    pub delay: Duration,
+    /// Interval between repeats
+    pub interval: Duration,
+}
+
+impl Default for KeyRepeat {
+    fn default() -> Self {
+        Self {
+            policy: KeyRepeatPolicy::Passthrough,
+            delay: Duration::from_millis(500),
+            interval: Duration::from_millis(50),
+        }
+    }
+}
+
+impl KeyRepeat {
+    pub fn new(policy: KeyRepeatPolicy, delay_ms: u64, rate_hz: u32) -> Self {
+        Self {
+            policy,
+            delay: Duration::from_millis(delay_ms),
+            interval: Duration::from_millis(1000 / rate_hz.max(1) as u64),
+        }
+    }
+}
+
+/// Modifier bits matched by a modifier name in a key combination
+fn modifier_mask(name: &str) -> Option<u8> {
+    let mask = match name {
+        "ctrl" | "control" => 0x11,
+        "lctrl" | "leftctrl" | "leftcontrol" => 0x01,
+        "rctrl" | "rightctrl" | "rightcontrol" => 0x10,
+        "shift" => 0x22,
+        "lshift" | "leftshift" => 0x02,
+        "rshift" | "rightshift" => 0x20,
+        "alt" => 0x44,
+        "lalt" | "leftalt" => 0x04,
+        "ralt" | "rightalt" | "altgr" => 0x40,
+        "super" | "gui" | "win" | "meta" => 0x88,
+        "lsuper" | "leftsuper" | "lgui" | "leftgui" => 0x08,
+        "rsuper" | "rightsuper" | "rgui" | "rightgui" => 0x80,
+        _ => return None,
+    };
+    Some(mask)
+}
+
+/// HID usage for a key name (case-insensitive) or a hexadecimal usage such as "0x46"
+pub fn usage_from_name(name: &str) -> Option<u8> {
+    let name = name.to_ascii_lowercase();
+    if let Some(hex) = name.strip_prefix("0x") {
+        return u8::from_str_radix(hex, 16).ok();
+    }
+
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return match c {
+            'a'..='z' => Some(c as u8 - b'a' + 0x04),
+            '1'..='9' => Some(c as u8 - b'1' + 0x1e),
+            '0' => Some(0x27),
+            _ => None,
+        };
+    }
+
+    if let Some(number) = name.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+        return match number {
+            1..=12 => Some(0x3a + number - 1),
+            13..=24 => Some(0x68 + number - 13),
+            _ => None,
+        };
+    }
+
+    let usage = match name.as_str() {
+        "enter" | "return" => 0x28,
+        "esc" | "escape" => 0x29,
+        "backspace" => 0x2a,
+        "tab" => 0x2b,
+        "space" => 0x2c,
+        "capslock" => 0x39,
+        "sysrq" | "print" | "printscreen" => 0x46,
+        "scrolllock" => 0x47,
+        "pause" | "break" => 0x48,
+        "insert" => 0x49,
+        "home" => 0x4a,
+        "pageup" => 0x4b,
+        "delete" | "del" => 0x4c,
+        "end" => 0x4d,
+        "pagedown" => 0x4e,
+        "right" => 0x4f,
+        "left" => 0x50,
+        "down" => 0x51,
+        "up" => 0x52,
+        "numlock" => NUM_LOCK_USAGE,
+        "nonusbackslash" | "102nd" => NON_US_BACKSLASH_USAGE,
+        "menu" | "application" => 0x65,
+        "power" => 0x66,
+        _ => return None,
+    };
+    Some(usage)
+}
+
+/// HID usage and whether Shift is needed to type `c` on a US layout
+pub fn usage_for_char(c: char) -> Option<(u8, bool)> {
+    const SHIFTED_DIGITS: &str = "!@#$%^&*(";
+    let key = match c {
+        'a'..='z' => (c as u8 - b'a' + 0x04, false),
+        'A'..='Z' => (c as u8 - b'A' + 0x04, true),
+        '1'..='9' => (c as u8 - b'1' + 0x1e, false),
+        '0' => (0x27, false),
+        ')' => (0x27, true),
+        '\n' => (0x28, false),
+        '\t' => (0x2b, false),
+        ' ' => (0x2c, false),
+        '-' => (0x2d, false),
+        '_' => (0x2d, true),
+        '=' => (0x2e, false),
+        '+' => (0x2e, true),
+        '[' => (0x2f, false),
+        '{' => (0x2f, true),
+        ']' => (0x30, false),
+        '}' => (0x30, true),
+        '\\' => (0x31, false),
+        '|' => (0x31, true),
+        ';' => (0x33, false),
+        ':' => (0x33, true),
+        '\'' => (0x34, false),
+        '"' => (0x34, true),
+        '`' => (0x35, false),
+        '~' => (0x35, true),
+        ',' => (0x36, false),
+        '<' => (0x36, true),
+        '.' => (0x37, false),
+        '>' => (0x37, true),
+        '/' => (0x38, false),
+        '?' => (0x38, true),
+        _ => {
+            let digit = SHIFTED_DIGITS.find(c)?;
+            (0x1e + digit as u8, true)
+        }
+    };
+    Some(key)
+}
+
+/// A key combination such as "Alt+SysRq": modifiers that must be held and a key
+#[derive(Debug, Clone)]
+pub struct KeyCombo {
+    text: String,
+    /// Each entry is satisfied if any of its modifier bits is held
+    modifiers: Vec<u8>,
+    usage: u8,
+}
+
+impl KeyCombo {
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut modifiers = Vec::new();
+        let mut usage = None;
+        for part in text.split('+').map(str::trim) {
+            let name = part.to_ascii_lowercase();
+            if let Some(mask) = modifier_mask(&name) {
+                modifiers.push(mask);
+            } else if usage.is_none() {
+                usage = Some(usage_from_name(&name)
+                    .ok_or_else(|| anyhow::anyhow!("Unknown key '{}' in combination '{}'", part, text))?);
+            } else {
+                return Err(anyhow::anyhow!("Key combination '{}' has more than one non-modifier key", text));
+            }
+        }
+        let usage = usage
+            .ok_or_else(|| anyhow::anyhow!("Key combination '{}' has no non-modifier key", text))?;
+        Ok(Self {
+            text: text.to_string(),
+            modifiers,
+            usage,
+        })
+    }
+
+    /// Usages to press for this combination, modifiers first; "ctrl" etc. press the left key
+    pub fn usages(&self) -> Vec<u8> {
+        let mut usages: Vec<u8> = self.modifiers.iter()
+            .map(|mask| MODIFIER_USAGE_MIN + mask.trailing_zeros() as u8)
+            .collect();
+        usages.push(self.usage);
+        usages
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    fn matches(&self, modifiers: u8, usage: u8) -> bool {
+        self.usage == usage && self.modifiers.iter().all(|mask| modifiers & mask != 0)
+    }
+}
+
+/// Key combinations that are never forwarded to the host
+#[derive(Debug, Clone, Default)]
+pub struct KeyBlocklist {
+    combos: Vec<KeyCombo>,
+}
+
+impl KeyBlocklist {
+    pub fn parse(combos: &[String]) -> anyhow::Result<Self> {
+        let combos = combos.iter()
+            .map(|combo| KeyCombo::parse(combo))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self { combos })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.combos.is_empty()
+    }
+
+    /// The blocked combination formed by `usage` with the held modifiers, if any
+    pub fn blocked(&self, modifiers: u8, usage: u8) -> Option<&str> {
+        self.combos.iter()
+            .find(|combo| combo.matches(modifiers, usage))
+            .map(|combo| combo.text.as_str())
+    }
+}
+
+/// HID usage of the left Shift key
+pub const LEFT_SHIFT_USAGE: u8 = 0xE1;
+
+/// Whether a HID usage is one of the eight modifier keys
+pub fn is_modifier(usage: u8) -> bool {
+    (MODIFIER_USAGE_MIN..=MODIFIER_USAGE_MAX).contains(&usage)
+}
+
+/// Modifier bits and pressed keys as seen by the host
+#[derive(Debug, Clone, Default)]
+pub struct KeyboardState {
+    modifiers: u8,
+    keys: Vec<u8>,
+}
+
+impl KeyboardState {
+    /// Press a key; returns false if it was already held
+    pub fn press(&mut self, usage: u8) -> bool {
+        if is_modifier(usage) {
+            let bit = 1 << (usage - MODIFIER_USAGE_MIN);
+            let changed = self.modifiers & bit == 0;
+            self.modifiers |= bit;
+            changed
+        } else if self.keys.contains(&usage) {
+            false
+        } else {
+            self.keys.push(usage);
+            true
+        }
+    }
+
+    /// Release a key; returns false if it was not held
+    pub fn release(&mut self, usage: u8) -> bool {
+        if is_modifier(usage) {
+            let bit = 1 << (usage - MODIFIER_USAGE_MIN);
+            let changed = self.modifiers & bit != 0;
+            self.modifiers &= !bit;
+            changed
+        } else {
+            let before = self.keys.len();
+            self.keys.retain(|key| *key != usage);
+            self.keys.len() != before
+        }
+    }
+
+    pub fn is_pressed(&self, usage: u8) -> bool {
+        if is_modifier(usage) {
+            self.modifiers & (1 << (usage - MODIFIER_USAGE_MIN)) != 0
+        } else {
+            self.keys.contains(&usage)
+        }
+    }
+
+    pub fn modifiers(&self) -> u8 {
+        self.modifiers
+    }
+
+    /// Pressed non-modifier keys, oldest first
+    pub fn keys(&self) -> &[u8] {
+        &self.keys
+    }
+
+    /// Release every key and modifier
+    pub fn clear(&mut self) {
+        self.modifiers = 0;
+        self.keys.clear();
+    }
+
+    /// Pressed non-modifier keys without `usage`
+    pub fn keys_without(&self, usage: u8) -> Vec<u8> {
+        self.keys.iter().copied().filter(|key| *key != usage).collect()
+    }
+}
@@ -0,0 +1,560 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Logins with LDAP and Active Directory accounts for kvm-rs
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
+#[cfg(feature = "tls")]
+use rustls::pki_types::ServerName;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, info, warn};
+use zbus::zvariant::{OwnedObjectPath, OwnedValue};
+use crate::{auth::UserRole, user_manager};
+
+/// phosphor-ldap-config, which bmcweb's AccountService LDAP and
+/// ActiveDirectory properties are stored in
+const LDAP_CONFIG_SERVICE: &str = "xyz.openbmc_project.Ldap.Config";
+const LDAP_CONFIG_ROOT: &str = "/xyz/openbmc_project/user/ldap";
+const LDAP_CONFIG_INTERFACE: &str = "xyz.openbmc_project.User.Ldap.Config";
+const ENABLE_INTERFACE: &str = "xyz.openbmc_project.Object.Enable";
+const PRIVILEGE_MAPPER_INTERFACE: &str = "xyz.openbmc_project.User.PrivilegeMapperEntry";
+/// CA certificates phosphor-certificate-manager installs for LDAP over TLS
+#[cfg(feature = "tls")]
+const OPENBMC_LDAP_CA_DIR: &str = "/etc/ssl/certs/authority";
+/// Connecting, binding and searching together
+const LDAP_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest message read from the server
+const MAX_MESSAGE: usize = 1024 * 1024;
+
+const LDAP_SUCCESS: u32 = 0;
+const LDAP_INVALID_CREDENTIALS: u32 = 49;
+/// Protocol operations, as BER application tags
+const BIND_REQUEST: u8 = 0x60;
+const BIND_RESPONSE: u8 = 0x61;
+const UNBIND_REQUEST: u8 = 0x42;
+const SEARCH_REQUEST: u8 = 0x63;
+const SEARCH_RESULT_ENTRY: u8 = 0x64;
+const SEARCH_RESULT_DONE: u8 = 0x65;
+
+/// Where the directory server and the group to role mapping are configured
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LdapSource {
+    /// The enabled LDAP or Active Directory configuration of phosphor-ldap-config, as set through Redfish
+    Openbmc,
+    /// The --ldap-* options
+    Direct,
+}
+
+/// A directory group whose members get a console role, `GROUP=ROLE`
+#[derive(Debug, Clone)]
+pub struct RoleMapping {
+    pub group: String,
+    pub role: UserRole,
+}
+
+impl FromStr for RoleMapping {
+    type Err = String;
+
+    fn from_str(mapping: &str) -> Result<Self, Self::Err> {
+        let (group, role) = mapping.rsplit_once('=').ok_or("expected GROUP=ROLE")?;
+        Ok(Self {
+            group: group.trim().to_string(),
+            role: <UserRole as ValueEnum>::from_str(role.trim(), true)?,
+        })
+    }
+}
+
+/// The --ldap-* options
+#[derive(Debug, Clone)]
+pub struct LdapSettings {
+    pub source: LdapSource,
+    /// ldap:// or ldaps:// URL of the server
+    pub url: Option<String>,
+    pub base_dn: Option<String>,
+    /// Account the user entries are looked up with; anonymous without one
+    pub bind_dn: Option<String>,
+    pub bind_password: Option<String>,
+    /// Attribute holding the login name, e.g. uid or sAMAccountName
+    pub user_attribute: String,
+    /// Attribute of user entries naming their groups, e.g. memberOf
+    pub group_attribute: String,
+    pub role_map: Vec<RoleMapping>,
+    pub ca_file: Option<PathBuf>,
+}
+
+/// Server and mapping a login is checked with
+struct DirectoryConfig {
+    url: String,
+    base_dn: String,
+    bind_dn: String,
+    bind_password: String,
+    user_attribute: String,
+    group_attribute: String,
+    role_map: Vec<RoleMapping>,
+}
+
+/// Checks logins of accounts that are not local to the BMC against an LDAP
+/// or Active Directory server, giving them the role of their groups
+pub struct LdapDirectory {
+    settings: LdapSettings,
+    dbus: Option<zbus::Connection>,
+}
+
+impl LdapDirectory {
+    /// `dbus` reads the OpenBMC configuration with `LdapSource::Openbmc`
+    pub fn new(settings: LdapSettings, dbus: Option<zbus::Connection>) -> Result<Self> {
+        match settings.source {
+            LdapSource::Openbmc if dbus.is_none() => bail!("--ldap openbmc needs a D-Bus connection"),
+            LdapSource::Direct if settings.url.is_none() || settings.base_dn.is_none() => {
+                bail!("--ldap direct needs --ldap-url and --ldap-base-dn")
+            }
+            _ => {}
+        }
+        Ok(Self { settings, dbus })
+    }
+
+    /// Role of `username` if `password` is theirs and one of their groups is
+    /// mapped to a role; None if not, or if no directory is enabled
+    pub async fn login(&self, username: &str, password: &str) -> Result<Option<UserRole>> {
+        // An empty password would make an unauthenticated bind, which succeeds
+        if username.is_empty() || password.is_empty() {
+            return Ok(None);
+        }
+        let Some(config) = self.config().await? else {
+            return Ok(None);
+        };
+        let groups = tokio::time::timeout(LDAP_TIMEOUT, self.authenticate(&config, username, password))
+            .await
+            .map_err(|_| anyhow!("LDAP server {} did not answer within {:?}", config.url, LDAP_TIMEOUT))??;
+        let Some(groups) = groups else {
+            return Ok(None);
+        };
+        let role = config.role_map.iter()
+            .filter(|mapping| groups.iter().any(|group| group.eq_ignore_ascii_case(&mapping.group)))
+            .map(|mapping| mapping.role)
+            .max();
+        match role {
+            Some(role) => info!("LDAP login by {} ({})", username, role.as_str()),
+            None => warn!("Refused console login by {}: no LDAP group of theirs has a role (groups: {})", username, groups.join(", ")),
+        }
+        Ok(role)
+    }
+
+    async fn config(&self) -> Result<Option<DirectoryConfig>> {
+        let settings = &self.settings;
+        match (settings.source, &self.dbus) {
+            (LdapSource::Openbmc, Some(dbus)) => openbmc_config(dbus, settings).await,
+            _ => Ok(Some(DirectoryConfig {
+                url: settings.url.clone().unwrap_or_default(),
+                base_dn: settings.base_dn.clone().unwrap_or_default(),
+                bind_dn: settings.bind_dn.clone().unwrap_or_default(),
+                bind_password: settings.bind_password.clone().unwrap_or_default(),
+                user_attribute: settings.user_attribute.clone(),
+                group_attribute: settings.group_attribute.clone(),
+                role_map: settings.role_map.clone(),
+            })),
+        }
+    }
+
+    /// Groups of `username` if `password` is theirs: look the entry up, bind
+    /// as it, and read its group attribute and the groups listing it as member
+    async fn authenticate(&self, config: &DirectoryConfig, username: &str, password: &str) -> Result<Option<Vec<String>>> {
+        let mut connection = self.connect(&config.url).await?;
+        if !config.bind_dn.is_empty() {
+            let code = connection.bind(&config.bind_dn, &config.bind_password).await?;
+            if code != LDAP_SUCCESS {
+                bail!("LDAP bind as {} failed with result {}", config.bind_dn, code);
+            }
+        }
+        let filter = Filter::Equal(&config.user_attribute, username);
+        let entries = connection.search(&config.base_dn, &filter, &[&config.group_attribute]).await?;
+        let [entry] = entries.as_slice() else {
+            debug!("LDAP lookup of {} found {} entries", username, entries.len());
+            return Ok(None);
+        };
+        match connection.bind(&entry.dn, password).await? {
+            LDAP_SUCCESS => {}
+            LDAP_INVALID_CREDENTIALS => return Ok(None),
+            code => bail!("LDAP bind as {} failed with result {}", entry.dn, code),
+        }
+        let mut groups: Vec<String> = entry.values(&config.group_attribute).map(group_name).collect();
+        // POSIX and groupOfNames groups list their members instead
+        let member = Filter::Or(vec![
+            Filter::Equal("memberUid", username),
+            Filter::Equal("member", &entry.dn),
+            Filter::Equal("uniqueMember", &entry.dn),
+        ]);
+        for group in connection.search(&config.base_dn, &member, &["cn"]).await? {
+            groups.extend(group.values("cn").map(str::to_string));
+        }
+        connection.unbind().await;
+        groups.sort();
+        groups.dedup();
+        Ok(Some(groups))
+    }
+
+    async fn connect(&self, url: &str) -> Result<Connection> {
+        let (tls, address) = match url.split_once("://") {
+            Some(("ldap", address)) => (false, address),
+            Some(("ldaps", address)) => (true, address),
+            _ => bail!("LDAP URL {} must start with ldap:// or ldaps://", url),
+        };
+        let address = address.trim_end_matches('/');
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host, port.parse().with_context(|| format!("Invalid port in {}", url))?),
+            _ => (address, if tls { 636 } else { 389 }),
+        };
+        let host = host.trim_matches(|c| c == '[' || c == ']');
+        let stream = tokio::net::TcpStream::connect((host, port))
+            .await
+            .with_context(|| format!("Failed to connect to LDAP server {}", url))?;
+        if !tls {
+            return Ok(Connection::new(Box::new(stream)));
+        }
+        self.connect_tls(url, host, stream).await
+    }
+
+    #[cfg(feature = "tls")]
+    async fn connect_tls(&self, url: &str, host: &str, stream: tokio::net::TcpStream) -> Result<Connection> {
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(self.client_config()?));
+        let stream = connector.connect(ServerName::try_from(host.to_string())?, stream)
+            .await
+            .with_context(|| format!("TLS handshake with LDAP server {} failed", url))?;
+        Ok(Connection::new(Box::new(stream)))
+    }
+
+    #[cfg(not(feature = "tls"))]
+    async fn connect_tls(&self, url: &str, _host: &str, _stream: tokio::net::TcpStream) -> Result<Connection> {
+        bail!("LDAP server {} needs kvm-rs built with the tls feature", url)
+    }
+
+    /// TLS client settings verifying the server against --ldap-ca, or the
+    /// LDAP CA certificates of the BMC with the OpenBMC configuration
+    #[cfg(feature = "tls")]
+    fn client_config(&self) -> Result<rustls::ClientConfig> {
+        let files = match (&self.settings.ca_file, self.settings.source) {
+            (Some(file), _) => vec![file.clone()],
+            (None, LdapSource::Openbmc) => std::fs::read_dir(OPENBMC_LDAP_CA_DIR)
+                .with_context(|| format!("Failed to read {}", OPENBMC_LDAP_CA_DIR))?
+                .flatten()
+                .map(|entry| entry.path())
+                .collect(),
+            (None, LdapSource::Direct) => bail!("--ldap-ca is required for ldaps:// servers"),
+        };
+        let mut roots = rustls::RootCertStore::empty();
+        for file in files {
+            let pem = std::fs::read(&file).with_context(|| format!("Failed to read LDAP CA file {}", file.display()))?;
+            for cert in rustls_pemfile::certs(&mut std::io::Cursor::new(&pem)) {
+                roots.add(cert.with_context(|| format!("Failed to parse LDAP CA file {}", file.display()))?)?;
+            }
+        }
+        Ok(rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth())
+    }
+}
+
+/// The enabled configuration of phosphor-ldap-config, None when LDAP and
+/// Active Directory are both disabled. Its bind password is only readable
+/// where the service returns it; --ldap-bind-password-file provides it otherwise
+async fn openbmc_config(dbus: &zbus::Connection, settings: &LdapSettings) -> Result<Option<DirectoryConfig>> {
+    type Properties = HashMap<String, HashMap<String, OwnedValue>>;
+    let reply = dbus.call_method(
+        Some(LDAP_CONFIG_SERVICE),
+        LDAP_CONFIG_ROOT,
+        Some("org.freedesktop.DBus.ObjectManager"),
+        "GetManagedObjects",
+        &(),
+    ).await.context("Failed to read the LDAP configuration")?;
+    let objects: HashMap<OwnedObjectPath, Properties> = reply.body().deserialize()
+        .context("Invalid LDAP configuration")?;
+    let property = |interfaces: &Properties, interface: &str, name: &str| -> Option<String> {
+        interfaces.get(interface)?.get(name)?.downcast_ref::<&str>().ok().map(str::to_string)
+    };
+    let enabled = objects.iter().find(|(_, interfaces)| {
+        interfaces.contains_key(LDAP_CONFIG_INTERFACE)
+            && interfaces.get(ENABLE_INTERFACE)
+                .and_then(|enable| enable.get("Enabled"))
+                .and_then(|value| value.downcast_ref::<bool>().ok())
+                .unwrap_or(false)
+    });
+    let Some((path, interfaces)) = enabled else {
+        debug!("Neither LDAP nor Active Directory is enabled");
+        return Ok(None);
+    };
+    // Role mappings are the children of the configuration, .../role_map/N
+    let prefix = format!("{}/", path.as_str());
+    let role_map = objects.iter()
+        .filter(|(entry, _)| entry.as_str().starts_with(&prefix))
+        .filter_map(|(_, entry)| {
+            let group = property(entry, PRIVILEGE_MAPPER_INTERFACE, "GroupName")?;
+            let privilege = property(entry, PRIVILEGE_MAPPER_INTERFACE, "Privilege")?;
+            Some(RoleMapping { group, role: user_manager::privilege_role(&privilege)? })
+        })
+        .collect();
+    let config = |name: &str| property(interfaces, LDAP_CONFIG_INTERFACE, name).unwrap_or_default();
+    let bind_password = Some(config("LDAPBindDNPassword"))
+        .filter(|password| !password.is_empty())
+        .or_else(|| settings.bind_password.clone())
+        .unwrap_or_default();
+    Ok(Some(DirectoryConfig {
+        url: config("LDAPServerURI"),
+        base_dn: config("LDAPBaseDN"),
+        bind_dn: config("LDAPBindDN"),
+        bind_password,
+        user_attribute: Some(config("UserNameAttribute")).filter(|name| !name.is_empty()).unwrap_or_else(|| settings.user_attribute.clone()),
+        group_attribute: Some(config("GroupNameAttribute")).filter(|name| !name.is_empty()).unwrap_or_else(|| settings.group_attribute.clone()),
+        role_map,
+    }))
+}
+
+/// Common name of a group DN, "CN=KVM Admins,OU=Groups,DC=example" -> "KVM Admins";
+/// other values, such as plain group names, as they are
+fn group_name(value: &str) -> String {
+    let first = value.split(',').next().unwrap_or_default();
+    match first.split_once('=') {
+        Some((attribute, name)) if attribute.trim().eq_ignore_ascii_case("cn") => name.trim().to_string(),
+        _ => value.to_string(),
+    }
+}
+
+/// Search filters the logins use
+enum Filter<'a> {
+    Equal(&'a str, &'a str),
+    Or(Vec<Filter<'a>>),
+}
+
+impl Filter<'_> {
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            // Values are sent as they are, there is nothing to escape
+            Filter::Equal(attribute, value) => ber(0xa3, &[octets(attribute.as_bytes()), octets(value.as_bytes())].concat()),
+            Filter::Or(filters) => ber(0xa1, &filters.iter().flat_map(Filter::encode).collect::<Vec<_>>()),
+        }
+    }
+}
+
+/// An entry a search returned, with the values of the requested attributes
+struct SearchEntry {
+    dn: String,
+    attributes: Vec<(String, Vec<String>)>,
+}
+
+impl SearchEntry {
+    fn values<'a>(&'a self, attribute: &'a str) -> impl Iterator<Item = &'a str> {
+        self.attributes.iter()
+            .filter(move |(name, _)| name.eq_ignore_ascii_case(attribute))
+            .flat_map(|(_, values)| values.iter().map(String::as_str))
+    }
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// An LDAPv3 connection doing one operation at a time
+struct Connection {
+    stream: Box<dyn Stream>,
+    message_id: u32,
+}
+
+impl Connection {
+    fn new(stream: Box<dyn Stream>) -> Self {
+        Self { stream, message_id: 0 }
+    }
+
+    /// Simple bind; the result code, 0 on success
+    async fn bind(&mut self, dn: &str, password: &str) -> Result<u32> {
+        let request = [integer(3), octets(dn.as_bytes()), ber(0x80, password.as_bytes())].concat();
+        let id = self.send(BIND_REQUEST, &request).await?;
+        let (tag, response) = self.receive(id).await?;
+        if tag != BIND_RESPONSE {
+            bail!("Unexpected LDAP response {:#x} to a bind", tag);
+        }
+        result_code(&response)
+    }
+
+    /// Entries under `base` matching `filter`, with `attributes`
+    async fn search(&mut self, base: &str, filter: &Filter<'_>, attributes: &[&str]) -> Result<Vec<SearchEntry>> {
+        let attributes: Vec<u8> = attributes.iter().flat_map(|attribute| octets(attribute.as_bytes())).collect();
+        let request = [
+            octets(base.as_bytes()),
+            // Whole subtree, never dereferencing aliases, no size or time limit, with values
+            ber(0x0a, &[2]), ber(0x0a, &[0]), integer(0), integer(0), ber(0x01, &[0]),
+            filter.encode(),
+            ber(0x30, &attributes),
+        ].concat();
+        let id = self.send(SEARCH_REQUEST, &request).await?;
+        let mut entries = Vec::new();
+        loop {
+            let (tag, response) = self.receive(id).await?;
+            match tag {
+                SEARCH_RESULT_ENTRY => entries.push(parse_entry(&response)?),
+                SEARCH_RESULT_DONE => {
+                    return match result_code(&response)? {
+                        LDAP_SUCCESS => Ok(entries),
+                        code => Err(anyhow!("LDAP search of {} failed with result {}", base, code)),
+                    };
+                }
+                // References to other servers are not followed
+                _ => {}
+            }
+        }
+    }
+
+    async fn unbind(&mut self) {
+        let _ = self.send(UNBIND_REQUEST, &[]).await;
+    }
+
+    async fn send(&mut self, operation: u8, content: &[u8]) -> Result<u32> {
+        self.message_id += 1;
+        let message = ber(0x30, &[integer(self.message_id), ber(operation, content)].concat());
+        self.stream.write_all(&message).await?;
+        Ok(self.message_id)
+    }
+
+    /// Next response to message `id`: its protocol operation tag and content
+    async fn receive(&mut self, id: u32) -> Result<(u8, Vec<u8>)> {
+        loop {
+            let message = self.read_message().await?;
+            let mut reader = BerReader(&message);
+            let message_id = reader.integer()?;
+            let (tag, content) = reader.next()?;
+            if message_id == id {
+                return Ok((tag, content.to_vec()));
+            }
+            // Unsolicited notifications, e.g. the server closing the connection
+            debug!("Ignoring LDAP message {} with operation {:#x}", message_id, tag);
+            if message_id == 0 {
+                bail!("LDAP server ended the connection");
+            }
+        }
+    }
+
+    /// Content of the next LDAPMessage SEQUENCE
+    async fn read_message(&mut self) -> Result<Vec<u8>> {
+        let mut header = [0u8; 2];
+        self.stream.read_exact(&mut header).await?;
+        if header[0] != 0x30 {
+            bail!("Invalid LDAP message tag {:#x}", header[0]);
+        }
+        let len = match header[1] {
+            len if len < 0x80 => len as usize,
+            count @ 0x81..=0x84 => {
+                let mut bytes = vec![0u8; (count & 0x7f) as usize];
+                self.stream.read_exact(&mut bytes).await?;
+                bytes.iter().fold(0usize, |len, byte| (len << 8) | *byte as usize)
+            }
+            _ => bail!("Invalid LDAP message length"),
+        };
+        if len > MAX_MESSAGE {
+            bail!("LDAP message of {} bytes is too large", len);
+        }
+        let mut message = vec![0u8; len];
+        self.stream.read_exact(&mut message).await?;
+        Ok(message)
+    }
+}
+
+/// resultCode of an LDAPResult
+fn result_code(content: &[u8]) -> Result<u32> {
+    BerReader(content).integer()
+}
+
+fn parse_entry(content: &[u8]) -> Result<SearchEntry> {
+    let mut reader = BerReader(content);
+    let dn = reader.string()?;
+    let (_, attributes) = reader.next()?;
+    let mut reader = BerReader(attributes);
+    let mut parsed = Vec::new();
+    while !reader.0.is_empty() {
+        let (_, attribute) = reader.next()?;
+        let mut attribute = BerReader(attribute);
+        let name = attribute.string()?;
+        let (_, values) = attribute.next()?;
+        let mut values = BerReader(values);
+        let mut strings = Vec::new();
+        while !values.0.is_empty() {
+            strings.push(values.string()?);
+        }
+        parsed.push((name, strings));
+    }
+    Ok(SearchEntry { dn, attributes: parsed })
+}
+
+/// Reads BER elements off the front of a buffer
+struct BerReader<'a>(&'a [u8]);
+
+impl<'a> BerReader<'a> {
+    /// Tag and content of the next element
+    fn next(&mut self) -> Result<(u8, &'a [u8])> {
+        let data = self.0;
+        let (&tag, rest) = data.split_first().ok_or_else(|| anyhow!("Truncated LDAP message"))?;
+        let (&first, rest) = rest.split_first().ok_or_else(|| anyhow!("Truncated LDAP message"))?;
+        let (len, rest) = match first {
+            len if len < 0x80 => (len as usize, rest),
+            count @ 0x81..=0x84 => {
+                let count = (count & 0x7f) as usize;
+                let bytes = rest.get(..count).ok_or_else(|| anyhow!("Truncated LDAP message"))?;
+                (bytes.iter().fold(0usize, |len, byte| (len << 8) | *byte as usize), &rest[count..])
+            }
+            _ => bail!("Invalid BER length"),
+        };
+        let content = rest.get(..len).ok_or_else(|| anyhow!("Truncated LDAP message"))?;
+        self.0 = &rest[len..];
+        Ok((tag, content))
+    }
+
+    fn integer(&mut self) -> Result<u32> {
+        let (_, content) = self.next()?;
+        if content.len() > 4 {
+            bail!("BER integer too large");
+        }
+        Ok(content.iter().fold(0u32, |value, byte| (value << 8) | *byte as u32))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let (_, content) = self.next()?;
+        Ok(String::from_utf8_lossy(content).into_owned())
+    }
+}
+
+/// BER element with `tag` and `content`
+fn ber(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut element = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        element.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len.to_be_bytes().into_iter().skip_while(|byte| *byte == 0).collect();
+        element.push(0x80 | bytes.len() as u8);
+        element.extend(bytes);
+    }
+    element.extend_from_slice(content);
+    element
+}
+
+fn octets(value: &[u8]) -> Vec<u8> {
+    ber(0x04, value)
+}
+
+/// Non-negative INTEGER, with a leading zero where the top bit is set
+fn integer(value: u32) -> Vec<u8> {
+    let mut bytes: Vec<u8> = value.to_be_bytes().into_iter().skip_while(|byte| *byte == 0).collect();
+    if bytes.first().is_none_or(|byte| byte & 0x80 != 0) {
+        bytes.insert(0, 0);
+    }
+    ber(0x02, &bytes)
+}
+
+/// Read `path` holding the bind password, without its trailing newline
+pub fn read_password_file(path: &Path) -> Result<String> {
+    let password = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(password.trim_end_matches(['\r', '\n']).to_string())
+}
@@ -0,0 +1,231 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Per-client connection and request limits for kvm-rs
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    serve::Listener,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tracing::warn;
+use crate::ip_filter::IpFilter;
+use crate::listen::MultiListener;
+use crate::security_audit::{SecurityAudit, SecurityEvent};
+
+/// Connection and request limits per source address; 0 disables a limit.
+/// Loopback clients, such as a reverse proxy on the BMC, are not limited
+#[derive(Clone)]
+pub struct IpLimits {
+    max_connections: u32,
+    /// Requests per second refilled into each client's bucket
+    request_rate: f64,
+    /// Requests a client may send at once, e.g. while a page loads its scripts
+    request_burst: u32,
+    clients: Arc<Mutex<HashMap<IpAddr, Client>>>,
+}
+
+struct Client {
+    connections: u32,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl IpLimits {
+    pub fn new(max_connections: u32, request_rate: f64, request_burst: u32) -> Self {
+        Self {
+            max_connections,
+            request_rate,
+            request_burst,
+            clients: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Count a new connection from `ip`; None if it already has the maximum
+    fn connect(&self, ip: IpAddr) -> Option<ConnectionPermit> {
+        if ip.is_loopback() {
+            return Some(ConnectionPermit {
+                limits: self.clone(),
+                ip,
+            });
+        }
+        let mut clients = self.lock();
+        // Forget clients without connections whose bucket has refilled
+        clients.retain(|_, client| client.connections > 0 || self.refilled_tokens(client) < self.request_burst as f64);
+        let client = clients.entry(ip).or_insert_with(|| self.new_client());
+        if self.max_connections > 0 && client.connections >= self.max_connections {
+            return None;
+        }
+        client.connections += 1;
+        Some(ConnectionPermit {
+            limits: self.clone(),
+            ip,
+        })
+    }
+
+    /// Take a request from `ip`'s bucket; the seconds until the next one is allowed if it is empty
+    fn request(&self, ip: IpAddr) -> Result<(), u64> {
+        if self.request_rate <= 0.0 || ip.is_loopback() {
+            return Ok(());
+        }
+        let mut clients = self.lock();
+        let client = clients.entry(ip).or_insert_with(|| self.new_client());
+        client.tokens = self.refilled_tokens(client);
+        client.refilled = Instant::now();
+        if client.tokens >= 1.0 {
+            client.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - client.tokens) / self.request_rate).ceil() as u64)
+        }
+    }
+
+    fn new_client(&self) -> Client {
+        Client {
+            connections: 0,
+            tokens: self.request_burst as f64,
+            refilled: Instant::now(),
+        }
+    }
+
+    fn refilled_tokens(&self, client: &Client) -> f64 {
+        let refill = client.refilled.elapsed().as_secs_f64() * self.request_rate;
+        (client.tokens + refill).min(self.request_burst as f64)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<IpAddr, Client>> {
+        self.clients.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Counts a connection against its client while it is open
+pub struct ConnectionPermit {
+    limits: IpLimits,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        if let Some(client) = self.limits.lock().get_mut(&self.ip) {
+            client.connections = client.connections.saturating_sub(1);
+        }
+    }
+}
+
+/// Answer requests over a client's rate with 429 Too Many Requests
+pub async fn limit_requests(
+    State(limits): State<IpLimits>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match limits.request(addr.ip()) {
+        Ok(()) => next.run(request).await,
+        Err(retry_after) => (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, retry_after.max(1).to_string())],
+            "Too many requests",
+        ).into_response(),
+    }
+}
+
+/// TCP listener closing connections of filtered clients and of clients over the connection limit
+pub struct LimitedListener {
+    listener: MultiListener,
+    limits: IpLimits,
+    filter: IpFilter,
+    audit: SecurityAudit,
+}
+
+impl LimitedListener {
+    pub fn new(listener: MultiListener, limits: IpLimits, filter: IpFilter) -> Self {
+        Self {
+            listener,
+            limits,
+            filter,
+            audit: SecurityAudit::default(),
+        }
+    }
+
+    /// Record accepted and refused connections in the security audit trail
+    pub fn with_security_audit(mut self, audit: SecurityAudit) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    fn refuse(&self, addr: SocketAddr, reason: &str) {
+        warn!("Refused connection from {}: {}", addr, reason);
+        self.audit.log(SecurityEvent::Connection {
+            transport: "web",
+            client: addr,
+            refused: Some(reason),
+        });
+    }
+}
+
+impl Listener for LimitedListener {
+    type Io = LimitedStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            let (stream, addr) = Listener::accept(&mut self.listener).await;
+            // Dropping the stream closes it, before any TLS or HTTP is spoken
+            if !self.filter.allows(addr.ip()) {
+                self.refuse(addr, "address not allowed");
+                continue;
+            }
+            match self.limits.connect(addr.ip()) {
+                Some(permit) => {
+                    self.audit.log(SecurityEvent::Connection {
+                        transport: "web",
+                        client: addr,
+                        refused: None,
+                    });
+                    return (LimitedStream { stream, _permit: permit }, addr);
+                }
+                None => self.refuse(addr, "too many connections"),
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.listener.local_addr()
+    }
+}
+
+/// Accepted connection holding its permit
+pub struct LimitedStream {
+    stream: TcpStream,
+    _permit: ConnectionPermit,
+}
+
+impl AsyncRead for LimitedStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for LimitedStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
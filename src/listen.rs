@@ -0,0 +1,93 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// TCP listeners on several addresses for kvm-rs
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use anyhow::{Context, Result};
+#[cfg(feature = "web")]
+use axum::serve::Listener;
+use futures_util::future::select_all;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::warn;
+
+/// Pending connections per listening socket
+const BACKLOG: i32 = 1024;
+/// Pause after a failed accept, e.g. when out of file descriptors
+const ACCEPT_RETRY: Duration = Duration::from_secs(1);
+
+/// Listening sockets on several addresses that accept as one, e.g. [::]:5900
+/// and 0.0.0.0:5900 for dual-stack
+pub struct MultiListener {
+    listeners: Vec<TcpListener>,
+}
+
+impl MultiListener {
+    /// Bind every address. An IPv6 wildcard also bound with an IPv4 address
+    /// on the same port only takes IPv6 connections, so both can be bound
+    pub fn bind(addrs: &[SocketAddr]) -> Result<Self> {
+        if addrs.is_empty() {
+            return Err(anyhow::anyhow!("No address to listen on"));
+        }
+        let listeners = addrs.iter()
+            .map(|addr| {
+                let only_v6 = addr.is_ipv6() && addrs.iter().any(|other| other.is_ipv4() && other.port() == addr.port());
+                bind(*addr, only_v6).with_context(|| format!("Failed to bind to {}", addr))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { listeners })
+    }
+
+    /// Addresses actually bound, with the ports chosen for port 0
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners.iter().filter_map(|listener| listener.local_addr().ok()).collect()
+    }
+
+    /// Next connection on any of the addresses
+    pub async fn accept(&mut self) -> (TcpStream, SocketAddr) {
+        let accepts = self.listeners.iter().map(|listener| Box::pin(accept(listener)));
+        select_all(accepts).await.0
+    }
+}
+
+#[cfg(feature = "web")]
+impl Listener for MultiListener {
+    type Io = TcpStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        MultiListener::accept(self).await
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.listeners[0].local_addr()
+    }
+}
+
+async fn accept(listener: &TcpListener) -> (TcpStream, SocketAddr) {
+    loop {
+        match listener.accept().await {
+            Ok(accepted) => return accepted,
+            Err(e) => {
+                warn!("Failed to accept a connection: {}", e);
+                tokio::time::sleep(ACCEPT_RETRY).await;
+            }
+        }
+    }
+}
+
+fn bind(addr: SocketAddr, only_v6: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if only_v6 {
+        socket.set_only_v6(true)?;
+    }
+    // As tokio does, so a restart can bind while old connections linger in TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
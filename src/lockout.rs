@@ -0,0 +1,135 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Login failure delays and lockout for kvm-rs
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Delay before answering the first failed login; it doubles with every further failure
+const BASE_DELAY: Duration = Duration::from_secs(1);
+/// Longest delay before answering a failed login
+const MAX_DELAY: Duration = Duration::from_secs(16);
+/// Failures are forgotten after this long without another one
+const FAILURE_WINDOW: Duration = Duration::from_secs(15 * 60);
+/// Addresses and accounts counted at once; a guesser trying many names must
+/// not fill the BMC's memory
+const MAX_SUBJECTS: usize = 4096;
+
+/// What failed logins are counted against
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Subject {
+    Address(IpAddr),
+    Account(String),
+}
+
+impl fmt::Display for Subject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Subject::Address(ip) => write!(f, "address {}", ip),
+            Subject::Account(name) => write!(f, "account {}", name),
+        }
+    }
+}
+
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Failed logins per source address and per account: each failure is answered
+/// later than the one before, and after `max_failures` the address or account
+/// is locked out for a while. A max_failures of 0 only delays. Logins without
+/// an account, e.g. a shared password, are counted per address only
+pub struct Lockout {
+    max_failures: u32,
+    duration: Duration,
+    failures: Mutex<HashMap<Subject, Failures>>,
+}
+
+impl Default for Lockout {
+    fn default() -> Self {
+        Self::new(0, Duration::ZERO)
+    }
+}
+
+impl Lockout {
+    pub fn new(max_failures: u32, duration: Duration) -> Self {
+        Self {
+            max_failures,
+            duration,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How much longer logins from `ip` or to `account` are refused, if they are
+    pub fn locked(&self, ip: IpAddr, account: Option<&str>) -> Option<Duration> {
+        let now = Instant::now();
+        let failures = self.lock();
+        Self::subjects(ip, account)
+            .filter_map(|subject| failures.get(&subject)?.locked_until)
+            .filter_map(|until| until.checked_duration_since(now))
+            .max()
+    }
+
+    /// Count a failed login; returns how long to wait before answering it
+    pub fn failed(&self, ip: IpAddr, account: Option<&str>) -> Duration {
+        let now = Instant::now();
+        let mut failures = self.lock();
+        failures.retain(|_, entry| {
+            entry.last.elapsed() < FAILURE_WINDOW || entry.locked_until.is_some_and(|until| until > now)
+        });
+        let mut count = 0;
+        for subject in Self::subjects(ip, account) {
+            if failures.len() >= MAX_SUBJECTS && !failures.contains_key(&subject) {
+                Self::evict_oldest(&mut failures, now);
+            }
+            let entry = failures.entry(subject.clone()).or_insert(Failures {
+                count: 0,
+                last: now,
+                locked_until: None,
+            });
+            entry.count += 1;
+            entry.last = now;
+            if self.max_failures > 0 && entry.count >= self.max_failures && entry.locked_until.is_none_or(|until| until <= now) {
+                warn!("Locking out {} for {}s after {} failed logins", subject, self.duration.as_secs(), entry.count);
+                entry.locked_until = Some(now + self.duration);
+                entry.count = 0;
+            }
+            count = count.max(entry.count);
+        }
+        BASE_DELAY.saturating_mul(1u32 << count.saturating_sub(1).min(4)).min(MAX_DELAY)
+    }
+
+    /// Forget the failures of a successful login
+    pub fn succeeded(&self, ip: IpAddr, account: Option<&str>) {
+        let mut failures = self.lock();
+        for subject in Self::subjects(ip, account) {
+            failures.remove(&subject);
+        }
+    }
+
+    fn subjects(ip: IpAddr, account: Option<&str>) -> impl Iterator<Item = Subject> {
+        std::iter::once(Subject::Address(ip.to_canonical()))
+            .chain(account.map(|account| Subject::Account(account.to_string())))
+    }
+
+    /// Make room by forgetting the subject that failed longest ago, preferring
+    /// one that is not locked out
+    fn evict_oldest(failures: &mut HashMap<Subject, Failures>, now: Instant) {
+        let oldest = failures.iter()
+            .min_by_key(|(_, entry)| (entry.locked_until.is_some_and(|until| until > now), entry.last))
+            .map(|(subject, _)| subject.clone());
+        if let Some(subject) = oldest {
+            failures.remove(&subject);
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<Subject, Failures>> {
+        self.failures.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
@@ -0,0 +1,65 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Log output and levels for kvm-rs
+
+use anyhow::{Context, Result};
+use tracing_subscriber::{fmt, layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Registry};
+
+/// Where log messages go
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogTarget {
+    /// Formatted lines on standard output
+    Stdout,
+    /// Structured entries through journald's native protocol
+    Journald,
+}
+
+impl LogTarget {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogTarget::Stdout => "stdout",
+            LogTarget::Journald => "journald",
+        }
+    }
+}
+
+/// Level filter of the log, changeable while running
+#[derive(Clone)]
+pub struct LogLevel {
+    handle: reload::Handle<EnvFilter, Registry>,
+}
+
+impl LogLevel {
+    /// Log to `target` what `filter` lets through: a level such as
+    /// "info", optionally with per-module levels ("info,kvm_rs::vnc=debug")
+    pub fn init(filter: &str, target: LogTarget) -> Result<Self> {
+        let (filter, handle) = reload::Layer::new(parse_filter(filter)?);
+        let stdout = (target == LogTarget::Stdout).then(fmt::layer);
+        #[cfg(target_os = "linux")]
+        let journald = match target {
+            LogTarget::Journald => Some(crate::journald::JournaldLayer::connect()?),
+            LogTarget::Stdout => None,
+        };
+        #[cfg(not(target_os = "linux"))]
+        let journald: Option<tracing_subscriber::layer::Identity> = match target {
+            LogTarget::Journald => anyhow::bail!("journald logging is only supported on Linux"),
+            LogTarget::Stdout => None,
+        };
+        tracing_subscriber::registry()
+            .with(filter)
+            .with(stdout)
+            .with(journald)
+            .try_init()
+            .context("Failed to set up logging")?;
+        Ok(Self { handle })
+    }
+
+    /// Let other messages through from now on
+    pub fn set(&self, filter: &str) -> Result<()> {
+        self.handle.reload(parse_filter(filter)?).context("Failed to change the log level")
+    }
+}
+
+fn parse_filter(filter: &str) -> Result<EnvFilter> {
+    EnvFilter::try_new(filter).with_context(|| format!("Invalid log level {}", filter))
+}
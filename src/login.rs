@@ -0,0 +1,266 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Login with OpenBMC accounts and cookie sessions for kvm-rs
+
+use std::collections::HashMap;
+#[cfg(feature = "web")]
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result};
+#[cfg(feature = "web")]
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap},
+    middleware::Next,
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Form, Router,
+};
+#[cfg(feature = "web")]
+use serde::Deserialize;
+use tracing::{info, warn};
+use crate::{auth::UserRole, credentials, ldap::LdapDirectory, user_manager::{self, UserManager}};
+#[cfg(feature = "web")]
+use crate::auth::{AuthError, Authenticator, Principal};
+
+/// Cookie holding the kvm-rs session token
+#[cfg(feature = "web")]
+pub const SESSION_COOKIE: &str = "KVM-RS-SESSION";
+
+/// Password hashes of the BMC's local accounts
+const SHADOW_FILE: &str = "/etc/shadow";
+/// Group memberships, which hold the OpenBMC privilege of each account
+const GROUP_FILE: &str = "/etc/group";
+
+#[cfg(feature = "web")]
+const LOGIN_HTML: &str = include_str!("../static/login.html");
+
+/// Sessions issued by the login page
+pub struct LocalSessions {
+    /// Sessions unused for this long expire
+    timeout: Duration,
+    /// Account privileges and state, else the privilege groups are read
+    users: Option<UserManager>,
+    /// Directory accounts that are not local are checked against
+    ldap: Option<LdapDirectory>,
+    sessions: Mutex<HashMap<String, LocalSession>>,
+}
+
+struct LocalSession {
+    username: String,
+    role: UserRole,
+    last_used: Instant,
+}
+
+impl LocalSessions {
+    pub fn new(timeout: Duration, users: Option<UserManager>, ldap: Option<LdapDirectory>) -> Self {
+        Self {
+            timeout,
+            users,
+            ldap,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Check the password of a local account with an OpenBMC privilege, or
+    /// else of a directory account with a mapped group; returns a new session
+    /// token and the account's role
+    pub async fn login(&self, username: String, password: String) -> Result<Option<(String, UserRole)>> {
+        let (name, secret) = (username.clone(), password.clone());
+        let local = tokio::task::spawn_blocking(move || verify_password(Path::new(SHADOW_FILE), &name, &secret)).await??;
+        let role = match (local, &self.ldap) {
+            (true, _) => self.account_role(&username).await?,
+            // As pam_ldap after pam_unix
+            (false, Some(ldap)) => ldap.login(&username, &password).await?,
+            (false, None) => None,
+        };
+        let Some(role) = role else {
+            return Ok(None);
+        };
+        let token = credentials::random_token()?;
+        info!("Console login by {} ({})", username, role.as_str());
+        self.lock().insert(token.clone(), LocalSession {
+            username,
+            role,
+            last_used: Instant::now(),
+        });
+        Ok(Some((token, role)))
+    }
+
+    /// Role of an enabled, unlocked account from phosphor-user-manager, or
+    /// from its privilege group where the user manager cannot be asked
+    async fn account_role(&self, username: &str) -> Result<Option<UserRole>> {
+        if let Some(ref users) = self.users {
+            match users.console_role(username).await {
+                Ok(role) => return Ok(role),
+                Err(e) => warn!("{:#}, reading privilege groups from {}", e, GROUP_FILE),
+            }
+        }
+        let username = username.to_string();
+        let role = tokio::task::spawn_blocking(move || {
+            let role = group_role(Path::new(GROUP_FILE), &username)?;
+            if role.is_none() {
+                warn!("Refused console login by {}: account has no console privilege", username);
+            }
+            anyhow::Ok(role)
+        })
+        .await??;
+        Ok(role)
+    }
+
+    /// User and role of the live session the token belongs to; using it keeps the session alive
+    pub fn validate(&self, token: &str) -> Option<(String, UserRole)> {
+        let mut sessions = self.lock();
+        sessions.retain(|_, session| session.last_used.elapsed() < self.timeout);
+        let session = sessions.get_mut(token)?;
+        session.last_used = Instant::now();
+        Some((session.username.clone(), session.role))
+    }
+
+    pub fn logout(&self, token: &str) {
+        if let Some(session) = self.lock().remove(token) {
+            info!("Console logout by {}", session.username);
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, LocalSession>> {
+        self.sessions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(feature = "web")]
+#[derive(Clone)]
+struct LoginState {
+    auth: Authenticator,
+    /// Mark the cookie Secure when the web server only speaks HTTPS
+    secure: bool,
+}
+
+#[cfg(feature = "web")]
+#[derive(Deserialize)]
+struct LoginForm {
+    username: String,
+    password: String,
+}
+
+/// Login page and form handlers; empty unless `auth` issues local sessions
+#[cfg(feature = "web")]
+pub fn router(auth: &Authenticator, secure: bool) -> Router {
+    if auth.local_sessions().is_none() {
+        return Router::new();
+    }
+    Router::new()
+        .route("/login", get(login_page).post(login))
+        .route("/logout", post(logout))
+        .with_state(LoginState {
+            auth: auth.clone(),
+            secure,
+        })
+}
+
+/// Send browsers without a valid session to the login page
+#[cfg(feature = "web")]
+pub async fn require_login(
+    State(auth): State<Authenticator>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match auth.check(request.headers()).await {
+        Ok(access) => {
+            let mut response = next.run(request).await;
+            if let Some(principal) = access.principal {
+                response.extensions_mut().insert(principal);
+            }
+            response
+        }
+        Err(_) => Redirect::to("/login").into_response(),
+    }
+}
+
+/// GET /login - login form
+#[cfg(feature = "web")]
+async fn login_page() -> Html<&'static str> {
+    Html(LOGIN_HTML)
+}
+
+/// POST /login - check the credentials and set the session cookie
+#[cfg(feature = "web")]
+async fn login(
+    State(state): State<LoginState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Form(form): Form<LoginForm>,
+) -> Response {
+    match state.auth.login(&form.username, &form.password, addr.ip(), "web").await {
+        Ok((token, _)) => {
+            let secure = if state.secure { "; Secure" } else { "" };
+            let cookie = format!("{}={}; Path=/; HttpOnly; SameSite=Strict{}", SESSION_COOKIE, token, secure);
+            let mut response = ([(header::SET_COOKIE, cookie)], Redirect::to("/")).into_response();
+            response.extensions_mut().insert(Principal(form.username));
+            response
+        }
+        Err(AuthError::LockedOut(_)) => Redirect::to("/login?locked").into_response(),
+        Err(e) if !e.is_recoverable() => Redirect::to("/login?failed").into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// POST /logout - end the session and clear the cookie
+#[cfg(feature = "web")]
+async fn logout(State(state): State<LoginState>, headers: HeaderMap) -> Response {
+    if let Some(token) = session_cookie(&headers) {
+        state.auth.logout(&token);
+    }
+    let cookie = format!("{}=; Path=/; HttpOnly; SameSite=Strict; Max-Age=0", SESSION_COOKIE);
+    ([(header::SET_COOKIE, cookie)], Redirect::to("/login")).into_response()
+}
+
+/// Token of the kvm-rs session cookie
+#[cfg(feature = "web")]
+pub fn session_cookie(headers: &HeaderMap) -> Option<String> {
+    headers.get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, value)| value.to_string())
+}
+
+/// Role of an account from its OpenBMC privilege group: priv-admin,
+/// priv-operator or priv-user (ReadOnly). None for accounts without one
+fn group_role(groups: &Path, username: &str) -> Result<Option<UserRole>> {
+    let groups = std::fs::read_to_string(groups)
+        .with_context(|| format!("Failed to read {}", groups.display()))?;
+    let role = groups.lines()
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next()?;
+            let members = fields.nth(2)?;
+            members.split(',').any(|member| member == username).then_some(name)
+        })
+        .filter_map(user_manager::privilege_role)
+        .max();
+    Ok(role)
+}
+
+/// Check a password against the account's hash in the shadow file
+fn verify_password(shadow: &Path, username: &str, password: &str) -> Result<bool> {
+    let shadow = std::fs::read_to_string(shadow)
+        .with_context(|| format!("Failed to read {}", shadow.display()))?;
+    let hash = shadow.lines()
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            Some((fields.next()?, fields.next()?))
+        })
+        .find(|(name, _)| *name == username)
+        .map(|(_, hash)| hash);
+    match hash {
+        // Locked ("!...") and password-less ("*", empty) accounts cannot log in
+        Some(hash) if !hash.is_empty() && !hash.starts_with('!') && !hash.starts_with('*') => {
+            Ok(pwhash::unix::verify(password, hash))
+        }
+        _ => Ok(false),
+    }
+}
@@ -0,0 +1,164 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Keyboard macro recording and storage for kvm-rs
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+/// One key transition of a macro
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MacroStep {
+    /// HID keyboard usage
+    pub usage: u8,
+    pub down: bool,
+    /// Delay before this step in milliseconds
+    #[serde(default)]
+    pub delay_ms: u64,
+}
+
+struct Recording {
+    name: String,
+    steps: Vec<MacroStep>,
+    last_event: Instant,
+}
+
+#[derive(Default)]
+struct MacroStoreInner {
+    macros: BTreeMap<String, Vec<MacroStep>>,
+    recording: Option<Recording>,
+}
+
+/// Named keyboard macros, optionally persisted to a JSON file
+#[derive(Clone, Default)]
+pub struct MacroStore {
+    inner: Arc<Mutex<MacroStoreInner>>,
+    path: Option<PathBuf>,
+}
+
+impl MacroStore {
+    /// Load macros from `path` if it exists; changes are saved back to it
+    pub fn load(path: Option<String>) -> Result<Self> {
+        let mut macros = BTreeMap::new();
+        if let Some(ref path) = path {
+            if std::path::Path::new(path).exists() {
+                let data = std::fs::read(path)
+                    .with_context(|| format!("Failed to read macro file: {}", path))?;
+                macros = serde_json::from_slice(&data)
+                    .with_context(|| format!("Failed to parse macro file: {}", path))?;
+                info!("Loaded {} keyboard macros from {}", macros.len(), path);
+            }
+        }
+
+        Ok(Self {
+            inner: Arc::new(Mutex::new(MacroStoreInner {
+                macros,
+                recording: None,
+            })),
+            path: path.map(PathBuf::from),
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MacroStoreInner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Start recording key events into the macro `name`
+    pub fn start_recording(&self, name: &str) -> Result<()> {
+        validate_name(name)?;
+        let mut inner = self.lock();
+        if let Some(ref recording) = inner.recording {
+            return Err(anyhow::anyhow!("Already recording macro '{}'", recording.name));
+        }
+        inner.recording = Some(Recording {
+            name: name.to_string(),
+            steps: Vec::new(),
+            last_event: Instant::now(),
+        });
+        info!("Recording keyboard macro '{}'", name);
+        Ok(())
+    }
+
+    /// Finish the current recording and store it; returns the name and step count
+    pub fn stop_recording(&self) -> Result<(String, usize)> {
+        let mut inner = self.lock();
+        let recording = inner.recording.take()
+            .ok_or_else(|| anyhow::anyhow!("No macro is being recorded"))?;
+        let count = recording.steps.len();
+        inner.macros.insert(recording.name.clone(), recording.steps);
+        self.save(&inner)?;
+        info!("Recorded keyboard macro '{}' with {} steps", recording.name, count);
+        Ok((recording.name, count))
+    }
+
+    /// Append a key event to the macro being recorded, if any
+    pub fn record_key(&self, usage: u8, down: bool) {
+        let mut inner = self.lock();
+        if let Some(ref mut recording) = inner.recording {
+            let now = Instant::now();
+            let delay_ms = if recording.steps.is_empty() {
+                0
+            } else {
+                now.duration_since(recording.last_event).as_millis() as u64
+            };
+            recording.last_event = now;
+            recording.steps.push(MacroStep { usage, down, delay_ms });
+        }
+    }
+
+    /// Name of the macro being recorded
+    pub fn recording(&self) -> Option<String> {
+        self.lock().recording.as_ref().map(|r| r.name.clone())
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.lock().macros.keys().cloned().collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<Vec<MacroStep>> {
+        self.lock().macros.get(name).cloned()
+    }
+
+    /// Define or replace a macro
+    pub fn insert(&self, name: &str, steps: Vec<MacroStep>) -> Result<()> {
+        validate_name(name)?;
+        let mut inner = self.lock();
+        inner.macros.insert(name.to_string(), steps);
+        self.save(&inner)
+    }
+
+    /// Delete a macro; returns false if it did not exist
+    pub fn remove(&self, name: &str) -> Result<bool> {
+        let mut inner = self.lock();
+        let removed = inner.macros.remove(name).is_some();
+        if removed {
+            self.save(&inner)?;
+        }
+        Ok(removed)
+    }
+
+    fn save(&self, inner: &MacroStoreInner) -> Result<()> {
+        let Some(ref path) = self.path else {
+            return Ok(());
+        };
+        let data = serde_json::to_vec_pretty(&inner.macros)?;
+        std::fs::write(path, data)
+            .with_context(|| format!("Failed to write macro file: {}", path.display()))
+    }
+}
+
+/// Macro names are used in URLs, keep them simple
+fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!("Macro names must be 1-64 characters of A-Z, a-z, 0-9, '-' or '_'"))
+    }
+}
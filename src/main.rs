@@ -5,89 +5,203 @@
 // Build: cargo build --release --target armv7-unknown-linux-gnueabihf
 // Run  : systemd unit (ver §4)
 
+// Without the web server, much of what the REST API and WebSocket consoles
+// call is left unused
+#![cfg_attr(not(feature = "web"), allow(dead_code))]
+
+#[cfg(feature = "web")]
+mod access_log;
+#[cfg(feature = "web")]
+mod api;
+mod arbiter;
 mod args;
+mod audit;
+mod auth;
+mod bandwidth;
+#[cfg(feature = "tls")]
+mod cert_manager;
+mod clipboard;
+mod commands;
+mod control;
+mod credentials;
+#[cfg(feature = "web")]
+mod cors;
 mod display;
+mod events;
+mod frame_budget;
+mod frame_dump;
+mod gadget;
 mod hid;
+mod hid_backend;
+mod hid_stats;
+mod hid_descriptor;
+mod host_agent;
+#[cfg(feature = "web")]
+mod https;
+mod ip_filter;
+#[cfg(target_os = "linux")]
+mod journald;
+mod keyboard;
+mod ldap;
+#[cfg(feature = "web")]
+mod limits;
+mod listen;
+mod lockout;
+mod logging;
+mod login;
+mod macros;
+mod mdns;
+mod media;
+#[cfg(feature = "web")]
+mod mjpeg;
+mod mock_capture;
+#[cfg(all(target_os = "linux", feature = "web"))]
+mod nbd;
+mod obmc_ikvm;
+mod openbmc_media;
+#[cfg(feature = "web")]
+mod origin;
+mod platform;
+mod pointer;
+mod power;
+mod privileges;
+mod probe;
+mod reload;
+mod remote_media;
+#[cfg(all(test, feature = "vnc"))]
+mod rfb_tests;
+#[cfg(feature = "web")]
+mod rtc;
+mod security_audit;
+mod server;
+mod services;
+mod sessions;
+mod shutdown;
+#[cfg(feature = "web")]
+mod sol;
+mod supervisor;
+mod systemd;
+mod targets;
+#[cfg(all(test, feature = "vnc"))]
+mod test_support;
+#[cfg(feature = "tls")]
+mod tls;
+mod transport;
+#[cfg(all(unix, feature = "web"))]
+mod unix_socket;
+mod user_manager;
 mod vnc;
+mod vnc_password;
+mod virtual_media;
+#[cfg(feature = "web")]
+mod web;
+#[cfg(feature = "web")]
+mod webhooks;
+#[cfg(feature = "web")]
 mod websocket;
+#[cfg(feature = "webtransport")]
+mod webtransport;
+#[cfg(feature = "web")]
+mod ws_protocol;
 
-use axum::{routing::get, Router};
-use clap::Parser;
+use anyhow::Context;
+use tracing::info;
 #[cfg(target_os = "linux")]
 use zbus::Connection;
 
-use args::Args;
-use display::DisplayHub;
-use hid::HidManager;
-use vnc::VncHandler;
-use websocket::kvm_ws;
+use args::{Args, Command};
+use credentials::CredentialStore;
+use logging::LogLevel;
+use macros::MacroStore;
+use server::KvmServerBuilder;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    // wtransport enables rustls' ring provider next to the default aws-lc-rs,
+    // after which rustls no longer picks one by itself
+    #[cfg(feature = "tls")]
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+
     // Parse command line arguments
-    let args = Args::parse();
+    let args = Args::load()?;
+    // HID gadgets only exist on Linux; elsewhere reports are logged
+    #[cfg(not(target_os = "linux"))]
+    let args = Args { mock_hid: true, ..args };
+    let log_level = LogLevel::init(&args.log_level, args.log_target)?;
 
-    // Print configuration and validate devices
-    args.print_config();
-    args.validate_devices();
+    // Secrets kept encrypted instead of in files or on the command line
+    let mut credentials = match args.credential_store {
+        Some(ref path) => Some(CredentialStore::open(path.as_ref(), args.device_secret.as_ref())?),
+        None => None,
+    };
+    if let Some(credential) = args.set_credential {
+        let store = credentials.as_mut()
+            .ok_or_else(|| anyhow::anyhow!("--set-credential needs --credential-store"))?;
+        let mut value = String::new();
+        std::io::stdin().read_line(&mut value).context("Failed to read the credential from stdin")?;
+        store.set(credential, value.trim_end_matches(['\r', '\n']))?;
+        println!("Stored {} in the credential store", credential.as_str());
+        return Ok(());
+    }
 
-    // 1. Conecta a DBus para verificar sesión válida (Redfish) - optional for development
-    #[cfg(target_os = "linux")]
-    {
-        println!("Target OS: Linux, connecting to D-Bus...");
-        let _dbus: Connection = Connection::system().await?;
+    if let Some(format) = args.dump_config {
+        return commands::dump_config(format);
     }
-    #[cfg(not(target_os = "linux"))]
-    {
-        println!("Note: D-Bus connection skipped on non-Linux systems");
+
+    if args.check {
+        return commands::check(&args).await;
     }
 
-    // 2. Framebuffer broadcaster
-    let hub = DisplayHub::new();
-    let video_device = args.video_device.clone();
-    let force_framebuffer = args.force_framebuffer;
-    tokio::spawn(hub.clone().spawn(video_device, force_framebuffer));
+    // One-shot commands for debugging on the BMC
+    match args.command {
+        Some(Command::Screenshot { ref file, quality }) => return commands::screenshot(&args, file, quality).await,
+        Some(Command::InjectKeys { ref text, combo }) => {
+            let backends = server::default_hid_backends(&args);
+            let hid_manager = server::console_hid(&args, &backends, &args.keyboard_hid, &args.mouse_hid, None, &MacroStore::default())?;
+            return commands::inject_keys(&hid_manager, text, combo).await;
+        }
+        Some(Command::Probe) => return commands::probe(&args),
+        Some(Command::CheckConfig) => return commands::check_config(&args).await,
+        Some(Command::Serve) | None => {}
+    }
 
-    // 3. HID manager
-    let hid_manager = HidManager::new(args.keyboard_hid.clone(), args.mouse_hid.clone());
+    // Print configuration; missing devices are handled by the builder
+    args.print_config();
+    args.validate_files();
 
-    // 4. VNC server with optional TLS encryption
-    let vnc_handler = if args.vnc_tls {
-        VncHandler::new_with_tls(
-            hub.clone(), 
-            hid_manager.clone(), 
-            args.vnc_cert.clone(), 
-            args.vnc_key.clone()
-        ).await?
-    } else {
-        VncHandler::new(hub.clone(), hid_manager.clone())
-    };
-    
-    let vnc_bind_addr = args.bind_address.clone();
-    let vnc_port = args.vnc_port;
-    tokio::spawn(async move {
-        if let Err(e) = vnc_handler.start_vnc_server(vnc_bind_addr, vnc_port).await {
-            eprintln!("VNC server error: {}", e);
-        }
-    });
+    // Root is given up once the listeners are bound and the devices found
+    let (user, group, seccomp) = (args.user.clone(), args.group.clone(), args.seccomp);
+    let devices = args.devices(!args.uses_mock_video(), !args.mock_hid);
 
-    // 5. Servidor HTTP → WS
-    let app = Router::new()
-        .route("/kvm/0", get({
-            let h = hub.clone();
-            let hid_mgr = hid_manager.clone();
-            move |ws| kvm_ws(ws, h, hid_mgr)
-        }));
+    // 1. D-Bus for host state notifications, account lookups and certificates; bmcweb
+    // sessions are validated against bmcweb itself
+    let mut builder = KvmServerBuilder::new(args).with_log_level(log_level);
+    #[cfg(target_os = "linux")]
+    {
+        info!("Target OS: Linux, connecting to D-Bus...");
+        builder = builder.with_dbus(Connection::system().await?);
+    }
+    #[cfg(not(target_os = "linux"))]
+    info!("Note: D-Bus connection skipped on non-Linux systems");
+    if let Some(credentials) = credentials {
+        builder = builder.with_credentials(credentials);
+    }
+    let server = builder.build().await?;
+    if let Some(ref user) = user {
+        privileges::drop_privileges(user, group.as_deref())?;
+        privileges::warn_inaccessible(&devices);
+    }
+    if seccomp {
+        privileges::apply_seccomp()?;
+    }
 
-    println!("KVM‑RS WebSocket listening on {}:{}", args.bind_address, args.port);
-    
-    // Create TCP listener with configurable address and port
-    let bind_addr = format!("{}:{}", args.bind_address, args.port);
-    let listener = tokio::net::TcpListener::bind(&bind_addr).await
-        .map_err(|e| anyhow::anyhow!("Failed to bind to {}: {}", bind_addr, e))?;
-    
-    // Start the server using axum::serve
-    axum::serve(listener, app).await?;
+    // Stop accepting clients and drain sessions on SIGTERM
+    tokio::spawn(server.shutdown().clone().on_signal());
+    tokio::spawn(server.reloader().clone().on_signal());
+    // The listeners are bound by now
+    tokio::spawn(systemd::notify_ready(server.hub().clone()));
 
+    server.run().await?;
+    info!("KVM-RS stopped");
     Ok(())
 }
@@ -0,0 +1,132 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// DNS-SD advertisement of the consoles through Avahi for kvm-rs
+
+use std::net::SocketAddr;
+use anyhow::{Context, Result};
+use futures_util::StreamExt;
+use tracing::{info, warn};
+use zbus::{proxy::SignalStream, zvariant::OwnedObjectPath};
+
+const AVAHI_SERVICE: &str = "org.freedesktop.Avahi";
+const AVAHI_SERVER_INTERFACE: &str = "org.freedesktop.Avahi.Server";
+const AVAHI_ENTRY_GROUP_INTERFACE: &str = "org.freedesktop.Avahi.EntryGroup";
+/// AVAHI_IF_UNSPEC and AVAHI_PROTO_UNSPEC: every interface, over IPv4 and IPv6
+const UNSPEC: i32 = -1;
+/// States of the server and of entry groups
+const STATE_REGISTERING: i32 = 1;
+const STATE_RUNNING: i32 = 2;
+const STATE_COLLISION: i32 = 3;
+const STATE_FAILURE: i32 = 4;
+
+/// A service of kvm-rs as DNS-SD browsers list it
+#[derive(Debug, Clone)]
+pub struct Advertisement {
+    /// Instance name, e.g. the BMC's hostname
+    pub name: String,
+    /// Service type, e.g. `_rfb._tcp`
+    pub service_type: &'static str,
+    pub port: u16,
+    /// TXT record entries, `key=value`
+    pub txt: Vec<String>,
+}
+
+impl Advertisement {
+    pub fn new(name: impl Into<String>, service_type: &'static str, port: u16) -> Self {
+        Self {
+            name: name.into(),
+            service_type,
+            port,
+            txt: Vec::new(),
+        }
+    }
+
+    pub fn with_txt(mut self, entry: impl Into<String>) -> Self {
+        self.txt.push(entry.into());
+        self
+    }
+}
+
+/// The BMC's hostname, lower case
+pub fn system_hostname() -> Option<String> {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+        .ok()
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+}
+
+/// The first port of `addrs` that is bound to more than loopback, which other
+/// hosts can connect to
+pub fn reachable_port(addrs: &[SocketAddr]) -> Option<u16> {
+    addrs.iter().find(|addr| !addr.ip().is_loopback()).map(SocketAddr::port)
+}
+
+/// Keep `services` published by the Avahi daemon while kvm-rs runs: again
+/// after Avahi restarts or the host name changes, and under an alternative
+/// name when another host on the LAN advertises the same one
+pub async fn advertise(dbus: zbus::Connection, mut services: Vec<Advertisement>) -> Result<()> {
+    let server = zbus::Proxy::new(&dbus, AVAHI_SERVICE, "/", AVAHI_SERVER_INTERFACE).await?;
+    let mut server_states = server.receive_signal("StateChanged").await?;
+    loop {
+        let state: i32 = server.call("GetState", &()).await.context("Avahi is not running")?;
+        if state != STATE_RUNNING {
+            next_state(&mut server_states, &[STATE_RUNNING]).await?;
+        }
+        let (group, mut group_states) = publish(&dbus, &server, &services).await?;
+        tokio::select! {
+            state = next_state(&mut group_states, &[STATE_COLLISION, STATE_FAILURE]) => match state? {
+                (STATE_COLLISION, _) => {
+                    for service in &mut services {
+                        let name: String = server.call("GetAlternativeServiceName", &(service.name.as_str(),)).await?;
+                        warn!("mDNS name '{}' is taken on the network, advertising '{}'", service.name, name);
+                        service.name = name;
+                    }
+                }
+                (_, error) => anyhow::bail!("Avahi failed to advertise the services: {}", error),
+            },
+            // The records are gone, and are published again once the server runs
+            state = next_state(&mut server_states, &[STATE_REGISTERING, STATE_COLLISION, STATE_FAILURE]) => {
+                state?;
+            }
+        }
+        let _ = group.call::<_, _, ()>("Free", &()).await;
+    }
+}
+
+/// Add `services` to a new entry group and commit it
+async fn publish(
+    dbus: &zbus::Connection,
+    server: &zbus::Proxy<'_>,
+    services: &[Advertisement],
+) -> Result<(zbus::Proxy<'static>, SignalStream<'static>)> {
+    let path: OwnedObjectPath = server.call("EntryGroupNew", &()).await?;
+    let group = zbus::Proxy::new_owned(dbus.clone(), AVAHI_SERVICE, path, AVAHI_ENTRY_GROUP_INTERFACE).await?;
+    // Subscribed first, so no state change is missed
+    let states = group.receive_signal("StateChanged").await?;
+    for service in services {
+        let txt: Vec<Vec<u8>> = service.txt.iter().map(|entry| entry.as_bytes().to_vec()).collect();
+        // Empty domain and host: .local and the host name Avahi announces
+        group.call::<_, _, ()>("AddService", &(
+            UNSPEC, UNSPEC, 0u32,
+            service.name.as_str(), service.service_type, "", "",
+            service.port, txt,
+        )).await.with_context(|| format!("Failed to add {} on port {}", service.service_type, service.port))?;
+    }
+    group.call::<_, _, ()>("Commit", &()).await?;
+    for service in services {
+        info!("Advertising {} '{}' on port {} over mDNS", service.service_type, service.name, service.port);
+    }
+    Ok((group, states))
+}
+
+/// The first state in `wanted` a StateChanged signal reports, with its error
+async fn next_state(states: &mut SignalStream<'_>, wanted: &[i32]) -> Result<(i32, String)> {
+    while let Some(message) = states.next().await {
+        let (state, error): (i32, String) = message.body().deserialize()?;
+        if wanted.contains(&state) {
+            return Ok((state, error));
+        }
+    }
+    anyhow::bail!("D-Bus connection closed")
+}
@@ -0,0 +1,280 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Virtual media image uploads for kvm-rs
+
+use std::path::PathBuf;
+#[cfg(feature = "web")]
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::collections::HashSet;
+use anyhow::{anyhow, Context, Result};
+#[cfg(feature = "web")]
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router,
+};
+#[cfg(feature = "web")]
+use futures_util::StreamExt;
+use serde::Serialize;
+#[cfg(feature = "web")]
+use serde::Deserialize;
+#[cfg(feature = "web")]
+use tokio::io::AsyncWriteExt;
+#[cfg(feature = "web")]
+use tracing::info;
+
+/// Suffix of images whose upload has not finished
+const PARTIAL_SUFFIX: &str = ".part";
+
+/// Disk images kept for attachment as virtual media
+pub struct MediaStore {
+    dir: PathBuf,
+    /// Largest image accepted, in bytes
+    max_size: u64,
+    /// Images a request is currently writing to
+    uploading: Mutex<HashSet<String>>,
+    /// Image inserted in the virtual drive, which cannot be removed
+    inserted: Mutex<Option<String>>,
+}
+
+/// A stored or partially uploaded image
+#[derive(Debug, Serialize)]
+pub struct MediaImage {
+    pub name: String,
+    /// Bytes stored so far; an interrupted upload resumes at this offset
+    pub size: u64,
+    pub complete: bool,
+}
+
+impl MediaStore {
+    pub fn open(dir: &str, max_size: u64) -> Result<Self> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create media directory {}", dir))?;
+        Ok(Self {
+            dir: PathBuf::from(dir),
+            max_size,
+            uploading: Mutex::new(HashSet::new()),
+            inserted: Mutex::new(None),
+        })
+    }
+
+    /// Absolute path of the complete image `name`
+    pub fn image_path(&self, name: &str) -> Result<PathBuf> {
+        validate_name(name)?;
+        let path = self.dir.join(name);
+        if !path.is_file() {
+            return Err(anyhow!("Unknown image '{}'", name));
+        }
+        path.canonicalize().with_context(|| format!("Failed to resolve {}", path.display()))
+    }
+
+    /// Name of the stored image at `path`, if it is one
+    pub fn image_name(&self, path: &std::path::Path) -> Option<String> {
+        let name = path.file_name()?.to_str()?;
+        let stored = self.image_path(name).ok()?;
+        (path.canonicalize().ok()? == stored).then(|| name.to_string())
+    }
+
+    /// Record the image inserted in the virtual drive, None once ejected
+    pub fn set_inserted(&self, name: Option<&str>) {
+        *self.inserted.lock().unwrap_or_else(|e| e.into_inner()) = name.map(str::to_string);
+    }
+
+    fn is_inserted(&self, name: &str) -> bool {
+        self.inserted.lock().unwrap_or_else(|e| e.into_inner()).as_deref() == Some(name)
+    }
+
+    pub fn list(&self) -> Result<Vec<MediaImage>> {
+        let mut images = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let Ok(file_name) = entry.file_name().into_string() else {
+                continue;
+            };
+            let (name, complete) = match file_name.strip_suffix(PARTIAL_SUFFIX) {
+                Some(name) => (name.to_string(), false),
+                None => (file_name, true),
+            };
+            if validate_name(&name).is_ok() {
+                images.push(MediaImage {
+                    name,
+                    size: entry.metadata()?.len(),
+                    complete,
+                });
+            }
+        }
+        images.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(images)
+    }
+
+    fn remove(&self, name: &str) -> Result<bool> {
+        validate_name(name)?;
+        let mut removed = false;
+        for path in [self.dir.join(name), self.partial_path(name)] {
+            match std::fs::remove_file(&path) {
+                Ok(()) => removed = true,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e).with_context(|| format!("Failed to remove {}", path.display())),
+            }
+        }
+        Ok(removed)
+    }
+
+    fn partial_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}{}", name, PARTIAL_SUFFIX))
+    }
+}
+
+/// Query of an upload chunk
+#[cfg(feature = "web")]
+#[derive(Debug, Deserialize)]
+struct ChunkQuery {
+    /// Where the chunk starts; must be the size stored so far
+    #[serde(default)]
+    offset: u64,
+    /// Size of the whole image; the upload completes when it is reached
+    total: u64,
+}
+
+/// Upload state handed back after every chunk
+#[cfg(feature = "web")]
+#[derive(Debug, Serialize)]
+struct UploadStatus {
+    name: String,
+    size: u64,
+    total: u64,
+    complete: bool,
+}
+
+/// Routes of the image store under /api/v1/media
+#[cfg(feature = "web")]
+pub fn router(store: Arc<MediaStore>) -> Router {
+    Router::new()
+        .route("/api/v1/media/images", get(list_images))
+        .route("/api/v1/media/images/{name}", post(upload_chunk).delete(delete_image))
+        .with_state(store)
+}
+
+/// GET /api/v1/media/images - stored images and unfinished uploads
+#[cfg(feature = "web")]
+async fn list_images(State(store): State<Arc<MediaStore>>) -> Result<Json<Vec<MediaImage>>, (StatusCode, String)> {
+    store.list()
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// POST /api/v1/media/images/{name}?offset=N&total=T - append a chunk to an upload
+#[cfg(feature = "web")]
+async fn upload_chunk(
+    State(store): State<Arc<MediaStore>>,
+    Path(name): Path<String>,
+    Query(query): Query<ChunkQuery>,
+    body: Body,
+) -> Result<Json<UploadStatus>, (StatusCode, String)> {
+    validate_name(&name).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    if query.total > store.max_size {
+        return Err((StatusCode::PAYLOAD_TOO_LARGE, format!("Images are limited to {} bytes", store.max_size)));
+    }
+    if store.dir.join(&name).exists() {
+        return Err((StatusCode::CONFLICT, format!("Image '{}' already exists", name)));
+    }
+    let _upload = UploadGuard::claim(&store, &name)
+        .ok_or((StatusCode::CONFLICT, format!("Image '{}' is being uploaded by another request", name)))?;
+
+    let partial = store.partial_path(&name);
+    let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(&partial).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to open {}: {}", partial.display(), e)))?;
+    let mut size = file.metadata().await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .len();
+    // The client resumes from the size it is told here
+    if query.offset != size {
+        return Err((StatusCode::CONFLICT, format!("Upload of '{}' continues at offset {}", name, size)));
+    }
+
+    let mut data = body.into_data_stream();
+    while let Some(chunk) = data.next().await {
+        let chunk = chunk.map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        size += chunk.len() as u64;
+        if size > query.total {
+            return Err((StatusCode::BAD_REQUEST, format!("Upload of '{}' exceeds its total of {} bytes", name, query.total)));
+        }
+        file.write_all(&chunk).await
+            .map_err(|e| (StatusCode::INSUFFICIENT_STORAGE, format!("Failed to store '{}': {}", name, e)))?;
+    }
+    file.sync_all().await
+        .map_err(|e| (StatusCode::INSUFFICIENT_STORAGE, format!("Failed to store '{}': {}", name, e)))?;
+
+    let complete = size == query.total;
+    if complete {
+        tokio::fs::rename(&partial, store.dir.join(&name)).await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        info!("Stored virtual media image '{}' ({} bytes)", name, size);
+    }
+    Ok(Json(UploadStatus {
+        name,
+        size,
+        total: query.total,
+        complete,
+    }))
+}
+
+/// DELETE /api/v1/media/images/{name} - remove an image or an unfinished upload
+#[cfg(feature = "web")]
+async fn delete_image(
+    State(store): State<Arc<MediaStore>>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let _upload = UploadGuard::claim(&store, &name)
+        .ok_or((StatusCode::CONFLICT, format!("Image '{}' is being uploaded", name)))?;
+    if store.is_inserted(&name) {
+        return Err((StatusCode::CONFLICT, format!("Image '{}' is inserted in the virtual drive", name)));
+    }
+    match store.remove(&name) {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err((StatusCode::NOT_FOUND, format!("Unknown image '{}'", name))),
+        Err(e) => Err((StatusCode::BAD_REQUEST, e.to_string())),
+    }
+}
+
+/// Keeps other requests off an image while one writes or removes it
+#[cfg(feature = "web")]
+struct UploadGuard<'a> {
+    store: &'a MediaStore,
+    name: String,
+}
+
+#[cfg(feature = "web")]
+impl<'a> UploadGuard<'a> {
+    fn claim(store: &'a MediaStore, name: &str) -> Option<Self> {
+        let mut uploading = store.uploading.lock().unwrap_or_else(|e| e.into_inner());
+        uploading.insert(name.to_string()).then(|| Self {
+            store,
+            name: name.to_string(),
+        })
+    }
+}
+
+#[cfg(feature = "web")]
+impl Drop for UploadGuard<'_> {
+    fn drop(&mut self) {
+        self.store.uploading.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.name);
+    }
+}
+
+/// Image names are file names in the media directory, keep them simple
+fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= 128
+        && !name.starts_with('.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        && (name.ends_with(".iso") || name.ends_with(".img"));
+    if valid {
+        Ok(())
+    } else {
+        Err(anyhow!("Image names must end in .iso or .img and use only A-Z, a-z, 0-9, '-', '_' or '.'"))
+    }
+}
@@ -0,0 +1,117 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// MJPEG over HTTP stream for kvm-rs
+
+use std::{net::SocketAddr, sync::Arc};
+use axum::{
+    body::{Body, Bytes},
+    extract::{ConnectInfo, Extension, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use futures_util::StreamExt;
+use serde::Deserialize;
+use tokio::sync::{broadcast, watch};
+use tracing::warn;
+use crate::{arbiter::Role, auth::Access, display::DisplayHub, vnc::VncHandler};
+
+/// Separator between the JPEG parts of the multipart response
+const BOUNDARY: &str = "frame";
+
+#[derive(Clone)]
+struct MjpegState {
+    hub: Arc<DisplayHub>,
+    vnc: VncHandler,
+    quality: u8,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamQuery {
+    /// JPEG quality (1-100); frames captured as JPEG are re-encoded when given
+    quality: Option<u8>,
+}
+
+/// Read-only view of the console as multipart/x-mixed-replace JPEG frames
+pub fn router(hub: Arc<DisplayHub>, vnc: VncHandler, quality: u8) -> Router {
+    Router::new()
+        .route("/stream.mjpg", get(stream))
+        .with_state(MjpegState { hub, vnc, quality })
+}
+
+/// GET /stream.mjpg - one JPEG part per captured frame
+async fn stream(
+    State(state): State<MjpegState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<StreamQuery>,
+    Extension(access): Extension<Access>,
+) -> Result<Response, (StatusCode, String)> {
+    if let Some(quality) = query.quality {
+        if !(1..=100).contains(&quality) {
+            return Err((StatusCode::BAD_REQUEST, format!("Quality must be between 1 and 100, got {}", quality)));
+        }
+    }
+    if !state.hub.budget().admit_client() {
+        warn!("Refused MJPEG viewer {}: frame memory budget used up", addr);
+        return Err((StatusCode::SERVICE_UNAVAILABLE, "Frame memory budget used up".to_string()));
+    }
+    let reencode = query.quality.is_some();
+    let quality = query.quality.unwrap_or(state.quality);
+
+    // The body is only polled when the client can take more data, frames
+    // captured in between are skipped. The viewer is listed until the body is dropped
+    let rx = state.hub.tx.subscribe();
+    let session = state.vnc.sessions().register("mjpeg", addr);
+    if let Some(principal) = &access.principal {
+        state.vnc.sessions().set_user(session.id(), &principal.0);
+    }
+    // MJPEG viewers never send input
+    state.vnc.sessions().set_role(session.id(), watch::channel(Role::Viewer).1);
+    let counters = session.counters();
+    let shutdown = state.vnc.shutdown().clone();
+    let disconnected = state.vnc.sessions().disconnected(session.id());
+    let parts = futures_util::stream::unfold((rx, state.vnc, session, counters), move |(mut rx, vnc, session, counters)| async move {
+        loop {
+            match rx.recv().await {
+                Ok(frame_data) => {
+                    // Frames captured while over a bandwidth cap are skipped
+                    if counters.throttled_until().is_some() {
+                        continue;
+                    }
+                    if let Some(jpeg) = vnc.frame_jpeg(&frame_data, quality, reencode).await {
+                        let part = multipart_part(&jpeg.data);
+                        counters.frame_sent(part.len());
+                        return Some((Ok::<_, std::io::Error>(part), (rx, vnc, session, counters)));
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+    // End the response on shutdown or disconnect so the connection can be closed
+    let parts = parts.take_until(shutdown.wait()).take_until(disconnected);
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, format!("multipart/x-mixed-replace; boundary={}", BOUNDARY)),
+            (header::CACHE_CONTROL, "no-cache, no-store".to_string()),
+        ],
+        Body::from_stream(parts),
+    )
+        .into_response())
+}
+
+fn multipart_part(jpeg: &[u8]) -> Bytes {
+    let header = format!(
+        "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+        BOUNDARY,
+        jpeg.len()
+    );
+    let mut part = Vec::with_capacity(header.len() + jpeg.len() + 2);
+    part.extend_from_slice(header.as_bytes());
+    part.extend_from_slice(jpeg);
+    part.extend_from_slice(b"\r\n");
+    Bytes::from(part)
+}
@@ -0,0 +1,188 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Scripted mock video capture for kvm-rs
+
+use std::path::Path;
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context, Result};
+use crate::vnc::RAW_FRAME_SIZES;
+
+/// Frame rate of steps that do not set one
+const DEFAULT_FPS: u32 = 30;
+
+/// Frames a mock capture plays, read from a script with one step per line
+/// and `#` starting a comment line:
+///
+/// ```text
+/// pattern 640x480 10s
+/// pattern 1280x720 #ff8000 5s fps=5
+/// solid 640x480 #00ff00 2s
+/// image host.jpg 3s
+/// stall 4s
+/// fail
+/// repeat
+/// ```
+#[derive(Debug)]
+pub struct MockScript {
+    pub steps: Vec<Step>,
+    /// Whether the script starts over after its last step
+    pub repeat: bool,
+}
+
+/// One step of a mock capture script
+#[derive(Debug)]
+pub enum Step {
+    /// Frames for `duration` at `fps`
+    Frames { source: FrameSource, duration: Duration, fps: u32 },
+    /// No frames, as from a stalled device
+    Stall(Duration),
+    /// Capture fails as if the device was unplugged
+    Fail,
+}
+
+/// What the frames of a step show
+#[derive(Debug)]
+pub enum FrameSource {
+    /// Moving gradient in `color`, or cycling red, green and blue every second
+    Pattern { width: usize, height: usize, color: Option<[u8; 3]> },
+    Solid { width: usize, height: usize, color: [u8; 3] },
+    /// JPEG data sent as it is
+    Jpeg(Vec<u8>),
+}
+
+impl Default for MockScript {
+    /// The cycling test pattern at 640x480, forever
+    fn default() -> Self {
+        Self {
+            steps: vec![Step::Frames {
+                source: FrameSource::Pattern { width: 640, height: 480, color: None },
+                duration: Duration::from_secs(60),
+                fps: DEFAULT_FPS,
+            }],
+            repeat: true,
+        }
+    }
+}
+
+impl MockScript {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read mock capture script {}", path.display()))?;
+        let base = path.parent().unwrap_or(Path::new("."));
+        let mut script = Self { steps: Vec::new(), repeat: false };
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if script.repeat {
+                bail!("{} line {}: steps after repeat", path.display(), number + 1);
+            }
+            if line == "repeat" {
+                script.repeat = true;
+                continue;
+            }
+            let step = parse_step(line, base)
+                .with_context(|| format!("{} line {}", path.display(), number + 1))?;
+            script.steps.push(step);
+        }
+        if script.steps.is_empty() {
+            bail!("Mock capture script {} has no steps", path.display());
+        }
+        Ok(script)
+    }
+}
+
+impl FrameSource {
+    /// Frame number `n` of the step
+    pub fn render(&self, n: u32) -> Vec<u8> {
+        match self {
+            FrameSource::Pattern { width, height, color } => {
+                let color = color.unwrap_or(match (n / 30) % 3 {
+                    0 => [255, 0, 0],
+                    1 => [0, 255, 0],
+                    _ => [0, 0, 255],
+                });
+                let mut frame = Vec::with_capacity(width * height * 3);
+                for y in 0..*height {
+                    for x in 0..*width {
+                        let intensity = ((x + y + n as usize) % 256) as u16;
+                        frame.extend(color.map(|c| (c as u16 * intensity / 255) as u8));
+                    }
+                }
+                frame
+            }
+            FrameSource::Solid { width, height, color } => color.repeat(width * height),
+            FrameSource::Jpeg(data) => data.clone(),
+        }
+    }
+}
+
+fn parse_step(line: &str, base: &Path) -> Result<Step> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        ["stall", duration] => Ok(Step::Stall(parse_duration(duration)?)),
+        ["fail"] => Ok(Step::Fail),
+        ["pattern", size, rest @ ..] => {
+            let (width, height) = parse_size(size)?;
+            let (color, rest) = match rest.first() {
+                Some(word) if word.starts_with('#') => (Some(parse_color(word)?), &rest[1..]),
+                _ => (None, rest),
+            };
+            frames(FrameSource::Pattern { width, height, color }, rest)
+        }
+        ["solid", size, color, rest @ ..] => {
+            let (width, height) = parse_size(size)?;
+            frames(FrameSource::Solid { width, height, color: parse_color(color)? }, rest)
+        }
+        ["image", file, rest @ ..] => {
+            let path = base.join(file);
+            let data = std::fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+            if !data.starts_with(&[0xFF, 0xD8]) {
+                bail!("{} is not a JPEG file", path.display());
+            }
+            frames(FrameSource::Jpeg(data), rest)
+        }
+        _ => bail!("Unknown step '{}'", line),
+    }
+}
+
+/// A frames step from its duration and optional fps=N
+fn frames(source: FrameSource, rest: &[&str]) -> Result<Step> {
+    let (duration, fps) = match rest {
+        [duration] => (duration, DEFAULT_FPS),
+        [duration, fps] => {
+            let fps = fps.strip_prefix("fps=")
+                .and_then(|fps| fps.parse::<u32>().ok())
+                .filter(|fps| (1..=60).contains(fps))
+                .ok_or_else(|| anyhow!("Invalid frame rate '{}', expected fps=1 to fps=60", fps))?;
+            (duration, fps)
+        }
+        _ => bail!("Expected a duration and an optional fps=N"),
+    };
+    Ok(Step::Frames { source, duration: parse_duration(duration)?, fps })
+}
+
+fn parse_duration(duration: &str) -> Result<Duration> {
+    humantime::parse_duration(duration).with_context(|| format!("Invalid duration '{}'", duration))
+}
+
+/// WIDTHxHEIGHT, one of the sizes raw frames are recognized at
+fn parse_size(size: &str) -> Result<(usize, usize)> {
+    let parsed = size.split_once('x')
+        .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+        .ok_or_else(|| anyhow!("Invalid size '{}', expected WIDTHxHEIGHT", size))?;
+    if !RAW_FRAME_SIZES.contains(&parsed) {
+        let sizes: Vec<String> = RAW_FRAME_SIZES.iter().map(|(w, h)| format!("{}x{}", w, h)).collect();
+        bail!("Unsupported size {}, raw frames must be one of {}", size, sizes.join(", "));
+    }
+    Ok(parsed)
+}
+
+/// #RRGGBB
+fn parse_color(color: &str) -> Result<[u8; 3]> {
+    let hex = color.strip_prefix('#').filter(|hex| hex.len() == 6 && hex.is_ascii());
+    let value = hex.and_then(|hex| u32::from_str_radix(hex, 16).ok())
+        .ok_or_else(|| anyhow!("Invalid colour '{}', expected #RRGGBB", color))?;
+    Ok([(value >> 16) as u8, (value >> 8) as u8, value as u8])
+}
@@ -0,0 +1,147 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Kernel network block device served from inside kvm-rs
+
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use anyhow::{Context, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{debug, warn};
+use crate::remote_media::HttpImage;
+
+const NBD_REQUEST_MAGIC: u32 = 0x2560_9513;
+const NBD_REPLY_MAGIC: u32 = 0x6744_6698;
+const NBD_CMD_READ: u32 = 0;
+const NBD_CMD_DISC: u32 = 2;
+const NBD_CMD_FLUSH: u32 = 3;
+const NBD_FLAG_HAS_FLAGS: i32 = 1 << 0;
+const NBD_FLAG_READ_ONLY: i32 = 1 << 1;
+const EPERM: u32 = 1;
+const EIO: u32 = 5;
+/// Sector size the device is set up with; the image is padded with zeros to a multiple
+const BLOCK_SIZE: u64 = 512;
+/// Largest read served, well above what the kernel asks for
+const MAX_READ: u32 = 32 * 1024 * 1024;
+
+nix::ioctl_write_int_bad!(nbd_set_sock, nix::request_code_none!(0xab, 0));
+nix::ioctl_write_int_bad!(nbd_set_blksize, nix::request_code_none!(0xab, 1));
+nix::ioctl_none_bad!(nbd_do_it, nix::request_code_none!(0xab, 3));
+nix::ioctl_none_bad!(nbd_clear_sock, nix::request_code_none!(0xab, 4));
+nix::ioctl_none_bad!(nbd_clear_que, nix::request_code_none!(0xab, 5));
+nix::ioctl_write_int_bad!(nbd_set_size_blocks, nix::request_code_none!(0xab, 7));
+nix::ioctl_none_bad!(nbd_disconnect, nix::request_code_none!(0xab, 8));
+nix::ioctl_write_int_bad!(nbd_set_flags, nix::request_code_none!(0xab, 10));
+
+/// A read-only /dev/nbdN whose reads are answered from a remote image, so
+/// the mass-storage gadget can be backed by it like by a file. The kernel
+/// end of the connection is run by a thread blocked in NBD_DO_IT
+pub struct NbdExport {
+    device: PathBuf,
+    file: Arc<File>,
+}
+
+impl NbdExport {
+    /// Connect `device` to `image`
+    pub fn start(device: &Path, image: Arc<HttpImage>) -> Result<Self> {
+        let file = Arc::new(std::fs::OpenOptions::new().read(true).write(true).open(device)
+            .with_context(|| format!("Failed to open {} (is the nbd module loaded?)", device.display()))?);
+        let (ours, kernel) = UnixStream::pair()?;
+        let fd = file.as_raw_fd();
+        let blocks = image.size().div_ceil(BLOCK_SIZE);
+        // SAFETY: fd is an open NBD device and the arguments are plain integers
+        unsafe {
+            let _ = nbd_clear_sock(fd);
+            nbd_set_blksize(fd, BLOCK_SIZE as i32)?;
+            nbd_set_size_blocks(fd, i32::try_from(blocks).context("Image is too large for an NBD device")?)?;
+            nbd_set_flags(fd, NBD_FLAG_HAS_FLAGS | NBD_FLAG_READ_ONLY)?;
+            nbd_set_sock(fd, kernel.as_raw_fd())
+                .with_context(|| format!("{} is in use", device.display()))?;
+        }
+
+        let device_file = file.clone();
+        let name = device.display().to_string();
+        std::thread::Builder::new().name("nbd".to_string()).spawn(move || {
+            // SAFETY: as above; returns once the device is disconnected
+            unsafe {
+                if let Err(e) = nbd_do_it(device_file.as_raw_fd()) {
+                    debug!("{} stopped: {}", name, e);
+                }
+                let _ = nbd_clear_que(device_file.as_raw_fd());
+                let _ = nbd_clear_sock(device_file.as_raw_fd());
+            }
+            drop(kernel);
+        })?;
+
+        ours.set_nonblocking(true)?;
+        let stream = tokio::net::UnixStream::from_std(ours)?;
+        tokio::spawn(serve(stream, image, device.display().to_string()));
+        Ok(Self {
+            device: device.to_path_buf(),
+            file,
+        })
+    }
+
+    /// Block device the gadget LUN is backed by
+    pub fn device(&self) -> &Path {
+        &self.device
+    }
+}
+
+impl Drop for NbdExport {
+    fn drop(&mut self) {
+        // SAFETY: the device is open as long as `file`
+        if let Err(e) = unsafe { nbd_disconnect(self.file.as_raw_fd()) } {
+            warn!("Failed to disconnect {}: {}", self.device.display(), e);
+        }
+    }
+}
+
+/// Answer the kernel's requests until it disconnects
+async fn serve(mut stream: tokio::net::UnixStream, image: Arc<HttpImage>, device: String) {
+    let mut request = [0u8; 28];
+    loop {
+        if stream.read_exact(&mut request).await.is_err() {
+            break;
+        }
+        let magic = u32::from_be_bytes(request[0..4].try_into().unwrap_or_default());
+        if magic != NBD_REQUEST_MAGIC {
+            warn!("{}: bad request magic {:#x}", device, magic);
+            break;
+        }
+        // Command flags are in the upper half
+        let command = u32::from_be_bytes(request[4..8].try_into().unwrap_or_default()) & 0xffff;
+        let handle = &request[8..16];
+        let offset = u64::from_be_bytes(request[16..24].try_into().unwrap_or_default());
+        let len = u32::from_be_bytes(request[24..28].try_into().unwrap_or_default());
+        let result = match command {
+            NBD_CMD_READ if len <= MAX_READ => match image.read(offset, len as usize).await {
+                Ok(data) => reply(&mut stream, handle, 0, &data).await,
+                Err(e) => {
+                    warn!("{}: read of {} bytes at {} failed: {:#}", device, len, offset, e);
+                    reply(&mut stream, handle, EIO, &[]).await
+                }
+            },
+            NBD_CMD_READ => reply(&mut stream, handle, EIO, &[]).await,
+            NBD_CMD_DISC => break,
+            NBD_CMD_FLUSH => reply(&mut stream, handle, 0, &[]).await,
+            // Writes and trims of a read-only device
+            _ => reply(&mut stream, handle, EPERM, &[]).await,
+        };
+        if result.is_err() {
+            break;
+        }
+    }
+    debug!("{} disconnected", device);
+}
+
+async fn reply(stream: &mut tokio::net::UnixStream, handle: &[u8], error: u32, data: &[u8]) -> std::io::Result<()> {
+    let mut header = Vec::with_capacity(16);
+    header.extend_from_slice(&NBD_REPLY_MAGIC.to_be_bytes());
+    header.extend_from_slice(&error.to_be_bytes());
+    header.extend_from_slice(handle);
+    stream.write_all(&header).await?;
+    stream.write_all(data).await
+}
@@ -0,0 +1,93 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Command line compatibility with obmc-ikvm, the KVM daemon of OpenBMC, for kvm-rs
+
+use std::ffi::OsString;
+use std::path::Path;
+use clap::Parser;
+
+/// Name kvm-rs answers to as a drop-in replacement, e.g. through a symlink
+const PROGRAM_NAME: &str = "obmc-ikvm";
+
+/// Address of the web server in compatibility mode: bmcweb serves the web
+/// console and the /kvm/0 WebSocket, so the REST API stays on the BMC
+const WEB_LISTEN: &str = "127.0.0.1:8443";
+
+/// Options of obmc-ikvm, as start-ipkvm.service passes them
+#[derive(Parser, Debug)]
+#[command(name = "obmc-ikvm")]
+#[command(about = "kvm-rs in obmc-ikvm compatibility mode; kvm-rs options go after --")]
+struct IkvmArgs {
+    /// Frame rate of the video device
+    #[arg(short = 'f', long = "frameRate", default_value = "30", value_parser = clap::value_parser!(u32).range(1..))]
+    frame_rate: u32,
+
+    /// JPEG chroma subsampling: 0 for 4:4:4, 1 for 4:2:0
+    #[arg(short = 's', long = "subsampling", default_value = "0", value_parser = clap::value_parser!(u8).range(0..=1))]
+    subsampling: u8,
+
+    /// HID gadget device of the keyboard
+    #[arg(short = 'k', long = "keyboard")]
+    keyboard: Option<String>,
+
+    /// HID gadget device of the mouse
+    #[arg(short = 'p', long = "mouse")]
+    mouse: Option<String>,
+
+    /// UDC the HID gadget is bound to; by default the first free one
+    #[arg(short = 'u', long = "udcName")]
+    udc_name: Option<String>,
+
+    /// Video device
+    #[arg(short = 'v', long = "videoDevice", default_value = "/dev/video0")]
+    video_device: String,
+
+    /// Skip frames whose CRC did not change; accepted without effect, kvm-rs
+    /// sends every frame the video engine delivers
+    #[arg(short = 'c', long = "calcCRC")]
+    calc_crc: bool,
+
+    /// Further kvm-rs options, taking precedence over the translated ones
+    #[arg(last = true)]
+    options: Vec<OsString>,
+}
+
+/// Whether kvm-rs was started as obmc-ikvm
+pub fn invoked_as(command_line: &[OsString]) -> bool {
+    command_line.first()
+        .and_then(|program| Path::new(program).file_name())
+        .is_some_and(|name| name == PROGRAM_NAME)
+}
+
+/// The obmc-ikvm command line as kvm-rs options. Like obmc-ikvm, the VNC
+/// port serves RFB without authentication on port 5900, where bmcweb's /kvm/0
+/// WebSocket proxy connects, and the HID gadget is bound to a UDC at startup.
+/// Exits with the usage on invalid options, as obmc-ikvm does
+pub fn translate(command_line: &[OsString]) -> Vec<OsString> {
+    let IkvmArgs { frame_rate, subsampling, keyboard, mouse, udc_name, video_device, calc_crc: _, options: extra } =
+        IkvmArgs::parse_from(command_line);
+    let mut options: Vec<String> = vec![
+        "--video".to_string(), video_device,
+        "--frame-rate".to_string(), frame_rate.to_string(),
+        "--jpeg-subsampling".to_string(), if subsampling == 1 { "420" } else { "444" }.to_string(),
+        "--listen".to_string(), WEB_LISTEN.to_string(),
+        "--vnc-port".to_string(), "5900".to_string(),
+        "--auth".to_string(), "none".to_string(),
+    ];
+    let input = keyboard.is_some() || mouse.is_some();
+    if let Some(keyboard) = keyboard {
+        options.extend(["--keyboard-hid".to_string(), keyboard]);
+    }
+    if let Some(mouse) = mouse {
+        options.extend(["--mouse-hid".to_string(), mouse]);
+    }
+    match udc_name {
+        Some(udc) => options.extend(["--bind-udc".to_string(), udc]),
+        None if input => options.extend(["--bind-udc".to_string(), "auto".to_string()]),
+        None => {}
+    }
+    let mut translated = command_line[..1].to_vec();
+    translated.extend(options.into_iter().map(OsString::from));
+    translated.extend(extra);
+    translated
+}
@@ -0,0 +1,173 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// OpenBMC virtual media D-Bus objects for bmcweb's Redfish VirtualMedia, for kvm-rs
+
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::Result;
+use tokio::io::AsyncReadExt;
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+use zbus::{fdo, object_server::InterfaceRef, zvariant};
+use crate::events::{Event, EventBus};
+use crate::virtual_media::{MediaError, VirtualMedia};
+
+/// Name bmcweb looks virtual media up under, otherwise taken by the virtual-media daemon
+pub const DBUS_NAME: &str = "xyz.openbmc_project.VirtualMedia";
+const ROOT_PATH: &str = "/xyz/openbmc_project/VirtualMedia";
+/// Legacy mode: the BMC reads the image from a URL, as opposed to proxy mode
+/// where the browser streams it through bmcweb, which is not offered
+const SLOT_PATH: &str = "/xyz/openbmc_project/VirtualMedia/Legacy/Slot_0";
+/// How long Mount waits for bmcweb to write the credentials
+const CREDENTIALS_TIMEOUT: Duration = Duration::from_secs(5);
+/// Credentials are a user name and a password, each NUL-terminated
+const MAX_CREDENTIALS: u64 = 4096;
+
+/// Offer the virtual media drive as the one Legacy slot of the OpenBMC
+/// virtual media service, so bmcweb's InsertMedia and EjectMedia actions
+/// drive it. Changes made over REST or the Control interface are signalled
+pub async fn serve_dbus(dbus: &zbus::Connection, drive: Arc<VirtualMedia>, events: &EventBus) -> Result<()> {
+    let object_server = dbus.object_server();
+    object_server.at(SLOT_PATH, MountPoint { drive: drive.clone() }).await?;
+    object_server.at(SLOT_PATH, Process { drive: drive.clone() }).await?;
+    object_server.at(SLOT_PATH, Legacy { drive }).await?;
+    // Added last, so InterfacesAdded covers the slot
+    object_server.at(ROOT_PATH, fdo::ObjectManager).await?;
+    let mount_point = object_server.interface::<_, MountPoint>(SLOT_PATH).await?;
+    let process = object_server.interface::<_, Process>(SLOT_PATH).await?;
+    tokio::spawn(signal_changes(mount_point, process, events.subscribe()));
+    dbus.request_name(DBUS_NAME).await?;
+    info!("Virtual media drive offered to bmcweb at {}", SLOT_PATH);
+    Ok(())
+}
+
+/// xyz.openbmc_project.VirtualMedia.MountPoint D-Bus interface
+struct MountPoint {
+    drive: Arc<VirtualMedia>,
+}
+
+#[zbus::interface(name = "xyz.openbmc_project.VirtualMedia.MountPoint")]
+impl MountPoint {
+    /// NBD endpoint of proxy mode; Legacy slots have none
+    #[zbus(property)]
+    async fn endpoint_id(&self) -> String {
+        String::new()
+    }
+
+    /// URL of the inserted image, empty when the drive is empty
+    #[zbus(property, name = "ImageURL")]
+    async fn image_url(&self) -> String {
+        self.drive.status().inserted.map(|medium| medium.image).unwrap_or_default()
+    }
+
+    #[zbus(property)]
+    async fn write_protected(&self) -> bool {
+        self.drive.status().inserted.is_none_or(|medium| medium.read_only)
+    }
+
+    /// Inactivity timeout of proxy mode, in seconds
+    #[zbus(property)]
+    async fn timeout(&self) -> i32 {
+        0
+    }
+
+    #[zbus(property)]
+    async fn remaining_inactivity_timeout(&self) -> i32 {
+        0
+    }
+}
+
+/// xyz.openbmc_project.VirtualMedia.Process D-Bus interface
+struct Process {
+    drive: Arc<VirtualMedia>,
+}
+
+#[zbus::interface(name = "xyz.openbmc_project.VirtualMedia.Process")]
+impl Process {
+    /// Whether an image is inserted; bmcweb reports it as Inserted
+    #[zbus(property)]
+    async fn active(&self) -> bool {
+        self.drive.status().inserted.is_some()
+    }
+
+    #[zbus(property)]
+    async fn exit_code(&self) -> i32 {
+        0
+    }
+}
+
+/// xyz.openbmc_project.VirtualMedia.Legacy D-Bus interface
+struct Legacy {
+    drive: Arc<VirtualMedia>,
+}
+
+#[zbus::interface(name = "xyz.openbmc_project.VirtualMedia.Legacy")]
+impl Legacy {
+    /// Insert the image at `image_url`; bmcweb writes the user name and
+    /// password to `credentials`. Remote images are read-only
+    async fn mount(&self, image_url: &str, rw: bool, credentials: zvariant::OwnedFd) -> fdo::Result<bool> {
+        if rw {
+            return Err(fdo::Error::NotSupported("Remote images are read-only, set WriteProtected".to_string()));
+        }
+        let (user, password) = read_credentials(credentials).await?;
+        let credentials = (!user.is_empty()).then_some((user.as_str(), password.as_str()));
+        match self.drive.insert_url(image_url, true, credentials).await {
+            Ok(_) => Ok(true),
+            Err(e) => {
+                warn!("Redfish InsertMedia failed: {}", e);
+                Err(match e {
+                    MediaError::Busy(_) => fdo::Error::ObjectPathInUse(e.to_string()),
+                    MediaError::InvalidUrl(_) => fdo::Error::InvalidArgs(e.to_string()),
+                    _ => fdo::Error::Failed(e.to_string()),
+                })
+            }
+        }
+    }
+
+    /// Eject the inserted image
+    async fn unmount(&self) -> fdo::Result<bool> {
+        match self.drive.eject() {
+            Ok(ejected) => Ok(ejected.is_some()),
+            Err(e) => Err(fdo::Error::Failed(e.to_string())),
+        }
+    }
+}
+
+/// User name and password from the pipe bmcweb hands to Mount; both empty
+/// when it sends none
+async fn read_credentials(fd: zvariant::OwnedFd) -> fdo::Result<(String, String)> {
+    let pipe = tokio::net::unix::pipe::Receiver::from_owned_fd(fd.into())
+        .map_err(|e| fdo::Error::InvalidArgs(format!("Invalid credentials pipe: {}", e)))?;
+    let mut secret = Vec::new();
+    tokio::time::timeout(CREDENTIALS_TIMEOUT, pipe.take(MAX_CREDENTIALS).read_to_end(&mut secret))
+        .await
+        .map_err(|_| fdo::Error::TimedOut("No credentials were written".to_string()))?
+        .map_err(|e| fdo::Error::IOError(format!("Failed to read the credentials: {}", e)))?;
+    let mut fields = secret.split(|byte| *byte == 0).map(|field| String::from_utf8_lossy(field).into_owned());
+    let user = fields.next().unwrap_or_default();
+    let password = fields.next().unwrap_or_default();
+    Ok((user, password))
+}
+
+/// Emit PropertiesChanged for the slot when an image is inserted or ejected
+async fn signal_changes(mount_point: InterfaceRef<MountPoint>, process: InterfaceRef<Process>, mut events: broadcast::Receiver<Event>) {
+    loop {
+        match events.recv().await {
+            Ok(Event::MediaInserted { .. } | Event::MediaEjected { .. }) => {}
+            Ok(_) => continue,
+            Err(broadcast::error::RecvError::Lagged(_)) => {}
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+        let ctxt = mount_point.signal_context();
+        let signalled = async {
+            let slot = mount_point.get().await;
+            // Named after the ImageURL property
+            slot.image_u_r_l_changed(ctxt).await?;
+            slot.write_protected_changed(ctxt).await?;
+            process.get().await.active_changed(process.signal_context()).await
+        };
+        if let Err(e) = signalled.await {
+            warn!("Failed to signal a virtual media change on D-Bus: {}", e);
+        }
+    }
+}
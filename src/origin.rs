@@ -0,0 +1,120 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Origin and Host header checks for console connections in kvm-rs
+
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, StatusCode, Uri},
+    middleware::Next,
+    response::Response,
+};
+use tracing::warn;
+
+/// Which browser pages may open the console WebSocket, and under which host names
+#[derive(Clone)]
+pub struct OriginPolicy {
+    /// Replaced as a whole on reload
+    inner: Arc<RwLock<Arc<Policy>>>,
+}
+
+struct Policy {
+    /// Extra origins (e.g. "https://bmc.example.com") or host names; "*" allows any
+    allowed_origins: Vec<String>,
+    /// Extra host names the server may be addressed by
+    allowed_hosts: Vec<String>,
+    /// Names the BMC knows itself by
+    own_names: Vec<String>,
+}
+
+impl Policy {
+    fn new(allowed_origins: &[String], allowed_hosts: &[String]) -> Self {
+        let mut own_names = vec!["localhost".to_string()];
+        if let Some(hostname) = crate::mdns::system_hostname() {
+            own_names.push(hostname);
+        }
+        Self {
+            allowed_origins: allowed_origins.iter().map(|origin| origin.trim_end_matches('/').to_ascii_lowercase()).collect(),
+            allowed_hosts: allowed_hosts.iter().map(|host| host.to_ascii_lowercase()).collect(),
+            own_names,
+        }
+    }
+}
+
+impl OriginPolicy {
+    pub fn new(allowed_origins: &[String], allowed_hosts: &[String]) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(Arc::new(Policy::new(allowed_origins, allowed_hosts)))),
+        }
+    }
+
+    /// Check later requests against other extra origins and host names
+    pub fn set_allowed(&self, allowed_origins: &[String], allowed_hosts: &[String]) {
+        *self.inner.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(Policy::new(allowed_origins, allowed_hosts));
+    }
+
+    /// Why the request must be refused, if it must
+    pub fn check(&self, uri: &Uri, headers: &HeaderMap) -> Result<(), String> {
+        let policy = self.inner.read().unwrap_or_else(|e| e.into_inner()).clone();
+        let host = request_host(uri, headers)
+            .map(|value| strip_port(value).to_ascii_lowercase())
+            .ok_or("Missing Host header")?;
+
+        // A DNS rebinding page reaches us under its own domain: only accept
+        // addresses and names the BMC is known by
+        let host_allowed = host.trim_matches(|c| c == '[' || c == ']').parse::<IpAddr>().is_ok()
+            || policy.own_names.iter().any(|name| host == *name || host.strip_prefix(name.as_str()).is_some_and(|rest| rest.starts_with('.')))
+            || policy.allowed_hosts.contains(&host);
+        if !host_allowed {
+            return Err(format!("Host {} is not allowed", host));
+        }
+
+        // Clients other than browsers send no Origin
+        let Some(origin) = headers.get(header::ORIGIN) else {
+            return Ok(());
+        };
+        let origin = origin.to_str().map_err(|_| "Invalid Origin header")?.to_ascii_lowercase();
+        if policy.allowed_origins.iter().any(|allowed| allowed == "*" || *allowed == origin) {
+            return Ok(());
+        }
+        let origin_host = origin.parse::<Uri>().ok()
+            .and_then(|uri| uri.host().map(str::to_string))
+            .ok_or_else(|| format!("Origin {} is not allowed", origin))?;
+        if origin_host == host || policy.allowed_origins.contains(&origin_host) {
+            Ok(())
+        } else {
+            Err(format!("Origin {} is not allowed", origin))
+        }
+    }
+}
+
+/// Refuse cross-site WebSocket upgrades and foreign Host names before they reach the handler
+pub async fn check_origin(
+    State(policy): State<OriginPolicy>,
+    request: Request,
+    next: Next,
+) -> Result<Response, (StatusCode, String)> {
+    match policy.check(request.uri(), request.headers()) {
+        Ok(()) => Ok(next.run(request).await),
+        Err(reason) => {
+            warn!("Refused console request: {}", reason);
+            Err((StatusCode::FORBIDDEN, reason))
+        }
+    }
+}
+
+/// Host the request was sent to: the Host header, or the :authority of HTTP/2 requests
+pub fn request_host<'a>(uri: &'a Uri, headers: &'a HeaderMap) -> Option<&'a str> {
+    headers.get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .or_else(|| uri.authority().map(|authority| authority.as_str()))
+}
+
+/// Host without the port, keeping IPv6 brackets
+pub fn strip_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') => name,
+        _ => host,
+    }
+}
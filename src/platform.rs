@@ -0,0 +1,38 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Option presets of BMC platforms for kvm-rs
+
+/// BMC platform whose preset options --platform applies, under those of the
+/// --config file and the command line
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    /// ASPEED AST2500
+    Ast2500,
+    /// ASPEED AST2600
+    Ast2600,
+    /// Nuvoton NPCM845
+    Npcm845,
+    /// Mock capture and HID for development under QEMU
+    QemuDev,
+}
+
+impl Platform {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Platform::Ast2500 => "ast2500",
+            Platform::Ast2600 => "ast2600",
+            Platform::Npcm845 => "npcm845",
+            Platform::QemuDev => "qemu-dev",
+        }
+    }
+
+    /// Options of the preset, in the format of a --config file
+    pub fn preset(&self) -> &'static str {
+        match self {
+            Platform::Ast2500 => include_str!("../presets/ast2500.conf"),
+            Platform::Ast2600 => include_str!("../presets/ast2600.conf"),
+            Platform::Npcm845 => include_str!("../presets/npcm845.conf"),
+            Platform::QemuDev => include_str!("../presets/qemu-dev.conf"),
+        }
+    }
+}
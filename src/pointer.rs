@@ -0,0 +1,101 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Pointer sensitivity and calibration for kvm-rs
+
+use serde::{Deserialize, Serialize};
+use crate::args::Args;
+
+/// Pointer scaling (relative mode) and calibration (absolute mode) settings
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct PointerSettings {
+    /// Horizontal scaling factor for relative motion
+    pub scale_x: f32,
+    /// Vertical scaling factor for relative motion
+    pub scale_y: f32,
+    /// Acceleration exponent for relative motion (0 = linear)
+    pub acceleration: f32,
+    /// Horizontal calibration offset in framebuffer pixels for absolute positioning
+    pub offset_x: i32,
+    /// Vertical calibration offset in framebuffer pixels for absolute positioning
+    pub offset_y: i32,
+}
+
+impl Default for PointerSettings {
+    fn default() -> Self {
+        Self {
+            scale_x: 1.0,
+            scale_y: 1.0,
+            acceleration: 0.0,
+            offset_x: 0,
+            offset_y: 0,
+        }
+    }
+}
+
+/// Partial update of pointer settings; missing fields are left unchanged
+#[derive(Debug, Default, Deserialize)]
+pub struct PointerSettingsUpdate {
+    pub scale_x: Option<f32>,
+    pub scale_y: Option<f32>,
+    pub acceleration: Option<f32>,
+    pub offset_x: Option<i32>,
+    pub offset_y: Option<i32>,
+}
+
+impl PointerSettings {
+    pub fn from_args(args: &Args) -> Self {
+        Self {
+            scale_x: args.mouse_scale_x,
+            scale_y: args.mouse_scale_y,
+            acceleration: args.mouse_acceleration,
+            offset_x: args.mouse_offset_x,
+            offset_y: args.mouse_offset_y,
+        }
+    }
+
+    /// Reject scaling factors and curves that would stall or invert the pointer
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(self.scale_x.is_finite() && self.scale_x > 0.0 && self.scale_y.is_finite() && self.scale_y > 0.0) {
+            return Err(anyhow::anyhow!("Pointer scale factors must be positive"));
+        }
+        if !(self.acceleration.is_finite() && self.acceleration >= 0.0) {
+            return Err(anyhow::anyhow!("Pointer acceleration must be zero or positive"));
+        }
+        Ok(())
+    }
+
+    /// Settings with the fields present in `update` replaced
+    pub fn updated(&self, update: &PointerSettingsUpdate) -> Self {
+        Self {
+            scale_x: update.scale_x.unwrap_or(self.scale_x),
+            scale_y: update.scale_y.unwrap_or(self.scale_y),
+            acceleration: update.acceleration.unwrap_or(self.acceleration),
+            offset_x: update.offset_x.unwrap_or(self.offset_x),
+            offset_y: update.offset_y.unwrap_or(self.offset_y),
+        }
+    }
+
+    /// Scale and accelerate a relative motion delta
+    pub fn relative(&self, dx: i32, dy: i32) -> (i32, i32) {
+        (
+            Self::curve(dx, self.scale_x, self.acceleration),
+            Self::curve(dy, self.scale_y, self.acceleration),
+        )
+    }
+
+    /// Apply calibration offsets to an absolute framebuffer position
+    pub fn absolute(&self, x: u16, y: u16, width: u16, height: u16) -> (u16, u16) {
+        let calibrate = |position: u16, offset: i32, extent: u16| {
+            (position as i32 + offset).clamp(0, extent.saturating_sub(1) as i32) as u16
+        };
+        (calibrate(x, self.offset_x, width), calibrate(y, self.offset_y, height))
+    }
+
+    fn curve(delta: i32, scale: f32, acceleration: f32) -> i32 {
+        if delta == 0 {
+            return 0;
+        }
+        let magnitude = (delta.abs() as f32).powf(1.0 + acceleration) * scale;
+        magnitude.round() as i32 * delta.signum()
+    }
+}
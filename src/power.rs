@@ -0,0 +1,203 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Host power state and control through the OpenBMC state manager for kvm-rs
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use anyhow::{Context, Result};
+#[cfg(feature = "web")]
+use axum::{
+    extract::{ConnectInfo, Path, State},
+    http::StatusCode,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tracing::{info, warn};
+#[cfg(feature = "web")]
+use crate::auth::{Access, UserRole};
+use crate::security_audit::{SecurityAudit, SecurityEvent};
+
+const HOST_SERVICE: &str = "xyz.openbmc_project.State.Host";
+const HOST_PATH: &str = "/xyz/openbmc_project/state/host0";
+const HOST_INTERFACE: &str = "xyz.openbmc_project.State.Host";
+const CHASSIS_SERVICE: &str = "xyz.openbmc_project.State.Chassis";
+const CHASSIS_PATH: &str = "/xyz/openbmc_project/state/chassis0";
+const CHASSIS_INTERFACE: &str = "xyz.openbmc_project.State.Chassis";
+
+/// Power operation on the host, named as in the REST path and WebSocket message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PowerAction {
+    On,
+    /// Ask the host OS to shut down
+    Off,
+    /// Cut the power without asking the host OS
+    ForceOff,
+    /// Cut the power and turn it back on
+    Cycle,
+    /// Reboot the host without cutting the power
+    Reset,
+}
+
+impl PowerAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PowerAction::On => "on",
+            PowerAction::Off => "off",
+            PowerAction::ForceOff => "force-off",
+            PowerAction::Cycle => "cycle",
+            PowerAction::Reset => "reset",
+        }
+    }
+
+    /// Service, object, interface and property the transition is requested
+    /// with, and the transition; the same phosphor-state-manager requests
+    /// bmcweb makes for the ComputerSystem Reset action
+    fn request(&self) -> (&'static str, &'static str, &'static str, &'static str, &'static str) {
+        let host = |transition| (HOST_SERVICE, HOST_PATH, HOST_INTERFACE, "RequestedHostTransition", transition);
+        let chassis = |transition| (CHASSIS_SERVICE, CHASSIS_PATH, CHASSIS_INTERFACE, "RequestedPowerTransition", transition);
+        match self {
+            PowerAction::On => host("xyz.openbmc_project.State.Host.Transition.On"),
+            PowerAction::Off => host("xyz.openbmc_project.State.Host.Transition.Off"),
+            PowerAction::ForceOff => chassis("xyz.openbmc_project.State.Chassis.Transition.Off"),
+            PowerAction::Cycle => chassis("xyz.openbmc_project.State.Chassis.Transition.PowerCycle"),
+            PowerAction::Reset => host("xyz.openbmc_project.State.Host.Transition.ForceWarmReboot"),
+        }
+    }
+}
+
+/// Whether host 0 is powered off, as phosphor-state-manager reports it.
+/// The host counts as on until it reports otherwise, and without D-Bus
+#[derive(Clone)]
+pub struct HostState {
+    off: Arc<watch::Sender<bool>>,
+}
+
+impl Default for HostState {
+    fn default() -> Self {
+        Self {
+            off: Arc::new(watch::channel(false).0),
+        }
+    }
+}
+
+impl HostState {
+    pub fn is_off(&self) -> bool {
+        *self.off.borrow()
+    }
+
+    /// Watch whether the host is off
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.off.subscribe()
+    }
+
+    /// Follow a CurrentHostState, e.g. "Off" or "Running"; the host counts
+    /// as on while it transitions, so capture resumes as early as possible
+    pub fn update(&self, state: &str) {
+        let off = state == "Off";
+        if self.off.send_replace(off) != off {
+            info!("Host is powered {}", if off { "off" } else { "on" });
+        }
+    }
+}
+
+/// Power state as reported by phosphor-state-manager, e.g. Running and On
+#[derive(Debug, Serialize)]
+pub struct PowerState {
+    pub host: String,
+    pub chassis: String,
+}
+
+/// Power control of host 0 and its chassis, audited in the security log
+#[derive(Clone)]
+pub struct HostPower {
+    dbus: zbus::Connection,
+    audit: SecurityAudit,
+}
+
+impl HostPower {
+    pub fn new(dbus: zbus::Connection, audit: SecurityAudit) -> Self {
+        Self { dbus, audit }
+    }
+
+    /// Request `action` for `client`, logged in as `user` if known. Returns
+    /// once the state manager accepted it, the host changes state afterwards
+    pub async fn request(&self, action: PowerAction, client: SocketAddr, user: Option<&str>) -> Result<()> {
+        let result = self.transition(action).await;
+        let failure = result.as_ref().err().map(|e| format!("{:#}", e));
+        self.audit.log(SecurityEvent::PowerAction {
+            client,
+            user,
+            action: action.as_str(),
+            failure: failure.as_deref(),
+        });
+        match failure {
+            Some(ref reason) => warn!("Power {} requested by {} failed: {}", action.as_str(), client, reason),
+            None => info!("Power {} requested by {}", action.as_str(), client),
+        }
+        result
+    }
+
+    async fn transition(&self, action: PowerAction) -> Result<()> {
+        let (service, path, interface, property, transition) = action.request();
+        let proxy = zbus::Proxy::new(&self.dbus, service, path, interface).await?;
+        proxy.set_property(property, transition)
+            .await
+            .with_context(|| format!("Failed to set {} of {}", property, path))
+    }
+
+    pub async fn state(&self) -> Result<PowerState> {
+        let host = self.read(HOST_SERVICE, HOST_PATH, HOST_INTERFACE, "CurrentHostState").await?;
+        let chassis = self.read(CHASSIS_SERVICE, CHASSIS_PATH, CHASSIS_INTERFACE, "CurrentPowerState").await?;
+        Ok(PowerState { host, chassis })
+    }
+
+    /// Enumeration property without its prefix, "...HostState.Running" -> "Running"
+    async fn read(&self, service: &'static str, path: &'static str, interface: &'static str, property: &str) -> Result<String> {
+        let proxy = zbus::Proxy::new(&self.dbus, service, path, interface).await?;
+        let value: String = proxy.get_property(property)
+            .await
+            .with_context(|| format!("Failed to read {} of {}", property, path))?;
+        Ok(value.rsplit('.').next().unwrap_or_default().to_string())
+    }
+}
+
+/// Routes of the host power under /api/v1/power
+#[cfg(feature = "web")]
+pub fn router(power: HostPower) -> Router {
+    Router::new()
+        .route("/api/v1/power", get(get_power))
+        .route("/api/v1/power/{action}", post(post_power))
+        .with_state(power)
+}
+
+/// GET /api/v1/power - host and chassis power state
+#[cfg(feature = "web")]
+async fn get_power(State(power): State<HostPower>) -> Result<Json<PowerState>, (StatusCode, String)> {
+    power.state().await
+        .map(Json)
+        .map_err(|e| (StatusCode::BAD_GATEWAY, format!("{:#}", e)))
+}
+
+/// POST /api/v1/power/{action} - turn the host on or off, power cycle or reset it;
+/// only for authenticated operators
+#[cfg(feature = "web")]
+async fn post_power(
+    State(power): State<HostPower>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    access: Option<Extension<Access>>,
+    Path(action): Path<PowerAction>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let Some(Extension(access)) = access.filter(|Extension(access)| access.token.is_some()) else {
+        return Err((StatusCode::UNAUTHORIZED, "Power control needs a session".to_string()));
+    };
+    if access.role < UserRole::Operator {
+        return Err((StatusCode::FORBIDDEN, format!("Requires the {} role", UserRole::Operator.as_str())));
+    }
+    let user = access.principal.as_ref().map(|principal| principal.0.as_str());
+    power.request(action, addr, user).await
+        .map(|()| StatusCode::ACCEPTED)
+        .map_err(|e| (StatusCode::BAD_GATEWAY, format!("{:#}", e)))
+}
@@ -0,0 +1,109 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Dropping root privileges after initialization for kvm-rs
+
+use anyhow::Result;
+#[cfg(target_os = "linux")]
+use anyhow::{bail, Context};
+#[cfg(target_os = "linux")]
+use tracing::{info, warn};
+
+/// System calls refused under --seccomp: running programs, debugging other
+/// processes, changing identity, mounts, namespaces, kernel modules, and
+/// settings of the whole system. Names the architecture lacks are skipped
+#[cfg(all(target_os = "linux", feature = "seccomp"))]
+const DENIED_SYSCALLS: &[&str] = &[
+    "execve", "execveat",
+    "ptrace", "process_vm_readv", "process_vm_writev",
+    "setuid", "setgid", "setreuid", "setregid", "setresuid", "setresgid", "setgroups", "setfsuid", "setfsgid",
+    "mount", "umount2", "pivot_root", "chroot", "setns", "unshare", "open_by_handle_at",
+    "init_module", "finit_module", "delete_module", "kexec_load", "kexec_file_load",
+    "bpf", "perf_event_open", "userfaultfd", "keyctl", "add_key", "request_key",
+    "reboot", "swapon", "swapoff", "acct", "quotactl", "iopl", "ioperm",
+    "settimeofday", "clock_settime", "adjtimex", "clock_adjtime", "sethostname", "setdomainname",
+];
+
+/// Switch every thread to `user` and `group` (by default the user's own),
+/// with the user's supplementary groups. Leaving root clears the permitted,
+/// effective and ambient capabilities, so client handling code that is
+/// compromised cannot regain root. kvm-rs must have been started as root
+#[cfg(target_os = "linux")]
+pub fn drop_privileges(user: &str, group: Option<&str>) -> Result<()> {
+    use std::ffi::CString;
+    use nix::unistd::{self, Group, Uid, User};
+
+    if !unistd::geteuid().is_root() {
+        bail!("--user needs kvm-rs to be started as root");
+    }
+    let account = User::from_name(user)?.with_context(|| format!("No user {}", user))?;
+    let gid = match group {
+        Some(group) => Group::from_name(group)?.with_context(|| format!("No group {}", group))?.gid,
+        None => account.gid,
+    };
+    // Inherited ambient capabilities would otherwise survive as long as permitted ones do
+    // SAFETY: prctl with integer arguments only
+    unsafe {
+        nix::libc::prctl(nix::libc::PR_CAP_AMBIENT, nix::libc::PR_CAP_AMBIENT_CLEAR_ALL, 0, 0, 0);
+    }
+    // The C library applies these to every thread of the process
+    let name = CString::new(account.name.as_str())?;
+    unistd::initgroups(&name, gid).context("Failed to set the supplementary groups")?;
+    unistd::setgid(gid).context("Failed to change the group")?;
+    unistd::setuid(account.uid).context("Failed to change the user")?;
+    if unistd::setuid(Uid::from_raw(0)).is_ok() {
+        bail!("Still able to become root after switching to {}", user);
+    }
+    info!("Running as user {} ({}), group {}", user, account.uid, gid);
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn drop_privileges(_user: &str, _group: Option<&str>) -> Result<()> {
+    Err(anyhow::anyhow!("--user is only supported on Linux"))
+}
+
+/// Warn about devices the current user cannot open, once root is given up:
+/// gadgets and capture devices are reopened after errors and reconnects
+#[cfg(target_os = "linux")]
+pub fn warn_inaccessible(devices: &[String]) {
+    use nix::unistd::{access, AccessFlags};
+
+    for device in devices {
+        // Framebuffers are only read
+        let mode = if device.starts_with("/dev/fb") { AccessFlags::R_OK } else { AccessFlags::R_OK | AccessFlags::W_OK };
+        if let Err(e) = access(device.as_str(), mode) {
+            warn!("{} cannot be opened after dropping privileges: {}", device, e);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn warn_inaccessible(_devices: &[String]) {}
+
+/// Make every thread refuse the system calls of `DENIED_SYSCALLS` with
+/// EPERM, and set no_new_privs
+#[cfg(all(target_os = "linux", feature = "seccomp"))]
+pub fn apply_seccomp() -> Result<()> {
+    use libseccomp::{ScmpAction, ScmpFilterContext, ScmpSyscall};
+
+    let mut filter = ScmpFilterContext::new_filter(ScmpAction::Allow)?;
+    let mut denied = 0;
+    for name in DENIED_SYSCALLS {
+        let Ok(syscall) = ScmpSyscall::from_name(name) else {
+            continue;
+        };
+        filter.add_rule(ScmpAction::Errno(nix::libc::EPERM), syscall)
+            .with_context(|| format!("Failed to add a seccomp rule for {}", name))?;
+        denied += 1;
+    }
+    // Threads of the runtime already run; the filter must cover them too
+    filter.set_ctl_tsync(true)?;
+    filter.load().context("Failed to load the seccomp filter")?;
+    info!("seccomp filter loaded, {} system calls refused", denied);
+    Ok(())
+}
+
+#[cfg(not(all(target_os = "linux", feature = "seccomp")))]
+pub fn apply_seccomp() -> Result<()> {
+    Err(anyhow::anyhow!("--seccomp needs Linux and kvm-rs built with the seccomp feature"))
+}
@@ -0,0 +1,359 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Discovery of capture devices, HID gadgets and codecs for kvm-rs
+
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use crate::{args::Args, gadget, hid_descriptor};
+
+/// Everything `probe` finds on the machine
+#[derive(Debug, Default, Serialize)]
+pub struct HardwareReport {
+    /// V4L2 capture devices
+    pub video: Vec<VideoDevice>,
+    pub framebuffers: Vec<Framebuffer>,
+    pub drm: Vec<DrmNode>,
+    pub hid_gadgets: Vec<HidGadget>,
+    /// V4L2 memory-to-memory devices, such as hardware JPEG or H.264 encoders
+    pub codecs: Vec<Codec>,
+    /// First working capture device, for setting --video automatically
+    pub suggested_video: Option<SuggestedVideo>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct VideoDevice {
+    pub path: String,
+    pub driver: String,
+    pub card: String,
+    pub bus: String,
+    pub formats: Vec<PixelFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current: Option<CurrentFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PixelFormat {
+    pub fourcc: String,
+    pub description: String,
+    pub compressed: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CurrentFormat {
+    pub fourcc: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Framebuffer {
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub width: usize,
+    pub height: usize,
+    pub bits_per_pixel: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DrmNode {
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub driver: Option<String>,
+    /// Outputs of a card node and whether a display is connected
+    pub connectors: Vec<Connector>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Connector {
+    pub name: String,
+    pub status: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct HidGadget {
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub descriptor_len: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keyboard: Option<KeyboardReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mouse: Option<MouseReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub touchscreen: Option<TouchscreenReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub udc_state: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct KeyboardReport {
+    pub report_len: usize,
+    pub key_count: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MouseReport {
+    pub report_len: usize,
+    pub absolute: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TouchscreenReport {
+    pub report_len: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Codec {
+    pub path: String,
+    pub driver: String,
+    pub card: String,
+    /// Formats it takes in, e.g. YUYV
+    pub accepts: Vec<String>,
+    /// Formats it produces, e.g. JPEG or H264
+    pub produces: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SuggestedVideo {
+    pub path: String,
+    /// "v4l2" or "framebuffer"
+    pub mode: &'static str,
+}
+
+impl HardwareReport {
+    /// Probe every device node; the report descriptor files of the options
+    /// replace those of the gadgets they are configured for
+    pub fn collect(args: &Args) -> Self {
+        let mut report = Self::default();
+        probe_v4l2(&mut report);
+        report.framebuffers = device_nodes(Path::new("/dev"), "fb").iter()
+            .map(|path| framebuffer(path).unwrap_or_else(|e| Framebuffer {
+                path: path.display().to_string(),
+                error: Some(format!("{:#}", e)),
+                ..Default::default()
+            }))
+            .collect();
+        report.drm = ["card", "renderD"].iter()
+            .flat_map(|prefix| device_nodes(Path::new("/dev/dri"), prefix))
+            .map(|path| drm_node(&path))
+            .collect();
+
+        let descriptor_files = [
+            (args.keyboard_hid.as_str(), args.keyboard_report_desc.as_deref()),
+            (args.mouse_hid.as_str(), args.mouse_report_desc.as_deref()),
+            (args.touchscreen_hid.as_deref().unwrap_or_default(), args.touchscreen_report_desc.as_deref()),
+        ];
+        report.hid_gadgets = device_nodes(Path::new("/dev"), "hidg").iter()
+            .map(|path| {
+                let path = path.display().to_string();
+                let descriptor_file = descriptor_files.iter()
+                    .find(|(device, _)| *device == path)
+                    .and_then(|(_, file)| *file);
+                hid_gadget(&path, descriptor_file)
+            })
+            .collect();
+
+        report.suggested_video = report.video.iter()
+            .find(|device| device.error.is_none() && !device.formats.is_empty())
+            .map(|device| SuggestedVideo { path: device.path.clone(), mode: "v4l2" })
+            .or_else(|| report.framebuffers.iter()
+                .find(|fb| fb.error.is_none())
+                .map(|fb| SuggestedVideo { path: fb.path.clone(), mode: "framebuffer" }));
+        report
+    }
+}
+
+/// Geometry of a framebuffer from sysfs
+pub fn framebuffer(path: &Path) -> Result<Framebuffer> {
+    let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    let sysfs = Path::new("/sys/class/graphics").join(name);
+    let read = |attribute: &str| std::fs::read_to_string(sysfs.join(attribute)).map(|value| value.trim().to_string());
+    let size = read("virtual_size").with_context(|| format!("No framebuffer {}", name))?;
+    let (width, height) = size.split_once(',')
+        .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+        .with_context(|| format!("Invalid virtual size '{}'", size))?;
+    let bits_per_pixel = read("bits_per_pixel")?.parse().context("Invalid bits per pixel")?;
+    Ok(Framebuffer {
+        path: path.display().to_string(),
+        name: read("name").ok(),
+        width,
+        height,
+        bits_per_pixel,
+        error: None,
+    })
+}
+
+/// Driver and capture formats of a V4L2 device
+#[cfg(target_os = "linux")]
+pub fn video_device(path: &str) -> Result<VideoDevice> {
+    use v4l::video::Capture;
+
+    let device = v4l::Device::with_path(path).with_context(|| format!("Failed to open {}", path))?;
+    let caps = device.query_caps().context("Failed to query capabilities")?;
+    let formats = device.enum_formats().context("Failed to list formats")?;
+    let current = device.format().context("Failed to read the current format")?;
+    Ok(VideoDevice {
+        path: path.to_string(),
+        driver: caps.driver,
+        card: caps.card,
+        bus: caps.bus,
+        formats: formats.iter().map(|format| PixelFormat {
+            fourcc: fourcc(&format.fourcc),
+            description: format.description.clone(),
+            compressed: format.flags.contains(v4l::format::description::Flags::COMPRESSED),
+        }).collect(),
+        current: Some(CurrentFormat {
+            fourcc: fourcc(&current.fourcc),
+            width: current.width,
+            height: current.height,
+        }),
+        error: None,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn video_device(_path: &str) -> Result<VideoDevice> {
+    Err(anyhow::anyhow!("video devices can only be probed on Linux"))
+}
+
+/// Sort the V4L2 nodes into capture devices and memory-to-memory codecs
+#[cfg(target_os = "linux")]
+fn probe_v4l2(report: &mut HardwareReport) {
+    use v4l::capability::Flags;
+
+    for path in device_nodes(Path::new("/dev"), "video") {
+        let path = path.display().to_string();
+        let caps = match v4l::Device::with_path(&path).and_then(|device| device.query_caps()) {
+            Ok(caps) => caps,
+            Err(e) => {
+                report.video.push(VideoDevice {
+                    path,
+                    error: Some(format!("Failed to open: {}", e)),
+                    ..Default::default()
+                });
+                continue;
+            }
+        };
+        if caps.capabilities.intersects(Flags::VIDEO_M2M | Flags::VIDEO_M2M_MPLANE) {
+            report.codecs.push(codec(&path).unwrap_or_else(|e| Codec {
+                path,
+                driver: caps.driver,
+                card: caps.card,
+                error: Some(format!("{:#}", e)),
+                ..Default::default()
+            }));
+        } else if caps.capabilities.contains(Flags::VIDEO_CAPTURE) {
+            report.video.push(video_device(&path).unwrap_or_else(|e| VideoDevice {
+                path,
+                driver: caps.driver,
+                card: caps.card,
+                bus: caps.bus,
+                error: Some(format!("{:#}", e)),
+                ..Default::default()
+            }));
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn probe_v4l2(_report: &mut HardwareReport) {}
+
+/// Formats a memory-to-memory device converts between
+#[cfg(target_os = "linux")]
+fn codec(path: &str) -> Result<Codec> {
+    let device = v4l::Device::with_path(path).with_context(|| format!("Failed to open {}", path))?;
+    let caps = device.query_caps().context("Failed to query capabilities")?;
+    // The output queue feeds the codec, the capture queue returns its results
+    let accepts = v4l::video::Output::enum_formats(&device).context("Failed to list input formats")?;
+    let produces = v4l::video::Capture::enum_formats(&device).context("Failed to list output formats")?;
+    Ok(Codec {
+        path: path.to_string(),
+        driver: caps.driver,
+        card: caps.card,
+        accepts: accepts.iter().map(|format| fourcc(&format.fourcc)).collect(),
+        produces: produces.iter().map(|format| fourcc(&format.fourcc)).collect(),
+        error: None,
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn fourcc(fourcc: &v4l::FourCC) -> String {
+    String::from_utf8_lossy(&fourcc.repr).trim_end().to_string()
+}
+
+/// Driver and connector states of a DRM node from sysfs
+fn drm_node(path: &Path) -> DrmNode {
+    let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    let sysfs = Path::new("/sys/class/drm").join(name);
+    let driver = std::fs::read_link(sysfs.join("device/driver")).ok()
+        .and_then(|driver| driver.file_name().map(|name| name.to_string_lossy().into_owned()));
+    // Connectors are listed beside the card as card0-HDMI-A-1
+    let prefix = format!("{}-", name);
+    let connectors = std::fs::read_dir("/sys/class/drm").into_iter().flatten().flatten()
+        .filter_map(|entry| {
+            let connector = entry.file_name().to_string_lossy().into_owned();
+            let status = std::fs::read_to_string(entry.path().join("status")).ok()?;
+            Some(Connector {
+                name: connector.strip_prefix(&prefix)?.to_string(),
+                status: status.trim().to_string(),
+            })
+        })
+        .collect();
+    DrmNode {
+        path: path.display().to_string(),
+        driver,
+        connectors,
+    }
+}
+
+/// Report layouts and UDC state of a HID gadget, from `descriptor_file` or its configfs function
+fn hid_gadget(path: &str, descriptor_file: Option<&str>) -> HidGadget {
+    let mut gadget = HidGadget {
+        path: path.to_string(),
+        udc_state: gadget::udc_state_path(path).and_then(|state| gadget::read_udc_state(&state)),
+        ..Default::default()
+    };
+    // Read directly rather than through hid_descriptor::load_descriptor, which logs to standard output
+    let descriptor = match descriptor_file {
+        Some(file) => std::fs::read(file).with_context(|| format!("Failed to read report descriptor file: {}", file)),
+        None => match gadget::find_hid_function(path) {
+            Some(function) => std::fs::read(function.join("report_desc"))
+                .with_context(|| format!("Failed to read {}/report_desc", function.display())),
+            None => return gadget,
+        },
+    };
+    let parsed = descriptor.and_then(|descriptor| {
+        gadget.descriptor_len = Some(descriptor.len());
+        hid_descriptor::parse(&descriptor)
+    });
+    match parsed {
+        Ok(parsed) => {
+            gadget.keyboard = parsed.keyboard.map(|layout| KeyboardReport { report_len: layout.report_len, key_count: layout.key_count });
+            gadget.mouse = parsed.mouse.map(|layout| MouseReport { report_len: layout.report_len, absolute: layout.absolute });
+            gadget.touchscreen = parsed.touch.map(|layout| TouchscreenReport { report_len: layout.report_len });
+        }
+        Err(e) => gadget.error = Some(format!("{:#}", e)),
+    }
+    gadget
+}
+
+/// Device nodes in `dir` named `prefix` followed by a number, in numeric order
+fn device_nodes(dir: &Path, prefix: &str) -> Vec<PathBuf> {
+    let mut nodes: Vec<(u32, PathBuf)> = std::fs::read_dir(dir).into_iter().flatten().flatten()
+        .filter_map(|entry| {
+            let number = entry.file_name().to_str()?.strip_prefix(prefix)?.parse().ok()?;
+            Some((number, entry.path()))
+        })
+        .collect();
+    nodes.sort();
+    nodes.into_iter().map(|(_, path)| path).collect()
+}
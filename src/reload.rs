@@ -0,0 +1,85 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Configuration reload on SIGHUP and over D-Bus for kvm-rs
+
+#[cfg(feature = "web")]
+use std::sync::{Arc, RwLock};
+use anyhow::Result;
+use tracing::{info, warn};
+use crate::{args::Args, ip_filter::{FilterRules, IpFilter}, logging::LogLevel};
+#[cfg(feature = "web")]
+use crate::{origin::OriginPolicy, websocket::WsSettings};
+
+/// Settings that can change without dropping sessions; the rest only apply after a restart
+#[derive(Clone)]
+pub struct Reloader {
+    /// Absent when the embedder owns the tracing subscriber
+    log_level: Option<LogLevel>,
+    ip_filter: IpFilter,
+    #[cfg(feature = "web")]
+    origin_policy: OriginPolicy,
+    #[cfg(feature = "web")]
+    ws_settings: Arc<RwLock<WsSettings>>,
+}
+
+impl Reloader {
+    #[cfg(feature = "web")]
+    pub fn new(log_level: Option<LogLevel>, ip_filter: IpFilter, origin_policy: OriginPolicy, ws_settings: Arc<RwLock<WsSettings>>) -> Self {
+        Self {
+            log_level,
+            ip_filter,
+            origin_policy,
+            ws_settings,
+        }
+    }
+
+    #[cfg(not(feature = "web"))]
+    pub fn new(log_level: Option<LogLevel>, ip_filter: IpFilter) -> Self {
+        Self {
+            log_level,
+            ip_filter,
+        }
+    }
+
+    /// Read the command line and --config file again and apply the changeable
+    /// settings; connected clients keep their sessions
+    pub fn reload(&self) -> Result<()> {
+        let args = Args::load()?;
+        if let Some(ref log_level) = self.log_level {
+            log_level.set(&args.log_level)?;
+        }
+        self.ip_filter.set_rules(FilterRules {
+            allow: args.allow_ip.clone(),
+            deny: args.deny_ip.clone(),
+        });
+        #[cfg(feature = "web")]
+        self.origin_policy.set_allowed(&args.allowed_origins, &args.allowed_hosts);
+        // Sessions keep the settings they were opened with
+        #[cfg(feature = "web")]
+        {
+            *self.ws_settings.write().unwrap_or_else(|e| e.into_inner()) = WsSettings::from_args(&args);
+        }
+        info!("Configuration reloaded");
+        Ok(())
+    }
+
+    /// Reload on every SIGHUP
+    pub async fn on_signal(self) {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut sighup = match signal(SignalKind::hangup()) {
+                Ok(sighup) => sighup,
+                Err(e) => {
+                    warn!("Failed to install SIGHUP handler: {}", e);
+                    return;
+                }
+            };
+            while sighup.recv().await.is_some() {
+                if let Err(e) = self.reload() {
+                    warn!("Configuration reload failed, keeping the current settings: {:#}", e);
+                }
+            }
+        }
+    }
+}
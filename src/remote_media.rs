@@ -0,0 +1,419 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Virtual media images read from HTTP(S), NFS and SMB servers for kvm-rs
+
+#[cfg(feature = "web")]
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+#[cfg(feature = "web")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "web")]
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context, Result};
+#[cfg(feature = "web")]
+use axum::{
+    body::{Body, Bytes},
+    http::{header, Request, StatusCode},
+};
+use http::Uri;
+#[cfg(feature = "web")]
+use hyper::client::conn::http1::SendRequest;
+#[cfg(feature = "web")]
+use hyper_util::rt::TokioIo;
+#[cfg(feature = "web")]
+use rustls::pki_types::ServerName;
+use tracing::{debug, info, warn};
+
+#[cfg(feature = "web")]
+/// Size of the ranges fetched from HTTP servers and kept in the cache
+const CACHE_BLOCK: u64 = 1024 * 1024;
+#[cfg(feature = "web")]
+/// How long one range request may take
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+#[cfg(feature = "web")]
+/// Further attempts at a range request that failed, e.g. after the server closed the connection
+const FETCH_RETRIES: u32 = 2;
+/// Where NFS and SMB shares are mounted
+const MOUNT_ROOT: &str = "/run/kvm-rs/remote-media";
+
+/// How remote images are reached
+#[derive(Clone)]
+pub struct RemoteSettings {
+    /// CA certificates (PEM) https servers are verified against
+    pub ca_file: Option<PathBuf>,
+    /// NBD device HTTP images are served on
+    pub nbd_device: PathBuf,
+    /// Bytes of HTTP images kept in memory
+    pub cache_size: u64,
+}
+
+/// Where a remote image is and how to log in to it
+pub enum RemoteImage {
+    Http(Uri),
+    /// `host:/export/dir` and the image in that directory
+    Nfs { host: String, dir: String, file: String },
+    /// `//host/share`, and the image's path in the share
+    Smb { host: String, share: String, path: String },
+}
+
+/// A remote image made available to the gadget; dropping it releases the
+/// NBD device or unmounts the share
+pub struct Attachment {
+    /// What the gadget LUN's `file` is set to
+    path: PathBuf,
+    #[cfg(all(target_os = "linux", feature = "web"))]
+    _nbd: Option<crate::nbd::NbdExport>,
+    mount: Option<PathBuf>,
+}
+
+impl RemoteImage {
+    /// Parse an `http(s)://`, `nfs://host/export/path/image.iso` or
+    /// `smb://host/share/path/image.iso` URL
+    pub fn parse(url: &str) -> Result<Self> {
+        let uri: Uri = url.parse().map_err(|_| anyhow!("Invalid image URL"))?;
+        let host = uri.host().ok_or_else(|| anyhow!("Image URL has no host"))?.to_string();
+        if uri.authority().is_some_and(|authority| authority.as_str().contains('@')) {
+            bail!("Give the user name and password of an image URL separately");
+        }
+        let path = uri.path();
+        match uri.scheme_str() {
+            Some("http") | Some("https") => Ok(Self::Http(uri)),
+            Some("nfs") => {
+                let (dir, file) = path.rsplit_once('/').filter(|(dir, file)| !dir.is_empty() && !file.is_empty())
+                    .ok_or_else(|| anyhow!("NFS image URLs are nfs://host/export/path/image"))?;
+                Ok(Self::Nfs { host, dir: dir.to_string(), file: file.to_string() })
+            }
+            Some("smb") | Some("cifs") => {
+                let (share, path) = path.trim_start_matches('/').split_once('/').filter(|(share, path)| !share.is_empty() && !path.is_empty())
+                    .ok_or_else(|| anyhow!("SMB image URLs are smb://host/share/path/image"))?;
+                Ok(Self::Smb { host, share: share.to_string(), path: path.to_string() })
+            }
+            _ => bail!("Image URLs must start with http://, https://, nfs:// or smb://"),
+        }
+    }
+
+    /// Make the image readable by the gadget, as a block device or a file on a
+    /// mounted share. Only SMB shares use `credentials`
+    #[cfg_attr(not(feature = "web"), allow(unused_variables))]
+    pub async fn attach(&self, settings: &RemoteSettings, credentials: Option<(&str, &str)>) -> Result<Attachment> {
+        match self {
+            #[cfg(feature = "web")]
+            Self::Http(uri) => {
+                let image = Arc::new(HttpImage::open(uri.clone(), settings).await?);
+                info!("Serving {} ({} bytes) on {}", image.name(), image.size(), settings.nbd_device.display());
+                attach_http(image, settings)
+            }
+            #[cfg(not(feature = "web"))]
+            Self::Http(_) => bail!("HTTP images need kvm-rs built with the web feature"),
+            Self::Nfs { host, dir, file } => {
+                let addr = resolve(host).await?;
+                let source = format!("{}:{}", host, dir);
+                let options = format!("vers=4.1,addr={},soft,timeo=100", addr);
+                let mount = mount_share(&source, "nfs", &options)?;
+                Ok(Attachment { path: mount.join(file), #[cfg(all(target_os = "linux", feature = "web"))] _nbd: None, mount: Some(mount) })
+            }
+            Self::Smb { host, share, path } => {
+                let addr = resolve(host).await?;
+                let source = format!("//{}/{}", host, share);
+                let mut options = format!("ip={},vers=3.0", addr);
+                match credentials {
+                    Some((user, password)) if user.contains(',') || password.contains(',') => {
+                        bail!("SMB user names and passwords cannot contain commas")
+                    }
+                    Some((user, password)) => options.push_str(&format!(",username={},password={}", user, password)),
+                    None => options.push_str(",guest"),
+                }
+                let mount = mount_share(&source, "cifs", &options)?;
+                Ok(Attachment { path: mount.join(path), #[cfg(all(target_os = "linux", feature = "web"))] _nbd: None, mount: Some(mount) })
+            }
+        }
+    }
+}
+
+impl Attachment {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Attachment {
+    fn drop(&mut self) {
+        if let Some(ref mount) = self.mount {
+            unmount_share(mount);
+        }
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "web"))]
+fn attach_http(image: Arc<HttpImage>, settings: &RemoteSettings) -> Result<Attachment> {
+    let nbd = crate::nbd::NbdExport::start(&settings.nbd_device, image)?;
+    Ok(Attachment {
+        path: nbd.device().to_path_buf(),
+        _nbd: Some(nbd),
+        mount: None,
+    })
+}
+
+#[cfg(all(not(target_os = "linux"), feature = "web"))]
+fn attach_http(_image: Arc<HttpImage>, _settings: &RemoteSettings) -> Result<Attachment> {
+    bail!("HTTP images need the Linux NBD driver")
+}
+
+async fn resolve(host: &str) -> Result<std::net::IpAddr> {
+    tokio::net::lookup_host((host.trim_matches(|c| c == '[' || c == ']'), 0))
+        .await
+        .with_context(|| format!("Failed to resolve {}", host))?
+        .next()
+        .map(|addr| addr.ip())
+        .ok_or_else(|| anyhow!("{} has no address", host))
+}
+
+/// Mount `source` read-only under the mount root, in a directory of its own
+#[cfg(target_os = "linux")]
+fn mount_share(source: &str, fstype: &str, options: &str) -> Result<PathBuf> {
+    use nix::mount::{mount, MsFlags};
+
+    static NEXT: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+    let target = Path::new(MOUNT_ROOT).join(NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed).to_string());
+    std::fs::create_dir_all(&target).with_context(|| format!("Failed to create {}", target.display()))?;
+    let flags = MsFlags::MS_RDONLY | MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC;
+    if let Err(e) = mount(Some(source), &target, Some(fstype), flags, Some(options)) {
+        let _ = std::fs::remove_dir(&target);
+        // Options may hold a password, leave them out
+        bail!("Failed to mount {} ({}): {}", source, fstype, e);
+    }
+    info!("Mounted {} on {}", source, target.display());
+    Ok(target)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn mount_share(source: &str, _fstype: &str, _options: &str) -> Result<PathBuf> {
+    bail!("Mounting {} needs Linux", source)
+}
+
+#[cfg(target_os = "linux")]
+fn unmount_share(target: &Path) {
+    // Lazily, the gadget may still be closing the image
+    match nix::mount::umount2(target, nix::mount::MntFlags::MNT_DETACH) {
+        Ok(()) => {
+            let _ = std::fs::remove_dir(target);
+            debug!("Unmounted {}", target.display());
+        }
+        Err(e) => warn!("Failed to unmount {}: {}", target.display(), e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unmount_share(_target: &Path) {}
+
+/// An image on an HTTP server that answers range requests, read in blocks
+/// that are kept in a cache of recently read ones
+#[cfg(feature = "web")]
+pub struct HttpImage {
+    uri: Uri,
+    host: String,
+    port: u16,
+    tls: Option<tokio_rustls::TlsConnector>,
+    size: u64,
+    /// Open connection, reused between range requests
+    connection: tokio::sync::Mutex<Option<SendRequest<Body>>>,
+    cache: Mutex<BlockCache>,
+}
+
+#[cfg(feature = "web")]
+impl HttpImage {
+    /// Check that the server has the image and serves ranges of it, and learn its size
+    pub async fn open(uri: Uri, settings: &RemoteSettings) -> Result<Self> {
+        let https = uri.scheme_str() == Some("https");
+        let host = uri.host().unwrap_or_default().trim_matches(|c| c == '[' || c == ']').to_string();
+        let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+        let tls = if https {
+            let ca_file = settings.ca_file.as_deref()
+                .ok_or_else(|| anyhow!("--remote-media-ca is required to verify {}", host))?;
+            Some(tokio_rustls::TlsConnector::from(Arc::new(client_config(ca_file)?)))
+        } else {
+            None
+        };
+        let mut image = Self {
+            uri,
+            host,
+            port,
+            tls,
+            size: 0,
+            connection: tokio::sync::Mutex::new(None),
+            cache: Mutex::new(BlockCache::new((settings.cache_size / CACHE_BLOCK).max(1) as usize)),
+        };
+        let (_, size) = image.fetch(0, 1).await?;
+        if size == 0 {
+            bail!("{} is empty", image.name());
+        }
+        image.size = size;
+        Ok(image)
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Scheme, host and port, for messages; the path may hold a secret
+    pub fn name(&self) -> String {
+        format!("{}://{}", self.uri.scheme_str().unwrap_or_default(), self.uri.authority().map(|a| a.as_str()).unwrap_or(&self.host))
+    }
+
+    /// `len` bytes at `offset`; past the end of the image they are zeros
+    pub async fn read(&self, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let mut data = vec![0u8; len];
+        let end = (offset + len as u64).min(self.size);
+        let mut position = offset;
+        while position < end {
+            let index = position / CACHE_BLOCK;
+            let block = self.block(index).await?;
+            let start = (position - index * CACHE_BLOCK) as usize;
+            let count = (block.len() - start).min((end - position) as usize);
+            let at = (position - offset) as usize;
+            data[at..at + count].copy_from_slice(&block[start..start + count]);
+            position += count as u64;
+        }
+        Ok(data)
+    }
+
+    async fn block(&self, index: u64) -> Result<Bytes> {
+        if let Some(block) = self.cache.lock().unwrap_or_else(|e| e.into_inner()).get(index) {
+            return Ok(block);
+        }
+        let start = index * CACHE_BLOCK;
+        let end = (start + CACHE_BLOCK).min(self.size);
+        let mut attempt = 0;
+        let (block, _) = loop {
+            match self.fetch(start, end - start).await {
+                Ok(fetched) => break fetched,
+                Err(e) if attempt < FETCH_RETRIES => {
+                    debug!("Range request to {} failed, retrying: {:#}", self.name(), e);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        if block.len() as u64 != end - start {
+            bail!("{} answered {} bytes instead of {}", self.name(), block.len(), end - start);
+        }
+        self.cache.lock().unwrap_or_else(|e| e.into_inner()).insert(index, block.clone());
+        Ok(block)
+    }
+
+    /// `len` bytes at `start`, and the size of the whole image
+    async fn fetch(&self, start: u64, len: u64) -> Result<(Bytes, u64)> {
+        let mut connection = self.connection.lock().await;
+        let result = tokio::time::timeout(FETCH_TIMEOUT, self.get_range(&mut connection, start, len))
+            .await
+            .map_err(|_| anyhow!("no answer within {:?}", FETCH_TIMEOUT))
+            .and_then(|result| result);
+        if result.is_err() {
+            // Reconnect for the next request
+            *connection = None;
+        }
+        result
+    }
+
+    async fn get_range(&self, connection: &mut Option<SendRequest<Body>>, start: u64, len: u64) -> Result<(Bytes, u64)> {
+        let sender = match connection.take() {
+            Some(sender) if !sender.is_closed() => sender,
+            _ => self.connect().await?,
+        };
+        let sender = connection.insert(sender);
+        sender.ready().await?;
+        let path = self.uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+        let request = Request::get(path)
+            .header(header::HOST, self.uri.authority().map(|a| a.as_str()).unwrap_or(&self.host))
+            .header(header::RANGE, format!("bytes={}-{}", start, start + len - 1))
+            .body(Body::empty())?;
+        let response = sender.send_request(request).await?;
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            bail!("{} answered {} to a range request", self.name(), response.status());
+        }
+        // Content-Range: bytes 0-0/734003200
+        let size = response.headers().get(header::CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit_once('/'))
+            .and_then(|(_, size)| size.parse().ok())
+            .ok_or_else(|| anyhow!("{} did not tell the size of the image", self.name()))?;
+        let body = axum::body::to_bytes(Body::new(response.into_body()), len as usize).await?;
+        Ok((body, size))
+    }
+
+    async fn connect(&self) -> Result<SendRequest<Body>> {
+        let stream = tokio::net::TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .with_context(|| format!("Failed to connect to {}:{}", self.host, self.port))?;
+        let sender = match self.tls {
+            Some(ref connector) => {
+                let server_name = ServerName::try_from(self.host.clone())?;
+                let stream = connector.connect(server_name, stream).await.context("TLS handshake failed")?;
+                handshake(TokioIo::new(stream)).await?
+            }
+            None => handshake(TokioIo::new(stream)).await?,
+        };
+        Ok(sender)
+    }
+}
+
+#[cfg(feature = "web")]
+async fn handshake<I>(io: I) -> Result<SendRequest<Body>>
+where
+    I: hyper::rt::Read + hyper::rt::Write + Unpin + Send + 'static,
+{
+    let (sender, connection) = hyper::client::conn::http1::handshake(io).await?;
+    tokio::spawn(async move {
+        let _ = connection.await;
+    });
+    Ok(sender)
+}
+
+/// Recently read blocks, the least recently inserted dropped first
+#[cfg(feature = "web")]
+struct BlockCache {
+    capacity: usize,
+    blocks: HashMap<u64, Bytes>,
+    order: VecDeque<u64>,
+}
+
+#[cfg(feature = "web")]
+impl BlockCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            blocks: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn get(&self, index: u64) -> Option<Bytes> {
+        self.blocks.get(&index).cloned()
+    }
+
+    fn insert(&mut self, index: u64, block: Bytes) {
+        if self.blocks.insert(index, block).is_some() {
+            return;
+        }
+        self.order.push_back(index);
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.blocks.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// TLS client settings verifying image servers against the certificates of `ca_file`
+#[cfg(feature = "web")]
+fn client_config(ca_file: &Path) -> Result<rustls::ClientConfig> {
+    let pem = std::fs::read(ca_file)
+        .with_context(|| format!("Failed to read remote media CA file {}", ca_file.display()))?;
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut std::io::Cursor::new(&pem)) {
+        roots.add(cert.context("Failed to parse remote media CA file")?)?;
+    }
+    Ok(rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
@@ -0,0 +1,102 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// End-to-end tests of the VNC server of kvm-rs
+
+use crate::test_support::{wait_for_reports, TestServer, TEST_COLOR};
+
+/// 16 bits per pixel, depth 16, little-endian RGB565
+const RGB565: [u8; 16] = [16, 16, 0, 1, 0, 31, 0, 63, 0, 31, 11, 5, 0, 0, 0, 0];
+
+#[tokio::test]
+async fn handshake_reports_default_pixel_format() {
+    let server = TestServer::start().await.unwrap();
+    let client = server.connect().await.unwrap();
+    assert_eq!(client.name, "KVM-RS");
+    // 32 bpp, depth 24, little-endian true colour xRGB
+    assert_eq!(client.pixel_format, [32, 24, 0, 1, 0, 255, 0, 255, 0, 255, 16, 8, 0, 0, 0, 0]);
+}
+
+#[tokio::test]
+async fn full_update_is_raw_in_default_format() {
+    let server = TestServer::start().await.unwrap();
+    let mut client = server.connect().await.unwrap();
+    client.set_encodings(&[0]).await.unwrap();
+    client.request_update(false).await.unwrap();
+    let rects = client.read_update().await.unwrap();
+    assert_eq!(rects.len(), 1);
+    let rect = &rects[0];
+    assert_eq!((rect.x, rect.y, rect.width, rect.height), (0, 0, 320, 240));
+    let [r, g, b] = TEST_COLOR;
+    assert!(rect.pixels.chunks_exact(4).all(|pixel| pixel == [b, g, r, 0]));
+}
+
+#[tokio::test]
+async fn update_follows_set_pixel_format() {
+    let server = TestServer::start().await.unwrap();
+    let mut client = server.connect().await.unwrap();
+    client.set_pixel_format(RGB565).await.unwrap();
+    client.request_update(false).await.unwrap();
+    let rects = client.read_update().await.unwrap();
+    // 0x20, 0x40, 0x80 scaled to 4, 16 and 16
+    assert!(rects[0].pixels.chunks_exact(2).all(|pixel| pixel == [0x10, 0x22]));
+}
+
+#[tokio::test]
+async fn incremental_update_waits_for_a_frame() {
+    let server = TestServer::start().await.unwrap();
+    let mut client = server.connect().await.unwrap();
+    client.request_update(false).await.unwrap();
+    client.read_update().await.unwrap();
+    client.request_update(true).await.unwrap();
+    let rects = client.read_update().await.unwrap();
+    assert_eq!((rects[0].width, rects[0].height), (320, 240));
+}
+
+#[tokio::test]
+async fn unsupported_pixel_format_closes_the_connection() {
+    let server = TestServer::start().await.unwrap();
+    let mut client = server.connect().await.unwrap();
+    // 8 bpp with a colour map
+    let mut format = RGB565;
+    format[0] = 8;
+    format[3] = 0;
+    client.set_pixel_format(format).await.unwrap();
+    assert!(client.is_closed().await.unwrap());
+}
+
+#[tokio::test]
+async fn unknown_message_closes_the_connection() {
+    let server = TestServer::start().await.unwrap();
+    let mut client = server.connect().await.unwrap();
+    client.send_raw(&[0x7f]).await.unwrap();
+    assert!(client.is_closed().await.unwrap());
+}
+
+#[tokio::test]
+async fn key_events_become_keyboard_reports() {
+    let server = TestServer::start().await.unwrap();
+    let mut client = server.connect().await.unwrap();
+    client.key_event(0xffe1, true).await.unwrap(); // Left Shift
+    client.key_event(0x0061, true).await.unwrap(); // a
+    client.key_event(0x0061, false).await.unwrap();
+    client.key_event(0xffe1, false).await.unwrap();
+    let reports = wait_for_reports(&server.keyboard, 4).await.unwrap();
+    assert_eq!(reports, vec![
+        vec![0x02, 0, 0, 0, 0, 0, 0, 0],
+        vec![0x02, 0, 0x04, 0, 0, 0, 0, 0],
+        vec![0x02, 0, 0, 0, 0, 0, 0, 0],
+        vec![0, 0, 0, 0, 0, 0, 0, 0],
+    ]);
+}
+
+#[tokio::test]
+async fn pointer_events_become_relative_mouse_reports() {
+    let server = TestServer::start().await.unwrap();
+    let mut client = server.connect().await.unwrap();
+    client.pointer_event(0, 100, 100).await.unwrap();
+    client.pointer_event(0, 110, 95).await.unwrap();
+    client.pointer_event(1, 110, 95).await.unwrap();
+    let reports = wait_for_reports(&server.mouse, 3).await.unwrap();
+    assert_eq!(reports[1], vec![0, 10, (-5i8) as u8, 0]);
+    assert_eq!(reports[2], vec![1, 0, 0, 0]);
+}
@@ -0,0 +1,254 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// WebRTC data channel transport for kvm-rs
+
+#[cfg(feature = "webrtc")]
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc, Mutex,
+};
+use anyhow::Result;
+#[cfg(feature = "webrtc")]
+use anyhow::Context;
+use bytes::Bytes;
+use tokio::sync::mpsc;
+#[cfg(feature = "webrtc")]
+use webrtc::{
+    api::APIBuilder,
+    data_channel::{data_channel_state::RTCDataChannelState, RTCDataChannel},
+    ice_transport::{ice_candidate::RTCIceCandidate, ice_server::RTCIceServer},
+    peer_connection::{
+        configuration::RTCConfiguration,
+        peer_connection_state::RTCPeerConnectionState,
+        sdp::session_description::RTCSessionDescription,
+        RTCPeerConnection,
+    },
+};
+use tracing::warn;
+use crate::args::Args;
+#[cfg(feature = "webrtc")]
+use crate::ws_protocol;
+
+/// ICE candidate as exchanged in webrtc-candidate messages
+#[cfg(feature = "webrtc")]
+pub use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit as IceCandidate;
+
+/// Label of the data channel frames are sent on; browsers should open it
+/// unordered and without retransmits so a lost chunk only drops one frame
+#[cfg(feature = "webrtc")]
+pub const VIDEO_CHANNEL: &str = "video";
+
+/// Label of the data channel carrying kvm-rs input messages
+#[cfg(feature = "webrtc")]
+pub const INPUT_CHANNEL: &str = "input";
+
+/// Frames are skipped while more than this is queued on the video channel
+#[cfg(feature = "webrtc")]
+const VIDEO_BUFFER_LIMIT: usize = 1024 * 1024;
+
+/// STUN/TURN servers handed to every peer connection
+#[cfg(feature = "webrtc")]
+#[derive(Debug, Clone, Default)]
+pub struct RtcSettings {
+    pub ice_servers: Vec<RTCIceServer>,
+}
+
+#[cfg(feature = "webrtc")]
+impl RtcSettings {
+    /// None unless --webrtc is given
+    pub fn from_args(args: &Args) -> Option<Self> {
+        if !args.webrtc {
+            return None;
+        }
+        let ice_servers = args.webrtc_ice_servers.iter()
+            .map(|url| {
+                // Only TURN servers take credentials
+                let turn = url.starts_with("turn:") || url.starts_with("turns:");
+                RTCIceServer {
+                    urls: vec![url.clone()],
+                    username: if turn { args.webrtc_ice_username.clone().unwrap_or_default() } else { String::new() },
+                    credential: if turn { args.webrtc_ice_credential.clone().unwrap_or_default() } else { String::new() },
+                    ..Default::default()
+                }
+            })
+            .collect();
+        Some(Self { ice_servers })
+    }
+}
+
+/// What a peer reports to the WebSocket session that signals it
+#[cfg_attr(not(feature = "webrtc"), allow(dead_code))]
+pub enum PeerEvent {
+    /// Local ICE candidate to send to the browser
+    Candidate(IceCandidate),
+    /// Input message received on the input channel
+    Input(Bytes),
+    /// The connection failed or was closed by the browser
+    Closed,
+}
+
+/// WebRTC connection of one console client, signaled over its WebSocket
+#[cfg(feature = "webrtc")]
+pub struct Peer {
+    connection: Arc<RTCPeerConnection>,
+    video: Arc<Mutex<Option<Arc<RTCDataChannel>>>>,
+    next_frame: AtomicU32,
+}
+
+#[cfg(feature = "webrtc")]
+impl Peer {
+    /// Answer the browser's offer; returns the peer and the answer SDP. ICE
+    /// candidates trickle in as events afterwards
+    pub async fn answer(settings: &RtcSettings, offer_sdp: String, events: mpsc::Sender<PeerEvent>) -> Result<(Self, String)> {
+        // Data channels only, no media engine needed
+        let api = APIBuilder::new().build();
+        let connection = Arc::new(api.new_peer_connection(RTCConfiguration {
+            ice_servers: settings.ice_servers.clone(),
+            ..Default::default()
+        }).await.context("Failed to create WebRTC peer connection")?);
+        let video = Arc::new(Mutex::new(None));
+
+        let candidate_events = events.clone();
+        connection.on_ice_candidate(Box::new(move |candidate: Option<RTCIceCandidate>| {
+            let events = candidate_events.clone();
+            Box::pin(async move {
+                // None marks the end of gathering, the browser does not need it
+                if let Some(init) = candidate.and_then(|candidate| candidate.to_json().ok()) {
+                    let _ = events.send(PeerEvent::Candidate(init)).await;
+                }
+            })
+        }));
+
+        let state_events = events.clone();
+        connection.on_peer_connection_state_change(Box::new(move |state: RTCPeerConnectionState| {
+            let events = state_events.clone();
+            Box::pin(async move {
+                if matches!(state, RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed) {
+                    let _ = events.send(PeerEvent::Closed).await;
+                }
+            })
+        }));
+
+        let video_slot = video.clone();
+        connection.on_data_channel(Box::new(move |channel: Arc<RTCDataChannel>| {
+            let video = video_slot.clone();
+            let events = events.clone();
+            Box::pin(async move {
+                match channel.label() {
+                    VIDEO_CHANNEL => {
+                        *video.lock().unwrap_or_else(|e| e.into_inner()) = Some(channel);
+                    }
+                    INPUT_CHANNEL => {
+                        channel.on_message(Box::new(move |message| {
+                            let events = events.clone();
+                            Box::pin(async move {
+                                let _ = events.send(PeerEvent::Input(message.data)).await;
+                            })
+                        }));
+                    }
+                    label => warn!("Ignoring WebRTC data channel '{}'", label),
+                }
+            })
+        }));
+
+        let offer = RTCSessionDescription::offer(offer_sdp).context("Invalid WebRTC offer")?;
+        connection.set_remote_description(offer).await.context("Invalid WebRTC offer")?;
+        let answer = connection.create_answer(None).await.context("Failed to create WebRTC answer")?;
+        connection.set_local_description(answer).await.context("Failed to set WebRTC answer")?;
+        let answer_sdp = connection.local_description().await
+            .map(|description| description.sdp)
+            .context("WebRTC answer missing")?;
+
+        Ok((
+            Self {
+                connection,
+                video,
+                next_frame: AtomicU32::new(0),
+            },
+            answer_sdp,
+        ))
+    }
+
+    pub async fn add_candidate(&self, candidate: IceCandidate) -> Result<()> {
+        self.connection.add_ice_candidate(candidate).await.context("Invalid ICE candidate")
+    }
+
+    /// Whether frames go over the video channel instead of the WebSocket
+    pub fn video_open(&self) -> bool {
+        let video = self.video.lock().unwrap_or_else(|e| e.into_inner());
+        video.as_ref().is_some_and(|channel| channel.ready_state() == RTCDataChannelState::Open)
+    }
+
+    /// Send a frame message in chunks; skipped while the channel is congested
+    pub async fn send_frame(&self, frame: &[u8]) -> Result<()> {
+        let Some(channel) = self.video.lock().unwrap_or_else(|e| e.into_inner()).clone() else {
+            return Ok(());
+        };
+        if channel.buffered_amount().await > VIDEO_BUFFER_LIMIT {
+            return Ok(());
+        }
+        let frame_id = self.next_frame.fetch_add(1, Ordering::Relaxed);
+        for chunk in ws_protocol::frame_chunks(frame_id, frame) {
+            channel.send(&Bytes::from(chunk)).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn close(&self) {
+        if let Err(e) = self.connection.close().await {
+            warn!("Failed to close WebRTC connection: {}", e);
+        }
+    }
+}
+
+/// ICE candidate as exchanged in webrtc-candidate messages
+#[cfg(not(feature = "webrtc"))]
+#[derive(Debug, Default)]
+#[allow(dead_code)] // Same fields as RTCIceCandidateInit
+pub struct IceCandidate {
+    pub candidate: String,
+    pub sdp_mid: Option<String>,
+    pub sdp_mline_index: Option<u16>,
+    pub username_fragment: Option<String>,
+}
+
+/// Built without the webrtc feature: offers are refused and frames stay on the WebSocket
+#[cfg(not(feature = "webrtc"))]
+#[derive(Debug, Clone, Default)]
+pub struct RtcSettings;
+
+#[cfg(not(feature = "webrtc"))]
+impl RtcSettings {
+    pub fn from_args(args: &Args) -> Option<Self> {
+        if args.webrtc {
+            warn!("--webrtc is ignored, kvm-rs was built without the webrtc feature");
+        }
+        None
+    }
+}
+
+#[cfg(not(feature = "webrtc"))]
+#[allow(dead_code)] // Never constructed without the webrtc feature
+pub struct Peer;
+
+#[cfg(not(feature = "webrtc"))]
+#[allow(dead_code)]
+impl Peer {
+    pub async fn answer(_settings: &RtcSettings, _offer_sdp: String, _events: mpsc::Sender<PeerEvent>) -> Result<(Self, String)> {
+        Err(anyhow::anyhow!("WebRTC is not enabled"))
+    }
+
+    pub async fn add_candidate(&self, _candidate: IceCandidate) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn video_open(&self) -> bool {
+        false
+    }
+
+    pub async fn send_frame(&self, _frame: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    pub async fn close(&self) {}
+}
@@ -0,0 +1,224 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Security audit log of kvm-rs, structured journald entries for SIEM ingestion
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use anyhow::Result;
+use tracing::warn;
+#[cfg(target_os = "linux")]
+use crate::audit::push_field;
+#[cfg(feature = "web")]
+use crate::webhooks::Webhooks;
+
+/// Identifier of security audit entries, e.g. `journalctl -t kvm-rs-security`
+const SYSLOG_IDENTIFIER: &str = "kvm-rs-security";
+/// journald priorities of entries
+const PRIORITY_WARNING: u8 = 4;
+const PRIORITY_INFO: u8 = 6;
+
+/// Security relevant event; each becomes one journald entry whose `KVM_EVENT`
+/// field is the kebab-case name of the variant
+pub enum SecurityEvent<'a> {
+    /// A connection reached a listener, or was refused before any handshake
+    Connection { transport: &'a str, client: SocketAddr, refused: Option<&'a str> },
+    /// Credentials were checked, `failure` says why they were refused
+    Login { transport: &'a str, client: IpAddr, user: &'a str, failure: Option<&'a str> },
+    /// A request carried no valid session token
+    TokenRefused { client: SocketAddr, reason: &'a str },
+    /// Logins from an address or to an account are refused for a while
+    Lockout { client: IpAddr, user: &'a str, duration: Duration },
+    /// A console session started
+    SessionStart { transport: &'a str, client: SocketAddr, session: u64 },
+    /// A console session ended
+    SessionStop { transport: &'a str, client: SocketAddr, session: u64, duration: Duration },
+    /// The arbiter gave a session another role, e.g. promoted it to controller
+    RoleChange { client: SocketAddr, session: u64, role: &'a str },
+    /// A session switched its own view-only mode
+    ViewOnly { client: SocketAddr, session: u64, enabled: bool },
+    /// Input forwarding to the host was locked or unlocked through the API
+    InputLock { client: SocketAddr, locked: bool },
+    /// A host power operation was requested, `failure` says why it was not accepted
+    PowerAction { client: SocketAddr, user: Option<&'a str>, action: &'a str, failure: Option<&'a str> },
+}
+
+impl SecurityEvent<'_> {
+    /// KVM_EVENT value, priority, message and the other fields of the entry
+    fn entry(&self) -> (&'static str, u8, String, Vec<(&'static str, String)>) {
+        match *self {
+            SecurityEvent::Connection { transport, client, refused } => {
+                let mut fields = vec![("KVM_TRANSPORT", transport.to_string()), ("KVM_CLIENT", client.to_string())];
+                match refused {
+                    Some(reason) => {
+                        fields.push(("KVM_RESULT", "refused".to_string()));
+                        fields.push(("KVM_REASON", reason.to_string()));
+                        ("connection", PRIORITY_WARNING, format!("Refused {} connection from {}: {}", transport, client, reason), fields)
+                    }
+                    None => {
+                        fields.push(("KVM_RESULT", "accepted".to_string()));
+                        ("connection", PRIORITY_INFO, format!("Accepted {} connection from {}", transport, client), fields)
+                    }
+                }
+            }
+            SecurityEvent::Login { transport, client, user, failure } => {
+                let mut fields = vec![
+                    ("KVM_TRANSPORT", transport.to_string()),
+                    ("KVM_CLIENT", client.to_string()),
+                    ("KVM_USER", user.to_string()),
+                ];
+                match failure {
+                    Some(reason) => {
+                        fields.push(("KVM_RESULT", "failure".to_string()));
+                        fields.push(("KVM_REASON", reason.to_string()));
+                        ("login", PRIORITY_WARNING, format!("Failed {} login for {} from {}: {}", transport, user, client, reason), fields)
+                    }
+                    None => {
+                        fields.push(("KVM_RESULT", "success".to_string()));
+                        ("login", PRIORITY_INFO, format!("{} login by {} from {}", transport, user, client), fields)
+                    }
+                }
+            }
+            SecurityEvent::TokenRefused { client, reason } => (
+                "token-refused",
+                PRIORITY_WARNING,
+                format!("Refused session token from {}: {}", client, reason),
+                vec![("KVM_CLIENT", client.to_string()), ("KVM_RESULT", "failure".to_string()), ("KVM_REASON", reason.to_string())],
+            ),
+            SecurityEvent::Lockout { client, user, duration } => (
+                "lockout",
+                PRIORITY_WARNING,
+                format!("Locked out logins from {} or for {} for {}s", client, user, duration.as_secs()),
+                vec![
+                    ("KVM_CLIENT", client.to_string()),
+                    ("KVM_USER", user.to_string()),
+                    ("KVM_LOCKOUT_SECS", duration.as_secs().to_string()),
+                ],
+            ),
+            SecurityEvent::SessionStart { transport, client, session } => (
+                "session-start",
+                PRIORITY_INFO,
+                format!("Session {} started: {} client {}", session, transport, client),
+                vec![
+                    ("KVM_TRANSPORT", transport.to_string()),
+                    ("KVM_CLIENT", client.to_string()),
+                    ("KVM_SESSION_ID", session.to_string()),
+                ],
+            ),
+            SecurityEvent::SessionStop { transport, client, session, duration } => (
+                "session-stop",
+                PRIORITY_INFO,
+                format!("Session {} ended after {}s: {} client {}", session, duration.as_secs(), transport, client),
+                vec![
+                    ("KVM_TRANSPORT", transport.to_string()),
+                    ("KVM_CLIENT", client.to_string()),
+                    ("KVM_SESSION_ID", session.to_string()),
+                    ("KVM_DURATION_SECS", duration.as_secs().to_string()),
+                ],
+            ),
+            SecurityEvent::RoleChange { client, session, role } => (
+                "role-change",
+                PRIORITY_INFO,
+                format!("Session {} of {} is now {}", session, client, role),
+                vec![
+                    ("KVM_CLIENT", client.to_string()),
+                    ("KVM_SESSION_ID", session.to_string()),
+                    ("KVM_ROLE", role.to_string()),
+                ],
+            ),
+            SecurityEvent::ViewOnly { client, session, enabled } => (
+                "view-only",
+                PRIORITY_INFO,
+                format!("Session {} of {} {} view-only mode", session, client, if enabled { "entered" } else { "left" }),
+                vec![
+                    ("KVM_CLIENT", client.to_string()),
+                    ("KVM_SESSION_ID", session.to_string()),
+                    ("KVM_ENABLED", enabled.to_string()),
+                ],
+            ),
+            SecurityEvent::InputLock { client, locked } => (
+                "input-lock",
+                PRIORITY_INFO,
+                format!("Input {} by {}", if locked { "locked" } else { "unlocked" }, client),
+                vec![("KVM_CLIENT", client.to_string()), ("KVM_ENABLED", locked.to_string())],
+            ),
+            SecurityEvent::PowerAction { client, user, action, failure } => {
+                let mut fields = vec![("KVM_CLIENT", client.to_string()), ("KVM_POWER_ACTION", action.to_string())];
+                if let Some(user) = user {
+                    fields.push(("KVM_USER", user.to_string()));
+                }
+                match failure {
+                    Some(reason) => {
+                        fields.push(("KVM_RESULT", "failure".to_string()));
+                        fields.push(("KVM_REASON", reason.to_string()));
+                        ("power-action", PRIORITY_WARNING, format!("Power {} by {} failed: {}", action, client, reason), fields)
+                    }
+                    None => {
+                        fields.push(("KVM_RESULT", "success".to_string()));
+                        ("power-action", PRIORITY_INFO, format!("Power {} by {}", action, client), fields)
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Security audit trail, separate from the debug output on stdout and stderr.
+/// Disabled by default, when events are dropped
+#[derive(Clone, Default)]
+pub struct SecurityAudit {
+    #[cfg(target_os = "linux")]
+    socket: Option<std::sync::Arc<std::os::unix::net::UnixDatagram>>,
+    /// Authentication failures are also sent to webhooks
+    #[cfg(feature = "web")]
+    webhooks: Webhooks,
+}
+
+impl SecurityAudit {
+    /// Send events to journald
+    #[cfg(target_os = "linux")]
+    #[cfg_attr(not(feature = "web"), allow(clippy::needless_update))]
+    pub fn journald() -> Result<Self> {
+        Ok(Self {
+            socket: Some(std::sync::Arc::new(crate::audit::connect_journald()?)),
+            ..Self::default()
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn journald() -> Result<Self> {
+        Err(anyhow::anyhow!("journald security audit logging is only supported on Linux"))
+    }
+
+    /// Send refused credentials and tokens and lockouts to `webhooks` too
+    #[cfg(feature = "web")]
+    pub fn with_webhooks(mut self, webhooks: Webhooks) -> Self {
+        self.webhooks = webhooks;
+        self
+    }
+
+    pub fn log(&self, event: SecurityEvent<'_>) {
+        #[cfg(feature = "web")]
+        self.webhooks.security_event(&event);
+        #[cfg(target_os = "linux")]
+        self.write_journald(&event);
+    }
+
+    #[cfg(target_os = "linux")]
+    fn write_journald(&self, event: &SecurityEvent<'_>) {
+        let Some(ref socket) = self.socket else {
+            return;
+        };
+        let (name, priority, message, fields) = event.entry();
+        let mut entry = Vec::new();
+        push_field(&mut entry, "MESSAGE", &message);
+        push_field(&mut entry, "PRIORITY", &priority.to_string());
+        push_field(&mut entry, "SYSLOG_IDENTIFIER", SYSLOG_IDENTIFIER);
+        push_field(&mut entry, "KVM_EVENT", name);
+        for (field, value) in fields {
+            push_field(&mut entry, field, &value);
+        }
+        if let Err(e) = socket.send(&entry) {
+            warn!("Failed to write security audit entry: {}", e);
+        }
+    }
+}
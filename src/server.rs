@@ -0,0 +1,996 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Construction of the server and its consoles for kvm-rs
+
+#[cfg(feature = "tls")]
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use anyhow::{bail, Context, Result};
+#[cfg(feature = "web")]
+use axum::{middleware, routing::any, Router};
+use tokio::task::JoinHandle;
+#[cfg(feature = "web")]
+use tracing::error;
+use tracing::{info, warn};
+use zbus::Connection;
+use crate::{
+    arbiter::Arbiter,
+    args::{Args, MissingDevicePolicy},
+    audit::InputAudit,
+    auth::{AuthMode, Authenticator},
+    bandwidth::Bandwidth,
+    control,
+    credentials::{Credential, CredentialStore},
+    display::{CaptureSettings, DisplayHub, HostPause},
+    events::EventBus,
+    frame_budget::FrameBudget,
+    frame_dump::FrameDump,
+    gadget::{self, CompositeGadget, HidDevices},
+    hid::{HidManager, PowerOffInput},
+    hid_backend::{gadget_backends, mock_backends, HidBackendFactory, HidRole},
+    host_agent::HostAgent,
+    ip_filter::{FilterRules, IpFilter},
+    keyboard::{KeyBlocklist, KeyRepeat},
+    ldap::{self, LdapDirectory, LdapSettings},
+    lockout::Lockout,
+    logging::LogLevel,
+    macros::MacroStore,
+    mdns::{self, Advertisement},
+    media::MediaStore,
+    openbmc_media,
+    pointer::PointerSettings,
+    power::{HostPower, HostState},
+    reload::Reloader,
+    remote_media::RemoteSettings,
+    security_audit::SecurityAudit,
+    services::Services,
+    sessions::SessionRegistry,
+    shutdown::{self, Shutdown},
+    supervisor::{RestartPolicy, Supervisor},
+    systemd,
+    targets::{Target, TargetRegistry},
+    transport::{ConsoleTransport, TransportRegistry},
+    user_manager::UserManager,
+    virtual_media::VirtualMedia,
+    vnc::VncHandler,
+    vnc_password::VncPassword,
+};
+#[cfg(feature = "web")]
+use crate::{
+    access_log,
+    api::{self, ApiState},
+    auth,
+    cors,
+    frame_dump,
+    host_agent,
+    https,
+    ip_filter,
+    limits::{self, IpLimits, LimitedListener},
+    login,
+    media,
+    mjpeg,
+    origin::{self, OriginPolicy},
+    power,
+    rtc::RtcSettings,
+    services,
+    sol::{self, HostConsole, SolState},
+    virtual_media,
+    web,
+    webhooks::Webhooks,
+    websocket::{kvm_ws, WsSettings, WsState},
+};
+#[cfg(any(feature = "vnc", feature = "web"))]
+use crate::listen::MultiListener;
+#[cfg(feature = "tls")]
+use crate::{cert_manager::CertManager, tls::TlsIdentity};
+#[cfg(all(unix, feature = "web"))]
+use crate::unix_socket::UnixSocketListener;
+
+/// Builds a `KvmServer` from the options, with parts replaceable for embedding and tests
+pub struct KvmServerBuilder {
+    args: Args,
+    dbus: Option<Connection>,
+    credentials: Option<CredentialStore>,
+    log_level: Option<LogLevel>,
+    capture: Option<CaptureSettings>,
+    hid_backends: Option<HidBackendFactory>,
+    #[cfg(feature = "tls")]
+    tls_identity: Option<TlsIdentity>,
+    authenticator: Option<Authenticator>,
+    transports: TransportRegistry,
+}
+
+impl KvmServerBuilder {
+    pub fn new(args: Args) -> Self {
+        Self {
+            args,
+            dbus: None,
+            credentials: None,
+            log_level: None,
+            capture: None,
+            hid_backends: None,
+            #[cfg(feature = "tls")]
+            tls_identity: None,
+            authenticator: None,
+            transports: TransportRegistry::default(),
+        }
+    }
+
+    /// System bus for host power state, accounts, certificates and the control interface
+    pub fn with_dbus(mut self, dbus: Connection) -> Self {
+        self.dbus = Some(dbus);
+        self
+    }
+
+    /// Store the VNC password is read from when --vnc-password-file is not set
+    pub fn with_credentials(mut self, credentials: CredentialStore) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Log level changed by a configuration reload
+    pub fn with_log_level(mut self, log_level: LogLevel) -> Self {
+        self.log_level = Some(log_level);
+        self
+    }
+
+    /// Capture of every console, instead of the one the options select
+    #[cfg_attr(not(all(test, feature = "vnc")), allow(dead_code))] // For tests and embedders
+    pub fn with_capture(mut self, settings: CaptureSettings) -> Self {
+        self.capture = Some(settings);
+        self
+    }
+
+    /// HID backends of every console, instead of the gadget devices or --mock-hid
+    #[cfg_attr(not(all(test, feature = "vnc")), allow(dead_code))] // For tests and embedders
+    pub fn with_hid_backends(mut self, backends: HidBackendFactory) -> Self {
+        self.hid_backends = Some(backends);
+        self
+    }
+
+    /// Certificate of the TLS listeners, instead of the one the options select
+    #[cfg(feature = "tls")]
+    #[allow(dead_code)] // For embedders, kvm-rs itself builds from its options
+    pub fn with_tls_identity(mut self, identity: TlsIdentity) -> Self {
+        self.tls_identity = Some(identity);
+        self
+    }
+
+    /// Session check of the consoles, instead of the one --auth selects
+    #[allow(dead_code)] // For embedders, kvm-rs itself builds from its options
+    pub fn with_authenticator(mut self, authenticator: Authenticator) -> Self {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    /// A transport served along with the VNC, web and WebTransport servers
+    #[allow(dead_code)] // For embedders, kvm-rs itself builds from its options
+    pub fn with_transport(mut self, transport: impl ConsoleTransport + 'static) -> Self {
+        self.transports = self.transports.with_transport(transport);
+        self
+    }
+
+    /// Start the captures and HID monitors and bind the listeners
+    pub async fn build(self) -> Result<KvmServer> {
+        let mut args = self.args;
+        let dbus = self.dbus;
+        let shutdown = Shutdown::default();
+        // Restarts capture, frame processing and monitors that fail
+        let supervisor = Supervisor::new(shutdown.clone());
+
+        // Notifications for /api/v1/events
+        let events = EventBus::default();
+        // Capture pauses and input is held while host 0 is off
+        let host_state = HostState::default();
+        #[cfg(target_os = "linux")]
+        if let Some(ref dbus) = dbus {
+            let (events, dbus, host_state) = (events.clone(), dbus.clone(), host_state.clone());
+            supervisor.spawn("Host power monitor", RestartPolicy::OnFailure, move || {
+                let (events, dbus, host_state) = (events.clone(), dbus.clone(), host_state.clone());
+                async move {
+                    events.watch_host_power(dbus, host_state).await;
+                    Ok(())
+                }
+            });
+        }
+
+        // 2. Framebuffer broadcaster; frames and client buffers of every console share one budget
+        let budget = FrameBudget::new(args.frame_memory_budget * 1024 * 1024);
+        let hub = DisplayHub::with_budget(budget.clone());
+        let video_device = args.video_device.clone();
+        let capture_settings = match self.capture {
+            Some(settings) => settings,
+            None => CaptureSettings::from_args(&args)?,
+        };
+        // Injected HID backends bring their own devices
+        let hid_devices = self.hid_backends.is_none() && !args.mock_hid;
+        // The console's HID devices are those of the gadget kvm-rs builds
+        let mut composite_gadget = None;
+        if let (Some(name), true) = (&args.create_gadget, hid_devices) {
+            let udc = args.bind_udc.as_deref().unwrap_or("auto");
+            let composite = CompositeGadget::create(name, args.gadget_touchscreen, args.host_agent.as_deref(), args.mass_storage.as_deref(), udc)?;
+            let devices = composite.hid_devices()?;
+            wait_for_gadget_devices(&devices).await;
+            args.keyboard_hid = devices.keyboard;
+            args.mouse_hid = devices.mouse;
+            if devices.touchscreen.is_some() {
+                args.touchscreen_hid = devices.touchscreen;
+            }
+            composite_gadget = Some(Arc::new(composite));
+        }
+        let missing_devices: Arc<[String]> = check_devices(&args, capture_settings.mock.is_none(), hid_devices).await?.into();
+        let host_pause = HostPause::new(host_state.clone(), args.power_off_image.as_deref())?;
+        let console_capture = CaptureSettings { host: Some(host_pause), ..capture_settings.clone() };
+        let mut captures = vec![supervise_capture(&supervisor, hub.clone(), video_device, console_capture)];
+
+        // Gadgets the script that created them left unbound, as obmc-ikvm expects
+        if let (Some(udc), None) = (&args.bind_udc, &composite_gadget) {
+            if hid_devices && !missing_devices.contains(&args.keyboard_hid) {
+                let gadget = gadget::gadget_dir(&args.keyboard_hid)
+                    .with_context(|| format!("No USB gadget found for {}", args.keyboard_hid))?;
+                let bound = gadget::bind_udc(&gadget, udc)?;
+                info!("HID gadget {} bound to {}", gadget.display(), bound);
+            }
+        }
+
+        // 3. HID manager
+        let hid_backends = self.hid_backends.unwrap_or_else(|| default_hid_backends(&args));
+        let macros = MacroStore::load(args.macro_file.clone())?;
+        let hid_manager = console_hid(&args, &hid_backends, &args.keyboard_hid, &args.mouse_hid, args.touchscreen_hid.as_deref(), &macros)?
+            .with_host_state(host_state, args.power_off_input);
+
+        // Reopen gadget devices when the host disconnects and reconnects, and
+        // follow the host's NumLock state for keypad translation
+        supervise_hid(&supervisor, &hid_manager);
+        if args.power_off_input == PowerOffInput::Queue {
+            let hid = hid_manager.clone();
+            supervisor.spawn("Power-off input queue", RestartPolicy::OnFailure, move || {
+                let hid = hid.clone();
+                async move {
+                    hid.monitor_host_state().await;
+                    Ok(())
+                }
+            });
+        }
+        {
+            let (events, hid_manager) = (events.clone(), hid_manager.clone());
+            supervisor.spawn("Input lock events", RestartPolicy::OnFailure, move || {
+                let (events, input_lock) = (events.clone(), hid_manager.subscribe_input_lock());
+                async move {
+                    events.forward_input_lock(input_lock).await;
+                    Ok(())
+                }
+            });
+        }
+
+        // Input audit trail
+        let input_audit = match args.input_audit {
+            Some(ref target) => InputAudit::open(target, args.input_audit_max_size, args.input_audit_full)?,
+            None => InputAudit::default(),
+        };
+
+        // Console events for NOC tooling
+        #[cfg(not(feature = "web"))]
+        if !args.webhooks.is_empty() {
+            bail!("--webhook needs kvm-rs built with the web feature");
+        }
+        #[cfg(feature = "web")]
+        let webhooks = Webhooks::new(&args.webhooks, args.webhook_ca.as_deref(), args.webhook_retries)?;
+        #[cfg(feature = "web")]
+        if webhooks.is_enabled() {
+            let (events, webhooks) = (events.clone(), webhooks.clone());
+            supervisor.spawn("Webhook events", RestartPolicy::OnFailure, move || {
+                let (events, webhooks) = (events.subscribe(), webhooks.clone());
+                async move {
+                    webhooks.forward(events).await;
+                    Ok(())
+                }
+            });
+        }
+
+        // Security events for SIEM ingestion, separate from the debug output
+        let security_audit = if args.security_audit {
+            SecurityAudit::journald()?
+        } else {
+            SecurityAudit::default()
+        };
+        #[cfg(feature = "web")]
+        let security_audit = security_audit.with_webhooks(webhooks);
+
+        // Account logins are encrypted: VeNCrypt on the VNC port, HTTPS or a
+        // reverse proxy on the web server
+        #[cfg(not(feature = "tls"))]
+        if args.auth != AuthMode::None {
+            bail!("--auth needs kvm-rs built with the tls feature");
+        }
+        #[cfg(not(feature = "tls"))]
+        if args.vnc_tls || args.https {
+            bail!("--vnc-tls and --https need kvm-rs built with the tls feature");
+        }
+
+        // Directory accounts besides the local ones, at logins of --auth local
+        let ldap = match args.ldap {
+            Some(_) if args.auth != AuthMode::Local => bail!("--ldap needs --auth local"),
+            Some(source) => {
+                let bind_password = match (&args.ldap_bind_password_file, &self.credentials) {
+                    (Some(path), _) => Some(ldap::read_password_file(path)?),
+                    (None, Some(store)) => store.get(Credential::LdapBindPassword)?,
+                    (None, None) => None,
+                };
+                let settings = LdapSettings {
+                    source,
+                    url: args.ldap_url.clone(),
+                    base_dn: args.ldap_base_dn.clone(),
+                    bind_dn: args.ldap_bind_dn.clone(),
+                    bind_password,
+                    user_attribute: args.ldap_user_attribute.clone(),
+                    group_attribute: args.ldap_group_attribute.clone(),
+                    role_map: args.ldap_role_map.clone(),
+                    ca_file: args.ldap_ca.clone(),
+                };
+                Some(LdapDirectory::new(settings, dbus.clone())?)
+            }
+            None => None,
+        };
+
+        // Session check for the console streams and VNC logins
+        let authenticator = match self.authenticator {
+            Some(authenticator) => authenticator,
+            None => {
+                let authenticator = match args.auth {
+                    AuthMode::None => Authenticator::disabled(),
+                    #[cfg(feature = "web")]
+                    AuthMode::Redfish => Authenticator::redfish(&args.redfish_url, args.redfish_ca.as_deref(), dbus.clone().map(UserManager::new), args.redfish_role)?,
+                    #[cfg(not(feature = "web"))]
+                    AuthMode::Redfish => bail!("--auth redfish needs kvm-rs built with the web feature"),
+                    AuthMode::Local => Authenticator::local(Duration::from_secs(args.login_timeout), dbus.clone().map(UserManager::new), ldap),
+                };
+                authenticator
+                    .with_lockout(args.auth_max_failures, Duration::from_secs(args.auth_lockout))
+                    .with_security_audit(security_audit.clone())
+            }
+        };
+
+        // Shared VNC port password, for clients that do not log in with accounts
+        let vnc_password = match (&args.vnc_password_file, &self.credentials) {
+            (Some(path), _) => Some(VncPassword::load(path.as_ref())?),
+            (None, Some(store)) => store.get(Credential::VncPassword)?.map(|password| VncPassword::from_plain(&password)),
+            (None, None) => None,
+        };
+        if vnc_password.is_some() && args.auth != AuthMode::None {
+            anyhow::bail!("A VNC password cannot be combined with --auth, VNC clients log in with accounts");
+        }
+        // Other passwords than a vncpasswd file are sent as VeNCrypt Plain credentials
+        #[cfg(not(feature = "tls"))]
+        if vnc_password.as_ref().is_some_and(|password| !password.supports_vnc_auth()) {
+            bail!("Only a vncpasswd file can be used without the tls feature, other VNC passwords need VeNCrypt");
+        }
+
+        // Certificate shared by the VNC and HTTPS listeners; VNC logins are always encrypted
+        #[cfg(feature = "tls")]
+        let tls_identity = match self.tls_identity {
+            Some(identity) => Some(identity),
+            None if args.vnc_tls || args.https || args.webtransport_port.is_some() || args.auth != AuthMode::None || vnc_password.is_some() => {
+                let identity = match (args.openbmc_certs, &dbus) {
+                    (true, Some(dbus)) => {
+                        let cert_manager = CertManager::new(dbus).await?;
+                        let (cert_chain, key) = cert_manager.server_certificate().await?;
+                        let identity = TlsIdentity::new(cert_chain, key)?;
+                        tokio::spawn(cert_manager.watch(identity.clone()));
+                        identity
+                    }
+                    (true, None) => anyhow::bail!("--openbmc-certs needs D-Bus"),
+                    (false, _) => {
+                        let listen_ips: Vec<IpAddr> = args.listen_addrs().iter().chain(&args.vnc_listen_addrs()).map(SocketAddr::ip).collect();
+                        TlsIdentity::from_paths(args.vnc_cert.as_deref(), args.vnc_key.as_deref(), &listen_ips).await?
+                    }
+                };
+                let identity = identity.with_sni_certs(&args.sni_certs).await?;
+                match args.client_ca {
+                    Some(ref ca) => Some(identity.with_client_ca(ca)?),
+                    None => Some(identity),
+                }
+            }
+            None => None,
+        };
+
+        // 4. VNC server with optional TLS encryption
+        #[cfg(feature = "tls")]
+        let vnc_handler = if let (true, Some(identity)) = (args.vnc_tls, &tls_identity) {
+            VncHandler::new_with_tls(hub.clone(), hid_manager.clone(), identity)?
+        } else {
+            VncHandler::new(hub.clone(), hid_manager.clone())
+        };
+        #[cfg(not(feature = "tls"))]
+        let vnc_handler = VncHandler::new(hub.clone(), hid_manager.clone());
+        let mut vnc_handler = vnc_handler.with_auth(authenticator.clone());
+        if let Some(password) = vnc_password {
+            let lockout = Lockout::new(args.auth_max_failures, Duration::from_secs(args.auth_lockout));
+            vnc_handler = vnc_handler.with_password(password, lockout);
+        }
+        #[cfg(feature = "tls")]
+        if let Some(ref identity) = tls_identity {
+            vnc_handler = vnc_handler.with_vencrypt(identity)?;
+        }
+        // Source addresses checked by the VNC, web and WebTransport listeners
+        let ip_filter = IpFilter::new(FilterRules {
+            allow: args.allow_ip.clone(),
+            deny: args.deny_ip.clone(),
+        });
+        let sessions = SessionRegistry::default()
+            .with_events(events.clone())
+            .with_security_audit(security_audit.clone())
+            // Console streams must leave room for the other traffic of the BMC's NIC
+            .with_bandwidth(Bandwidth::new(args.client_bandwidth_limit * 1024, args.bandwidth_limit * 1024))
+            .with_max_duration(
+                Duration::from_secs(args.max_session_duration),
+                Duration::from_secs(args.session_expiry_warning),
+            );
+        // VNC, WebSocket and screenshots, turned off and on over the API and D-Bus
+        let services = Services::new(&args.disable_service, sessions.clone());
+        let frame_dump = FrameDump::new(&args.frame_dump_dir);
+        let vnc_handler = vnc_handler
+            .with_ip_filter(ip_filter.clone())
+            .with_input_audit(input_audit.clone())
+            .with_sessions(sessions)
+            .with_services(services.clone())
+            .with_frame_dump(frame_dump.clone())
+            .with_events(events.clone())
+            .with_shutdown(shutdown.clone())
+            .with_keyboard_layout(args.keyboard_layout);
+        // WebSocket clients run RFB sessions on the same handler
+        let ws_vnc_handler = vnc_handler.clone();
+        ws_vnc_handler.start_frame_processing(&supervisor);
+
+        // Every way clients reach the consoles, served together by `KvmServer::run`
+        #[cfg_attr(not(any(feature = "vnc", feature = "web")), allow(unused_mut))]
+        let mut transports = self.transports;
+        #[cfg_attr(not(feature = "vnc"), allow(unused_mut))]
+        let mut vnc_addrs = Vec::new();
+        #[cfg(feature = "vnc")]
+        {
+            let vnc_listener = MultiListener::bind(&args.vnc_listen_addrs()).context("Failed to start the VNC server")?;
+            vnc_addrs.extend(vnc_listener.local_addrs());
+            transports = transports.with_transport(crate::vnc::VncServer::new("VNC server".to_string(), vnc_handler, vnc_listener));
+        }
+
+        // Token for the input injection API
+        #[cfg(feature = "web")]
+        let input_token = match args.api_token_file {
+            Some(ref file) => {
+                let token = std::fs::read_to_string(file)
+                    .map_err(|e| anyhow::anyhow!("Failed to read API token file {}: {}", file, e))?;
+                let token = token.trim();
+                if token.is_empty() {
+                    return Err(anyhow::anyhow!("API token file {} is empty", file));
+                }
+                Some(Arc::from(token))
+            }
+            None => None,
+        };
+
+        // Power control of host 0, for the REST API and the controller of console 0;
+        // never for whoever can reach the port
+        let host_power = match (args.power_control, &dbus) {
+            (true, _) if !authenticator.is_enabled() => bail!("--power-control needs --auth local or --auth redfish"),
+            (true, Some(dbus)) => Some(HostPower::new(dbus.clone(), security_audit.clone())),
+            (true, None) => bail!("--power-control needs a D-Bus connection"),
+            (false, _) => None,
+        };
+
+        // 5. Servidor HTTP → WS
+        // The configured capture and HID gadget are console 0
+        let mut targets = TargetRegistry::default().with_target(0, Target {
+            hub: hub.clone(),
+            hid_manager: hid_manager.clone(),
+            vnc: ws_vnc_handler.clone(),
+            arbiter: Arbiter::new(args.ws_policy, args.ws_max_sessions)
+                .with_resume_grace(Duration::from_secs(args.ws_resume_grace)),
+            power: host_power.clone(),
+        });
+        // Further hosts of a multi-node sled share TLS, authentication and sessions
+        let mut hid_managers = vec![hid_manager.clone()];
+        #[cfg_attr(not(feature = "vnc"), allow(unused_mut))]
+        let mut console_vnc_addrs: Vec<(u32, Vec<SocketAddr>)> = Vec::new();
+        for console in &args.consoles {
+            if targets.get(console.id).is_some() {
+                anyhow::bail!("Console {} is configured more than once", console.id);
+            }
+            let console_hub = DisplayHub::with_budget(budget.clone());
+            captures.push(supervise_capture(&supervisor, console_hub.clone(), console.video_device.clone(), capture_settings.clone()));
+            let hid = console_hid(&args, &hid_backends, &console.keyboard_hid, &console.mouse_hid, console.touchscreen_hid.as_deref(), &macros)?;
+            supervise_hid(&supervisor, &hid);
+            let console_vnc = ws_vnc_handler.for_console(console_hub.clone(), hid.clone());
+            console_vnc.start_frame_processing(&supervisor);
+            #[cfg(feature = "vnc")]
+            if !console.vnc_listen.is_empty() {
+                let listener = MultiListener::bind(&console.vnc_listen)
+                    .with_context(|| format!("Failed to start the VNC server of console {}", console.id))?;
+                console_vnc_addrs.push((console.id, listener.local_addrs()));
+                let name = format!("VNC server of console {}", console.id);
+                transports = transports.with_transport(crate::vnc::VncServer::new(name, console_vnc.clone(), listener));
+            }
+            targets = targets.with_target(console.id, Target {
+                hub: console_hub,
+                hid_manager: hid.clone(),
+                vnc: console_vnc,
+                arbiter: Arbiter::new(args.ws_policy, args.ws_max_sessions)
+                    .with_resume_grace(Duration::from_secs(args.ws_resume_grace)),
+                power: None,
+            });
+            hid_managers.push(hid);
+        }
+        // Lost and restored video of every console, for /api/v1/events and webhooks
+        for id in targets.ids() {
+            let Some(hub) = targets.get(id).map(|target| target.hub.clone()) else { continue };
+            let events = events.clone();
+            supervisor.spawn(format!("Video monitor of console {}", id), RestartPolicy::OnFailure, move || {
+                let (events, hub) = (events.clone(), hub.clone());
+                async move {
+                    events.watch_capture(id, hub).await;
+                    Ok(())
+                }
+            });
+        }
+        let ids: Vec<String> = targets.ids().map(|id| format!("/kvm/{}", id)).collect();
+        info!("Console targets: {}", ids.join(", "));
+        let targets = Arc::new(targets);
+
+        let mut virtual_media = None;
+        let media_store = match args.media_dir {
+            Some(ref media_dir) => Some(Arc::new(MediaStore::open(media_dir, args.media_max_size * 1024 * 1024)?)),
+            None => None,
+        };
+        // Uploaded images are inserted in a drive of the keyboard's gadget
+        if let (Some(store), Some(function)) = (&media_store, &args.mass_storage) {
+            let remote = RemoteSettings {
+                ca_file: args.remote_media_ca.clone(),
+                nbd_device: args.nbd_device.clone(),
+                cache_size: args.remote_media_cache * 1024 * 1024,
+            };
+            let mut drive = VirtualMedia::open(&args.keyboard_hid, function, store.clone(), remote, events.clone())?;
+            if let Some(ref composite) = composite_gadget {
+                drive = drive.with_gadget(composite.clone())?;
+            }
+            virtual_media = Some(Arc::new(drive));
+        }
+        // Agent in the host OS on a serial port of the keyboard's gadget
+        #[cfg_attr(not(feature = "web"), allow(unused_variables))]
+        let host_agent = match (&args.host_agent, hid_devices) {
+            (Some(function), true) => {
+                let gadget = match composite_gadget {
+                    Some(ref composite) => composite.dir().to_path_buf(),
+                    None => gadget::gadget_dir(&args.keyboard_hid)
+                        .with_context(|| format!("No USB gadget found for {}", args.keyboard_hid))?,
+                };
+                let tty = gadget::acm_tty(&gadget::add_function(&gadget, function)?)?;
+                let agent = HostAgent::new(ws_vnc_handler.clipboard().clone(), events.clone());
+                let served = agent.clone();
+                supervisor.spawn("Host agent channel", RestartPolicy::OnFailure, move || {
+                    let (agent, tty) = (served.clone(), tty.clone());
+                    async move { agent.serve(&tty).await }
+                });
+                Some(agent)
+            }
+            _ => None,
+        };
+
+        // Apply a changed log level, address filters, origins and stream defaults on SIGHUP or D-Bus Reload
+        #[cfg(feature = "web")]
+        let ws_settings = Arc::new(std::sync::RwLock::new(WsSettings::from_args(&args)));
+        #[cfg(feature = "web")]
+        let origin_policy = OriginPolicy::new(&args.allowed_origins, &args.allowed_hosts);
+        #[cfg(feature = "web")]
+        let reloader = Reloader::new(self.log_level, ip_filter.clone(), origin_policy.clone(), ws_settings.clone());
+        #[cfg(not(feature = "web"))]
+        let reloader = Reloader::new(self.log_level, ip_filter.clone());
+        if let Some(ref dbus) = dbus {
+            if let (true, Some(drive)) = (args.redfish_virtual_media, &virtual_media) {
+                if let Err(e) = openbmc_media::serve_dbus(dbus, drive.clone(), &events).await {
+                    warn!("Redfish virtual media unavailable: {:#}", e);
+                }
+            }
+            if let Err(e) = control::serve_dbus(dbus, reloader.clone(), ws_vnc_handler.sessions().clone(), services.clone(), &targets, &events, frame_dump.clone(), virtual_media.clone()).await {
+                warn!("D-Bus control interface unavailable: {:#}", e);
+            }
+        }
+
+        // The same consoles over HTTP/3 for clients on lossy links
+        #[cfg(not(feature = "webtransport"))]
+        if args.webtransport_port.is_some() {
+            anyhow::bail!("--webtransport-port needs kvm-rs built with the webtransport feature");
+        }
+
+        // 5. Servidor HTTP → WS
+        #[cfg_attr(not(feature = "web"), allow(unused_mut))]
+        let mut web_addrs = Vec::new();
+        #[cfg(feature = "web")]
+        {
+            // Cross-origin access for dashboards, limited to the REST API and the MJPEG stream
+            let cors = cors::layer(&args.cors_origins, &args.cors_methods)?;
+            let mut mjpeg = mjpeg::router(hub.clone(), ws_vnc_handler.clone(), args.ws_jpeg_quality);
+            let mut api = api::router(ApiState {
+                hid_manager: hid_manager.clone(),
+                hub: hub.clone(),
+                vnc: ws_vnc_handler.clone(),
+                audit: input_audit.clone(),
+                input_token,
+                missing_devices: missing_devices.clone(),
+                pointer: Arc::default(),
+            });
+            if let Some(ref drive) = virtual_media {
+                api = api.merge(virtual_media::router(drive.clone()));
+            }
+            if let Some(store) = media_store {
+                api = api.merge(media::router(store));
+            }
+            if let Some(agent) = host_agent {
+                api = api.merge(host_agent::router(agent));
+            }
+            if let Some(power) = host_power {
+                api = api.merge(power::router(power));
+            }
+            api = api.merge(ip_filter::router(ip_filter.clone()));
+            api = api.merge(services::router(services.clone()));
+            api = api.merge(frame_dump::router(frame_dump.clone()));
+            let mut web = web::router(&args.novnc_dir);
+            // Without bmcweb in front, the login page guards the web UI
+            if args.auth == AuthMode::Local {
+                web = web.route_layer(middleware::from_fn_with_state(authenticator.clone(), login::require_login));
+            }
+            if authenticator.is_enabled() {
+                api = api
+                    .route_layer(middleware::from_fn(auth::require_role))
+                    .route_layer(middleware::from_fn_with_state(authenticator.clone(), auth::require_session));
+            }
+            if let Some(cors) = cors {
+                mjpeg = mjpeg.layer(cors.clone());
+                api = api.layer(cors);
+            }
+
+            let ws_state = WsState {
+                targets,
+                audit: input_audit.clone(),
+                settings: ws_settings,
+                rtc: RtcSettings::from_args(&args).map(Arc::new),
+                authenticator: authenticator.clone(),
+                services: services.clone(),
+            };
+            // Host serial console, behind the same sessions as the graphical one
+            let sol = args.sol.as_deref().map(|id| SolState {
+                console: Arc::new(HostConsole::new(id, dbus.clone())),
+                sessions: ws_vnc_handler.sessions().clone(),
+                audit: input_audit.clone(),
+                settings: ws_state.settings.clone(),
+                authenticator: authenticator.clone(),
+                services: services.clone(),
+                shutdown: shutdown.clone(),
+            });
+
+            #[cfg(feature = "webtransport")]
+            if let (Some(port), Some(identity)) = (args.webtransport_port, &tls_identity) {
+                let tls = (*identity.server_config(&[b"h3"])?).clone();
+                let admission = crate::webtransport::Admission {
+                    origin_policy: origin_policy.clone(),
+                    authenticator: authenticator.clone(),
+                    ip_filter: ip_filter.clone(),
+                    security_audit: security_audit.clone(),
+                };
+                // One endpoint per address of the web server, as MultiListener binds them
+                let addrs = args.listen_addrs_on(port);
+                for addr in &addrs {
+                    let only_v6 = addr.is_ipv6() && addrs.iter().any(SocketAddr::is_ipv4);
+                    let endpoint = crate::webtransport::bind(*addr, only_v6, tls.clone())?;
+                    transports = transports.with_transport(crate::webtransport::WebTransportServer::new(endpoint, ws_state.clone(), admission.clone()));
+                }
+            }
+
+            let mut console = Router::new()
+                // HTTP/2 WebSockets are CONNECT requests
+                .route("/kvm/{id}", any(kvm_ws))
+                .with_state(ws_state)
+                .merge(mjpeg);
+            if let Some(state) = sol {
+                console = console.merge(Router::new().route("/sol", any(sol::sol_ws)).with_state(state));
+            }
+            let console = console
+                .merge(auth::handoff_router(authenticator.clone()))
+                .route_layer(middleware::from_fn_with_state(authenticator.clone(), auth::require_session))
+                // Checked first: cross-site pages are refused before any session lookup
+                .route_layer(middleware::from_fn_with_state(origin_policy, origin::check_origin));
+            // Scanners and runaway dashboards must not exhaust the BMC
+            let ip_limits = IpLimits::new(args.ip_max_connections, args.ip_request_rate, args.ip_request_burst);
+            let app = Router::new()
+                .merge(console)
+                .merge(api)
+                .merge(web)
+                .merge(login::router(&authenticator, args.https))
+                .layer(middleware::from_fn_with_state(ip_limits.clone(), limits::limit_requests));
+            let mut app = web::with_base_path(app, &args.base_path);
+            if !args.no_access_log {
+                app = app.layer(middleware::from_fn(access_log::log_requests));
+            }
+
+            let tls_acceptor = match tls_identity {
+                Some(ref identity) if args.https => {
+                    let config = identity.server_config(&[b"h2", b"http/1.1"])?;
+                    Some(tokio_rustls::TlsAcceptor::from(config))
+                }
+                _ => None,
+            };
+
+            transports = match args.unix_socket {
+                // Only a local reverse proxy reaches the web server
+                #[cfg(unix)]
+                Some(ref path) => {
+                    let listener = UnixSocketListener::bind(path)?;
+                    info!("KVM‑RS WebSocket listening on {}", listener.path().display());
+                    transports.with_transport(https::WebServer::new(listener, tls_acceptor, app))
+                }
+                #[cfg(not(unix))]
+                Some(_) => return Err(anyhow::anyhow!("--unix-socket is only supported on Unix")),
+                None => {
+                    // Create TCP listeners on the configured addresses
+                    let listener = MultiListener::bind(&args.listen_addrs())?;
+                    web_addrs.extend(listener.local_addrs());
+                    let local_addrs: Vec<String> = web_addrs.iter().map(SocketAddr::to_string).collect();
+                    info!("KVM‑RS WebSocket listening on {}", local_addrs.join(", "));
+                    let listener = LimitedListener::new(listener, ip_limits, ip_filter).with_security_audit(security_audit);
+                    transports.with_transport(https::WebServer::new(listener, tls_acceptor, app))
+                }
+            };
+
+            if let Some(redirect_port) = args.http_redirect_port {
+                let redirect_listener = MultiListener::bind(&args.listen_addrs_on(redirect_port))?;
+                // To the port actually bound, e.g. the one chosen for port 0
+                let https_port = web_addrs.first().map_or(args.port, SocketAddr::port);
+                let shutdown = shutdown.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = https::serve_redirect(redirect_listener, https_port, shutdown).await {
+                        error!("HTTP redirect server error: {}", e);
+                    }
+                });
+            }
+        }
+
+        // Discovery on the management LAN, of the ports other hosts can reach
+        if args.mdns {
+            let Some(ref dbus) = dbus else {
+                bail!("--mdns needs a D-Bus connection");
+            };
+            let hostname = mdns::system_hostname().unwrap_or_else(|| "kvm-rs".to_string());
+            let mut advertised = Vec::new();
+            advertised.extend(mdns::reachable_port(&vnc_addrs).map(|port| Advertisement::new(&hostname, "_rfb._tcp", port)));
+            for (id, addrs) in &console_vnc_addrs {
+                let name = format!("{} console {}", hostname, id);
+                advertised.extend(mdns::reachable_port(addrs).map(|port| Advertisement::new(name, "_rfb._tcp", port)));
+            }
+            let web_type = if args.https { "_https._tcp" } else { "_http._tcp" };
+            advertised.extend(mdns::reachable_port(&web_addrs)
+                .map(|port| Advertisement::new(&hostname, web_type, port).with_txt(format!("path={}/", args.base_path))));
+            let dbus = dbus.clone();
+            supervisor.spawn("mDNS advertisement", RestartPolicy::OnFailure, move || mdns::advertise(dbus.clone(), advertised.clone()));
+        }
+
+        Ok(KvmServer {
+            transports,
+            shutdown,
+            supervisor,
+            sessions: ws_vnc_handler.sessions().clone(),
+            reloader,
+            hub,
+            captures,
+            hid_managers,
+            input_audit,
+            shutdown_timeout: Duration::from_secs(args.shutdown_timeout),
+            vnc_addrs,
+            web_addrs,
+        })
+    }
+}
+
+/// The consoles with their listeners bound, ready to serve
+pub struct KvmServer {
+    transports: TransportRegistry,
+    shutdown: Shutdown,
+    supervisor: Supervisor,
+    sessions: SessionRegistry,
+    reloader: Reloader,
+    hub: Arc<DisplayHub>,
+    captures: Vec<JoinHandle<()>>,
+    hid_managers: Vec<HidManager>,
+    input_audit: InputAudit,
+    shutdown_timeout: Duration,
+    #[cfg_attr(not(all(test, feature = "vnc")), allow(dead_code))]
+    vnc_addrs: Vec<SocketAddr>,
+    #[allow(dead_code)]
+    web_addrs: Vec<SocketAddr>,
+}
+
+impl KvmServer {
+    /// Triggering it stops the server
+    pub fn shutdown(&self) -> &Shutdown {
+        &self.shutdown
+    }
+
+    /// Runs the background tasks; embedders can supervise their own with it
+    #[allow(dead_code)] // For embedders, kvm-rs itself builds from its options
+    pub fn supervisor(&self) -> &Supervisor {
+        &self.supervisor
+    }
+
+    /// Applies changed options on SIGHUP or D-Bus Reload
+    pub fn reloader(&self) -> &Reloader {
+        &self.reloader
+    }
+
+    /// Frames of console 0
+    pub fn hub(&self) -> &Arc<DisplayHub> {
+        &self.hub
+    }
+
+    /// Bound addresses of the VNC port of console 0
+    #[cfg_attr(not(all(test, feature = "vnc")), allow(dead_code))] // For tests and embedders
+    pub fn vnc_addrs(&self) -> &[SocketAddr] {
+        &self.vnc_addrs
+    }
+
+    /// Bound TCP addresses of the web server; empty with --unix-socket
+    #[allow(dead_code)] // For embedders, kvm-rs itself builds from its options
+    pub fn web_addrs(&self) -> &[SocketAddr] {
+        &self.web_addrs
+    }
+
+    /// Serve until shutdown, wait up to the shutdown timeout for sessions to
+    /// close, then stop the captures and release the HID gadgets. Fails if a
+    /// critical task kept failing and stopped the server
+    pub async fn run(self) -> Result<()> {
+        let shutdown = self.shutdown;
+        let sessions = self.sessions;
+        let transports = self.transports;
+        let server = async {
+            transports.serve(shutdown.clone()).await?;
+            // Sessions close themselves once shutdown is triggered
+            shutdown::drained(&sessions).await;
+            anyhow::Ok(())
+        };
+        let shutdown_timeout = self.shutdown_timeout;
+        tokio::select! {
+            result = server => result?,
+            _ = async {
+                shutdown.wait().await;
+                tokio::time::sleep(shutdown_timeout).await;
+            } => {
+                let open = sessions.list().len();
+                warn!("Shutdown timeout reached, closing {} remaining sessions", open);
+            }
+        }
+
+        shutdown::cleanup(self.captures, &self.hid_managers, &self.input_audit).await;
+        match self.supervisor.escalation() {
+            Some(reason) => Err(anyhow::anyhow!(reason)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// How often --missing-devices wait looks for the devices again
+const DEVICE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Apply --missing-devices to the video devices and HID gadgets that do not
+/// exist, returning those the server runs without
+async fn check_devices(args: &Args, video: bool, hid: bool) -> Result<Vec<String>> {
+    let mut missing = args.absent_devices(video, hid);
+    if missing.is_empty() {
+        return Ok(missing);
+    }
+    match args.missing_devices {
+        MissingDevicePolicy::Fail => bail!("Missing devices: {} (--missing-devices fail)", missing.join(", ")),
+        MissingDevicePolicy::Degrade => {
+            warn!("Missing devices: {}; serving without them", missing.join(", "));
+            systemd::notify_status(&format!("Degraded, missing {}", missing.join(", ")));
+            Ok(missing)
+        }
+        MissingDevicePolicy::Wait => {
+            let started = Instant::now();
+            let timeout = Duration::from_secs(args.device_wait_timeout);
+            info!("Waiting for {}", missing.join(", "));
+            while !missing.is_empty() {
+                systemd::notify_status(&format!("Waiting for {}", missing.join(", ")));
+                if args.device_wait_timeout > 0 && started.elapsed() >= timeout {
+                    bail!("Devices did not appear within {}s: {}", args.device_wait_timeout, missing.join(", "));
+                }
+                tokio::time::sleep(DEVICE_POLL_INTERVAL).await;
+                missing = args.absent_devices(video, hid);
+            }
+            info!("All devices present after {}s", started.elapsed().as_secs());
+            systemd::notify_status("");
+            Ok(missing)
+        }
+    }
+}
+
+/// How long the device nodes of a new gadget's HID functions may take to appear
+const GADGET_DEVICE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Give udev time to create the device nodes of a gadget just built; missing
+/// ones are then handled as --missing-devices says
+async fn wait_for_gadget_devices(devices: &HidDevices) {
+    let started = Instant::now();
+    let paths = [Some(&devices.keyboard), Some(&devices.mouse), devices.touchscreen.as_ref()];
+    while paths.iter().flatten().any(|path| !Path::new(path).exists()) && started.elapsed() < GADGET_DEVICE_TIMEOUT {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Capture of one console, restarted after it fails, e.g. while the device is unplugged
+fn supervise_capture(supervisor: &Supervisor, hub: Arc<DisplayHub>, video_device: String, settings: CaptureSettings) -> JoinHandle<()> {
+    let name = format!("Capture of {}", video_device);
+    supervisor.spawn(name, RestartPolicy::OnFailure, move || {
+        let capture = hub.clone().spawn(video_device.clone(), settings.clone());
+        async move { Ok(capture.await?) }
+    })
+}
+
+/// Gadget reconnection and keyboard LED monitors of one console
+fn supervise_hid(supervisor: &Supervisor, hid_manager: &HidManager) {
+    let hid = hid_manager.clone();
+    supervisor.spawn("HID gadget monitor", RestartPolicy::OnFailure, move || {
+        let hid = hid.clone();
+        async move {
+            hid.monitor_udc().await;
+            Ok(())
+        }
+    });
+    let hid = hid_manager.clone();
+    supervisor.spawn("Keyboard LED monitor", RestartPolicy::OnFailure, move || {
+        let hid = hid.clone();
+        async move {
+            hid.monitor_leds().await;
+            Ok(())
+        }
+    });
+}
+
+/// Logging backends with --mock-hid, the gadget devices otherwise
+pub fn default_hid_backends(args: &Args) -> HidBackendFactory {
+    if args.mock_hid {
+        mock_backends()
+    } else {
+        gadget_backends()
+    }
+}
+
+/// HID gadget of one console with the shared keyboard and pointer settings
+pub fn console_hid(
+    args: &Args,
+    backends: &HidBackendFactory,
+    keyboard: &str,
+    mouse: &str,
+    touchscreen: Option<&str>,
+    macros: &MacroStore,
+) -> Result<HidManager> {
+    let pointer_settings = PointerSettings::from_args(args);
+    pointer_settings.validate()?;
+
+    let hid_manager = HidManager::from_backends(
+        backends(HidRole::Keyboard, keyboard),
+        backends(HidRole::Mouse, mouse),
+        args.keyboard_report_desc.clone(),
+        args.mouse_report_desc.clone(),
+    );
+    let mut hid_manager = hid_manager
+        .with_key_repeat(KeyRepeat::new(args.key_repeat, args.key_repeat_delay, args.key_repeat_rate))
+        .with_keyboard_protocol(args.keyboard_protocol)
+        .with_report_validation(args.report_validation)
+        .with_blocked_keys(KeyBlocklist::parse(&args.block_keys)?)
+        .with_pointer_settings(pointer_settings)
+        .with_macros(macros.clone());
+    if let Some(touchscreen) = touchscreen {
+        hid_manager = hid_manager.with_touch_backend(backends(HidRole::Touchscreen, touchscreen), args.touchscreen_report_desc.clone());
+    }
+    Ok(hid_manager)
+}
@@ -0,0 +1,179 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Services turned off and on at runtime for kvm-rs
+
+use std::sync::Arc;
+#[cfg(feature = "web")]
+use axum::{
+    extract::State,
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tracing::info;
+use crate::sessions::SessionRegistry;
+
+/// A way of reaching the consoles that can be turned off without a restart
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Service {
+    /// The VNC ports, closed while off
+    Vnc,
+    /// The /kvm WebSocket endpoints, RFB and kvm-rs subprotocol, and the /sol serial console
+    Websocket,
+    /// The D-Bus Screenshot method
+    Screenshot,
+}
+
+impl Service {
+    pub const ALL: [Service; 3] = [Service::Vnc, Service::Websocket, Service::Screenshot];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Service::Vnc => "vnc",
+            Service::Websocket => "websocket",
+            Service::Screenshot => "screenshot",
+        }
+    }
+
+    /// Session transports of the service, closed when it is turned off
+    fn transports(&self) -> &'static [&'static str] {
+        match self {
+            Service::Vnc => &["vnc"],
+            Service::Websocket => &["websocket", "kvm-rs", "sol"],
+            Service::Screenshot => &[],
+        }
+    }
+}
+
+/// Which services are on, as GET /api/v1/services reports them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ServiceState {
+    pub vnc: bool,
+    pub websocket: bool,
+    pub screenshot: bool,
+}
+
+impl ServiceState {
+    pub fn enabled(&self, service: Service) -> bool {
+        match service {
+            Service::Vnc => self.vnc,
+            Service::Websocket => self.websocket,
+            Service::Screenshot => self.screenshot,
+        }
+    }
+
+    fn enabled_mut(&mut self, service: Service) -> &mut bool {
+        match service {
+            Service::Vnc => &mut self.vnc,
+            Service::Websocket => &mut self.websocket,
+            Service::Screenshot => &mut self.screenshot,
+        }
+    }
+}
+
+/// Body of PUT /api/v1/services; services left out keep their state
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceChanges {
+    vnc: Option<bool>,
+    websocket: Option<bool>,
+    screenshot: Option<bool>,
+}
+
+/// Services that are on, shared by the listeners, the control API and D-Bus
+#[derive(Clone)]
+pub struct Services {
+    state: Arc<watch::Sender<ServiceState>>,
+    /// Sessions closed when their service is turned off
+    sessions: SessionRegistry,
+}
+
+impl Default for Services {
+    fn default() -> Self {
+        Self::new(&[], SessionRegistry::default())
+    }
+}
+
+impl Services {
+    /// Every service on except those in `disabled`
+    pub fn new(disabled: &[Service], sessions: SessionRegistry) -> Self {
+        let mut state = ServiceState {
+            vnc: true,
+            websocket: true,
+            screenshot: true,
+        };
+        for service in disabled {
+            *state.enabled_mut(*service) = false;
+        }
+        Self {
+            state: Arc::new(watch::channel(state).0),
+            sessions,
+        }
+    }
+
+    pub fn state(&self) -> ServiceState {
+        *self.state.borrow()
+    }
+
+    pub fn is_enabled(&self, service: Service) -> bool {
+        self.state.borrow().enabled(service)
+    }
+
+    /// Turn `service` on or off; turning it off closes its sessions
+    pub fn set(&self, service: Service, enabled: bool) {
+        let changed = self.state.send_if_modified(|state| {
+            let slot = state.enabled_mut(service);
+            let changed = *slot != enabled;
+            *slot = enabled;
+            changed
+        });
+        if !changed {
+            return;
+        }
+        info!("Service {} {}", service.as_str(), if enabled { "enabled" } else { "disabled" });
+        if !enabled {
+            for session in self.sessions.list() {
+                if service.transports().contains(&session.transport) {
+                    self.sessions.disconnect(session.id);
+                }
+            }
+        }
+    }
+
+    /// Changes of the state, for listeners that close while their service is off
+    #[cfg_attr(not(feature = "vnc"), allow(dead_code))]
+    pub fn subscribe(&self) -> watch::Receiver<ServiceState> {
+        self.state.subscribe()
+    }
+}
+
+/// Routes to read and change the services that are on
+#[cfg(feature = "web")]
+pub fn router(services: Services) -> Router {
+    Router::new()
+        .route("/api/v1/services", get(get_services).put(put_services))
+        .with_state(services)
+}
+
+/// GET /api/v1/services - which services are on
+#[cfg(feature = "web")]
+async fn get_services(State(services): State<Services>) -> Json<ServiceState> {
+    Json(services.state())
+}
+
+/// PUT /api/v1/services - turn services on or off; their open sessions are closed
+#[cfg(feature = "web")]
+async fn put_services(State(services): State<Services>, Json(changes): Json<ServiceChanges>) -> Json<ServiceState> {
+    let changes = [
+        (Service::Vnc, changes.vnc),
+        (Service::Websocket, changes.websocket),
+        (Service::Screenshot, changes.screenshot),
+    ];
+    for (service, enabled) in changes {
+        if let Some(enabled) = enabled {
+            services.set(service, enabled);
+        }
+    }
+    Json(services.state())
+}
@@ -0,0 +1,424 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Registry of connected console clients for kvm-rs
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use serde::Serialize;
+use tokio::sync::watch;
+use crate::arbiter::Role;
+use crate::bandwidth::{Bandwidth, ClientBandwidth};
+use crate::events::{Event, EventBus};
+use crate::security_audit::{SecurityAudit, SecurityEvent};
+
+/// Window the frame rate delivered to a session is measured over
+const FPS_WINDOW: Duration = Duration::from_secs(1);
+
+/// Connected VNC, WebSocket and MJPEG clients
+#[derive(Clone, Default)]
+pub struct SessionRegistry {
+    inner: Arc<Mutex<Registry>>,
+    events: EventBus,
+    audit: SecurityAudit,
+    /// Egress caps the sessions are throttled to
+    bandwidth: Bandwidth,
+    /// Absolute lifetime of VNC and WebSocket sessions, and how long before
+    /// its end they are warned
+    max_duration: Option<Duration>,
+    expiry_warning: Duration,
+}
+
+#[derive(Default)]
+struct Registry {
+    next_id: u64,
+    sessions: BTreeMap<u64, Session>,
+}
+
+struct Session {
+    transport: &'static str,
+    addr: SocketAddr,
+    connected_at: SystemTime,
+    started: Instant,
+    /// Set to ask the session to close
+    disconnect: watch::Sender<bool>,
+    /// Account the client logged in with, if any
+    user: Option<String>,
+    /// Role assigned by the console's arbiter, or fixed by the transport
+    role: Option<watch::Receiver<Role>>,
+    counters: Arc<SessionCounters>,
+}
+
+/// Live counters of one session, updated by its transport
+#[derive(Default)]
+pub struct SessionCounters {
+    frames: AtomicU64,
+    bytes_sent: AtomicU64,
+    input_events: AtomicU64,
+    rate: Mutex<FrameRate>,
+    bandwidth: ClientBandwidth,
+}
+
+#[derive(Default)]
+struct FrameRate {
+    window_start: Option<Instant>,
+    window_frames: u32,
+    fps: f64,
+    last_frame: Option<Instant>,
+}
+
+impl SessionCounters {
+    /// Count a frame or framebuffer update of `bytes` sent to the client
+    pub fn frame_sent(&self, bytes: usize) {
+        self.frames.fetch_add(1, Ordering::Relaxed);
+        self.sent(bytes);
+        let mut rate = self.rate.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        rate.last_frame = Some(now);
+        rate.window_frames += 1;
+        let window_start = *rate.window_start.get_or_insert(now);
+        let elapsed = now.duration_since(window_start);
+        if elapsed >= FPS_WINDOW {
+            rate.fps = rate.window_frames as f64 / elapsed.as_secs_f64();
+            rate.window_start = Some(now);
+            rate.window_frames = 0;
+        }
+    }
+
+    /// Count other messages sent to the client, e.g. clipboard text
+    pub fn sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.bandwidth.sent(bytes);
+    }
+
+    /// When the next frame fits the bandwidth caps; None if it may be sent now.
+    /// Frames are skipped until then, not delayed
+    pub fn throttled_until(&self) -> Option<Instant> {
+        self.bandwidth.throttled_until()
+    }
+
+    /// Count a key, pointer or other input event forwarded to the host
+    pub fn input_event(&self) {
+        self.input_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Frames per second over the last window; 0 when none came for a whole window
+    fn fps(&self) -> f64 {
+        let rate = self.rate.lock().unwrap_or_else(|e| e.into_inner());
+        if rate.last_frame.is_none_or(|last| last.elapsed() >= FPS_WINDOW) {
+            return 0.0;
+        }
+        (rate.fps * 10.0).round() / 10.0
+    }
+}
+
+/// A connected client as reported by the status API
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub id: u64,
+    /// "vnc", "websocket" (RFB over WebSocket), "kvm-rs" or "mjpeg"
+    pub transport: &'static str,
+    pub address: String,
+    /// RFC 3339 time the client connected
+    pub connected_at: String,
+    pub duration_secs: u64,
+    /// Account the client logged in with; None without logins or for a shared VNC password
+    pub user: Option<String>,
+    /// "controller", "viewer" or "preempted"
+    pub role: Option<Role>,
+    /// Frames or framebuffer updates sent, and their rate
+    pub frames: u64,
+    pub fps: f64,
+    pub bytes_sent: u64,
+    /// Bytes sent over the last second
+    pub bytes_per_sec: u64,
+    /// Key, pointer and other input events forwarded to the host
+    pub input_events: u64,
+}
+
+impl SessionRegistry {
+    /// Publish connects, disconnects and role changes on `events`
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Record session starts and stops in the security audit trail
+    pub fn with_security_audit(mut self, audit: SecurityAudit) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    /// Throttle the sessions' frames to the caps of `bandwidth`
+    pub fn with_bandwidth(mut self, bandwidth: Bandwidth) -> Self {
+        self.bandwidth = bandwidth;
+        self
+    }
+
+    /// Egress of every session and its caps
+    pub fn bandwidth(&self) -> &Bandwidth {
+        &self.bandwidth
+    }
+
+    /// End VNC and WebSocket sessions `max_duration` after they started, whatever
+    /// their activity, warning them `warning` before (a zero duration never ends them)
+    pub fn with_max_duration(mut self, max_duration: Duration, warning: Duration) -> Self {
+        self.max_duration = (!max_duration.is_zero()).then_some(max_duration);
+        self.expiry_warning = warning.min(max_duration);
+        self
+    }
+
+    /// Warning and end of the session `id` under the maximum session duration
+    pub fn lifetime(&self, id: u64) -> SessionLifetime {
+        let registry = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let end = self.max_duration
+            .zip(registry.sessions.get(&id))
+            .map(|(max_duration, session)| session.started + max_duration);
+        SessionLifetime {
+            warning: end.map(|end| end - self.expiry_warning).filter(|_| !self.expiry_warning.is_zero()),
+            end,
+            disconnect: registry.sessions.get(&id).map(|session| session.disconnect.subscribe()),
+        }
+    }
+
+    /// Completes once the session `id` is asked to close with `disconnect`
+    pub fn disconnected(&self, id: u64) -> impl std::future::Future<Output = ()> + Send + 'static {
+        let registry = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        disconnect_requested(registry.sessions.get(&id).map(|session| session.disconnect.subscribe()))
+    }
+
+    /// Ask the session `id` to close; false if there is no such session
+    pub fn disconnect(&self, id: u64) -> bool {
+        let registry = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        match registry.sessions.get(&id) {
+            Some(session) => {
+                session.disconnect.send_replace(true);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Record the account the session `id` logged in with
+    pub fn set_user(&self, id: u64, user: &str) {
+        let mut registry = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(session) = registry.sessions.get_mut(&id) {
+            session.user = Some(user.to_string());
+        }
+    }
+
+    /// Report the role of the session `id` as `role` has it, and publish it
+    /// now and whenever it changes while the session lasts
+    pub fn set_role(&self, id: u64, role: watch::Receiver<Role>) {
+        let (user, closed) = {
+            let mut registry = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+            let Some(session) = registry.sessions.get_mut(&id) else {
+                return;
+            };
+            session.role = Some(role.clone());
+            (session.user.clone(), session.disconnect.subscribe())
+        };
+        tokio::spawn(publish_roles(self.events.clone(), id, user, role, closed));
+    }
+
+    /// Security audit trail of the console's transports
+    pub fn security_audit(&self) -> &SecurityAudit {
+        &self.audit
+    }
+
+    /// Add a session; it is removed when the guard is dropped
+    pub fn register(&self, transport: &'static str, addr: SocketAddr) -> SessionGuard {
+        let mut registry = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        registry.next_id += 1;
+        let id = registry.next_id;
+        registry.sessions.insert(id, Session {
+            transport,
+            addr,
+            connected_at: SystemTime::now(),
+            started: Instant::now(),
+            disconnect: watch::channel(false).0,
+            user: None,
+            role: None,
+            counters: Arc::new(SessionCounters {
+                bandwidth: self.bandwidth.client(),
+                ..Default::default()
+            }),
+        });
+        self.audit.log(SecurityEvent::SessionStart {
+            transport,
+            client: addr,
+            session: id,
+        });
+        // Connection spans declare the field for the messages of the session
+        tracing::Span::current().record("session", id);
+        self.events.publish(Event::ClientConnected {
+            id,
+            transport,
+            address: addr.to_string(),
+        });
+        SessionGuard {
+            registry: self.clone(),
+            id,
+        }
+    }
+
+    /// Connected sessions, oldest first
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let registry = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        registry.sessions.iter().map(|(id, session)| session.info(*id)).collect()
+    }
+
+    /// Counters of the session `id`; detached ones for an unknown session
+    pub fn counters(&self, id: u64) -> Arc<SessionCounters> {
+        let registry = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        registry.sessions.get(&id).map(|session| session.counters.clone()).unwrap_or_default()
+    }
+
+    /// The session `id` with its counters as they are now
+    pub fn get(&self, id: u64) -> Option<SessionInfo> {
+        let registry = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        registry.sessions.get(&id).map(|session| session.info(id))
+    }
+}
+
+impl Session {
+    fn info(&self, id: u64) -> SessionInfo {
+        SessionInfo {
+            id,
+            transport: self.transport,
+            address: self.addr.to_string(),
+            connected_at: humantime::format_rfc3339_seconds(self.connected_at).to_string(),
+            duration_secs: self.started.elapsed().as_secs(),
+            user: self.user.clone(),
+            role: self.role.as_ref().map(|role| *role.borrow()),
+            frames: self.counters.frames.load(Ordering::Relaxed),
+            fps: self.counters.fps(),
+            bytes_sent: self.counters.bytes_sent.load(Ordering::Relaxed),
+            bytes_per_sec: self.counters.bandwidth.bytes_per_sec(),
+            input_events: self.counters.input_events.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Step of a session's lifetime
+pub enum LifetimeEvent {
+    /// The session ends after this long
+    Warning(Duration),
+    /// The session has reached its maximum duration and must be closed
+    Expired,
+    /// The session was disconnected through the control interface and must be closed
+    Disconnected,
+}
+
+/// When a session is warned and ended under the maximum session duration
+pub struct SessionLifetime {
+    warning: Option<Instant>,
+    end: Option<Instant>,
+    disconnect: Option<watch::Receiver<bool>>,
+}
+
+impl SessionLifetime {
+    /// Completes with the warning, then with the expiry, or when the session
+    /// is disconnected; never without a maximum duration. Cancel safe, for use
+    /// in `select!` loops
+    pub async fn next(&mut self) -> LifetimeEvent {
+        let disconnect = self.disconnect.clone();
+        let event = tokio::select! {
+            event = self.expiry() => Some(event),
+            () = disconnect_requested(disconnect) => None,
+        };
+        // Reported once, like the expiry
+        event.unwrap_or_else(|| {
+            self.disconnect = None;
+            LifetimeEvent::Disconnected
+        })
+    }
+
+    async fn expiry(&mut self) -> LifetimeEvent {
+        if let (Some(warning), Some(end)) = (self.warning, self.end) {
+            tokio::time::sleep_until(warning.into()).await;
+            self.warning = None;
+            return LifetimeEvent::Warning(end.saturating_duration_since(Instant::now()));
+        }
+        match self.end {
+            Some(end) => {
+                tokio::time::sleep_until(end.into()).await;
+                self.end = None;
+                LifetimeEvent::Expired
+            }
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// Publish the role of session `id`, then its changes until the session is removed
+async fn publish_roles(events: EventBus, id: u64, user: Option<String>, mut role: watch::Receiver<Role>, mut closed: watch::Receiver<bool>) {
+    loop {
+        let current = *role.borrow_and_update();
+        events.publish(Event::RoleChanged {
+            id,
+            user: user.clone(),
+            role: current,
+        });
+        loop {
+            tokio::select! {
+                changed = role.changed() => match changed {
+                    Ok(()) => break,
+                    Err(_) => return,
+                },
+                // Only dropping the session closes the channel; a disconnect request just marks it
+                changed = closed.changed() => if changed.is_err() {
+                    return;
+                },
+            }
+        }
+    }
+}
+
+/// Completes once a disconnect is requested; never for an unknown or closed session
+async fn disconnect_requested(disconnect: Option<watch::Receiver<bool>>) {
+    if let Some(mut disconnect) = disconnect {
+        if disconnect.wait_for(|requested| *requested).await.is_ok() {
+            return;
+        }
+    }
+    std::future::pending().await
+}
+
+/// Keeps a session registered while the client is connected
+pub struct SessionGuard {
+    registry: SessionRegistry,
+    id: u64,
+}
+
+impl SessionGuard {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Counters the transport updates as it serves the session
+    pub fn counters(&self) -> Arc<SessionCounters> {
+        self.registry.counters(self.id)
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        let mut registry = self.registry.inner.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(session) = registry.sessions.remove(&self.id) {
+            self.registry.audit.log(SecurityEvent::SessionStop {
+                transport: session.transport,
+                client: session.addr,
+                session: self.id,
+                duration: session.started.elapsed(),
+            });
+            self.registry.events.publish(Event::ClientDisconnected {
+                id: self.id,
+                transport: session.transport,
+                address: session.addr.to_string(),
+            });
+        }
+    }
+}
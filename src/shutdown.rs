@@ -0,0 +1,111 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Graceful shutdown for kvm-rs
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+use crate::{audit::InputAudit, hid::HidManager, sessions::SessionRegistry, systemd};
+
+/// How often the session registry is checked while draining
+const DRAIN_POLL: Duration = Duration::from_millis(100);
+
+/// Longest wait for the gadgets while releasing held keys and buttons
+const RELEASE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Tells listeners and connected sessions that the server is shutting down
+#[derive(Clone)]
+pub struct Shutdown {
+    tx: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            tx: Arc::new(watch::channel(false).0),
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn trigger(&self) {
+        self.tx.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.tx.borrow()
+    }
+
+    /// Resolves once shutdown has been triggered
+    pub fn wait(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut rx = self.tx.subscribe();
+        async move {
+            let _ = rx.wait_for(|stopping| *stopping).await;
+        }
+    }
+
+    /// Trigger shutdown on SIGTERM or Ctrl+C
+    pub async fn on_signal(self) {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            match signal(SignalKind::terminate()) {
+                Ok(mut sigterm) => {
+                    tokio::select! {
+                        _ = sigterm.recv() => {}
+                        _ = tokio::signal::ctrl_c() => {}
+                    }
+                }
+                Err(e) => {
+                    warn!("Failed to install SIGTERM handler: {}", e);
+                    let _ = tokio::signal::ctrl_c().await;
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = tokio::signal::ctrl_c().await;
+        }
+        info!("Shutting down: closing listeners and client connections");
+        systemd::notify_stopping();
+        self.trigger();
+    }
+}
+
+/// Wait until every console client has disconnected
+pub async fn drained(sessions: &SessionRegistry) {
+    while !sessions.list().is_empty() {
+        tokio::time::sleep(DRAIN_POLL).await;
+    }
+}
+
+/// Leave the capture device, the host and the logs in a clean state before exiting
+pub async fn cleanup<T>(captures: Vec<JoinHandle<T>>, hid_managers: &[HidManager], audit: &InputAudit) {
+    // Dropping the capture tasks stops streaming and closes the video devices
+    for capture in captures {
+        capture.abort();
+        let _ = capture.await;
+    }
+
+    for hid_manager in hid_managers {
+        match tokio::time::timeout(RELEASE_TIMEOUT, hid_manager.release_all()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("Failed to release held input: {}", e),
+            Err(_) => warn!("Timed out releasing held input"),
+        }
+        // No report is left half written when the process exits
+        hid_manager.close_devices().await;
+
+        // Keep a macro that was being recorded
+        if hid_manager.macros().recording().is_some() {
+            if let Err(e) = hid_manager.macros().stop_recording() {
+                warn!("Failed to store macro recording: {}", e);
+            }
+        }
+    }
+
+    audit.flush();
+}
@@ -0,0 +1,221 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Host serial console (IPMI SOL) over WebSocket for kvm-rs
+
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use anyhow::{Context, Result};
+use axum::{
+    extract::{ws::{Message, WebSocket}, ConnectInfo, State, WebSocketUpgrade},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tracing::{debug, field, info, info_span, warn, Instrument};
+use crate::{
+    audit::{InputAudit, InputClass},
+    auth::{Access, Authenticator},
+    services::{Service, Services},
+    sessions::{LifetimeEvent, SessionRegistry},
+    shutdown::Shutdown,
+    websocket::{self, Keepalive, WsSettings},
+};
+
+/// Bytes read from the console at once, sent as one message
+const READ_BUFFER: usize = 4096;
+
+/// A console of obmc-console, the serial port of the host that IPMI SOL and
+/// bmcweb's /console0 also attach to; every client gets its own connection
+/// and sees the same output
+pub struct HostConsole {
+    /// Console ID of obmc-console-server, `default` for the one without
+    id: String,
+    dbus: Option<zbus::Connection>,
+}
+
+impl HostConsole {
+    pub fn new(id: &str, dbus: Option<zbus::Connection>) -> Self {
+        Self {
+            id: id.to_string(),
+            dbus,
+        }
+    }
+
+    /// Attach to the console: through the Connect method of its D-Bus
+    /// service, or where that is missing, its abstract socket
+    #[cfg(target_os = "linux")]
+    async fn connect(&self) -> Result<UnixStream> {
+        use std::os::linux::net::SocketAddrExt;
+
+        if let Some(ref dbus) = self.dbus {
+            match self.connect_dbus(dbus).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => debug!("Console {} over D-Bus unavailable, using its socket: {:#}", self.id, e),
+            }
+        }
+        // The console without ID has the plain name
+        let name = match self.id.as_str() {
+            "default" => "obmc-console".to_string(),
+            id => format!("obmc-console.{}", id),
+        };
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(&name)?;
+        let stream = std::os::unix::net::UnixStream::connect_addr(&addr)
+            .with_context(|| format!("Failed to connect to host console {}; is obmc-console-server running?", self.id))?;
+        stream.set_nonblocking(true)?;
+        Ok(UnixStream::from_std(stream)?)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    async fn connect(&self) -> Result<UnixStream> {
+        anyhow::bail!("Host console {} needs obmc-console on Linux", self.id)
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn connect_dbus(&self, dbus: &zbus::Connection) -> Result<UnixStream> {
+        let service = format!("xyz.openbmc_project.Console.{}", self.id);
+        let path = format!("/xyz/openbmc_project/console/{}", self.id);
+        let proxy = zbus::Proxy::new(dbus, service.as_str(), path.as_str(), "xyz.openbmc_project.Console.Access").await?;
+        let fd: zbus::zvariant::OwnedFd = proxy.call("Connect", &()).await?;
+        let stream = std::os::unix::net::UnixStream::from(std::os::fd::OwnedFd::from(fd));
+        stream.set_nonblocking(true)?;
+        Ok(UnixStream::from_std(stream)?)
+    }
+}
+
+/// State of the /sol route
+#[derive(Clone)]
+pub struct SolState {
+    pub console: Arc<HostConsole>,
+    pub sessions: SessionRegistry,
+    pub audit: InputAudit,
+    /// Settings of the KVM WebSockets, whose pings and idle timeout apply
+    pub settings: Arc<RwLock<WsSettings>>,
+    pub authenticator: Authenticator,
+    pub services: Services,
+    pub shutdown: Shutdown,
+}
+
+/// WebSocket handler of the host's serial console: binary messages carry its
+/// output, and from clients that may send input, what they type. Text
+/// messages are typed as UTF-8
+pub async fn sol_ws(
+    ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<SolState>,
+    Extension(access): Extension<Access>,
+) -> Response {
+    if !state.services.is_enabled(Service::Websocket) {
+        return (StatusCode::SERVICE_UNAVAILABLE, "WebSocket service is turned off".to_string()).into_response();
+    }
+    // Attached before the upgrade, so clients learn why it failed
+    let stream = match state.console.connect().await {
+        Ok(stream) => stream,
+        Err(e) => {
+            warn!("Host console unavailable for {}: {:#}", addr, e);
+            return (StatusCode::BAD_GATEWAY, format!("Host console unavailable: {:#}", e)).into_response();
+        }
+    };
+    let settings = *state.settings.read().unwrap_or_else(|e| e.into_inner());
+    let auth = state.authenticator.clone();
+    // Checked by the auth middleware, watched here for expiry
+    let token = access.token.clone();
+    let span = info_span!("sol_ws", client = %addr, session = field::Empty);
+    ws.protocols(["binary"]).on_upgrade(move |socket| {
+        websocket::until_session_ends(sol_session(socket, stream, addr, access, state, settings), auth, token, addr).instrument(span)
+    })
+}
+
+/// Copy between the WebSocket and the console until either closes
+async fn sol_session(socket: WebSocket, stream: UnixStream, addr: SocketAddr, access: Access, state: SolState, settings: WsSettings) {
+    let session = state.sessions.register("sol", addr);
+    if let Some(ref principal) = access.principal {
+        state.sessions.set_user(session.id(), &principal.0);
+    }
+    let counters = session.counters();
+    let may_type = access.role.may_send_input();
+    info!("Serial console client connected from {}{}", addr, if may_type { "" } else { " (read-only)" });
+    let (mut console_rx, mut console_tx) = stream.into_split();
+    let (mut ws_tx, mut ws_rx) = socket.split();
+    let keepalive = Keepalive::new(settings);
+
+    // Console to client, pings, and the end of the session
+    let outgoing = async {
+        let mut buffer = vec![0u8; READ_BUFFER];
+        let mut ticker = keepalive.ticker();
+        let mut lifetime = state.sessions.lifetime(session.id());
+        let stopping = state.shutdown.wait();
+        tokio::pin!(stopping);
+        loop {
+            let message = tokio::select! {
+                read = console_rx.read(&mut buffer) => match read {
+                    Ok(0) | Err(_) => {
+                        info!("Host console closed the connection of {}", addr);
+                        break;
+                    }
+                    Ok(n) => {
+                        counters.sent(n);
+                        Message::Binary(buffer[..n].to_vec().into())
+                    }
+                },
+                _ = ticker.tick() => {
+                    if let Err(reason) = keepalive.check() {
+                        info!("Closing serial console connection from {}: {}", addr, reason);
+                        break;
+                    }
+                    Message::Ping(Default::default())
+                }
+                // A terminal has no place for warnings, the session just ends
+                event = lifetime.next() => match event {
+                    LifetimeEvent::Warning(_) => continue,
+                    LifetimeEvent::Expired => websocket::expired_message(),
+                    LifetimeEvent::Disconnected => websocket::disconnected_message(),
+                },
+                _ = &mut stopping => websocket::going_away_message(),
+            };
+            let close = matches!(message, Message::Close(_));
+            if ws_tx.send(message).await.is_err() || close {
+                break;
+            }
+        }
+    };
+
+    // Client to console
+    let incoming = async {
+        let mut refused = false;
+        while let Some(Ok(message)) = ws_rx.next().await {
+            let data = match message {
+                Message::Binary(data) => data,
+                Message::Text(text) => text.as_bytes().to_vec().into(),
+                Message::Pong(_) => {
+                    keepalive.pong();
+                    continue;
+                }
+                Message::Close(_) => break,
+                Message::Ping(_) => continue,
+            };
+            keepalive.activity();
+            if !may_type {
+                if !refused {
+                    info!("Dropping serial console input of read-only client {}", addr);
+                    refused = true;
+                }
+                continue;
+            }
+            // Typed text may hold passwords, so only its length is recorded
+            state.audit.record("sol", &addr.to_string(), InputClass::Serial, || format!("bytes={}", data.len()));
+            counters.input_event();
+            if console_tx.write_all(&data).await.is_err() {
+                break;
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = outgoing => {}
+        _ = incoming => {}
+    }
+    info!("Serial console client disconnected: {}", addr);
+}
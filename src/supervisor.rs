@@ -0,0 +1,123 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Supervision and restart of background tasks for kvm-rs
+
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Result};
+use futures_util::FutureExt;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+use crate::shutdown::Shutdown;
+
+/// Wait before the first restart of a failed task, doubled for each further failure
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Longest wait before a restart
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Failures of a critical task within this window that stop the server
+const MAX_FAILURES: usize = 5;
+const FAILURE_WINDOW: Duration = Duration::from_secs(60);
+
+/// What happens when a supervised task fails, by returning an error or
+/// panicking; a task that returns `Ok` is done and not restarted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+    /// Log the failure and leave the task stopped
+    Never,
+    /// Restart with backoff, for as long as the server runs
+    OnFailure,
+    /// Restart with backoff, and stop the server with an error when the
+    /// task fails `MAX_FAILURES` times within `FAILURE_WINDOW`
+    Critical,
+}
+
+/// Runs the background tasks of the server and restarts those that fail
+#[derive(Clone)]
+pub struct Supervisor {
+    shutdown: Shutdown,
+    /// Why a critical task made the server stop
+    escalation: Arc<Mutex<Option<String>>>,
+}
+
+impl Supervisor {
+    pub fn new(shutdown: Shutdown) -> Self {
+        Self {
+            shutdown,
+            escalation: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Run the task `start` makes until it is done or shutdown is triggered,
+    /// calling `start` again for every restart
+    pub fn spawn<F, Fut>(&self, name: impl Into<String>, policy: RestartPolicy, start: F) -> JoinHandle<()>
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let supervisor = self.clone();
+        tokio::spawn(supervisor.supervise(name.into(), policy, start))
+    }
+
+    /// The reason a critical task stopped the server, if one did
+    pub fn escalation(&self) -> Option<String> {
+        self.escalation.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    async fn supervise<F, Fut>(self, name: String, policy: RestartPolicy, mut start: F)
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let mut failures: Vec<Instant> = Vec::new();
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            let started = Instant::now();
+            // A panic fails the task instead of ending it unnoticed
+            let result = tokio::select! {
+                result = AssertUnwindSafe(start()).catch_unwind() => result.unwrap_or_else(|panic| Err(anyhow!("panicked: {}", panic_message(&*panic)))),
+                _ = self.shutdown.wait() => return,
+            };
+            let e = match result {
+                Ok(()) => {
+                    info!("{} finished", name);
+                    return;
+                }
+                Err(e) => e,
+            };
+            if policy == RestartPolicy::Never {
+                error!("{} failed: {:#}", name, e);
+                return;
+            }
+
+            // A task that ran for a while before failing starts over with short waits
+            if started.elapsed() > FAILURE_WINDOW {
+                backoff = INITIAL_BACKOFF;
+            }
+            failures.retain(|failure| failure.elapsed() < FAILURE_WINDOW);
+            failures.push(Instant::now());
+            if policy == RestartPolicy::Critical && failures.len() >= MAX_FAILURES {
+                let reason = format!("{} failed {} times within {}s, last: {:#}", name, failures.len(), FAILURE_WINDOW.as_secs(), e);
+                error!("{}, stopping", reason);
+                self.escalation.lock().unwrap_or_else(|e| e.into_inner()).get_or_insert(reason);
+                self.shutdown.trigger();
+                return;
+            }
+            warn!("{} failed, restarting in {}ms: {:#}", name, backoff.as_millis(), e);
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {}
+                _ = self.shutdown.wait() => return,
+            }
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+}
+
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> &str {
+    panic.downcast_ref::<&str>().copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown cause")
+}
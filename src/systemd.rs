@@ -0,0 +1,81 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// systemd readiness notification and watchdog for kvm-rs
+
+use std::sync::Arc;
+use std::time::Duration;
+use sd_notify::NotifyState;
+use tokio::sync::broadcast;
+use tracing::{debug, info, warn};
+use crate::display::DisplayHub;
+
+/// Tell systemd the service is up once capture has produced its first frame,
+/// then keep its watchdog fed. Call after every listener is bound; without
+/// NOTIFY_SOCKET (not started by systemd) nothing is sent
+pub async fn notify_ready(hub: Arc<DisplayHub>) {
+    let mut frames = hub.tx.subscribe();
+    if hub.status().frames == 0 {
+        if let Err(broadcast::error::RecvError::Closed) = frames.recv().await {
+            return;
+        }
+    }
+    drop(frames);
+
+    if let Err(e) = sd_notify::notify(false, &[NotifyState::Ready]) {
+        warn!("Failed to notify systemd: {}", e);
+        return;
+    }
+    debug!("Notified systemd of readiness");
+
+    let mut usec = 0;
+    if sd_notify::watchdog_enabled(false, &mut usec) {
+        watchdog(hub, Duration::from_micros(usec)).await;
+    }
+}
+
+/// Show `status` in `systemctl status`, e.g. what startup is waiting for;
+/// an empty status clears it
+pub fn notify_status(status: &str) {
+    if let Err(e) = sd_notify::notify(false, &[NotifyState::Status(status)]) {
+        warn!("Failed to notify systemd: {}", e);
+    }
+}
+
+/// Tell systemd the service is shutting down, so a slow drain of the
+/// sessions is not mistaken for a hang
+pub fn notify_stopping() {
+    if let Err(e) = sd_notify::notify(false, &[NotifyState::Stopping]) {
+        warn!("Failed to notify systemd: {}", e);
+    }
+}
+
+/// Ping the watchdog twice per `timeout` while capture delivers frames, so
+/// systemd restarts the service when the capture loop is wedged or has stopped
+async fn watchdog(hub: Arc<DisplayHub>, timeout: Duration) {
+    info!("systemd watchdog enabled, timeout {}s", timeout.as_secs_f64());
+    let mut interval = tokio::time::interval(timeout / 2);
+    // The first tick completes immediately
+    interval.tick().await;
+    let mut last_frames = hub.status().frames;
+    let mut stalled = false;
+    loop {
+        interval.tick().await;
+        let status = hub.status();
+        let healthy = status.error.is_none() && status.frames != last_frames;
+        last_frames = status.frames;
+        if !healthy {
+            if !stalled {
+                warn!("Capture is not delivering frames, withholding systemd watchdog pings");
+                stalled = true;
+            }
+            continue;
+        }
+        if stalled {
+            info!("Capture recovered, resuming systemd watchdog pings");
+            stalled = false;
+        }
+        if let Err(e) = sd_notify::notify(false, &[NotifyState::Watchdog]) {
+            warn!("Failed to ping the systemd watchdog: {}", e);
+        }
+    }
+}
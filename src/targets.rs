@@ -0,0 +1,93 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Console targets served on /kvm/{id} for kvm-rs
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use crate::{arbiter::Arbiter, display::DisplayHub, hid::HidManager, power::HostPower, vnc::VncHandler};
+
+/// Console of one host: its video capture, HID gadget and the VNC handler joining them
+#[derive(Clone)]
+pub struct Target {
+    pub hub: Arc<DisplayHub>,
+    pub hid_manager: HidManager,
+    pub vnc: VncHandler,
+    /// Admits the WebSocket clients of this console
+    pub arbiter: Arbiter,
+    /// Power control its controller may use, for the console of host 0 with --power-control
+    pub power: Option<HostPower>,
+}
+
+/// Console of a further host given as
+/// `ID:video=PATH,keyboard=PATH,mouse=PATH[,touchscreen=PATH][,vnc=ADDR:PORT...]`;
+/// it shares TLS, authentication and the other settings with console 0
+#[derive(Debug, Clone)]
+pub struct ConsoleSpec {
+    pub id: u32,
+    pub video_device: String,
+    pub keyboard_hid: String,
+    pub mouse_hid: String,
+    pub touchscreen_hid: Option<String>,
+    /// Addresses of its own VNC server; without any it is only reachable on /kvm/{id}
+    pub vnc_listen: Vec<SocketAddr>,
+}
+
+impl FromStr for ConsoleSpec {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        const USAGE: &str = "expected ID:video=PATH,keyboard=PATH,mouse=PATH[,touchscreen=PATH][,vnc=ADDR:PORT]";
+        let (id, settings) = spec.split_once(':').ok_or(USAGE)?;
+        let id: u32 = id.trim().parse().map_err(|_| format!("invalid console id '{}'", id))?;
+        if id == 0 {
+            return Err("console 0 is set up by the top-level options".to_string());
+        }
+        let (mut video_device, mut keyboard_hid, mut mouse_hid) = (None, None, None);
+        let mut touchscreen_hid = None;
+        let mut vnc_listen = Vec::new();
+        for setting in settings.split(',') {
+            let (name, value) = setting.split_once('=').ok_or(USAGE)?;
+            let value = value.trim().to_string();
+            match name.trim() {
+                "video" => video_device = Some(value),
+                "keyboard" => keyboard_hid = Some(value),
+                "mouse" => mouse_hid = Some(value),
+                "touchscreen" => touchscreen_hid = Some(value),
+                "vnc" => vnc_listen.push(value.parse().map_err(|_| format!("invalid VNC address '{}'", value))?),
+                other => return Err(format!("unknown console setting '{}'", other)),
+            }
+        }
+        Ok(Self {
+            id,
+            video_device: video_device.ok_or("missing video=PATH")?,
+            keyboard_hid: keyboard_hid.ok_or("missing keyboard=PATH")?,
+            mouse_hid: mouse_hid.ok_or("missing mouse=PATH")?,
+            touchscreen_hid,
+            vnc_listen,
+        })
+    }
+}
+
+/// Consoles by id, so a BMC managing several host nodes can serve one per node
+#[derive(Clone, Default)]
+pub struct TargetRegistry {
+    targets: BTreeMap<u32, Target>,
+}
+
+impl TargetRegistry {
+    pub fn with_target(mut self, id: u32, target: Target) -> Self {
+        self.targets.insert(id, target);
+        self
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Target> {
+        self.targets.get(&id)
+    }
+
+    /// Configured ids, in ascending order
+    pub fn ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.targets.keys().copied()
+    }
+}
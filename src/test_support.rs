@@ -0,0 +1,297 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// In-process servers and a minimal RFB client for the end-to-end tests of kvm-rs
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use crate::{
+    args::Args,
+    display::CaptureSettings,
+    hid_backend::{HidBackend, HidRole, MockHidBackend},
+    mock_capture::{FrameSource, MockScript, Step},
+    server::KvmServerBuilder,
+    shutdown::Shutdown,
+};
+
+/// Longest wait for the server in any one step of a test
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Colour of the frames `TestServer::start` captures
+pub const TEST_COLOR: [u8; 3] = [0x20, 0x40, 0x80];
+
+/// The full server on ephemeral VNC and web ports, with mock capture and HID
+pub struct TestServer {
+    pub vnc_addr: SocketAddr,
+    pub keyboard: MockHidBackend,
+    pub mouse: MockHidBackend,
+    pub shutdown: Shutdown,
+}
+
+impl TestServer {
+    /// Serve 320x240 frames of `TEST_COLOR`
+    pub async fn start() -> Result<Self> {
+        Self::with_script(MockScript {
+            steps: vec![Step::Frames {
+                source: FrameSource::Solid { width: 320, height: 240, color: TEST_COLOR },
+                duration: Duration::from_secs(1),
+                fps: 30,
+            }],
+            repeat: true,
+        })
+        .await
+    }
+
+    /// Serve the frames of `script`
+    pub async fn with_script(script: MockScript) -> Result<Self> {
+        let args = Args::try_parse_from([
+            "kvm-rs",
+            "--listen", "127.0.0.1:0",
+            "--vnc-listen", "127.0.0.1:0",
+            "--no-access-log",
+        ])?;
+        let keyboard = MockHidBackend::new("mock-keyboard");
+        let mouse = MockHidBackend::new("mock-mouse");
+        let backends = {
+            let (keyboard, mouse) = (keyboard.clone(), mouse.clone());
+            Arc::new(move |role: HidRole, _: &str| -> Arc<dyn HidBackend> {
+                match role {
+                    HidRole::Keyboard => Arc::new(keyboard.clone()),
+                    HidRole::Mouse => Arc::new(mouse.clone()),
+                    HidRole::Touchscreen => Arc::new(MockHidBackend::new("mock-touchscreen")),
+                }
+            })
+        };
+        let server = KvmServerBuilder::new(args)
+            .with_capture(CaptureSettings {
+                force_framebuffer: false,
+                mock: Some(Arc::new(script)),
+                host: None,
+                frame_rate: None,
+                subsampling: None,
+                jpeg_quality: None,
+            })
+            .with_hid_backends(backends)
+            .build()
+            .await?;
+
+        let vnc_addr = server.vnc_addrs()[0];
+        let shutdown = server.shutdown().clone();
+        tokio::spawn(server.run());
+
+        Ok(Self { vnc_addr, keyboard, mouse, shutdown })
+    }
+
+    /// A client that has completed the RFB handshake
+    pub async fn connect(&self) -> Result<RfbClient> {
+        RfbClient::connect(self.vnc_addr).await
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.shutdown.trigger();
+    }
+}
+
+/// Wait until `backend` has recorded at least `count` reports, and take them
+pub async fn wait_for_reports(backend: &MockHidBackend, count: usize) -> Result<Vec<Vec<u8>>> {
+    tokio::time::timeout(TIMEOUT, async {
+        loop {
+            if backend.reports().len() >= count {
+                return backend.take_reports();
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .with_context(|| format!("Fewer than {} HID reports from {}", count, backend.path()))
+}
+
+/// One rectangle of a FramebufferUpdate
+#[derive(Debug)]
+pub struct Rect {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+    pub pixels: Vec<u8>,
+}
+
+/// RFB 3.8 client with security type None and Raw rectangles
+pub struct RfbClient {
+    stream: TcpStream,
+    pub width: u16,
+    pub height: u16,
+    pub pixel_format: [u8; 16],
+    pub name: String,
+    /// Bytes per pixel of the current pixel format
+    bytes_per_pixel: usize,
+}
+
+impl RfbClient {
+    /// Connect and run the handshake up to ServerInit
+    pub async fn connect(addr: SocketAddr) -> Result<Self> {
+        let mut stream = tokio::time::timeout(TIMEOUT, TcpStream::connect(addr)).await??;
+        let mut version = [0u8; 12];
+        read_exact(&mut stream, &mut version).await?;
+        if &version != b"RFB 003.008\n" {
+            bail!("Unexpected protocol version {:?}", String::from_utf8_lossy(&version));
+        }
+        stream.write_all(b"RFB 003.008\n").await?;
+
+        let count = read_u8(&mut stream).await?;
+        if count == 0 {
+            bail!("Server refused the connection");
+        }
+        let mut types = vec![0u8; count as usize];
+        read_exact(&mut stream, &mut types).await?;
+        if !types.contains(&1) {
+            bail!("Security type None not offered: {:?}", types);
+        }
+        stream.write_all(&[1]).await?;
+        let mut result = [0u8; 4];
+        read_exact(&mut stream, &mut result).await?;
+        if result != [0; 4] {
+            bail!("Security handshake failed");
+        }
+
+        // Shared ClientInit
+        stream.write_all(&[1]).await?;
+        let mut init = [0u8; 24];
+        read_exact(&mut stream, &mut init).await?;
+        let width = u16::from_be_bytes([init[0], init[1]]);
+        let height = u16::from_be_bytes([init[2], init[3]]);
+        let mut pixel_format = [0u8; 16];
+        pixel_format.copy_from_slice(&init[4..20]);
+        let mut name = vec![0u8; u32::from_be_bytes([init[20], init[21], init[22], init[23]]) as usize];
+        read_exact(&mut stream, &mut name).await?;
+
+        Ok(Self {
+            stream,
+            width,
+            height,
+            pixel_format,
+            name: String::from_utf8_lossy(&name).into_owned(),
+            bytes_per_pixel: pixel_format[0] as usize / 8,
+        })
+    }
+
+    pub async fn set_pixel_format(&mut self, format: [u8; 16]) -> Result<()> {
+        let mut message = vec![0u8, 0, 0, 0];
+        message.extend_from_slice(&format);
+        self.stream.write_all(&message).await?;
+        self.pixel_format = format;
+        self.bytes_per_pixel = format[0] as usize / 8;
+        Ok(())
+    }
+
+    pub async fn set_encodings(&mut self, encodings: &[i32]) -> Result<()> {
+        let mut message = vec![2u8, 0];
+        message.extend_from_slice(&(encodings.len() as u16).to_be_bytes());
+        for encoding in encodings {
+            message.extend_from_slice(&encoding.to_be_bytes());
+        }
+        self.stream.write_all(&message).await?;
+        Ok(())
+    }
+
+    /// FramebufferUpdateRequest for the whole screen
+    pub async fn request_update(&mut self, incremental: bool) -> Result<()> {
+        let mut message = vec![3u8, incremental as u8, 0, 0, 0, 0];
+        message.extend_from_slice(&self.width.to_be_bytes());
+        message.extend_from_slice(&self.height.to_be_bytes());
+        self.stream.write_all(&message).await?;
+        Ok(())
+    }
+
+    pub async fn key_event(&mut self, keysym: u32, down: bool) -> Result<()> {
+        let mut message = vec![4u8, down as u8, 0, 0];
+        message.extend_from_slice(&keysym.to_be_bytes());
+        self.stream.write_all(&message).await?;
+        Ok(())
+    }
+
+    pub async fn pointer_event(&mut self, buttons: u8, x: u16, y: u16) -> Result<()> {
+        let mut message = vec![5u8, buttons];
+        message.extend_from_slice(&x.to_be_bytes());
+        message.extend_from_slice(&y.to_be_bytes());
+        self.stream.write_all(&message).await?;
+        Ok(())
+    }
+
+    /// Send bytes as they are, e.g. a malformed message
+    pub async fn send_raw(&mut self, data: &[u8]) -> Result<()> {
+        self.stream.write_all(data).await?;
+        Ok(())
+    }
+
+    /// Rectangles of the next FramebufferUpdate, skipping bells and clipboard text
+    pub async fn read_update(&mut self) -> Result<Vec<Rect>> {
+        loop {
+            match read_u8(&mut self.stream).await? {
+                0 => break,
+                2 => continue,
+                3 => {
+                    let mut header = [0u8; 7];
+                    read_exact(&mut self.stream, &mut header).await?;
+                    let len = u32::from_be_bytes([header[3], header[4], header[5], header[6]]);
+                    read_exact(&mut self.stream, &mut vec![0u8; len as usize]).await?;
+                }
+                other => bail!("Unexpected server message type {}", other),
+            }
+        }
+        let mut header = [0u8; 3];
+        read_exact(&mut self.stream, &mut header).await?;
+        let count = u16::from_be_bytes([header[1], header[2]]);
+        let mut rects = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let mut rect = [0u8; 12];
+            read_exact(&mut self.stream, &mut rect).await?;
+            let width = u16::from_be_bytes([rect[4], rect[5]]);
+            let height = u16::from_be_bytes([rect[6], rect[7]]);
+            let encoding = i32::from_be_bytes([rect[8], rect[9], rect[10], rect[11]]);
+            if encoding != 0 {
+                bail!("Unsupported encoding {}", encoding);
+            }
+            let mut pixels = vec![0u8; width as usize * height as usize * self.bytes_per_pixel];
+            read_exact(&mut self.stream, &mut pixels).await?;
+            rects.push(Rect {
+                x: u16::from_be_bytes([rect[0], rect[1]]),
+                y: u16::from_be_bytes([rect[2], rect[3]]),
+                width,
+                height,
+                pixels,
+            });
+        }
+        Ok(rects)
+    }
+
+    /// Whether the server has closed the connection, reading anything it sent first
+    pub async fn is_closed(&mut self) -> Result<bool> {
+        let mut buffer = [0u8; 4096];
+        loop {
+            match tokio::time::timeout(TIMEOUT, self.stream.read(&mut buffer)).await {
+                Ok(Ok(0)) | Ok(Err(_)) => return Ok(true),
+                Ok(Ok(_)) => continue,
+                Err(_) => return Ok(false),
+            }
+        }
+    }
+}
+
+async fn read_exact(stream: &mut TcpStream, buffer: &mut [u8]) -> Result<()> {
+    tokio::time::timeout(TIMEOUT, stream.read_exact(buffer)).await
+        .context("Timed out waiting for the server")??;
+    Ok(())
+}
+
+async fn read_u8(stream: &mut TcpStream) -> Result<u8> {
+    let mut byte = [0u8; 1];
+    read_exact(stream, &mut byte).await?;
+    Ok(byte[0])
+}
@@ -0,0 +1,258 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// TLS certificate handling shared by the VNC and HTTPS listeners of kvm-rs
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, RwLock};
+use anyhow::{Context, Result};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{RootCertStore, ServerConfig};
+use tracing::info;
+use crate::args::SniCert;
+
+/// Certificate chain and private key presented by the TLS listeners; clones
+/// share the default certificate
+#[derive(Clone)]
+pub struct TlsIdentity {
+    /// Default certificate, which can be replaced while the listeners run
+    default: Arc<RwLock<Arc<CertifiedKey>>>,
+    /// CAs client certificates must be issued by, if clients must present one
+    client_roots: Option<Arc<RootCertStore>>,
+    /// Certificates for the server names clients ask for by SNI, instead of the default one
+    sni_certs: Vec<(String, Arc<CertifiedKey>)>,
+}
+
+impl TlsIdentity {
+    /// Load the given PEM files, or generate a self-signed certificate for the
+    /// names the BMC is reached by on `bind_addresses` if either is missing
+    pub async fn from_paths(cert_path: Option<&str>, key_path: Option<&str>, bind_addresses: &[IpAddr]) -> Result<Self> {
+        match (cert_path, key_path) {
+            (Some(cert), Some(key)) => Self::load(cert, key).await,
+            _ => Self::self_signed(bind_addresses),
+        }
+    }
+
+    /// Present the given certificate chain and key
+    pub fn new(cert_chain: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> Result<Self> {
+        Ok(Self {
+            default: Arc::new(RwLock::new(certified_key(cert_chain, key)?)),
+            client_roots: None,
+            sni_certs: Vec::new(),
+        })
+    }
+
+    async fn load(cert_path: &str, key_path: &str) -> Result<Self> {
+        let (cert_chain, key) = load_pem(cert_path, key_path).await?;
+        Self::new(cert_chain, key)
+    }
+
+    fn self_signed(bind_addresses: &[IpAddr]) -> Result<Self> {
+        use rcgen::{CertificateParams, DistinguishedName, KeyPair};
+
+        info!("Generating self-signed TLS certificate...");
+
+        // Generate key pair
+        let key_pair = KeyPair::generate()
+            .context("Failed to generate key pair")?;
+
+        // Generate self-signed certificate
+        // Addresses become IP address subjectAltNames, the rest DNS names
+        let names = own_names(bind_addresses);
+        info!("Certificate names: {}", names.join(", "));
+        let mut params = CertificateParams::new(names)?;
+        let mut dn = DistinguishedName::new();
+        dn.push(rcgen::DnType::CommonName, "KVM-RS Server");
+        dn.push(rcgen::DnType::OrganizationName, "OpenBMC");
+        params.distinguished_name = dn;
+
+        let cert = params.self_signed(&key_pair)
+            .context("Failed to generate self-signed certificate")?;
+
+        info!("Self-signed certificate generated successfully");
+        Self::new(
+            vec![cert.der().clone()],
+            PrivateKeyDer::from(PrivatePkcs8KeyDer::from(key_pair.serialize_der())),
+        )
+    }
+
+    /// Present another default certificate from now on; established
+    /// connections keep the one they were opened with
+    pub fn replace(&self, cert_chain: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> Result<()> {
+        let certified = certified_key(cert_chain, key)?;
+        *self.default.write().unwrap_or_else(|e| e.into_inner()) = certified;
+        Ok(())
+    }
+
+    /// Require clients to present a certificate issued by a CA of the PEM bundle `ca_path`
+    pub fn with_client_ca(mut self, ca_path: &str) -> Result<Self> {
+        use rustls_pemfile::certs;
+        use std::io::Cursor;
+
+        let pem = std::fs::read(ca_path)
+            .with_context(|| format!("Failed to read client CA file: {}", ca_path))?;
+        let mut roots = RootCertStore::empty();
+        for cert in certs(&mut Cursor::new(&pem)) {
+            roots.add(cert.context("Failed to parse client CA file")?)?;
+        }
+        if roots.is_empty() {
+            return Err(anyhow::anyhow!("No certificates found in client CA file {}", ca_path));
+        }
+        self.client_roots = Some(Arc::new(roots));
+        Ok(self)
+    }
+
+    /// Serve the given certificates to clients asking for their names by SNI;
+    /// other clients, and those sending no SNI, get the default certificate
+    pub async fn with_sni_certs(mut self, sni_certs: &[SniCert]) -> Result<Self> {
+        for sni in sni_certs {
+            let (cert_chain, key) = load_pem(&sni.cert_path, &sni.key_path).await?;
+            let certified = certified_key(cert_chain, key)
+                .with_context(|| format!("Invalid certificate for {}", sni.name))?;
+            self.sni_certs.push((sni.name.clone(), certified));
+        }
+        Ok(self)
+    }
+
+    /// Server configuration offering the given ALPN protocols (none for VNC)
+    pub fn server_config(&self, alpn_protocols: &[&[u8]]) -> Result<Arc<ServerConfig>> {
+        let builder = ServerConfig::builder();
+        let builder = match self.client_roots {
+            Some(ref roots) => builder.with_client_cert_verifier(
+                WebPkiClientVerifier::builder(roots.clone()).build().context("Invalid client CA file")?,
+            ),
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder.with_cert_resolver(Arc::new(SniResolver {
+            default: self.default.clone(),
+            by_name: self.sni_certs.iter().cloned().collect(),
+        }));
+        config.alpn_protocols = alpn_protocols.iter().map(|protocol| protocol.to_vec()).collect();
+        Ok(Arc::new(config))
+    }
+}
+
+/// Picks the certificate of the server name a client asks for
+#[derive(Debug)]
+struct SniResolver {
+    default: Arc<RwLock<Arc<CertifiedKey>>>,
+    /// Server names, or `*.` wildcards, and their certificates
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let default = || self.default.read().unwrap_or_else(|e| e.into_inner()).clone();
+        let Some(name) = client_hello.server_name().map(str::to_ascii_lowercase) else {
+            return Some(default());
+        };
+        let wildcard = name.split_once('.').map(|(_, parent)| format!("*.{}", parent));
+        let key = self.by_name.get(&name)
+            .or_else(|| wildcard.and_then(|wildcard| self.by_name.get(&wildcard)))
+            .cloned()
+            .unwrap_or_else(default);
+        Some(key)
+    }
+}
+
+/// Signing key for a certificate chain, checking that they belong together
+fn certified_key(cert_chain: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> Result<Arc<CertifiedKey>> {
+    let provider = rustls::crypto::aws_lc_rs::default_provider();
+    let certified = CertifiedKey::from_der(cert_chain, key, &provider)
+        .context("Invalid certificate or private key, or they do not belong together")?;
+    Ok(Arc::new(certified))
+}
+
+/// Certificate chain and private key from PEM files
+async fn load_pem(cert_path: &str, key_path: &str) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    use tokio::fs;
+    use rustls_pemfile::{certs, private_key};
+    use std::io::Cursor;
+
+    // Read certificate file
+    let cert_data = fs::read(cert_path).await
+        .with_context(|| format!("Failed to read certificate file: {}", cert_path))?;
+
+    // Read private key file
+    let key_data = fs::read(key_path).await
+        .with_context(|| format!("Failed to read private key file: {}", key_path))?;
+
+    // Parse certificates
+    let cert_chain = certs(&mut Cursor::new(&cert_data))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to parse certificate chain in {}", cert_path))?;
+
+    // Parse private key
+    let key = private_key(&mut Cursor::new(&key_data))
+        .with_context(|| format!("Failed to parse private key in {}", key_path))?
+        .ok_or_else(|| anyhow::anyhow!("No private key found in key file {}", key_path))?;
+
+    Ok((cert_chain, key))
+}
+
+/// Names and addresses clients may reach the BMC by on `bind_addresses`
+fn own_names(bind_addresses: &[IpAddr]) -> Vec<String> {
+    let mut names = vec!["localhost".to_string()];
+    if let Some(hostname) = crate::mdns::system_hostname() {
+        // mDNS name of the host
+        let short = hostname.split('.').next().unwrap_or(&hostname);
+        names.push(format!("{}.local", short));
+        names.push(hostname);
+    }
+    let mut addresses = vec![IpAddr::V4(Ipv4Addr::LOCALHOST), IpAddr::V6(Ipv6Addr::LOCALHOST)];
+    if bind_addresses.iter().any(IpAddr::is_unspecified) {
+        // Listening on every interface: the addresses they have now
+        addresses.extend(interface_addresses());
+    } else {
+        addresses.extend_from_slice(bind_addresses);
+    }
+    for address in addresses {
+        names.push(address.to_string());
+    }
+    let mut unique = Vec::new();
+    for name in names {
+        if !unique.contains(&name) {
+            unique.push(name);
+        }
+    }
+    unique
+}
+
+/// IPv4 and global IPv6 addresses of the network interfaces, from procfs
+fn interface_addresses() -> Vec<IpAddr> {
+    let mut addresses = Vec::new();
+    // Local routing table entries: "|-- <address>" followed by "/32 host LOCAL"
+    if let Ok(fib) = std::fs::read_to_string("/proc/net/fib_trie") {
+        let mut last = None;
+        for line in fib.lines().map(str::trim) {
+            if let Some(address) = line.strip_prefix("|-- ") {
+                last = address.parse::<Ipv4Addr>().ok();
+            } else if line == "/32 host LOCAL" {
+                addresses.extend(last.take().map(IpAddr::V4));
+            }
+        }
+    }
+    // "<address as 32 hex digits> <index> <prefix> <scope> <flags> <name>"; scope 0x20 is link-local
+    if let Ok(if_inet6) = std::fs::read_to_string("/proc/net/if_inet6") {
+        for line in if_inet6.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 4 || fields[3] == "20" {
+                continue;
+            }
+            if let Ok(bits) = u128::from_str_radix(fields[0], 16) {
+                addresses.push(IpAddr::V6(Ipv6Addr::from(bits)));
+            }
+        }
+    }
+    addresses
+}
+
+/// Common name of the verified client certificate of a connection, the user
+/// it is audited as
+pub fn client_name(certs: Option<&[CertificateDer<'_>]>) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(certs?.first()?).ok()?;
+    let name = cert.subject().iter_common_name().next()?.as_str().ok()?;
+    Some(name.to_string())
+}
@@ -0,0 +1,58 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Registry of the transports clients reach the consoles over for kvm-rs
+
+use anyhow::Result;
+use futures_util::{future::BoxFuture, stream::FuturesUnordered, FutureExt, StreamExt};
+use tracing::error;
+use crate::shutdown::Shutdown;
+
+/// A way for clients to reach the consoles, such as the VNC port or the web server.
+///
+/// A transport is built from the consoles it serves: the `DisplayHub` of each
+/// target is its frame source, the `HidManager` takes its clients' input, and
+/// its clients register in the `SessionRegistry`, which tells them when an
+/// administrator or the session lifetime closes them.
+pub trait ConsoleTransport: Send {
+    /// Name used in log messages, e.g. "VNC server"
+    fn name(&self) -> &str;
+
+    /// Serve clients until `shutdown` is triggered, then stop accepting
+    /// connections; open sessions close themselves on shutdown
+    fn serve(self: Box<Self>, shutdown: Shutdown) -> BoxFuture<'static, Result<()>>;
+}
+
+/// The transports started together
+#[derive(Default)]
+pub struct TransportRegistry {
+    transports: Vec<Box<dyn ConsoleTransport>>,
+}
+
+impl TransportRegistry {
+    pub fn with_transport(mut self, transport: impl ConsoleTransport + 'static) -> Self {
+        self.transports.push(Box::new(transport));
+        self
+    }
+
+    /// Run every transport until all have stopped. A transport that fails
+    /// triggers shutdown of the others, and its error is returned
+    pub async fn serve(self, shutdown: Shutdown) -> Result<()> {
+        let mut running: FuturesUnordered<_> = self.transports.into_iter()
+            .map(|transport| {
+                let name = transport.name().to_string();
+                transport.serve(shutdown.clone()).map(move |result| (name, result))
+            })
+            .collect();
+        let mut failure = None;
+        while let Some((name, result)) = running.next().await {
+            if let Err(e) = result {
+                error!("{} failed: {:#}", name, e);
+                if failure.is_none() {
+                    shutdown.trigger();
+                    failure = Some(e);
+                }
+            }
+        }
+        failure.map_or(Ok(()), Err)
+    }
+}
@@ -0,0 +1,64 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Unix domain socket listener for the kvm-rs web server
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use axum::serve::Listener;
+use tokio::net::{UnixListener, UnixStream};
+
+/// Owner and group may connect, e.g. bmcweb running in the socket's group
+const SOCKET_MODE: u32 = 0o660;
+
+/// Unix socket a local reverse proxy connects to. Clients are reported with
+/// the loopback address, since handlers expect an IP peer address
+pub struct UnixSocketListener {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl UnixSocketListener {
+    pub fn bind(path: &str) -> Result<Self> {
+        // A socket left behind by a previous run would make bind fail
+        match std::fs::remove_file(path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("Failed to remove stale socket {}", path)),
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("Failed to bind to {}", path))?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(SOCKET_MODE))
+            .with_context(|| format!("Failed to set permissions of {}", path))?;
+        Ok(Self {
+            listener,
+            path: PathBuf::from(path),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Listener for UnixSocketListener {
+    type Io = UnixStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        let (stream, _) = Listener::accept(&mut self.listener).await;
+        (stream, SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+    }
+}
+
+impl Drop for UnixSocketListener {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
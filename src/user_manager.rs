@@ -0,0 +1,68 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// OpenBMC account privileges and state from phosphor-user-manager for kvm-rs
+
+use std::collections::HashMap;
+use anyhow::{Context, Result};
+use zbus::zvariant::OwnedValue;
+use tracing::warn;
+use crate::auth::UserRole;
+
+const USER_MANAGER_SERVICE: &str = "xyz.openbmc_project.User.Manager";
+const USER_MANAGER_PATH: &str = "/xyz/openbmc_project/user";
+const USER_MANAGER_INTERFACE: &str = "xyz.openbmc_project.User.Manager";
+
+/// Looks up accounts in xyz.openbmc_project.User.Manager
+#[derive(Clone)]
+pub struct UserManager {
+    dbus: zbus::Connection,
+}
+
+impl UserManager {
+    pub fn new(dbus: zbus::Connection) -> Self {
+        Self { dbus }
+    }
+
+    /// Console role of an account from its OpenBMC privilege (Administrator,
+    /// Operator, ReadOnly); None if it has no console privilege, is disabled
+    /// or is locked after failed logins
+    pub async fn console_role(&self, username: &str) -> Result<Option<UserRole>> {
+        let reply = self.dbus.call_method(
+            Some(USER_MANAGER_SERVICE),
+            USER_MANAGER_PATH,
+            Some(USER_MANAGER_INTERFACE),
+            "GetUserInfo",
+            &(username,),
+        ).await.with_context(|| format!("GetUserInfo for {} failed", username))?;
+        let info: HashMap<String, OwnedValue> = reply.body().deserialize()
+            .context("Invalid GetUserInfo reply")?;
+        let flag = |name: &str| info.get(name).and_then(|value| value.downcast_ref::<bool>().ok()).unwrap_or(false);
+
+        if !flag("UserEnabled") {
+            warn!("Refused console login by {}: account disabled", username);
+            return Ok(None);
+        }
+        if flag("UserLockedForFailedAttempt") {
+            warn!("Refused console login by {}: account locked after failed logins", username);
+            return Ok(None);
+        }
+        let privilege = info.get("UserPrivilege")
+            .and_then(|value| value.downcast_ref::<&str>().ok())
+            .unwrap_or_default();
+        let role = privilege_role(privilege);
+        if role.is_none() {
+            warn!("Refused console login by {}: account has no console privilege", username);
+        }
+        Ok(role)
+    }
+}
+
+/// Console role of an OpenBMC privilege, also the name of the group that grants it
+pub fn privilege_role(privilege: &str) -> Option<UserRole> {
+    match privilege {
+        "priv-admin" => Some(UserRole::Admin),
+        "priv-operator" => Some(UserRole::Operator),
+        "priv-user" => Some(UserRole::Observer),
+        _ => None,
+    }
+}
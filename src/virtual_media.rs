@@ -0,0 +1,317 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Virtual media drive on a USB mass-storage gadget function for kvm-rs
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use anyhow::{Context, Result};
+#[cfg(feature = "web")]
+use axum::{
+    extract::State,
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Serialize;
+#[cfg(feature = "web")]
+use serde::Deserialize;
+use tracing::{info, warn};
+use crate::events::{Event, EventBus};
+use crate::gadget::{self, CompositeGadget};
+use crate::media::MediaStore;
+use crate::remote_media::{Attachment, RemoteImage, RemoteSettings};
+
+/// Logical unit of the function the images are inserted in
+const LUN: &str = "lun.0";
+
+/// A drive the host sees as a removable USB disk or CD-ROM, holding one
+/// image of the media store, or one read from a server, at a time
+pub struct VirtualMedia {
+    /// configfs directory of the LUN
+    lun: PathBuf,
+    store: Arc<MediaStore>,
+    remote: RemoteSettings,
+    /// The medium, and the NBD device or mounted share of a remote one
+    inserted: Mutex<Option<(Medium, Option<Attachment>)>>,
+    events: EventBus,
+    /// Composite gadget the function is linked into while a medium is inserted
+    gadget: Option<Arc<CompositeGadget>>,
+}
+
+/// The image in the drive and how the host sees it
+#[derive(Debug, Clone, Serialize)]
+pub struct Medium {
+    /// Name of an uploaded image, or URL of a remote one
+    pub image: String,
+    pub read_only: bool,
+    /// Whether the host may eject it; a fixed disk stays until ejected here
+    pub removable: bool,
+    /// Whether the host sees a CD-ROM, as for ISO images
+    pub cdrom: bool,
+}
+
+/// Drive state as reported by the REST API
+#[derive(Debug, Serialize)]
+pub struct DriveStatus {
+    pub inserted: Option<Medium>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MediaError {
+    #[error("Image '{0}' is inserted, eject it first")]
+    Busy(String),
+    #[error("{0:#}")]
+    UnknownImage(anyhow::Error),
+    #[error("{0:#}")]
+    InvalidUrl(anyhow::Error),
+    /// The server of a remote image could not be reached or refused it
+    #[error("{0:#}")]
+    Remote(anyhow::Error),
+    #[error("{0:#}")]
+    Gadget(anyhow::Error),
+}
+
+impl VirtualMedia {
+    /// The mass-storage `function` of the gadget `keyboard_hid` belongs to,
+    /// created if the gadget has none. An uploaded image left inserted by a
+    /// previous run stays inserted; a remote one is ejected, its server is not
+    /// connected anymore
+    pub fn open(keyboard_hid: &str, function: &str, store: Arc<MediaStore>, remote: RemoteSettings, events: EventBus) -> Result<Self> {
+        let gadget = gadget::gadget_dir(keyboard_hid)
+            .with_context(|| format!("No USB gadget found for {}", keyboard_hid))?;
+        let lun = gadget::add_function(&gadget, function)?.join(LUN);
+        let file = read_attribute(&lun, "file").filter(|file| !file.is_empty());
+        let inserted = file.as_deref()
+            .and_then(|file| store.image_name(Path::new(file)))
+            .map(|image| Medium {
+                read_only: read_attribute(&lun, "ro").as_deref() == Some("1"),
+                removable: read_attribute(&lun, "removable").as_deref() == Some("1"),
+                cdrom: read_attribute(&lun, "cdrom").as_deref() == Some("1"),
+                image,
+            });
+        if let Some(ref medium) = inserted {
+            info!("Virtual media drive holds '{}' from before", medium.image);
+            store.set_inserted(Some(&medium.image));
+        } else if let Some(file) = file {
+            warn!("Ejecting {} left in the virtual media drive", file);
+            write_attribute(&lun, "forced_eject", "1").or_else(|_| write_attribute(&lun, "file", ""))?;
+        }
+        info!("Virtual media drive on {}", lun.display());
+        Ok(Self {
+            lun,
+            store,
+            remote,
+            inserted: Mutex::new(inserted.map(|medium| (medium, None))),
+            events,
+            gadget: None,
+        })
+    }
+
+    /// Present the drive to the host only while it holds a medium, by linking
+    /// the function into the composite `gadget` on insert and unlinking it on
+    /// eject
+    pub fn with_gadget(mut self, gadget: Arc<CompositeGadget>) -> Result<Self> {
+        let inserted = self.inserted.get_mut().unwrap_or_else(|e| e.into_inner()).is_some();
+        gadget.set_storage(inserted)?;
+        self.gadget = Some(gadget);
+        Ok(self)
+    }
+
+    /// Insert the stored image `image`. ISO images are inserted as a CD-ROM,
+    /// which is always read-only
+    pub fn insert(&self, image: &str, read_only: bool, removable: bool) -> Result<Medium, MediaError> {
+        let mut inserted = self.inserted.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((ref medium, _)) = *inserted {
+            return Err(MediaError::Busy(medium.image.clone()));
+        }
+        let path = self.store.image_path(image).map_err(MediaError::UnknownImage)?;
+        let cdrom = image.ends_with(".iso");
+        let medium = Medium {
+            image: image.to_string(),
+            read_only: read_only || cdrom,
+            removable,
+            cdrom,
+        };
+        self.load(&medium, &path)?;
+        self.store.set_inserted(Some(image));
+        *inserted = Some((medium.clone(), None));
+        Ok(medium)
+    }
+
+    /// Insert the image at `url` (http, https, nfs or smb), read-only. ISO
+    /// images are inserted as a CD-ROM. `credentials` log in to SMB shares
+    pub async fn insert_url(&self, url: &str, removable: bool, credentials: Option<(&str, &str)>) -> Result<Medium, MediaError> {
+        let remote = RemoteImage::parse(url).map_err(MediaError::InvalidUrl)?;
+        if let Some((ref medium, _)) = *self.inserted.lock().unwrap_or_else(|e| e.into_inner()) {
+            return Err(MediaError::Busy(medium.image.clone()));
+        }
+        // Reaching the server takes a while, the drive is checked again afterwards
+        let attachment = remote.attach(&self.remote, credentials).await.map_err(MediaError::Remote)?;
+        let mut inserted = self.inserted.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((ref medium, _)) = *inserted {
+            return Err(MediaError::Busy(medium.image.clone()));
+        }
+        let medium = Medium {
+            image: url.to_string(),
+            read_only: true,
+            removable,
+            cdrom: url.ends_with(".iso"),
+        };
+        self.load(&medium, attachment.path())?;
+        *inserted = Some((medium.clone(), Some(attachment)));
+        Ok(medium)
+    }
+
+    /// Point the LUN at `path` as `medium` has it
+    fn load(&self, medium: &Medium, path: &Path) -> Result<(), MediaError> {
+        // ro and cdrom can only change while the drive is empty
+        self.write("cdrom", flag(medium.cdrom))
+            .and_then(|()| self.write("ro", flag(medium.read_only)))
+            .and_then(|()| self.write("removable", flag(medium.removable)))
+            .and_then(|()| self.write("file", &path.to_string_lossy()))
+            .map_err(MediaError::Gadget)?;
+        if let Some(ref gadget) = self.gadget {
+            if let Err(e) = gadget.set_storage(true) {
+                let _ = self.write("file", "");
+                return Err(MediaError::Gadget(e));
+            }
+        }
+        info!("Inserted '{}' in the virtual media drive ({})", medium.image, if medium.read_only { "read-only" } else { "writable" });
+        self.events.publish(Event::MediaInserted {
+            image: medium.image.clone(),
+            read_only: medium.read_only,
+        });
+        Ok(())
+    }
+
+    /// Eject the image, even if the host locked the drive; the ejected
+    /// medium, or None if the drive was empty
+    pub fn eject(&self) -> Result<Option<Medium>, MediaError> {
+        let mut inserted = self.inserted.lock().unwrap_or_else(|e| e.into_inner());
+        let Some((medium, attachment)) = inserted.take() else {
+            return Ok(None);
+        };
+        // Older kernels have no forced_eject, and refuse while the host prevents removal
+        let ejected = self.write("forced_eject", "1").or_else(|_| self.write("file", ""));
+        if let Err(e) = ejected {
+            let image = medium.image.clone();
+            *inserted = Some((medium, attachment));
+            warn!("Failed to eject '{}': {:#}", image, e);
+            return Err(MediaError::Gadget(e));
+        }
+        info!("Ejected '{}' from the virtual media drive", medium.image);
+        if let Some(ref gadget) = self.gadget {
+            if let Err(e) = gadget.set_storage(false) {
+                warn!("Failed to remove the empty virtual media drive: {:#}", e);
+            }
+        }
+        // Releases the NBD device or unmounts the share of a remote image
+        drop(attachment);
+        self.store.set_inserted(None);
+        self.events.publish(Event::MediaEjected {
+            image: medium.image.clone(),
+        });
+        Ok(Some(medium))
+    }
+
+    pub fn status(&self) -> DriveStatus {
+        DriveStatus {
+            inserted: self.inserted.lock().unwrap_or_else(|e| e.into_inner()).as_ref().map(|(medium, _)| medium.clone()),
+        }
+    }
+
+    fn write(&self, attribute: &str, value: &str) -> Result<()> {
+        write_attribute(&self.lun, attribute, value)
+    }
+}
+
+fn write_attribute(lun: &Path, attribute: &str, value: &str) -> Result<()> {
+    let path = lun.join(attribute);
+    std::fs::write(&path, value).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+fn read_attribute(lun: &Path, attribute: &str) -> Option<String> {
+    std::fs::read_to_string(lun.join(attribute))
+        .ok()
+        .map(|value| value.trim().to_string())
+}
+
+fn flag(value: bool) -> &'static str {
+    if value { "1" } else { "0" }
+}
+
+/// Body of an insert request: an uploaded image or the URL of a remote one
+#[cfg(feature = "web")]
+#[derive(Debug, Deserialize)]
+struct InsertRequest {
+    image: Option<String>,
+    url: Option<String>,
+    #[serde(default)]
+    read_only: bool,
+    #[serde(default = "default_removable")]
+    removable: bool,
+    /// Login to an SMB share
+    username: Option<String>,
+    password: Option<String>,
+}
+
+#[cfg(feature = "web")]
+fn default_removable() -> bool {
+    true
+}
+
+/// Routes of the drive under /api/v1/media/drive
+#[cfg(feature = "web")]
+pub fn router(drive: Arc<VirtualMedia>) -> Router {
+    Router::new()
+        .route("/api/v1/media/drive", get(get_drive))
+        .route("/api/v1/media/drive/insert", post(insert))
+        .route("/api/v1/media/drive/eject", post(eject))
+        .with_state(drive)
+}
+
+/// GET /api/v1/media/drive - the inserted image, if any
+#[cfg(feature = "web")]
+async fn get_drive(State(drive): State<Arc<VirtualMedia>>) -> Json<DriveStatus> {
+    Json(drive.status())
+}
+
+/// POST /api/v1/media/drive/insert - insert a stored or remote image
+#[cfg(feature = "web")]
+async fn insert(
+    State(drive): State<Arc<VirtualMedia>>,
+    Json(request): Json<InsertRequest>,
+) -> Result<Json<Medium>, (StatusCode, String)> {
+    let result = match (&request.image, &request.url) {
+        (Some(image), None) => drive.insert(image, request.read_only, request.removable),
+        (None, Some(url)) => {
+            let credentials = request.username.as_deref().map(|user| (user, request.password.as_deref().unwrap_or_default()));
+            drive.insert_url(url, request.removable, credentials).await
+        }
+        _ => return Err((StatusCode::BAD_REQUEST, "Give either an image or a url".to_string())),
+    };
+    result.map(Json).map_err(|e| (e.status(), e.to_string()))
+}
+
+/// POST /api/v1/media/drive/eject - eject the inserted image
+#[cfg(feature = "web")]
+async fn eject(State(drive): State<Arc<VirtualMedia>>) -> Result<StatusCode, (StatusCode, String)> {
+    match drive.eject() {
+        Ok(Some(_)) => Ok(StatusCode::NO_CONTENT),
+        Ok(None) => Err((StatusCode::CONFLICT, "No image is inserted".to_string())),
+        Err(e) => Err((e.status(), e.to_string())),
+    }
+}
+
+#[cfg(feature = "web")]
+impl MediaError {
+    fn status(&self) -> StatusCode {
+        match self {
+            MediaError::Busy(_) => StatusCode::CONFLICT,
+            MediaError::UnknownImage(_) => StatusCode::NOT_FOUND,
+            MediaError::InvalidUrl(_) => StatusCode::BAD_REQUEST,
+            MediaError::Remote(_) => StatusCode::BAD_GATEWAY,
+            MediaError::Gadget(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
@@ -2,20 +2,247 @@
 //
 // VNC server implementation for kvm-rs with TLS encryption support
 
+use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use crate::{display::DisplayHub, hid::HidManager};
-use anyhow::{Result, Context};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::{broadcast, watch, RwLock};
+use std::net::SocketAddr;
+use tracing::{debug, info, trace, warn};
+use crate::{arbiter::Role, audit::{InputAudit, InputClass}, auth::Authenticator, clipboard::{self, Clipboard, MAX_CLIPBOARD_TEXT}, display::{DisplayHub, Frame, Jpeg, JpegKey}, events::{Event, EventBus}, frame_budget::Reservation, frame_dump::{Conversion, FrameDump}, hid::{HidError, HidManager}, ip_filter::IpFilter, keyboard::{self, KeyboardLayout}, lockout::Lockout, security_audit::SecurityEvent, services::Services, sessions::{LifetimeEvent, SessionCounters, SessionRegistry}, shutdown::Shutdown, supervisor::{RestartPolicy, Supervisor}, vnc_password::{VncPassword, CHALLENGE_LEN}};
+#[cfg(feature = "tls")]
+use crate::tls::TlsIdentity;
+use anyhow::Result;
+#[cfg(feature = "tls")]
+use anyhow::Context;
+
+/// RFB security type None
+const SECURITY_NONE: u8 = 1;
+/// RFB security type VNC Authentication (DES challenge-response with a shared password)
+const SECURITY_VNC_AUTH: u8 = 2;
+/// RFB security type TLS (anonymous TLS, already negotiated by the acceptor)
+#[cfg_attr(not(feature = "vnc"), allow(dead_code))]
+const SECURITY_TLS: u8 = 18;
+/// RFB security type VeNCrypt, for clients that must log in
+const SECURITY_VENCRYPT: u8 = 19;
+/// VeNCrypt subtype Plain: credentials on a connection already wrapped in TLS by --vnc-tls
+#[cfg(feature = "tls")]
+const VENCRYPT_PLAIN: u32 = 256;
+/// VeNCrypt subtype X509Plain: TLS with the server certificate, then credentials
+#[cfg(feature = "tls")]
+const VENCRYPT_X509_PLAIN: u32 = 262;
+/// Longest user name or password accepted in VeNCrypt credentials
+#[cfg(feature = "tls")]
+const MAX_CREDENTIAL_LEN: u32 = 4096;
+/// User VNC password checks are audited as; they are locked out per source
+/// address only, so one guesser cannot lock out everyone sharing the password
+const PASSWORD_ACCOUNT: &str = "vnc";
+
+/// Largest ClientCutText accepted from a client
+const MAX_CUT_TEXT: usize = MAX_CLIPBOARD_TEXT;
+
+/// Bell server message, the only warning RFB clients show
+const RFB_BELL: u8 = 2;
+
+/// Wait before binding the VNC port again when turning the service on failed
+#[cfg(feature = "vnc")]
+const VNC_REBIND_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Sizes raw YUYV and RGB frames are recognized at, by their length
+pub const RAW_FRAME_SIZES: [(usize, usize); 4] = [(1920, 1080), (1280, 720), (640, 480), (320, 240)];
+
+/// Ways a client breaks the RFB protocol; its connection is closed
+#[derive(Debug, thiserror::Error)]
+pub enum ProtocolError {
+    #[error("Client chose unsupported security type {0}")]
+    SecurityType(u8),
+    #[cfg(feature = "tls")]
+    #[error("Unsupported VeNCrypt version {0}.{1}")]
+    VencryptVersion(u8, u8),
+    #[cfg(feature = "tls")]
+    #[error("Client chose unsupported VeNCrypt subtype {0}")]
+    VencryptSubtype(u32),
+    #[cfg(feature = "tls")]
+    #[error("VeNCrypt credentials too long")]
+    CredentialsTooLong,
+    #[error("ClientCutText of {0} bytes is too long")]
+    CutTextTooLong(usize),
+    #[error("Unknown VNC message type: {0}")]
+    UnknownMessage(u8),
+    #[error("Unsupported pixel format: {bits_per_pixel} bpp, true colour {true_color}")]
+    PixelFormat { bits_per_pixel: u8, true_color: bool },
+}
+
+/// RFB pixel format of framebuffer updates
+#[derive(Debug, Clone, Copy)]
+struct PixelFormat {
+    bits_per_pixel: u8,
+    depth: u8,
+    big_endian: bool,
+    true_color: bool,
+    red_max: u16,
+    green_max: u16,
+    blue_max: u16,
+    red_shift: u8,
+    green_shift: u8,
+    blue_shift: u8,
+}
+
+impl PixelFormat {
+    /// 32 bits per pixel, depth 24, little-endian xRGB
+    const DEFAULT: PixelFormat = PixelFormat {
+        bits_per_pixel: 32,
+        depth: 24,
+        big_endian: false,
+        true_color: true,
+        red_max: 255,
+        green_max: 255,
+        blue_max: 255,
+        red_shift: 16,
+        green_shift: 8,
+        blue_shift: 0,
+    };
+
+    /// Parse the 16-byte PIXEL_FORMAT structure
+    fn from_bytes(data: &[u8]) -> Self {
+        Self {
+            bits_per_pixel: data[0],
+            depth: data[1],
+            big_endian: data[2] != 0,
+            true_color: data[3] != 0,
+            red_max: u16::from_be_bytes([data[4], data[5]]),
+            green_max: u16::from_be_bytes([data[6], data[7]]),
+            blue_max: u16::from_be_bytes([data[8], data[9]]),
+            red_shift: data[10],
+            green_shift: data[11],
+            blue_shift: data[12],
+        }
+    }
+
+    fn to_bytes(self) -> [u8; 16] {
+        let mut data = [0u8; 16];
+        data[0] = self.bits_per_pixel;
+        data[1] = self.depth;
+        data[2] = self.big_endian as u8;
+        data[3] = self.true_color as u8;
+        data[4..6].copy_from_slice(&self.red_max.to_be_bytes());
+        data[6..8].copy_from_slice(&self.green_max.to_be_bytes());
+        data[8..10].copy_from_slice(&self.blue_max.to_be_bytes());
+        data[10] = self.red_shift;
+        data[11] = self.green_shift;
+        data[12] = self.blue_shift;
+        data
+    }
+
+    /// Colour maps are not implemented
+    fn is_supported(&self) -> bool {
+        self.true_color && matches!(self.bits_per_pixel, 8 | 16 | 32)
+    }
+
+    /// Convert packed RGB24 pixels to this format
+    fn encode(&self, rgb: &[u8]) -> Vec<u8> {
+        let bytes = self.bits_per_pixel as usize / 8;
+        let scale = |value: u8, max: u16| (value as u32 * max as u32 + 127) / 255;
+        let mut pixels = Vec::with_capacity(rgb.len() / 3 * bytes);
+        for px in rgb.chunks_exact(3) {
+            let value = (scale(px[0], self.red_max) << self.red_shift)
+                | (scale(px[1], self.green_max) << self.green_shift)
+                | (scale(px[2], self.blue_max) << self.blue_shift);
+            match (bytes, self.big_endian) {
+                (1, _) => pixels.push(value as u8),
+                (2, false) => pixels.extend_from_slice(&(value as u16).to_le_bytes()),
+                (2, true) => pixels.extend_from_slice(&(value as u16).to_be_bytes()),
+                (_, false) => pixels.extend_from_slice(&value.to_le_bytes()),
+                (_, true) => pixels.extend_from_slice(&value.to_be_bytes()),
+            }
+        }
+        pixels
+    }
+}
+
+/// Per-connection RFB state
+struct RfbSession {
+    /// Id in the session registry
+    id: u64,
+    pixel_format: PixelFormat,
+    /// Whether the client is waiting for a framebuffer update
+    update_requested: bool,
+    /// Role assigned by the console's arbiter, None for clients that always control
+    role: Option<watch::Receiver<Role>>,
+    counters: Arc<SessionCounters>,
+    input: InputState,
+}
+
+impl RfbSession {
+    fn new(id: u64, role: Option<watch::Receiver<Role>>, counters: Arc<SessionCounters>) -> Self {
+        Self {
+            id,
+            pixel_format: PixelFormat::DEFAULT,
+            update_requested: false,
+            role,
+            counters,
+            input: InputState::default(),
+        }
+    }
+
+    /// Whether key and pointer events reach the host
+    fn may_send_input(&self) -> bool {
+        match &self.role {
+            Some(role) => *role.borrow() == Role::Controller,
+            None => true,
+        }
+    }
+}
+
+/// Input state of one client, so that clients sharing a console do not move
+/// relative to, audit against or release each other's pointer and keys
+#[derive(Debug, Default)]
+pub struct InputState {
+    /// Position of the previous pointer event, which relative gadgets report
+    /// the movement from
+    last_pointer: Option<(u16, u16)>,
+    /// Buttons of the previous pointer event, whose transitions are audited
+    last_buttons: u8,
+    /// HID usage each held keysym was pressed as
+    pressed_keys: HashMap<u32, u8>,
+}
+
+/// A frame as RGB, with its share of the frame budget
+type RgbFrame = (Vec<u8>, Reservation);
 
 /// VNC Server handler for noVNC clients with TLS encryption
 #[derive(Clone)]
 pub struct VncHandler {
     hub: Arc<DisplayHub>,
     hid_manager: HidManager,
+    #[cfg(feature = "tls")]
     tls_acceptor: Option<tokio_rustls::TlsAcceptor>,
-    last_frame: Arc<RwLock<Option<Vec<u8>>>>,
+    /// Sessions VNC port clients log in to, if they must
+    auth: Authenticator,
+    /// TLS started inside VeNCrypt on connections --vnc-tls does not wrap
+    #[cfg(feature = "tls")]
+    vencrypt_acceptor: Option<tokio_rustls::TlsAcceptor>,
+    /// Shared password of the VNC port, when clients do not log in with accounts
+    password: Option<Arc<VncPassword>>,
+    /// Failed password checks, by source address
+    password_lockout: Arc<Lockout>,
+    /// Newest frame
+    last_frame: Arc<RwLock<Option<RgbFrame>>>,
     frame_width: Arc<RwLock<u16>>,
     frame_height: Arc<RwLock<u16>>,
+    audit: InputAudit,
+    /// Addresses VNC port clients may connect from
+    ip_filter: IpFilter,
+    sessions: SessionRegistry,
+    shutdown: Shutdown,
+    events: EventBus,
+    clipboard: Clipboard,
+    /// Clients see a black screen instead of the host's while set
+    privacy_mode: Arc<AtomicBool>,
+    /// The VNC port is closed while its service is off
+    services: Services,
+    /// Captured frames written out for debugging on request
+    frame_dump: FrameDump,
+    keyboard_layout: KeyboardLayout,
 }
 
 impl VncHandler {
@@ -23,216 +250,408 @@ impl VncHandler {
         Self {
             hub,
             hid_manager,
+            #[cfg(feature = "tls")]
             tls_acceptor: None,
+            auth: Authenticator::disabled(),
+            #[cfg(feature = "tls")]
+            vencrypt_acceptor: None,
+            password: None,
+            password_lockout: Arc::default(),
             last_frame: Arc::new(RwLock::new(None)),
             frame_width: Arc::new(RwLock::new(1920)),
             frame_height: Arc::new(RwLock::new(1080)),
+            audit: InputAudit::default(),
+            ip_filter: IpFilter::default(),
+            sessions: SessionRegistry::default(),
+            shutdown: Shutdown::default(),
+            events: EventBus::default(),
+            clipboard: Clipboard::default(),
+            privacy_mode: Arc::default(),
+            services: Services::default(),
+            frame_dump: FrameDump::new(std::env::temp_dir()),
+            keyboard_layout: KeyboardLayout::Us,
         }
     }
 
-    pub async fn new_with_tls(hub: Arc<DisplayHub>, hid_manager: HidManager, cert_path: Option<String>, key_path: Option<String>) -> Result<Self> {
-        let tls_acceptor = if let (Some(cert), Some(key)) = (cert_path, key_path) {
-            Some(Self::create_tls_acceptor(&cert, &key).await?)
-        } else {
-            // Generate self-signed certificate if no paths provided
-            Some(Self::create_self_signed_tls_acceptor().await?)
-        };
-
+    #[cfg(feature = "tls")]
+    pub fn new_with_tls(hub: Arc<DisplayHub>, hid_manager: HidManager, identity: &TlsIdentity) -> Result<Self> {
+        let config = identity.server_config(&[])?;
         Ok(Self {
-            hub,
-            hid_manager,
-            tls_acceptor,
-            last_frame: Arc::new(RwLock::new(None)),
-            frame_width: Arc::new(RwLock::new(1920)),
-            frame_height: Arc::new(RwLock::new(1080)),
+            tls_acceptor: Some(tokio_rustls::TlsAcceptor::from(config)),
+            ..Self::new(hub, hid_manager)
         })
     }
 
-    async fn create_tls_acceptor(cert_path: &str, key_path: &str) -> Result<tokio_rustls::TlsAcceptor> {
-        use tokio::fs;
-        use rustls::ServerConfig;
-        use rustls_pemfile::{certs, private_key};
-        use std::io::Cursor;
+    /// Handler for the console of another host, sharing TLS, authentication,
+    /// the password, filters, audit trails and sessions with this one
+    pub fn for_console(&self, hub: Arc<DisplayHub>, hid_manager: HidManager) -> Self {
+        let fresh = Self::new(hub, hid_manager);
+        Self {
+            #[cfg(feature = "tls")]
+            tls_acceptor: self.tls_acceptor.clone(),
+            auth: self.auth.clone(),
+            #[cfg(feature = "tls")]
+            vencrypt_acceptor: self.vencrypt_acceptor.clone(),
+            password: self.password.clone(),
+            password_lockout: self.password_lockout.clone(),
+            audit: self.audit.clone(),
+            ip_filter: self.ip_filter.clone(),
+            sessions: self.sessions.clone(),
+            shutdown: self.shutdown.clone(),
+            events: self.events.clone(),
+            services: self.services.clone(),
+            frame_dump: self.frame_dump.clone(),
+            keyboard_layout: self.keyboard_layout,
+            ..fresh
+        }
+    }
 
-        // Read certificate file
-        let cert_data = fs::read(cert_path).await
-            .with_context(|| format!("Failed to read certificate file: {}", cert_path))?;
-        
-        // Read private key file
-        let key_data = fs::read(key_path).await
-            .with_context(|| format!("Failed to read private key file: {}", key_path))?;
-
-        // Parse certificates
-        let cert_chain = certs(&mut Cursor::new(&cert_data))
-            .collect::<Result<Vec<_>, _>>()
-            .context("Failed to parse certificate chain")?;
-
-        // Parse private key
-        let private_key = private_key(&mut Cursor::new(&key_data))
-            .context("Failed to parse private key")?
-            .ok_or_else(|| anyhow::anyhow!("No private key found in key file"))?;
-
-        // Create TLS config
-        let config = ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(cert_chain, private_key)
-            .context("Failed to create TLS configuration")?;
-
-        Ok(tokio_rustls::TlsAcceptor::from(Arc::new(config)))
-    }
-
-    async fn create_self_signed_tls_acceptor() -> Result<tokio_rustls::TlsAcceptor> {
-        use rustls::ServerConfig;
-        use rcgen::{CertificateParams, DistinguishedName, KeyPair};
-        use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
-
-        println!("Generating self-signed certificate for VNC TLS...");
-
-        // Generate key pair
-        let key_pair = KeyPair::generate()
-            .context("Failed to generate key pair")?;
-
-        // Generate self-signed certificate
-        let mut params = CertificateParams::new(vec!["localhost".to_string()])?;
-        let mut dn = DistinguishedName::new();
-        dn.push(rcgen::DnType::CommonName, "KVM-RS VNC Server");
-        dn.push(rcgen::DnType::OrganizationName, "OpenBMC");
-        params.distinguished_name = dn;
-        
-        let cert = params.self_signed(&key_pair)
-            .context("Failed to generate self-signed certificate")?;
+    /// Translate keysyms for a host keyboard of `layout`
+    pub fn with_keyboard_layout(mut self, layout: KeyboardLayout) -> Self {
+        self.keyboard_layout = layout;
+        self
+    }
 
-        // Convert to rustls format  
-        let cert_der = CertificateDer::from(cert.der().clone());
-        let key_der = PrivateKeyDer::from(PrivatePkcs8KeyDer::from(key_pair.serialize_der()));
+    /// Make VNC port clients log in with VeNCrypt credentials checked by `auth`
+    pub fn with_auth(mut self, auth: Authenticator) -> Self {
+        self.auth = auth;
+        self
+    }
 
-        // Create TLS config
-        let config = ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(vec![cert_der], key_der)
-            .context("Failed to create TLS configuration with self-signed certificate")?;
+    /// Make VNC port clients give `password`: with VNC Authentication for a
+    /// vncpasswd file, else as VeNCrypt Plain credentials. Failures count
+    /// towards `lockout`
+    pub fn with_password(mut self, password: VncPassword, lockout: Lockout) -> Self {
+        self.password = Some(Arc::new(password));
+        self.password_lockout = Arc::new(lockout);
+        self
+    }
 
-        println!("Self-signed certificate generated successfully");
-        Ok(tokio_rustls::TlsAcceptor::from(Arc::new(config)))
+    /// Run VeNCrypt logins over TLS with `identity` on connections --vnc-tls
+    /// does not wrap
+    #[cfg(feature = "tls")]
+    pub fn with_vencrypt(mut self, identity: &TlsIdentity) -> Result<Self> {
+        let config = identity.server_config(&[])?;
+        self.vencrypt_acceptor = Some(tokio_rustls::TlsAcceptor::from(config));
+        Ok(self)
     }
 
-    pub async fn start_vnc_server(self, bind_addr: String, port: u16) -> Result<()> {
-        use tokio::net::TcpListener;
-        
-        // Start frame processing task
+    /// Whether connections to the VNC port are wrapped in TLS, by --vnc-tls
+    #[cfg(feature = "tls")]
+    #[cfg_attr(not(feature = "vnc"), allow(dead_code))]
+    fn tls_wrapped(&self) -> bool {
+        self.tls_acceptor.is_some()
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn tls_wrapped(&self) -> bool {
+        false
+    }
+
+    /// Record forwarded input in the given audit trail
+    pub fn with_input_audit(mut self, audit: InputAudit) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    /// Close VNC port connections from addresses `filter` does not allow, before the handshake
+    pub fn with_ip_filter(mut self, filter: IpFilter) -> Self {
+        self.ip_filter = filter;
+        self
+    }
+
+    /// Register connected clients in the given registry
+    pub fn with_sessions(mut self, sessions: SessionRegistry) -> Self {
+        self.sessions = sessions;
+        self
+    }
+
+    /// Close the VNC port while `services` has it turned off
+    pub fn with_services(mut self, services: Services) -> Self {
+        self.services = services;
+        self
+    }
+
+    /// Write captured frames and their conversions out when `dump` is asked to
+    pub fn with_frame_dump(mut self, dump: FrameDump) -> Self {
+        self.frame_dump = dump;
+        self
+    }
+
+    /// Connected console clients, including those of other transports
+    pub fn sessions(&self) -> &SessionRegistry {
+        &self.sessions
+    }
+
+    /// Whether clients of every transport are shown a black screen
+    pub fn privacy_mode(&self) -> bool {
+        self.privacy_mode.load(Ordering::Relaxed)
+    }
+
+    /// Hide the host's screen from clients, e.g. while secrets are entered at
+    /// the host; capture keeps running. Applies from the next captured frame
+    pub fn set_privacy_mode(&self, enabled: bool) {
+        if self.privacy_mode.swap(enabled, Ordering::Relaxed) != enabled {
+            info!("Privacy mode {}", if enabled { "enabled" } else { "disabled" });
+        }
+    }
+
+    /// Publish resolution changes on `events`
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Clipboard shared with the other console clients
+    pub fn clipboard(&self) -> &Clipboard {
+        &self.clipboard
+    }
+
+    /// Notifications for web UIs
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    /// Stop accepting clients and end sessions when `shutdown` is triggered
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Shutdown signal the sessions of every transport follow
+    pub fn shutdown(&self) -> &Shutdown {
+        &self.shutdown
+    }
+
+    /// Convert captured frames for the RFB sessions of every transport
+    pub fn start_frame_processing(&self, supervisor: &Supervisor) {
         let frame_processor = self.clone();
-        tokio::spawn(async move {
-            frame_processor.process_frames().await;
+        supervisor.spawn("Frame processor", RestartPolicy::Critical, move || {
+            let frame_processor = frame_processor.clone();
+            async move { frame_processor.process_frames().await }
         });
-        
-        let listener = TcpListener::bind(format!("{}:{}", bind_addr, port)).await
-            .with_context(|| format!("Failed to bind VNC server to {}:{}", bind_addr, port))?;
-        
-        if self.tls_acceptor.is_some() {
-            println!("VNC server with TLS encryption listening on {}:{}", bind_addr, port);
+    }
+
+    /// Serve VNC clients on bound listeners until shutdown
+    #[cfg(feature = "vnc")]
+    async fn start_vnc_server(self, listener: crate::listen::MultiListener) -> Result<()> {
+        use tracing::{field, info_span, Instrument};
+        use crate::listen::MultiListener;
+
+        // Clients must log in if sessions are required; the TLS types then carry on inside VeNCrypt.
+        // A hashed password can only be checked against one sent in the clear, so also inside VeNCrypt
+        let security_type = match (self.auth.is_enabled(), self.password.as_deref(), self.tls_wrapped()) {
+            (true, _, _) => SECURITY_VENCRYPT,
+            (false, Some(password), _) if password.supports_vnc_auth() => SECURITY_VNC_AUTH,
+            (false, Some(_), _) => SECURITY_VENCRYPT,
+            (false, None, true) => SECURITY_TLS,
+            (false, None, false) => SECURITY_NONE,
+        };
+
+        let addrs = listener.local_addrs();
+        let local_addrs: Vec<String> = addrs.iter().map(SocketAddr::to_string).collect();
+        if self.tls_wrapped() {
+            info!("VNC server with TLS encryption listening on {}", local_addrs.join(", "));
         } else {
-            println!("VNC server (unencrypted) listening on {}:{}", bind_addr, port);
+            info!("VNC server (unencrypted) listening on {}", local_addrs.join(", "));
         }
 
-        while let Ok((stream, addr)) = listener.accept().await {
-            println!("VNC client connected from: {}", addr);
+        let stopping = self.shutdown.wait();
+        tokio::pin!(stopping);
+        let mut services = self.services.subscribe();
+        let mut listener = Some(listener);
+        loop {
+            // The port is closed while the service is off, and bound again when it is turned on
+            let enabled = services.borrow_and_update().vnc;
+            if !enabled && listener.take().is_some() {
+                info!("VNC service turned off, closed {}", local_addrs.join(", "));
+            }
+            if enabled && listener.is_none() {
+                match MultiListener::bind(&addrs) {
+                    Ok(bound) => {
+                        info!("VNC service turned on, listening on {}", local_addrs.join(", "));
+                        listener = Some(bound);
+                    }
+                    Err(e) => {
+                        warn!("Failed to listen for VNC clients again, retrying: {:#}", e);
+                        tokio::select! {
+                            _ = tokio::time::sleep(VNC_REBIND_INTERVAL) => continue,
+                            _ = &mut stopping => break,
+                        }
+                    }
+                }
+            }
+            let Some(ref mut active) = listener else {
+                tokio::select! {
+                    _ = services.changed() => continue,
+                    _ = &mut stopping => break,
+                }
+            };
+            let (stream, addr) = tokio::select! {
+                accepted = active.accept() => accepted,
+                _ = services.changed() => continue,
+                _ = &mut stopping => break,
+            };
+            if !self.ip_filter.allows(addr.ip()) {
+                warn!("Refused VNC connection from {}: address not allowed", addr);
+                self.sessions.security_audit().log(SecurityEvent::Connection {
+                    transport: "vnc",
+                    client: addr,
+                    refused: Some("address not allowed"),
+                });
+                continue;
+            }
+            if !self.hub.budget().admit_client() {
+                warn!("Refused VNC connection from {}: frame memory budget used up", addr);
+                continue;
+            }
+            info!("VNC client connected from: {}", addr);
+            self.sessions.security_audit().log(SecurityEvent::Connection {
+                transport: "vnc",
+                client: addr,
+                refused: None,
+            });
             
             let handler = self.clone();
+            let span = info_span!("vnc", client = %addr, session = field::Empty);
             
             tokio::spawn(async move {
+                #[cfg(not(feature = "tls"))]
+                let result = handler.handle_vnc_client(stream, addr, security_type, "vnc", None, None).await;
+                #[cfg(feature = "tls")]
                 let result = if let Some(ref tls_acceptor) = handler.tls_acceptor {
                     // Handle TLS connection
                     match tls_acceptor.accept(stream).await {
                         Ok(tls_stream) => {
-                            handler.handle_vnc_client_tls(tls_stream).await
+                            if let Some(name) = crate::tls::client_name(tls_stream.get_ref().1.peer_certificates()) {
+                                info!("VNC client {} presented the certificate of {}", addr, name);
+                            }
+                            handler.handle_vnc_client(tls_stream, addr, security_type, "vnc", None, None).await
                         }
                         Err(e) => {
-                            eprintln!("TLS handshake failed for {}: {}", addr, e);
+                            warn!("TLS handshake failed for {}: {}", addr, e);
                             return;
                         }
                     }
                 } else {
                     // Handle plain TCP connection
-                    handler.handle_vnc_client_tcp(stream).await
+                    handler.handle_vnc_client(stream, addr, security_type, "vnc", None, None).await
                 };
 
-                if let Err(e) = result {
-                    eprintln!("VNC client error for {}: {}", addr, e);
+                match result {
+                    Ok(()) => {}
+                    Err(e) if e.is::<ProtocolError>() => warn!("VNC protocol error from {}: {}", addr, e),
+                    Err(e) => warn!("VNC client error for {}: {}", addr, e),
                 }
-            });
+            }.instrument(span));
         }
         
         Ok(())
     }
 
-    async fn process_frames(&self) {
+    async fn process_frames(&self) -> Result<()> {
         let mut rx = self.hub.tx.subscribe();
         
-        while let Ok(frame_data) = rx.recv().await {
+        loop {
+            let frame_data = match rx.recv().await {
+                Ok(frame_data) => frame_data,
+                // Only the latest frame matters
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => anyhow::bail!("Frame source closed"),
+            };
             // Convert frame data to RGB format for VNC
-            let rgb_data = self.convert_frame_to_rgb(&frame_data).await;
+            let (rgb_data, format) = self.frame_rgb(&frame_data).await;
+            // Frames hidden from clients are not written out either
+            if !self.privacy_mode() {
+                let (width, height) = self.resolution().await;
+                self.frame_dump.capture(&frame_data, &rgb_data, Conversion {
+                    format,
+                    width,
+                    height,
+                    capture_mode: self.hub.status().mode,
+                });
+            }
             
-            // Update last frame
-            *self.last_frame.write().await = Some(rgb_data);
+            // Replace the last frame, unless the new one does not fit in its place
+            let mut last_frame = self.last_frame.write().await;
+            match *last_frame {
+                Some((ref mut frame, ref mut reservation)) => {
+                    if reservation.try_resize(rgb_data.len()) {
+                        *frame = rgb_data;
+                    }
+                }
+                None => {
+                    if let Some(reservation) = self.hub.budget().try_reserve(rgb_data.len()) {
+                        *last_frame = Some((rgb_data, reservation));
+                    }
+                }
+            }
             
             // Update frame dimensions if needed (detect from frame data)
             // For now, assume the frame is already in the right format
         }
     }
 
-    async fn convert_frame_to_rgb(&self, frame_data: &[u8]) -> Vec<u8> {
+    /// A captured frame as RGB, black in privacy mode, with the format it was taken for
+    async fn frame_rgb(&self, frame_data: &[u8]) -> (Vec<u8>, &'static str) {
+        let (mut rgb, format) = self.convert_frame_to_rgb(frame_data).await;
+        if self.privacy_mode() {
+            rgb.fill(0);
+        }
+        (rgb, format)
+    }
+
+    async fn convert_frame_to_rgb(&self, frame_data: &[u8]) -> (Vec<u8>, &'static str) {
+        let (rgb, format, size) = Self::decode_frame(frame_data);
+        if let Some((width, height)) = size {
+            self.set_resolution(width, height).await;
+        }
+        (rgb, format)
+    }
+
+    /// A captured frame as RGB, with its format and, when recognised, its size
+    fn decode_frame(frame_data: &[u8]) -> (Vec<u8>, &'static str, Option<(u16, u16)>) {
         // Try to detect frame format and convert to RGB
         // For now, assume it's already RGB or MJPEG
         
         // Check if it looks like MJPEG (starts with FF D8)
+        #[cfg(feature = "software-codecs")]
         if frame_data.len() > 2 && frame_data[0] == 0xFF && frame_data[1] == 0xD8 {
             // MJPEG data - decode to RGB
             if let Ok(img) = image::load_from_memory_with_format(frame_data, image::ImageFormat::Jpeg) {
                 let rgb_img = img.to_rgb8();
                 let (width, height) = rgb_img.dimensions();
                 
-                // Update dimensions
-                *self.frame_width.write().await = width as u16;
-                *self.frame_height.write().await = height as u16;
-                
-                println!("Decoded MJPEG frame: {}x{}", width, height);
-                return rgb_img.into_raw();
+                debug!("Decoded MJPEG frame: {}x{}", width, height);
+                return (rgb_img.into_raw(), "mjpeg", Some((width as u16, height as u16)));
             }
         }
         
         // Check if it might be YUYV (specific size patterns)
         let pixel_count = frame_data.len() / 2; // YUYV is 2 bytes per pixel
-        let width_height_pairs = [
-            (1920, 1080), (1280, 720), (640, 480), (320, 240)
-        ];
-        
-        for (w, h) in width_height_pairs {
+        for (w, h) in RAW_FRAME_SIZES {
             if pixel_count == w * h {
                 // Looks like YUYV with these dimensions
-                println!("Converting YUYV frame: {}x{}", w, h);
-                *self.frame_width.write().await = w as u16;
-                *self.frame_height.write().await = h as u16;
-                return self.convert_yuyv_to_rgb(frame_data, w, h);
+                debug!("Converting YUYV frame: {}x{}", w, h);
+                return (Self::convert_yuyv_to_rgb(frame_data, w, h), "yuyv", Some((w as u16, h as u16)));
             }
         }
         
         // Check if it might be RGB (3 bytes per pixel)
         let rgb_pixel_count = frame_data.len() / 3;
-        for (w, h) in width_height_pairs {
+        for (w, h) in RAW_FRAME_SIZES {
             if rgb_pixel_count == w * h {
                 // Already RGB
-                println!("Using RGB frame: {}x{}", w, h);
-                *self.frame_width.write().await = w as u16;
-                *self.frame_height.write().await = h as u16;
-                return frame_data.to_vec();
+                debug!("Using RGB frame: {}x{}", w, h);
+                return (frame_data.to_vec(), "rgb", Some((w as u16, h as u16)));
             }
         }
         
         // Default: assume it's RGB data, use default dimensions
-        frame_data.to_vec()
+        (frame_data.to_vec(), "unknown", None)
     }
 
-    fn convert_yuyv_to_rgb(&self, yuyv_data: &[u8], width: usize, height: usize) -> Vec<u8> {
+    fn convert_yuyv_to_rgb(yuyv_data: &[u8], width: usize, height: usize) -> Vec<u8> {
         let mut rgb_data = Vec::with_capacity(width * height * 3);
         
         for chunk in yuyv_data.chunks_exact(4) {
@@ -257,73 +676,227 @@ impl VncHandler {
         rgb_data
     }
 
-    async fn handle_vnc_client_tls(
+    /// Run an RFB session over a stream carried by another transport, e.g. WebSocket;
+    /// input is dropped while the role is not controller. `user` is the account the
+    /// transport authenticated, if any
+    pub async fn handle_rfb_stream<S>(
+        &self,
+        stream: S,
+        addr: SocketAddr,
+        transport: &'static str,
+        role: watch::Receiver<Role>,
+        user: Option<String>,
+    ) -> Result<()>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        // The transport is responsible for encryption, no RFB security
+        self.handle_vnc_client(stream, addr, SECURITY_NONE, transport, Some(role), user).await
+    }
+
+    async fn handle_vnc_client<S>(
         &self,
-        mut stream: tokio_rustls::server::TlsStream<tokio::net::TcpStream>,
-    ) -> Result<()> {
+        mut stream: S,
+        addr: SocketAddr,
+        security_type: u8,
+        transport: &'static str,
+        role: Option<watch::Receiver<Role>>,
+        user: Option<String>,
+    ) -> Result<()>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
         use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-        // VNC handshake over TLS
+        let session = self.sessions.register(transport, addr);
+        if let Some(user) = &user {
+            self.sessions.set_user(session.id(), user);
+        }
+
         // Send RFB protocol version
         stream.write_all(b"RFB 003.008\n").await?;
         
         // Read client protocol version
         let mut version_buf = [0u8; 12];
         stream.read_exact(&mut version_buf).await?;
-        println!("Client VNC version (TLS): {}", String::from_utf8_lossy(&version_buf));
+        debug!("Client VNC version ({}): {}", transport, String::from_utf8_lossy(&version_buf).trim_end());
 
-        // Security handshake - TLS security type
-        stream.write_all(&[1u8, 18u8]).await?; // 1 security type: TLS (type 18)
+        // Security handshake - offer the single security type of this transport
+        stream.write_all(&[1u8, security_type]).await?;
         let mut security_choice = [0u8; 1];
         stream.read_exact(&mut security_choice).await?;
         
-        if security_choice[0] != 18 {
-            return Err(anyhow::anyhow!("Client chose unsupported security type for TLS connection"));
+        if security_choice[0] != security_type {
+            return Err(ProtocolError::SecurityType(security_choice[0]).into());
+        }
+
+        #[cfg(not(feature = "tls"))]
+        if security_type == SECURITY_VENCRYPT {
+            anyhow::bail!("VeNCrypt needs kvm-rs built with the tls feature");
+        }
+        #[cfg(feature = "tls")]
+        if security_type == SECURITY_VENCRYPT {
+            // Connections wrapped in TLS by --vnc-tls only need the credentials
+            if self.tls_acceptor.is_some() {
+                negotiate_vencrypt(&mut stream, VENCRYPT_PLAIN).await?;
+                return self.authenticate(stream, addr, transport, session.id(), role).await;
+            }
+            let acceptor = self.vencrypt_acceptor.clone()
+                .ok_or_else(|| anyhow::anyhow!("VeNCrypt needs a TLS certificate"))?;
+            negotiate_vencrypt(&mut stream, VENCRYPT_X509_PLAIN).await?;
+            let stream = acceptor.accept(stream).await.context("VeNCrypt TLS handshake failed")?;
+            return self.authenticate(stream, addr, transport, session.id(), role).await;
+        }
+
+        if security_type == SECURITY_VNC_AUTH {
+            let mut challenge = [0u8; CHALLENGE_LEN];
+            crate::credentials::random_bytes(&mut challenge)?;
+            stream.write_all(&challenge).await?;
+            let mut response = [0u8; CHALLENGE_LEN];
+            stream.read_exact(&mut response).await?;
+            let accepted = self.password.as_ref().is_some_and(|password| password.check_response(&challenge, &response));
+            if !self.password_result(&mut stream, addr, transport, accepted).await? {
+                return Ok(());
+            }
+            return self.start_session(stream, addr, transport, session.id(), role).await;
         }
 
         // Security result - OK
         stream.write_all(&[0u8, 0u8, 0u8, 0u8]).await?;
+        self.start_session(stream, addr, transport, session.id(), role).await
+    }
 
-        // Read ClientInit
-        let mut client_init = [0u8; 1];
-        stream.read_exact(&mut client_init).await?;
+    /// Check VeNCrypt Plain credentials, then run the session until it ends or its login session does;
+    /// with a VNC password only the password is checked
+    #[cfg(feature = "tls")]
+    async fn authenticate<S>(
+        &self,
+        mut stream: S,
+        addr: SocketAddr,
+        transport: &'static str,
+        session_id: u64,
+        role: Option<watch::Receiver<Role>>,
+    ) -> Result<()>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-        // Send ServerInit
-        let server_init = self.create_server_init().await;
-        stream.write_all(&server_init).await?;
+        let username_len = stream.read_u32().await?;
+        let password_len = stream.read_u32().await?;
+        if username_len > MAX_CREDENTIAL_LEN || password_len > MAX_CREDENTIAL_LEN {
+            return Err(ProtocolError::CredentialsTooLong.into());
+        }
+        let mut username = vec![0u8; username_len as usize];
+        stream.read_exact(&mut username).await?;
+        let mut password = vec![0u8; password_len as usize];
+        stream.read_exact(&mut password).await?;
+        let username = String::from_utf8_lossy(&username).into_owned();
+        let password = String::from_utf8_lossy(&password).into_owned();
+
+        if let Some(vnc_password) = self.password.clone() {
+            let accepted = tokio::task::spawn_blocking(move || vnc_password.check_plain(&password)).await?;
+            if !self.password_result(&mut stream, addr, transport, accepted).await? {
+                return Ok(());
+            }
+            return self.start_session(stream, addr, transport, session_id, role).await;
+        }
 
-        // Start framebuffer updates and input handling
-        self.handle_vnc_session_tls(stream).await?;
+        let (token, user_role) = match self.auth.login(&username, &password, addr.ip(), transport).await {
+            Ok(session) => session,
+            Err(e) => {
+                warn!("VNC login failed for {}: {}", addr, e);
+                stream.write_all(&security_failure(&e.to_string())).await?;
+                return Ok(());
+            }
+        };
+        stream.write_all(&[0u8, 0u8, 0u8, 0u8]).await?;
+        self.sessions.set_user(session_id, &username);
+        // Observers view only: their session has a viewer role that never changes
+        let role = if user_role.may_send_input() {
+            role
+        } else {
+            info!("VNC client {} logged in as observer, input is ignored", addr);
+            Some(watch::channel(Role::Viewer).1)
+        };
 
-        Ok(())
+        let result = tokio::select! {
+            result = self.start_session(stream, addr, transport, session_id, role) => result,
+            _ = self.auth.session_ended(Some(token.clone())) => {
+                info!("Closing VNC connection from {}: session expired", addr);
+                Ok(())
+            }
+        };
+        self.auth.logout(&token);
+        result
     }
 
-    async fn handle_vnc_client_tcp(
-        &self,
-        mut stream: tokio::net::TcpStream,
-    ) -> Result<()> {
-        use tokio::io::{AsyncReadExt, AsyncWriteExt};
-
-        // VNC handshake over plain TCP
-        // Send RFB protocol version
-        stream.write_all(b"RFB 003.008\n").await?;
-        
-        // Read client protocol version
-        let mut version_buf = [0u8; 12];
-        stream.read_exact(&mut version_buf).await?;
-        println!("Client VNC version: {}", String::from_utf8_lossy(&version_buf));
+    /// Send the SecurityResult of a VNC password check; failures are answered
+    /// late and can lock the source address out. Returns whether to go on
+    async fn password_result<S>(&self, stream: &mut S, addr: SocketAddr, transport: &str, accepted: bool) -> Result<bool>
+    where
+        S: tokio::io::AsyncWrite + Unpin,
+    {
+        use tokio::io::AsyncWriteExt;
 
-        // Security handshake - no authentication for plain connections
-        stream.write_all(&[1u8, 1u8]).await?; // 1 security type: None
-        let mut security_choice = [0u8; 1];
-        stream.read_exact(&mut security_choice).await?;
-        
-        if security_choice[0] != 1 {
-            return Err(anyhow::anyhow!("Client chose unsupported security type"));
+        let audit = self.sessions.security_audit();
+        let ip = addr.ip();
+        if let Some(remaining) = self.password_lockout.locked(ip, None) {
+            audit.log(SecurityEvent::Login {
+                transport,
+                client: ip,
+                user: PASSWORD_ACCOUNT,
+                failure: Some("locked out"),
+            });
+            let reason = format!("Too many failed logins, try again in {}s", remaining.as_secs().max(1));
+            stream.write_all(&security_failure(&reason)).await?;
+            return Ok(false);
+        }
+        if accepted {
+            self.password_lockout.succeeded(ip, None);
+            audit.log(SecurityEvent::Login {
+                transport,
+                client: ip,
+                user: PASSWORD_ACCOUNT,
+                failure: None,
+            });
+            // Security result - OK
+            stream.write_all(&[0u8, 0u8, 0u8, 0u8]).await?;
+            return Ok(true);
+        }
+        warn!("VNC password check failed for {}", addr);
+        audit.log(SecurityEvent::Login {
+            transport,
+            client: ip,
+            user: PASSWORD_ACCOUNT,
+            failure: Some("wrong password"),
+        });
+        let delay = self.password_lockout.failed(ip, None);
+        if let Some(duration) = self.password_lockout.locked(ip, None) {
+            audit.log(SecurityEvent::Lockout {
+                client: ip,
+                user: PASSWORD_ACCOUNT,
+                duration,
+            });
         }
+        tokio::time::sleep(delay).await;
+        stream.write_all(&security_failure("Wrong password")).await?;
+        Ok(false)
+    }
 
-        // Security result - OK
-        stream.write_all(&[0u8, 0u8, 0u8, 0u8]).await?;
+    /// Initialisation messages, then the session
+    async fn start_session<S>(
+        &self,
+        mut stream: S,
+        addr: SocketAddr,
+        transport: &'static str,
+        session_id: u64,
+        role: Option<watch::Receiver<Role>>,
+    ) -> Result<()>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
         // Read ClientInit
         let mut client_init = [0u8; 1];
@@ -334,9 +907,7 @@ impl VncHandler {
         stream.write_all(&server_init).await?;
 
         // Start framebuffer updates and input handling
-        self.handle_vnc_session_tcp(stream).await?;
-
-        Ok(())
+        self.handle_vnc_session(stream, addr, transport, session_id, role).await
     }
 
     async fn create_server_init(&self) -> Vec<u8> {
@@ -350,116 +921,132 @@ impl VncHandler {
         // Framebuffer height - big endian  
         init.extend_from_slice(&height.to_be_bytes());
         
-        // Pixel format (24-bit RGB)
-        init.push(24); // bits per pixel
-        init.push(24); // depth
-        init.push(0);  // big endian flag (0 = little endian)
-        init.push(1);  // true color flag
-        init.extend_from_slice(&255u16.to_be_bytes()); // red max
-        init.extend_from_slice(&255u16.to_be_bytes()); // green max
-        init.extend_from_slice(&255u16.to_be_bytes()); // blue max
-        init.push(16); // red shift
-        init.push(8);  // green shift
-        init.push(0);  // blue shift
-        init.extend_from_slice(&[0u8; 3]); // padding
+        // Pixel format (32 bits per pixel, depth 24, little-endian xRGB)
+        init.extend_from_slice(&PixelFormat::DEFAULT.to_bytes());
 
         // Desktop name
         let name = b"KVM-RS";
         init.extend_from_slice(&(name.len() as u32).to_be_bytes());
         init.extend_from_slice(name);
         
-        println!("Sent ServerInit: {}x{} RGB24", width, height);
+        debug!("Sent ServerInit: {}x{} 32bpp", width, height);
         init
     }
 
-    async fn handle_vnc_session_tls(
+    async fn handle_vnc_session<S>(
         &self,
-        mut stream: tokio_rustls::server::TlsStream<tokio::net::TcpStream>,
-    ) -> Result<()> {
-        use tokio::io::AsyncReadExt;
+        mut stream: S,
+        addr: SocketAddr,
+        transport: &'static str,
+        session_id: u64,
+        role: Option<watch::Receiver<Role>>,
+    ) -> Result<()>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::sync::broadcast::error::RecvError;
         
         let mut rx = self.hub.tx.subscribe();
-        let mut buffer = [0u8; 1024];
+        let mut buffer = [0u8; 4096];
+        // Client bytes not yet forming a complete message
+        let mut pending = Vec::new();
+        // Clients without an arbiter always control
+        self.sessions.set_role(session_id, role.clone().unwrap_or_else(|| watch::channel(Role::Controller).1));
+        let mut session = RfbSession::new(session_id, role, self.sessions.counters(session_id));
+        let mut lifetime = self.sessions.lifetime(session_id);
+        let stopping = self.shutdown.wait();
+        tokio::pin!(stopping);
+
+        // Offer what was copied before the client connected
+        let mut clipboard = self.clipboard.subscribe();
+        let text = clipboard.borrow_and_update().text.clone();
+        if !text.is_empty() {
+            let message = server_cut_text(&text);
+            stream.write_all(&message).await?;
+            session.counters.sent(message.len());
+        }
         
-        loop {
+        'session: loop {
             tokio::select! {
-                // Send framebuffer updates when new frames arrive
-                frame_result = rx.recv() => {
-                    match frame_result {
-                        Ok(_) => {
-                            // Frame is already processed by process_frames task
-                            if let Some(ref frame_data) = *self.last_frame.read().await {
-                                if let Err(e) = self.send_framebuffer_update_tls(&mut stream, frame_data).await {
-                                    eprintln!("Failed to send framebuffer update (TLS): {}", e);
-                                    break;
-                                }
-                            }
+                // RFB has no close message, the connection is just closed
+                _ = &mut stopping => break,
+
+                // Ring the bell before the maximum session duration, then close
+                event = lifetime.next() => match event {
+                    LifetimeEvent::Warning(remaining) => {
+                        info!("VNC session of {} ({}) ends in {}s", addr, transport, remaining.as_secs());
+                        if let Err(e) = stream.write_all(&[RFB_BELL]).await {
+                            warn!("Failed to send bell ({}): {}", transport, e);
+                            break;
                         }
-                        Err(_) => break,
                     }
-                }
-                
-                // Handle client messages
-                read_result = stream.read(&mut buffer) => {
-                    match read_result {
-                        Ok(0) => break, // Connection closed
-                        Ok(n) => {
-                            if let Err(e) = self.process_vnc_message(&buffer[..n], &mut stream).await {
-                                eprintln!("VNC message processing error (TLS): {}", e);
-                                break;
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("VNC read error (TLS): {}", e);
+                    LifetimeEvent::Expired => {
+                        info!("Closing VNC session of {} ({}): maximum session duration reached", addr, transport);
+                        break;
+                    }
+                    LifetimeEvent::Disconnected => {
+                        info!("Closing VNC session of {} ({}): disconnected through the control interface", addr, transport);
+                        break;
+                    }
+                },
+
+                // Text copied in another client
+                changed = clipboard.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                    let copied = clipboard.borrow_and_update().clone();
+                    if copied.source != session.id {
+                        let message = server_cut_text(&copied.text);
+                        if let Err(e) = stream.write_all(&message).await {
+                            warn!("Failed to send clipboard ({}): {}", transport, e);
                             break;
                         }
+                        session.counters.sent(message.len());
                     }
                 }
-            }
-        }
 
-        Ok(())
-    }
-
-    async fn handle_vnc_session_tcp(
-        &self,
-        mut stream: tokio::net::TcpStream,
-    ) -> Result<()> {
-        use tokio::io::AsyncReadExt;
-        
-        let mut rx = self.hub.tx.subscribe();
-        let mut buffer = [0u8; 1024];
-        
-        loop {
-            tokio::select! {
-                // Send framebuffer updates when new frames arrive
+                // Answer a pending update request when a new frame arrives
                 frame_result = rx.recv() => {
                     match frame_result {
                         Ok(_) => {
-                            // Frame is already processed by process_frames task
-                            if let Some(ref frame_data) = *self.last_frame.read().await {
-                                if let Err(e) = self.send_framebuffer_update_tcp(&mut stream, frame_data).await {
-                                    eprintln!("Failed to send framebuffer update: {}", e);
+                            if session.update_requested {
+                                if let Err(e) = self.send_framebuffer_update(&mut stream, &mut session).await {
+                                    warn!("Failed to send framebuffer update ({}): {}", transport, e);
                                     break;
                                 }
                             }
                         }
-                        Err(_) => break,
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => break,
                     }
                 }
                 
-                // Handle client messages
+                // Handle client messages; a read may hold several messages or part of one
                 read_result = stream.read(&mut buffer) => {
                     match read_result {
                         Ok(0) => break, // Connection closed
                         Ok(n) => {
-                            if let Err(e) = self.process_vnc_message(&buffer[..n], &mut stream).await {
-                                eprintln!("VNC message processing error: {}", e);
-                                break;
+                            pending.extend_from_slice(&buffer[..n]);
+                            loop {
+                                let len = match Self::client_message_len(&pending) {
+                                    Ok(Some(len)) => len,
+                                    Ok(None) => break,
+                                    Err(e) => {
+                                        warn!("VNC protocol error ({}): {}", transport, e);
+                                        break 'session;
+                                    }
+                                };
+                                let message: Vec<u8> = pending.drain(..len).collect();
+                                if let Err(e) = self.process_vnc_message(&message, &mut stream, &mut session, addr, transport).await {
+                                    warn!("VNC message processing error ({}): {}", transport, e);
+                                    break 'session;
+                                }
                             }
                         }
                         Err(e) => {
-                            eprintln!("VNC read error: {}", e);
+                            warn!("VNC read error ({}): {}", transport, e);
                             break;
                         }
                     }
@@ -470,10 +1057,44 @@ impl VncHandler {
         Ok(())
     }
 
+    /// Length of the client message at the start of `data`, None if incomplete
+    fn client_message_len(data: &[u8]) -> Result<Option<usize>> {
+        let Some(&message_type) = data.first() else {
+            return Ok(None);
+        };
+        let len = match message_type {
+            0 => 20, // SetPixelFormat
+            2 => { // SetEncodings
+                if data.len() < 4 {
+                    return Ok(None);
+                }
+                4 + 4 * u16::from_be_bytes([data[2], data[3]]) as usize
+            }
+            3 => 10, // FramebufferUpdateRequest
+            4 => 8,  // KeyEvent
+            5 => 6,  // PointerEvent
+            6 => { // ClientCutText
+                if data.len() < 8 {
+                    return Ok(None);
+                }
+                let text_len = u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize;
+                if text_len > MAX_CUT_TEXT {
+                    return Err(ProtocolError::CutTextTooLong(text_len).into());
+                }
+                8 + text_len
+            }
+            _ => return Err(ProtocolError::UnknownMessage(message_type).into()),
+        };
+        Ok((data.len() >= len).then_some(len))
+    }
+
     async fn process_vnc_message<S>(
         &self,
         data: &[u8],
         stream: &mut S,
+        session: &mut RfbSession,
+        addr: SocketAddr,
+        transport: &'static str,
     ) -> Result<()> 
     where
         S: tokio::io::AsyncWrite + Unpin,
@@ -484,115 +1105,228 @@ impl VncHandler {
 
         match data[0] {
             0 => { // SetPixelFormat
-                println!("Received SetPixelFormat message");
+                let format = PixelFormat::from_bytes(&data[4..20]);
+                if !format.is_supported() {
+                    return Err(ProtocolError::PixelFormat {
+                        bits_per_pixel: format.bits_per_pixel,
+                        true_color: format.true_color,
+                    }.into());
+                }
+                debug!("Client pixel format: {} bpp", format.bits_per_pixel);
+                session.pixel_format = format;
             }
             2 => { // SetEncodings
-                println!("Received SetEncodings message");
+                // Only Raw is implemented, which every client supports
+                debug!("Received SetEncodings message");
             }
             3 => { // FramebufferUpdateRequest
-                println!("Received FramebufferUpdateRequest");
-                
-                // Send current framebuffer immediately if we have one
-                if let Some(ref frame_data) = *self.last_frame.read().await {
-                    use tokio::io::AsyncWriteExt;
-                    
-                    let width = *self.frame_width.read().await;
-                    let height = *self.frame_height.read().await;
-                    
-                    // FramebufferUpdate message
-                    let mut update = Vec::new();
-                    update.push(0); // message type
-                    update.push(0); // padding
-                    update.extend_from_slice(&1u16.to_be_bytes()); // number of rectangles
-
-                    // Rectangle header
-                    update.extend_from_slice(&0u16.to_be_bytes()); // x
-                    update.extend_from_slice(&0u16.to_be_bytes()); // y
-                    update.extend_from_slice(&width.to_be_bytes()); // width
-                    update.extend_from_slice(&height.to_be_bytes()); // height
-                    update.extend_from_slice(&0u32.to_be_bytes()); // encoding (Raw)
-
-                    stream.write_all(&update).await?;
-                    stream.write_all(frame_data).await?;
-                    stream.flush().await?;
-                    
-                    println!("Sent immediate framebuffer update: {}x{}, {} bytes", width, height, frame_data.len());
+                let incremental = data[1] != 0;
+                session.update_requested = true;
+                // A full update is sent right away, incremental ones with the next frame
+                if !incremental {
+                    self.send_framebuffer_update(stream, session).await?;
                 }
             }
+            4 if !session.may_send_input() => {} // KeyEvent from a viewer
+            5 if !session.may_send_input() => {} // PointerEvent from a viewer
             4 => { // KeyEvent
-                if data.len() >= 8 {
-                    let down_flag = data[1] != 0;
-                    let key = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
-                    
-                    println!("Key event: key={}, down={}", key, down_flag);
-                    
-                    if let Some(hid_report) = Self::vnc_key_to_hid(key, down_flag) {
-                        let _ = self.hid_manager.send_keyboard_input(&hid_report).await;
-                    }
-                }
+                let down_flag = data[1] != 0;
+                let key = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+                self.key_event(&mut session.input, key, down_flag, addr, transport).await;
+                session.counters.input_event();
             }
             5 => { // PointerEvent
-                if data.len() >= 6 {
-                    let button_mask = data[1];
-                    let x = u16::from_be_bytes([data[2], data[3]]);
-                    let y = u16::from_be_bytes([data[4], data[5]]);
-                    
-                    println!("Pointer event: buttons={}, x={}, y={}", button_mask, x, y);
-                    
-                    let hid_report = Self::vnc_pointer_to_hid(button_mask, x, y);
-                    let _ = self.hid_manager.send_mouse_input(&hid_report).await;
-                }
+                let button_mask = data[1];
+                let x = u16::from_be_bytes([data[2], data[3]]);
+                let y = u16::from_be_bytes([data[4], data[5]]);
+                self.pointer_event(&mut session.input, button_mask, x, y, addr, transport).await;
+                session.counters.input_event();
             }
             6 => { // ClientCutText
-                println!("Received ClientCutText message");
+                self.clipboard.set(&clipboard::from_latin1(&data[8..]), session.id);
             }
             _ => {
-                println!("Unknown VNC message type: {}", data[0]);
+                debug!("Unknown VNC message type: {}", data[0]);
             }
         }
         
         Ok(())
     }
 
-    async fn send_framebuffer_update_tls(
-        &self,
-        stream: &mut tokio_rustls::server::TlsStream<tokio::net::TcpStream>,
-        frame_data: &[u8],
-    ) -> Result<()> {
-        use tokio::io::AsyncWriteExt;
+    /// Width and height of the most recently decoded frame
+    pub async fn resolution(&self) -> (u16, u16) {
+        (*self.frame_width.read().await, *self.frame_height.read().await)
+    }
 
-        let width = *self.frame_width.read().await;
-        let height = *self.frame_height.read().await;
+    async fn set_resolution(&self, width: u16, height: u16) {
+        let mut frame_width = self.frame_width.write().await;
+        let mut frame_height = self.frame_height.write().await;
+        if (*frame_width, *frame_height) != (width, height) {
+            *frame_width = width;
+            *frame_height = height;
+            self.events.publish(Event::ResolutionChanged { width, height });
+        }
+    }
 
-        // FramebufferUpdate message
-        let mut update = Vec::new();
-        update.push(0); // message type
-        update.push(0); // padding
-        update.extend_from_slice(&1u16.to_be_bytes()); // number of rectangles
+    /// A captured frame as JPEG. Frames the capture hardware already encoded
+    /// are passed through unless `reencode` is set or privacy mode is on.
+    /// Each encoding is made once per frame, whatever the number of clients
+    pub async fn frame_jpeg(&self, frame: &Arc<Frame>, quality: u8, reencode: bool) -> Option<Arc<Jpeg>> {
+        let blank = self.privacy_mode();
+        if frame.starts_with(&[0xFF, 0xD8]) && !reencode && !blank {
+            return frame.jpeg(JpegKey { quality: None, blank }, || async {
+                let (width, height) = jpeg_dimensions(frame)?;
+                Some(Jpeg { width, height, data: frame.to_vec() })
+            }).await;
+        }
+        frame.jpeg(JpegKey { quality: Some(quality), blank }, || self.encode_jpeg(frame.clone(), quality, blank)).await
+    }
 
-        // Rectangle header
-        update.extend_from_slice(&0u16.to_be_bytes()); // x
-        update.extend_from_slice(&0u16.to_be_bytes()); // y
-        update.extend_from_slice(&width.to_be_bytes()); // width
-        update.extend_from_slice(&height.to_be_bytes()); // height
-        update.extend_from_slice(&0u32.to_be_bytes()); // encoding (Raw)
+    /// Encode a captured frame as JPEG in software, off the async runtime
+    #[cfg(feature = "software-codecs")]
+    async fn encode_jpeg(&self, frame: Arc<Frame>, quality: u8, blank: bool) -> Option<Jpeg> {
+        let resolution = self.resolution().await;
+        let (jpeg, size) = tokio::task::spawn_blocking(move || {
+            let (mut rgb, _, size) = Self::decode_frame(&frame);
+            if blank {
+                rgb.fill(0);
+            }
+            let (width, height) = size.unwrap_or(resolution);
+            if rgb.len() != width as usize * height as usize * 3 {
+                return (None, size);
+            }
+            let mut data = Vec::new();
+            let encoded = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut data, quality)
+                .encode(&rgb, width as u32, height as u32, image::ExtendedColorType::Rgb8);
+            (encoded.ok().map(|()| Jpeg { width, height, data }), size)
+        })
+        .await
+        .ok()?;
+        if let Some((width, height)) = size {
+            self.set_resolution(width, height).await;
+        }
+        jpeg
+    }
 
-        stream.write_all(&update).await?;
-        stream.write_all(frame_data).await?;
-        stream.flush().await?;
+    #[cfg(not(feature = "software-codecs"))]
+    async fn encode_jpeg(&self, _frame: Arc<Frame>, _quality: u8, _blank: bool) -> Option<Jpeg> {
+        debug!("JPEG encoding needs kvm-rs built with the software-codecs feature");
+        None
+    }
+
+    /// Translate a keysym press or release of the client with `input` and
+    /// forward it to the keyboard gadget
+    pub async fn key_event(&self, input: &mut InputState, key: u32, down_flag: bool, addr: SocketAddr, transport: &str) {
+        trace!("Key event: key={}, down={}", key, down_flag);
+        self.audit.record(transport, &addr.to_string(), InputClass::Key, || {
+            format!("keysym=0x{:04x} down={}", key, down_flag)
+        });
+        
+        // Release the usage the key was pressed as, the host NumLock may have changed since
+        let usage = if down_flag {
+            let usage = self.key_usage(key);
+            if let Some(usage) = usage {
+                input.pressed_keys.insert(key, usage);
+            }
+            usage
+        } else {
+            input.pressed_keys.remove(&key).or_else(|| self.key_usage(key))
+        };
+        if let Some(usage) = usage {
+            let _ = self.hid_manager.key_event(usage, down_flag).await;
+        }
+    }
+
+    /// Translate an RFB pointer event (button mask, framebuffer position) of
+    /// the client with `input` and forward it to the mouse or touchscreen gadget
+    pub async fn pointer_event(&self, input: &mut InputState, button_mask: u8, x: u16, y: u16, addr: SocketAddr, transport: &str) {
+        trace!("Pointer event: buttons={}, x={}, y={}", button_mask, x, y);
+
+        // Audit button transitions, not every motion event
+        let previous_buttons = std::mem::replace(&mut input.last_buttons, button_mask);
+        if previous_buttons != button_mask {
+            let class = if self.hid_manager.touch_layout().is_some() {
+                InputClass::Touch
+            } else {
+                InputClass::PointerButton
+            };
+            self.audit.record(transport, &addr.to_string(), class, || {
+                format!("buttons=0x{:02x} x={} y={}", button_mask, x, y)
+            });
+        }
+        
+        if self.hid_manager.touch_layout().is_some() {
+            let touch_report = self.vnc_pointer_to_touch(button_mask, x, y).await;
+            let _ = self.hid_manager.send_touch_input(&touch_report).await;
+
+            // Touchscreens have no wheel, scroll through the mouse gadget
+            if Self::vnc_wheel(button_mask) != 0 {
+                let hid_report = self.vnc_pointer_to_hid(input, button_mask & 0x18, x, y).await;
+                let _ = self.hid_manager.send_mouse_input(&hid_report).await;
+            }
+        } else {
+            let hid_report = self.vnc_pointer_to_hid(input, button_mask, x, y).await;
+            let _ = self.hid_manager.send_mouse_input(&hid_report).await;
+        }
+    }
 
+    /// Release every key and button held on the host, whichever client
+    /// pressed it, and forget the keys and buttons of the client with `input`
+    pub async fn release_all(&self, input: &mut InputState) -> Result<(), HidError> {
+        input.pressed_keys.clear();
+        input.last_buttons = 0;
+        self.hid_manager.release_all().await
+    }
+
+    /// Release only the keys and buttons the client with `input` holds, leaving
+    /// those of other clients pressed
+    pub async fn release_input(&self, input: &mut InputState) -> Result<(), HidError> {
+        for (_, usage) in input.pressed_keys.drain() {
+            self.hid_manager.key_event(usage, false).await?;
+        }
+        if input.last_buttons != 0 {
+            let (x, y) = input.last_pointer.unwrap_or_default();
+            input.last_buttons = 0;
+            if self.hid_manager.touch_layout().is_some() {
+                let touch_report = self.vnc_pointer_to_touch(0, x, y).await;
+                self.hid_manager.send_touch_input(&touch_report).await?;
+            } else {
+                let hid_report = self.vnc_pointer_to_hid(input, 0, x, y).await;
+                self.hid_manager.send_mouse_input(&hid_report).await?;
+            }
+        }
         Ok(())
     }
 
-    async fn send_framebuffer_update_tcp(
-        &self,
-        stream: &mut tokio::net::TcpStream,
-        frame_data: &[u8],
-    ) -> Result<()> {
+    /// Send the latest frame in the client's pixel format
+    async fn send_framebuffer_update<S>(&self, stream: &mut S, session: &mut RfbSession) -> Result<()>
+    where
+        S: tokio::io::AsyncWrite + Unpin,
+    {
         use tokio::io::AsyncWriteExt;
 
+        // Over a bandwidth cap the update is sent with a later frame
+        if session.counters.throttled_until().is_some() {
+            return Ok(());
+        }
         let width = *self.frame_width.read().await;
         let height = *self.frame_height.read().await;
+        let (pixels, _reservation) = {
+            let last_frame = self.last_frame.read().await;
+            let Some((ref frame_data, _)) = *last_frame else {
+                return Ok(());
+            };
+            // Frames of unknown format cannot be sent as a rectangle
+            if frame_data.len() != width as usize * height as usize * 3 {
+                return Ok(());
+            }
+            // Without room for the update it is sent with a later frame
+            let size = width as usize * height as usize * session.pixel_format.bits_per_pixel as usize / 8;
+            let Some(reservation) = self.hub.budget().try_reserve(size) else {
+                return Ok(());
+            };
+            (session.pixel_format.encode(frame_data), reservation)
+        };
 
         // FramebufferUpdate message
         let mut update = Vec::new();
@@ -608,13 +1342,28 @@ impl VncHandler {
         update.extend_from_slice(&0u32.to_be_bytes()); // encoding (Raw)
 
         stream.write_all(&update).await?;
-        stream.write_all(frame_data).await?;
+        stream.write_all(&pixels).await?;
         stream.flush().await?;
+        session.update_requested = false;
+        session.counters.frame_sent(update.len() + pixels.len());
 
         Ok(())
     }
 
-    fn vnc_key_to_hid(vnc_key: u32, down: bool) -> Option<[u8; 8]> {
+    /// HID usage of a keysym on the host's keyboard layout
+    fn key_usage(&self, key: u32) -> Option<u8> {
+        match (self.keyboard_layout, key) {
+            // < > on the extra key between left Shift and Z
+            (KeyboardLayout::Iso, 0x003c | 0x003e) => Some(keyboard::NON_US_BACKSLASH_USAGE),
+            _ => Self::vnc_key_to_hid(key, self.hid_manager.num_lock()),
+        }
+    }
+
+    fn vnc_key_to_hid(vnc_key: u32, num_lock: bool) -> Option<u8> {
+        if (0xff7f..=0xffbd).contains(&vnc_key) {
+            return Self::vnc_keypad_to_hid(vnc_key, num_lock);
+        }
+
         // Basic VNC to HID keyboard mapping
         // This is a simplified mapping - you'd want a complete translation table
         let hid_key = match vnc_key {
@@ -622,34 +1371,371 @@ impl VncHandler {
             0xff09 => 0x2b, // Tab
             0xff0d => 0x28, // Enter
             0xff1b => 0x29, // Escape
-            0xff50 => 0x4f, // Home
+            0xff50 => 0x4a, // Home
             0xff51 => 0x50, // Left arrow
             0xff52 => 0x52, // Up arrow
             0xff53 => 0x4f, // Right arrow
             0xff54 => 0x51, // Down arrow
+            0xff55 => 0x4b, // Page Up
+            0xff56 => 0x4e, // Page Down
+            0xff57 => 0x4d, // End
+            0xff63 => 0x49, // Insert
+            0xffff => 0x4c, // Delete
+            0xff61 => 0x46, // Print / SysRq
+            0xffe5 => 0x39, // Caps Lock
+            0xffbe..=0xffc9 => (vnc_key - 0xffbe + 0x3a) as u8, // F1-F12
+            0xffca..=0xffd5 => (vnc_key - 0xffca + 0x68) as u8, // F13-F24
             0x0020 => 0x2c, // Space
+            0xffe3 => 0xe0, // Left Control
+            0xffe1 => 0xe1, // Left Shift
+            0xffe9 | 0xffe7 => 0xe2, // Left Alt / Meta
+            0xffeb => 0xe3, // Left Super
+            0xffe4 => 0xe4, // Right Control
+            0xffe2 => 0xe5, // Right Shift
+            0xffea | 0xffe8 | 0xfe03 => 0xe6, // Right Alt / Meta / AltGr
+            0xffec => 0xe7, // Right Super
+            // Punctuation of the US main block; shifted keysyms share the key of
+            // the unshifted one, the client sends Shift
+            0x002d | 0x005f => 0x2d, // - _
+            0x003d | 0x002b => 0x2e, // = +
+            0x005b | 0x007b => 0x2f, // [ {
+            0x005d | 0x007d => 0x30, // ] }
+            0x005c | 0x007c => 0x31, // \ |
+            0x003b | 0x003a => 0x33, // ; :
+            0x0027 | 0x0022 => 0x34, // ' "
+            0x0060 | 0x007e => 0x35, // ` ~
+            0x002c | 0x003c => 0x36, // , <
+            0x002e | 0x003e => 0x37, // . >
+            0x002f | 0x003f => 0x38, // / ?
+            // JIS
+            0x00a5 => 0x89, // Yen (International3)
+            0x04db => 0x87, // Ro (International1)
+            0xff27 => 0x88, // Hiragana/Katakana (International2)
+            0xff23 => 0x8a, // Henkan (International4)
+            0xff22 => 0x8b, // Muhenkan (International5)
+            0xff2a => 0x35, // Zenkaku/Hankaku, the grave key position on JIS keyboards
+            0xff26 => 0x92, // Katakana (LANG3)
+            0xff25 => 0x93, // Hiragana (LANG4)
+            // Korean
+            0xff31 => 0x90, // Hangul (LANG1)
+            0xff34 => 0x91, // Hanja (LANG2)
             0x0041..=0x005a => (vnc_key - 0x0041 + 0x04) as u8, // A-Z
             0x0061..=0x007a => (vnc_key - 0x0061 + 0x04) as u8, // a-z
-            0x0030..=0x0039 => (vnc_key - 0x0030 + 0x27) as u8, // 0-9
+            0x0030 => 0x27, // 0, after 9 in the HID usages
+            0x0031..=0x0039 => (vnc_key - 0x0031 + 0x1e) as u8, // 1-9
             _ => return None,
         };
 
-        if down {
-            Some([0, 0, hid_key, 0, 0, 0, 0, 0])
-        } else {
-            Some([0, 0, 0, 0, 0, 0, 0, 0]) // Key release
-        }
+        Some(hid_key)
+    }
+
+    /// Map keypad keysyms so the host sees the key the client meant: a digit
+    /// or a navigation key, whatever the host's NumLock state
+    fn vnc_keypad_to_hid(vnc_key: u32, num_lock: bool) -> Option<u8> {
+        let hid_key = match vnc_key {
+            0xff7f => 0x53, // Num Lock
+            0xff8d => 0x58, // KP_Enter
+            0xffaa => 0x55, // KP_Multiply
+            0xffab => 0x57, // KP_Add
+            0xffac => 0x85, // KP_Separator (keypad comma)
+            0xffad => 0x56, // KP_Subtract
+            0xffaf => 0x54, // KP_Divide
+            0xffbd => 0x67, // KP_Equal
+
+            // Navigation keysyms: keypad usages only navigate with NumLock off,
+            // otherwise use the dedicated navigation keys
+            0xff95 => if num_lock { 0x4a } else { 0x5f }, // KP_Home
+            0xff96 => if num_lock { 0x50 } else { 0x5c }, // KP_Left
+            0xff97 => if num_lock { 0x52 } else { 0x60 }, // KP_Up
+            0xff98 => if num_lock { 0x4f } else { 0x5e }, // KP_Right
+            0xff99 => if num_lock { 0x51 } else { 0x5a }, // KP_Down
+            0xff9a => if num_lock { 0x4b } else { 0x61 }, // KP_Prior (Page Up)
+            0xff9b => if num_lock { 0x4e } else { 0x5b }, // KP_Next (Page Down)
+            0xff9c => if num_lock { 0x4d } else { 0x59 }, // KP_End
+            0xff9d if !num_lock => 0x5d, // KP_Begin, no dedicated key
+            0xff9e => if num_lock { 0x49 } else { 0x62 }, // KP_Insert
+            0xff9f => if num_lock { 0x4c } else { 0x63 }, // KP_Delete
+
+            // Digit keysyms: keypad usages only type digits with NumLock on,
+            // otherwise use the main row
+            0xffae => if num_lock { 0x63 } else { 0x37 }, // KP_Decimal
+            0xffb0 => if num_lock { 0x62 } else { 0x27 }, // KP_0
+            0xffb1..=0xffb9 => {
+                let digit = (vnc_key - 0xffb1) as u8;
+                if num_lock { 0x59 + digit } else { 0x1e + digit }
+            }
+            _ => return None,
+        };
+
+        Some(hid_key)
     }
 
-    fn vnc_pointer_to_hid(button_mask: u8, _x: u16, _y: u16) -> [u8; 4] {
+    async fn vnc_pointer_to_hid(&self, input: &mut InputState, button_mask: u8, x: u16, y: u16) -> Vec<u8> {
         // Basic VNC to HID mouse mapping
         let buttons = button_mask & 0x07; // Left, middle, right buttons
-        
-        // For simplicity, we're not doing relative movement calculation here
-        // In a real implementation, you'd calculate dx/dy from previous position
-        let dx = 0i8; // Relative X movement
-        let dy = 0i8; // Relative Y movement
-        
-        [buttons, dx as u8, dy as u8, 0]
+        let wheel = Self::vnc_wheel(button_mask);
+
+        let layout = self.hid_manager.mouse_layout();
+        let settings = self.hid_manager.pointer_settings();
+        if layout.absolute {
+            // Absolute gadget: scale framebuffer coordinates onto the logical range
+            let width = *self.frame_width.read().await;
+            let height = *self.frame_height.read().await;
+            let (x, y) = settings.absolute(x, y, width, height);
+            layout.report(buttons, layout.x.scale(x, width), layout.y.scale(y, height), wheel)
+        } else {
+            // Relative gadget: send the movement since the previous pointer event
+            let (dx, dy) = match input.last_pointer.replace((x, y)) {
+                Some((last_x, last_y)) => (x as i32 - last_x as i32, y as i32 - last_y as i32),
+                None => (0, 0),
+            };
+            let (dx, dy) = settings.relative(dx, dy);
+            layout.report(buttons, dx, dy, wheel)
+        }
+    }
+
+    async fn vnc_pointer_to_touch(&self, button_mask: u8, x: u16, y: u16) -> Vec<u8> {
+        let touch_layout = self.hid_manager.touch_layout()
+            .expect("touchscreen gadget not configured");
+        let width = *self.frame_width.read().await;
+        let height = *self.frame_height.read().await;
+        let (x, y) = self.hid_manager.pointer_settings().absolute(x, y, width, height);
+
+        // The primary button is the finger contact
+        let touching = button_mask & 0x01 != 0;
+        touch_layout.report(touching, touch_layout.x.scale(x, width), touch_layout.y.scale(y, height))
+    }
+
+    fn vnc_wheel(button_mask: u8) -> i32 {
+        // RFB buttons 4 and 5 are wheel up and down
+        if button_mask & 0x08 != 0 {
+            1
+        } else if button_mask & 0x10 != 0 {
+            -1
+        } else {
+            0
+        }
+    }
+}
+
+/// ServerCutText message offering clipboard text to the client
+fn server_cut_text(text: &str) -> Vec<u8> {
+    let text = clipboard::to_latin1(text);
+    let mut message = vec![3u8, 0, 0, 0];
+    message.extend_from_slice(&(text.len() as u32).to_be_bytes());
+    message.extend_from_slice(&text);
+    message
+}
+
+/// The VNC port of a console as a console transport
+#[cfg(feature = "vnc")]
+pub struct VncServer {
+    name: String,
+    handler: VncHandler,
+    listener: crate::listen::MultiListener,
+}
+
+#[cfg(feature = "vnc")]
+impl VncServer {
+    pub fn new(name: String, handler: VncHandler, listener: crate::listen::MultiListener) -> Self {
+        Self { name, handler, listener }
+    }
+}
+
+#[cfg(feature = "vnc")]
+impl crate::transport::ConsoleTransport for VncServer {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn serve(self: Box<Self>, shutdown: Shutdown) -> futures_util::future::BoxFuture<'static, Result<()>> {
+        Box::pin(self.handler.with_shutdown(shutdown).start_vnc_server(self.listener))
+    }
+}
+
+/// Width and height from the start-of-frame segment of a JPEG
+fn jpeg_dimensions(data: &[u8]) -> Option<(u16, u16)> {
+    let mut pos = 2;
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xFF {
+            return None;
+        }
+        let marker = data[pos + 1];
+        if marker == 0xFF {
+            pos += 1;
+            continue;
+        }
+        if marker == 0x01 || (0xD0..=0xD7).contains(&marker) {
+            pos += 2;
+            continue;
+        }
+        let length = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        // SOF0..SOF15 except DHT (C4), JPG (C8) and DAC (CC)
+        if (0xC0..=0xCF).contains(&marker) && !matches!(marker, 0xC4 | 0xC8 | 0xCC) {
+            let segment = data.get(pos + 4..pos + 9)?;
+            let height = u16::from_be_bytes([segment[1], segment[2]]);
+            let width = u16::from_be_bytes([segment[3], segment[4]]);
+            return Some((width, height));
+        }
+        pos += 2 + length;
+    }
+    None
+}
+
+/// Failed SecurityResult with the reason
+fn security_failure(reason: &str) -> Vec<u8> {
+    let mut result = 1u32.to_be_bytes().to_vec();
+    result.extend_from_slice(&(reason.len() as u32).to_be_bytes());
+    result.extend_from_slice(reason.as_bytes());
+    result
+}
+
+/// VeNCrypt 0.2 handshake offering the single `subtype`
+#[cfg(feature = "tls")]
+async fn negotiate_vencrypt<S>(stream: &mut S, subtype: u32) -> Result<()>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    stream.write_all(&[0, 2]).await?;
+    let mut version = [0u8; 2];
+    stream.read_exact(&mut version).await?;
+    if version != [0, 2] {
+        stream.write_all(&[1]).await?;
+        return Err(ProtocolError::VencryptVersion(version[0], version[1]).into());
+    }
+    stream.write_all(&[0, 1]).await?;
+    stream.write_all(&subtype.to_be_bytes()).await?;
+    let choice = stream.read_u32().await?;
+    if choice != subtype {
+        stream.write_all(&[0]).await?;
+        return Err(ProtocolError::VencryptSubtype(choice).into());
+    }
+    stream.write_all(&[1]).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hid_backend::MockHidBackend;
+
+    fn mock_handler() -> VncHandler {
+        let keyboard = MockHidBackend::new("mock-keyboard");
+        let mouse = MockHidBackend::new("mock-mouse");
+        let hid_manager = HidManager::from_backends(Arc::new(keyboard), Arc::new(mouse), None, None);
+        VncHandler::new(DisplayHub::new(), hid_manager)
+    }
+
+    #[test]
+    fn letters_and_digits() {
+        assert_eq!(VncHandler::vnc_key_to_hid(0x0061, false), Some(0x04)); // a
+        assert_eq!(VncHandler::vnc_key_to_hid(0x005a, false), Some(0x1d)); // Z
+        assert_eq!(VncHandler::vnc_key_to_hid(0x0031, false), Some(0x1e)); // 1
+        assert_eq!(VncHandler::vnc_key_to_hid(0x0039, false), Some(0x26)); // 9
+        assert_eq!(VncHandler::vnc_key_to_hid(0x0030, false), Some(0x27)); // 0
+    }
+
+    #[test]
+    fn shifted_punctuation_uses_the_unshifted_key() {
+        assert_eq!(VncHandler::vnc_key_to_hid(0x005c, false), Some(0x31)); // backslash
+        assert_eq!(VncHandler::vnc_key_to_hid(0x007c, false), Some(0x31)); // |
+        assert_eq!(VncHandler::vnc_key_to_hid(0x002c, false), Some(0x36)); // ,
+        assert_eq!(VncHandler::vnc_key_to_hid(0x003c, false), Some(0x36)); // <
+        assert_eq!(VncHandler::vnc_key_to_hid(0x003f, false), Some(0x38)); // ?
+    }
+
+    #[test]
+    fn iso_layout_types_angle_brackets_with_the_extra_key() {
+        let iso = mock_handler().with_keyboard_layout(KeyboardLayout::Iso);
+        assert_eq!(iso.key_usage(0x003c), Some(0x64)); // <
+        assert_eq!(iso.key_usage(0x003e), Some(0x64)); // >
+        assert_eq!(iso.key_usage(0x002c), Some(0x36)); // ,
+        assert_eq!(mock_handler().key_usage(0x003c), Some(0x36));
+    }
+
+    #[test]
+    fn navigation_and_modifier_keys() {
+        assert_eq!(VncHandler::vnc_key_to_hid(0xff50, false), Some(0x4a)); // Home
+        assert_eq!(VncHandler::vnc_key_to_hid(0xff53, false), Some(0x4f)); // Right
+        assert_eq!(VncHandler::vnc_key_to_hid(0xffe3, false), Some(0xe0)); // Left Control
+        assert_eq!(VncHandler::vnc_key_to_hid(0xfe03, false), Some(0xe6)); // AltGr
+        assert_eq!(VncHandler::vnc_key_to_hid(0x20ac, false), None); // EuroSign
+    }
+
+    #[test]
+    fn editing_and_lock_keys() {
+        assert_eq!(VncHandler::vnc_key_to_hid(0xff63, false), Some(0x49)); // Insert
+        assert_eq!(VncHandler::vnc_key_to_hid(0xffff, false), Some(0x4c)); // Delete
+        assert_eq!(VncHandler::vnc_key_to_hid(0xff57, false), Some(0x4d)); // End
+        assert_eq!(VncHandler::vnc_key_to_hid(0xff55, false), Some(0x4b)); // Page Up
+        assert_eq!(VncHandler::vnc_key_to_hid(0xff56, false), Some(0x4e)); // Page Down
+        assert_eq!(VncHandler::vnc_key_to_hid(0xff61, false), Some(0x46)); // Print / SysRq
+        assert_eq!(VncHandler::vnc_key_to_hid(0xffe5, false), Some(0x39)); // Caps Lock
+    }
+
+    #[test]
+    fn function_keys() {
+        assert_eq!(VncHandler::vnc_key_to_hid(0xffbe, false), Some(0x3a)); // F1
+        assert_eq!(VncHandler::vnc_key_to_hid(0xffc9, false), Some(0x45)); // F12
+        assert_eq!(VncHandler::vnc_key_to_hid(0xffca, false), Some(0x68)); // F13
+        assert_eq!(VncHandler::vnc_key_to_hid(0xffd5, false), Some(0x73)); // F24
+        // Keysyms agree with the key names of macros and --block-keys
+        for n in 1..=24 {
+            let keysym = 0xffbe + n - 1;
+            assert_eq!(VncHandler::vnc_key_to_hid(keysym, false), keyboard::usage_from_name(&format!("F{}", n)));
+        }
+        assert_eq!(VncHandler::vnc_key_to_hid(0xffff, false), keyboard::usage_from_name("Delete"));
+    }
+
+    #[test]
+    fn keypad_digits_follow_num_lock() {
+        // KP_1 is the keypad key with NumLock on, the main row key otherwise
+        assert_eq!(VncHandler::vnc_key_to_hid(0xffb1, true), Some(0x59));
+        assert_eq!(VncHandler::vnc_key_to_hid(0xffb1, false), Some(0x1e));
+        assert_eq!(VncHandler::vnc_key_to_hid(0xffb0, true), Some(0x62));
+        assert_eq!(VncHandler::vnc_key_to_hid(0xffb0, false), Some(0x27));
+        assert_eq!(VncHandler::vnc_key_to_hid(0xffae, false), Some(0x37)); // KP_Decimal
+    }
+
+    #[test]
+    fn keypad_navigation_follows_num_lock() {
+        // KP_Left is the arrow key with NumLock on, keypad 4 otherwise
+        assert_eq!(VncHandler::vnc_key_to_hid(0xff96, true), Some(0x50));
+        assert_eq!(VncHandler::vnc_key_to_hid(0xff96, false), Some(0x5c));
+        assert_eq!(VncHandler::vnc_key_to_hid(0xff9d, true), None); // KP_Begin
+        assert_eq!(VncHandler::vnc_key_to_hid(0xff9d, false), Some(0x5d));
+        assert_eq!(VncHandler::vnc_key_to_hid(0xff7f, true), Some(0x53)); // Num Lock
+        assert_eq!(VncHandler::vnc_key_to_hid(0xff8d, false), Some(0x58)); // KP_Enter
+    }
+
+    #[tokio::test]
+    async fn relative_pointer_reports_movement() {
+        let handler = mock_handler();
+        let mut input = InputState::default();
+        assert_eq!(handler.vnc_pointer_to_hid(&mut input, 0, 100, 100).await, [0, 0, 0, 0]);
+        assert_eq!(handler.vnc_pointer_to_hid(&mut input, 0x01, 110, 95).await, [0x01, 10, (-5i8) as u8, 0]);
+        // Buttons 4 and 5 are the wheel
+        assert_eq!(handler.vnc_pointer_to_hid(&mut input, 0x08, 110, 95).await, [0, 0, 0, 1]);
+        assert_eq!(handler.vnc_pointer_to_hid(&mut input, 0x10, 110, 95).await, [0, 0, 0, 0xff]);
+    }
+
+    #[tokio::test]
+    async fn relative_pointer_moves_per_session() {
+        let handler = mock_handler();
+        let mut first = InputState::default();
+        let mut second = InputState::default();
+        handler.vnc_pointer_to_hid(&mut first, 0, 100, 100).await;
+        assert_eq!(handler.vnc_pointer_to_hid(&mut second, 0, 500, 500).await, [0, 0, 0, 0]);
+        assert_eq!(handler.vnc_pointer_to_hid(&mut first, 0, 101, 102).await, [0, 1, 2, 0]);
+    }
+
+    #[tokio::test]
+    async fn relative_pointer_movement_is_clamped() {
+        let handler = mock_handler();
+        let mut input = InputState::default();
+        handler.vnc_pointer_to_hid(&mut input, 0, 0, 0).await;
+        assert_eq!(handler.vnc_pointer_to_hid(&mut input, 0, 1000, 0).await, [0, 127, 0, 0]);
     }
 }
@@ -0,0 +1,91 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// VNC password files for kvm-rs
+
+use std::path::Path;
+use anyhow::{Context, Result};
+use des::cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit};
+use des::Des;
+
+/// Fixed key vncpasswd obfuscates ~/.vnc/passwd files with
+const OBFUSCATION_KEY: [u8; 8] = [23, 82, 107, 6, 35, 78, 88, 7];
+
+/// Length of the VNC Authentication challenge and response
+pub const CHALLENGE_LEN: usize = 16;
+
+/// Password of the VNC port
+pub enum VncPassword {
+    /// From a vncpasswd file: the password itself (at most 8 bytes, zero
+    /// padded), as VNC Authentication needs it to check responses
+    Classic([u8; 8]),
+    /// crypt(3) hash, e.g. from `openssl passwd -6`; only checked against
+    /// passwords sent in the clear inside TLS
+    Hashed(String),
+}
+
+impl VncPassword {
+    /// Read a vncpasswd file (8 DES-obfuscated bytes) or a text file with a crypt hash
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read(path)
+            .with_context(|| format!("Failed to read VNC password file {}", path.display()))?;
+        if contents.first() == Some(&b'$') {
+            let hash = String::from_utf8(contents)
+                .with_context(|| format!("Invalid password hash in {}", path.display()))?;
+            return Ok(VncPassword::Hashed(hash.trim().to_string()));
+        }
+        let obfuscated: [u8; 8] = contents.get(..8)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| anyhow::anyhow!("VNC password file {} is shorter than 8 bytes", path.display()))?;
+        let mut password = GenericArray::from(obfuscated);
+        Des::new(&GenericArray::from(OBFUSCATION_KEY)).decrypt_block(&mut password);
+        Ok(VncPassword::Classic(password.into()))
+    }
+
+    /// A password given in the clear, e.g. from the credential store; like
+    /// vncpasswd, VNC Authentication only uses its first 8 bytes
+    pub fn from_plain(password: &str) -> Self {
+        VncPassword::Classic(pad(password))
+    }
+
+    /// Whether `response` is the challenge encrypted with the password, as
+    /// VNC Authentication clients answer; never for hashed passwords
+    pub fn check_response(&self, challenge: &[u8; CHALLENGE_LEN], response: &[u8; CHALLENGE_LEN]) -> bool {
+        let VncPassword::Classic(password) = self else {
+            return false;
+        };
+        // VNC uses the password bytes with their bits mirrored as the DES key
+        let key: [u8; 8] = password.map(u8::reverse_bits);
+        let cipher = Des::new(&GenericArray::from(key));
+        let mut expected = *challenge;
+        for block in expected.chunks_exact_mut(8) {
+            cipher.encrypt_block(GenericArray::from_mut_slice(block));
+        }
+        expected.iter().zip(response).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+    }
+
+    /// Whether a password sent in the clear (VeNCrypt Plain) is this one
+    pub fn check_plain(&self, password: &str) -> bool {
+        match self {
+            VncPassword::Classic(expected) => {
+                // vncpasswd keeps only the first 8 bytes
+                pad(password).iter().zip(expected).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+            }
+            VncPassword::Hashed(hash) => pwhash::unix::verify(password, hash),
+        }
+    }
+
+    /// Whether clients can use VNC Authentication, which needs the password itself
+    #[cfg_attr(not(feature = "vnc"), allow(dead_code))]
+    pub fn supports_vnc_auth(&self) -> bool {
+        matches!(self, VncPassword::Classic(_))
+    }
+}
+
+/// First 8 bytes of a password, zero padded
+fn pad(password: &str) -> [u8; 8] {
+    let mut padded = [0u8; 8];
+    let bytes = password.as_bytes();
+    let len = bytes.len().min(8);
+    padded[..len].copy_from_slice(&bytes[..len]);
+    padded
+}
@@ -0,0 +1,133 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Embedded web console for kvm-rs
+
+use std::path::{Component, Path, PathBuf};
+use axum::{
+    extract::{Path as UrlPath, Request, State},
+    http::{header, HeaderValue, StatusCode, Uri},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::get,
+    Router,
+};
+use tower::ServiceExt;
+
+/// Console page; loads noVNC from /novnc and connects to /kvm/0
+const INDEX_HTML: &str = include_str!("../static/index.html");
+/// Console page without noVNC; shows the JPEG frames of the kvm-rs
+/// subprotocol on /kvm/0
+const VIEWER_HTML: &str = include_str!("../static/viewer.html");
+/// Terminal page of the host's serial console; connects to /sol
+const SERIAL_HTML: &str = include_str!("../static/serial.html");
+
+#[derive(Clone)]
+struct WebState {
+    novnc_dir: PathBuf,
+}
+
+/// Routes of the web console: the page at /, the noVNC files it loads and
+/// the serial console page at /serial. Without noVNC in `novnc_dir`, the page
+/// is the built-in viewer
+pub fn router(novnc_dir: &str) -> Router {
+    Router::new()
+        .route("/", get(index))
+        .route("/serial", get(serial))
+        .route("/novnc/{*path}", get(novnc_file))
+        .with_state(WebState {
+            novnc_dir: PathBuf::from(novnc_dir),
+        })
+}
+
+/// Serve `app` under `base_path` (e.g. "/kvm-rs"), for reverse proxies that
+/// forward a path prefix without rewriting it
+pub fn with_base_path(app: Router, base_path: &str) -> Router {
+    let base_path = base_path.trim_end_matches('/').to_string();
+    if base_path.is_empty() {
+        return app;
+    }
+    Router::new().fallback(move |request: Request| strip_base_path(app.clone(), base_path.clone(), request))
+}
+
+async fn strip_base_path(app: Router, base_path: String, mut request: Request) -> Response {
+    let Some(rest) = request.uri().path().strip_prefix(base_path.as_str()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    // The console page loads everything relative to its directory
+    if rest.is_empty() {
+        return Redirect::permanent(&format!("{}/", base_path)).into_response();
+    }
+    if !rest.starts_with('/') {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let path_and_query = match request.uri().query() {
+        Some(query) => format!("{}?{}", rest, query),
+        None => rest.to_string(),
+    };
+    let mut parts = request.uri().clone().into_parts();
+    parts.path_and_query = path_and_query.parse().ok();
+    let Ok(uri) = Uri::from_parts(parts) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    *request.uri_mut() = uri;
+
+    let mut response = app.oneshot(request).await.unwrap_or_else(|e| match e {});
+    // Redirects to absolute paths, e.g. to the login page, stay under the base path
+    let location = response.headers().get(header::LOCATION).and_then(|value| value.to_str().ok());
+    if let Some(location) = location.filter(|location| location.starts_with('/') && !location.starts_with("//")) {
+        if let Ok(value) = HeaderValue::from_str(&format!("{}{}", base_path, location)) {
+            response.headers_mut().insert(header::LOCATION, value);
+        }
+    }
+    response
+}
+
+/// GET / - console page, the built-in viewer until noVNC is installed
+async fn index(State(state): State<WebState>) -> Html<&'static str> {
+    if novnc_installed(&state.novnc_dir).await {
+        Html(INDEX_HTML)
+    } else {
+        Html(VIEWER_HTML)
+    }
+}
+
+/// GET /serial - serial console page
+async fn serial() -> Html<&'static str> {
+    Html(SERIAL_HTML)
+}
+
+/// GET /novnc/{path} - file from the noVNC installation
+async fn novnc_file(
+    State(state): State<WebState>,
+    UrlPath(path): UrlPath<String>,
+) -> Result<Response, (StatusCode, String)> {
+    // Only plain relative paths, nothing outside the noVNC directory
+    let relative = Path::new(&path);
+    if !relative.components().all(|component| matches!(component, Component::Normal(_))) {
+        return Err((StatusCode::NOT_FOUND, format!("{} not found", path)));
+    }
+
+    let file = state.novnc_dir.join(relative);
+    let data = tokio::fs::read(&file)
+        .await
+        .map_err(|_| (StatusCode::NOT_FOUND, format!("{} not found", path)))?;
+    Ok(([(header::CONTENT_TYPE, content_type(&file))], data).into_response())
+}
+
+/// Whether `dir` holds the noVNC RFB client the noVNC console page loads
+async fn novnc_installed(dir: &Path) -> bool {
+    tokio::fs::try_exists(dir.join("core/rfb.js")).await.unwrap_or(false)
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("js") => "text/javascript",
+        Some("html") => "text/html; charset=utf-8",
+        Some("css") => "text/css",
+        Some("json") => "application/json",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("ico") => "image/x-icon",
+        Some("woff2") => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
@@ -0,0 +1,216 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Console event notifications POSTed to webhook URLs for kvm-rs
+
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use anyhow::{anyhow, Context, Result};
+use axum::{
+    body::{Body, Bytes},
+    http::{header, Uri},
+};
+use hyper_util::rt::TokioIo;
+use rustls::pki_types::ServerName;
+use serde_json::{json, Value};
+use tokio::sync::{broadcast, mpsc};
+use tracing::{debug, warn};
+use crate::auth::send_request;
+use crate::events::Event;
+use crate::security_audit::SecurityEvent;
+
+/// Notifications kept for a slow webhook before new ones are dropped
+const WEBHOOK_QUEUE: usize = 64;
+/// How long one delivery attempt may take
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Wait before the first retry; it doubles with every further one
+const WEBHOOK_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Sends console events as JSON to every configured URL, each with its own
+/// queue so a slow or unreachable endpoint does not hold up the others
+#[derive(Clone, Default)]
+pub struct Webhooks {
+    queues: Arc<[mpsc::Sender<Bytes>]>,
+}
+
+impl Webhooks {
+    /// POST to `urls`, retrying a failed delivery `retries` times. https URLs
+    /// are verified against the CA certificates in `ca_file`
+    pub fn new(urls: &[String], ca_file: Option<&Path>, retries: u32) -> Result<Self> {
+        let mut tls = None;
+        let mut queues = Vec::new();
+        for url in urls {
+            let endpoint = Endpoint::parse(url, ca_file, &mut tls)?;
+            let (tx, rx) = mpsc::channel(WEBHOOK_QUEUE);
+            tokio::spawn(endpoint.deliver(rx, retries));
+            queues.push(tx);
+        }
+        Ok(Self { queues: queues.into() })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.queues.is_empty()
+    }
+
+    /// Send the sessions and video events of `events` as they are published
+    pub async fn forward(self, mut events: broadcast::Receiver<Event>) {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("{} events were not sent to webhooks", missed);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            match event {
+                Event::ClientConnected { id, transport, address } => {
+                    self.notify("session-started", json!({ "session": id, "transport": transport, "client": address }));
+                }
+                Event::ClientDisconnected { id, transport, address } => {
+                    self.notify("session-ended", json!({ "session": id, "transport": transport, "client": address }));
+                }
+                Event::VideoLost { console, reason } => {
+                    self.notify("video-lost", json!({ "console": console, "reason": reason }));
+                }
+                Event::VideoRestored { console } => self.notify("video-restored", json!({ "console": console })),
+                _ => {}
+            }
+        }
+    }
+
+    /// Send refused credentials, session tokens and lockouts
+    pub fn security_event(&self, event: &SecurityEvent<'_>) {
+        if !self.is_enabled() {
+            return;
+        }
+        match *event {
+            SecurityEvent::Login { transport, client, user, failure: Some(reason) } => {
+                self.notify("auth-failure", json!({ "transport": transport, "client": client, "user": user, "reason": reason }));
+            }
+            SecurityEvent::TokenRefused { client, reason } => {
+                self.notify("auth-failure", json!({ "client": client, "reason": reason }));
+            }
+            SecurityEvent::Lockout { client, user, duration } => {
+                self.notify("auth-lockout", json!({ "client": client, "user": user, "duration_secs": duration.as_secs() }));
+            }
+            _ => {}
+        }
+    }
+
+    /// Queue `event` with its `fields` for every webhook
+    fn notify(&self, event: &str, fields: Value) {
+        let mut body = json!({
+            "event": event,
+            "time": humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            "source": "kvm-rs",
+        });
+        if let (Some(body), Value::Object(fields)) = (body.as_object_mut(), fields) {
+            body.extend(fields);
+        }
+        let body = Bytes::from(body.to_string());
+        for queue in self.queues.iter() {
+            if queue.try_send(body.clone()).is_err() {
+                warn!("Webhook queue is full, dropped a {} event", event);
+            }
+        }
+    }
+}
+
+/// A URL events are POSTed to
+struct Endpoint {
+    uri: Uri,
+    host: String,
+    port: u16,
+    tls: Option<tokio_rustls::TlsConnector>,
+}
+
+impl Endpoint {
+    /// The TLS settings are built for the first https URL and shared by the others
+    fn parse(url: &str, ca_file: Option<&Path>, tls: &mut Option<tokio_rustls::TlsConnector>) -> Result<Self> {
+        let uri: Uri = url.parse().with_context(|| format!("Invalid webhook URL {}", url))?;
+        let https = match uri.scheme_str() {
+            Some("https") => true,
+            Some("http") => false,
+            _ => return Err(anyhow!("Webhook URL {} must start with http:// or https://", url)),
+        };
+        let host = uri.host()
+            .ok_or_else(|| anyhow!("Webhook URL {} has no host", url))?
+            .trim_matches(|c| c == '[' || c == ']')
+            .to_string();
+        let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+        let tls = if https {
+            if tls.is_none() {
+                let ca_file = ca_file.ok_or_else(|| anyhow!("--webhook-ca is required to verify the webhook at {}", host))?;
+                *tls = Some(tokio_rustls::TlsConnector::from(Arc::new(client_config(ca_file)?)));
+            }
+            tls.clone()
+        } else {
+            None
+        };
+        Ok(Self { uri, host, port, tls })
+    }
+
+    /// Scheme, host and port, for messages; the path may hold a secret
+    fn name(&self) -> String {
+        format!("{}://{}", self.uri.scheme_str().unwrap_or_default(), self.uri.authority().map(|a| a.as_str()).unwrap_or(&self.host))
+    }
+
+    /// Deliver queued notifications in order until the queue is dropped
+    async fn deliver(self, mut queue: mpsc::Receiver<Bytes>, retries: u32) {
+        while let Some(body) = queue.recv().await {
+            let mut backoff = WEBHOOK_BACKOFF;
+            for attempt in 0..=retries {
+                if attempt > 0 {
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                let result = tokio::time::timeout(WEBHOOK_TIMEOUT, self.post(body.clone()))
+                    .await
+                    .map_err(|_| anyhow!("no answer within {:?}", WEBHOOK_TIMEOUT))
+                    .and_then(|result| result);
+                match result {
+                    Ok(()) => break,
+                    Err(e) if attempt < retries => debug!("Webhook {} failed, retrying: {:#}", self.name(), e),
+                    Err(e) => warn!("Dropped an event for webhook {} after {} attempts: {:#}", self.name(), attempt + 1, e),
+                }
+            }
+        }
+    }
+
+    async fn post(&self, body: Bytes) -> Result<()> {
+        let stream = tokio::net::TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .with_context(|| format!("Failed to connect to {}:{}", self.host, self.port))?;
+        let path = self.uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+        let request = axum::http::Request::post(path)
+            .header(header::HOST, self.uri.authority().map(|a| a.as_str()).unwrap_or(&self.host))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))?;
+        let status = match self.tls {
+            Some(ref connector) => {
+                let server_name = ServerName::try_from(self.host.clone())?;
+                let stream = connector.connect(server_name, stream).await.context("TLS handshake failed")?;
+                send_request(TokioIo::new(stream), request).await?
+            }
+            None => send_request(TokioIo::new(stream), request).await?,
+        };
+        if !status.is_success() {
+            return Err(anyhow!("answered {}", status));
+        }
+        Ok(())
+    }
+}
+
+/// TLS client settings verifying webhooks against the certificates of `ca_file`
+fn client_config(ca_file: &Path) -> Result<rustls::ClientConfig> {
+    let pem = std::fs::read(ca_file)
+        .with_context(|| format!("Failed to read webhook CA file {}", ca_file.display()))?;
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut std::io::Cursor::new(&pem)) {
+        roots.add(cert.context("Failed to parse webhook CA file")?)?;
+    }
+    Ok(rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
@@ -2,64 +2,676 @@
 //
 // WebSocket handler for kvm-rs
 
-use std::sync::Arc;
+use std::{
+    future::Future,
+    net::SocketAddr,
+    sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
 use axum::{
-    extract::ws::{Message, WebSocket, WebSocketUpgrade},
-    response::Response,
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, Path, Query, State,
+    },
+    Extension,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use serde::Deserialize;
+use tokio::sync::{broadcast, mpsc, watch};
+use tracing::{debug, field, info, info_span, warn, Instrument};
+use crate::{
+    arbiter::{Role, Seat},
+    args::Args,
+    audit::{InputAudit, InputClass},
+    auth::{Access, Authenticator},
+    clipboard::MAX_CLIPBOARD_TEXT,
+    display::Frame,
+    hid::{HidError, HidManager},
+    keyboard,
+    power::{HostPower, PowerAction},
+    rtc::{IceCandidate, Peer, PeerEvent, RtcSettings},
+    security_audit::SecurityEvent,
+    services::{Service, Services},
+    sessions::LifetimeEvent,
+    targets::{Target, TargetRegistry},
+    vnc::{InputState, VncHandler},
+    ws_protocol::{self, ControlMessage, InputMessage, StreamSettings},
 };
-use crate::{display::DisplayHub, hid::HidManager};
 
-/// WebSocket handler for KVM over WebSocket connections
+/// Buffer between the WebSocket and the RFB session
+const RFB_BRIDGE_BUFFER: usize = 256 * 1024;
+
+/// Control messages queued for a kvm-rs subprotocol client
+const CONTROL_QUEUE: usize = 16;
+
+/// Longest clipboard text typed on the host at once; typing blocks the session
+const MAX_TYPED_CLIPBOARD: usize = 4096;
+
+/// Connection settings shared by all KVM WebSockets
+#[derive(Debug, Clone, Copy)]
+pub struct WsSettings {
+    pub ping_interval: Duration,
+    /// Close connections without client messages for this long
+    pub idle_timeout: Option<Duration>,
+    /// Initial JPEG quality of kvm-rs subprotocol frames
+    pub jpeg_quality: u8,
+    /// Write raw HID reports of kvm-rs clients to the gadgets, instead of
+    /// ignoring them
+    pub raw_reports: bool,
+}
+
+impl WsSettings {
+    pub fn from_args(args: &Args) -> Self {
+        Self {
+            ping_interval: Duration::from_secs(args.ws_ping_interval),
+            idle_timeout: (args.ws_idle_timeout > 0).then(|| Duration::from_secs(args.ws_idle_timeout)),
+            jpeg_quality: args.ws_jpeg_quality,
+            raw_reports: args.ws_raw_reports,
+        }
+    }
+}
+
+/// Ping and idle bookkeeping for one WebSocket connection
+pub struct Keepalive {
+    settings: WsSettings,
+    awaiting_pong: AtomicBool,
+    last_activity: Mutex<Instant>,
+}
+
+impl Keepalive {
+    pub fn new(settings: WsSettings) -> Self {
+        Self {
+            settings,
+            awaiting_pong: AtomicBool::new(false),
+            last_activity: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Timer for the pings, first firing one interval from now
+    pub fn ticker(&self) -> tokio::time::Interval {
+        let interval = self.settings.ping_interval;
+        tokio::time::interval_at(tokio::time::Instant::now() + interval, interval)
+    }
+
+    /// Called on every tick before sending a ping; the error is why the
+    /// connection should be closed instead
+    pub fn check(&self) -> Result<(), &'static str> {
+        if self.awaiting_pong.swap(true, Ordering::Relaxed) {
+            return Err("no pong received");
+        }
+        if let Some(timeout) = self.settings.idle_timeout {
+            let last_activity = *self.last_activity.lock().unwrap_or_else(|e| e.into_inner());
+            if last_activity.elapsed() >= timeout {
+                return Err("idle timeout");
+            }
+        }
+        Ok(())
+    }
+
+    pub fn pong(&self) {
+        self.awaiting_pong.store(false, Ordering::Relaxed);
+    }
+
+    /// Note a message from the client
+    pub fn activity(&self) {
+        *self.last_activity.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+    }
+}
+
+/// State of the /kvm/{id} route
+#[derive(Clone)]
+pub struct WsState {
+    pub targets: Arc<TargetRegistry>,
+    pub audit: InputAudit,
+    /// Settings of new connections, replaced on reload
+    pub settings: Arc<RwLock<WsSettings>>,
+    /// WebRTC signaling for kvm-rs subprotocol clients, if enabled
+    pub rtc: Option<Arc<RtcSettings>>,
+    /// Sessions whose expiry closes the connections opened with them
+    pub authenticator: Authenticator,
+    /// Clients are refused while the websocket service is off
+    pub services: Services,
+}
+
+impl WsState {
+    /// Settings for a connection opening now
+    pub fn settings(&self) -> WsSettings {
+        *self.settings.read().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Query of the /kvm/{id} route
+#[derive(Debug, Deserialize)]
+pub struct KvmQuery {
+    /// Resume token of a dropped kvm-rs session, to take back its seat
+    resume: Option<String>,
+}
+
+/// WebSocket handler for KVM over WebSocket connections.
+///
+/// Speaks RFB (as noVNC and bmcweb's KVM page expect) unless the client asks
+/// for the kvm-rs subprotocol (JPEG frames and the input protocol of ws_protocol).
 pub async fn kvm_ws(
     ws: WebSocketUpgrade,
-    hub: Arc<DisplayHub>,
-    hid_manager: HidManager,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(id): Path<u32>,
+    Query(query): Query<KvmQuery>,
+    State(state): State<WsState>,
+    Extension(access): Extension<Access>,
 ) -> Response {
-    ws.on_upgrade(|mut socket: WebSocket| async move {
-        let mut rx = hub.tx.subscribe();
-        // TODO: Handshake RFB / VNC here
-        
+    if !state.services.is_enabled(Service::Websocket) {
+        return (StatusCode::SERVICE_UNAVAILABLE, "WebSocket service is turned off".to_string()).into_response();
+    }
+    let Some(target) = state.targets.get(id).cloned() else {
+        return (StatusCode::NOT_FOUND, format!("No console target {}", id)).into_response();
+    };
+    if !target.hub.budget().admit_client() {
+        warn!("Refused WebSocket client {}: frame memory budget used up", addr);
+        return (StatusCode::SERVICE_UNAVAILABLE, "Frame memory budget used up".to_string()).into_response();
+    }
+    // Observers are admitted as viewers, which cannot send input
+    let Some(mut seat) = target.arbiter.admit(query.resume.as_deref(), access.role.may_send_input()) else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Console session limit reached".to_string()).into_response();
+    };
+    seat.user = access.principal.map(|principal| principal.0);
+    let settings = state.settings();
+    let (audit, rtc) = (state.audit, state.rtc);
+    // Checked by the auth middleware, watched here for expiry
+    let token = access.token;
+    let auth = state.authenticator;
+
+    // noVNC offers the "binary" subprotocol
+    let ws = ws.protocols(["binary", ws_protocol::PROTOCOL]);
+    let native = ws.selected_protocol()
+        .is_some_and(|protocol| protocol.as_bytes() == ws_protocol::PROTOCOL.as_bytes());
+
+    let span = info_span!("kvm_ws", client = %addr, console = id, session = field::Empty);
+    if native {
+        ws.on_upgrade(move |socket| {
+            let session = native_session(socket.split(), addr, target, audit, settings, rtc, seat);
+            until_session_ends(session, auth, token, addr).instrument(span)
+        })
+    } else {
+        ws.on_upgrade(move |socket| {
+            until_session_ends(rfb_session(socket, addr, target.vnc, settings, seat), auth, token, addr).instrument(span)
+        })
+    }
+}
+
+/// Run a connection until it ends, or until the login session it was opened with
+/// expires or is logged out
+pub async fn until_session_ends(connection: impl Future<Output = ()>, auth: Authenticator, token: Option<String>, addr: SocketAddr) {
+    tokio::select! {
+        _ = connection => {}
+        _ = auth.session_ended(token) => info!("Closing connection from {}: session expired", addr),
+    }
+}
+
+/// Tunnel an RFB session through binary WebSocket messages
+async fn rfb_session(socket: WebSocket, addr: SocketAddr, vnc: VncHandler, settings: WsSettings, seat: Seat) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    info!("RFB over WebSocket client connected from: {}", addr);
+    let (rfb_stream, bridge) = tokio::io::duplex(RFB_BRIDGE_BUFFER);
+    let (mut bridge_rx, mut bridge_tx) = tokio::io::split(bridge);
+    let (mut ws_tx, mut ws_rx) = socket.split();
+    let keepalive = Keepalive::new(settings);
+    let mut role = seat.role.clone();
+
+    // Server to client: whatever the session wrote, one message per read, and pings
+    let to_client = async {
+        let mut buffer = vec![0u8; RFB_BRIDGE_BUFFER];
+        let mut ticker = keepalive.ticker();
+        loop {
+            tokio::select! {
+                read = bridge_rx.read(&mut buffer) => {
+                    match read {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => {
+                            if ws_tx.send(Message::Binary(buffer[..n].to_vec().into())).await.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+                _ = ticker.tick() => {
+                    if let Err(reason) = keepalive.check() {
+                        info!("Closing RFB over WebSocket connection from {}: {}", addr, reason);
+                        break;
+                    }
+                    if ws_tx.send(Message::Ping(Default::default())).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    };
+
+    // Client to server: RFB messages may be split across or batched in WebSocket messages
+    let from_client = async {
+        while let Some(Ok(message)) = ws_rx.next().await {
+            match message {
+                Message::Binary(data) => {
+                    keepalive.activity();
+                    if bridge_tx.write_all(&data).await.is_err() {
+                        break;
+                    }
+                }
+                Message::Pong(_) => keepalive.pong(),
+                Message::Close(_) => break,
+                _ => {}
+            }
+        }
+    };
+
+    tokio::select! {
+        result = vnc.handle_rfb_stream(rfb_stream, addr, "websocket", seat.role.clone(), seat.user.clone()) => {
+            if let Err(e) = result {
+                warn!("RFB over WebSocket error for {}: {}", addr, e);
+            }
+        }
+        _ = to_client => {}
+        _ = from_client => {}
+        // RFB has no message for roles; a preempted client is just disconnected
+        _ = role.wait_for(|role| *role == Role::Preempted) => {
+            info!("RFB over WebSocket client {} preempted by a newer client", addr);
+        }
+    }
+    if vnc.shutdown().is_triggered() {
+        let _ = ws_tx.send(going_away_message()).await;
+    }
+    info!("RFB over WebSocket client disconnected: {}", addr);
+}
+
+/// kvm-rs subprotocol: JPEG frames as binary messages, input and control messages
+/// from the client. Runs over a WebSocket or a transport adapted to its messages
+pub async fn native_session<Tx, Rx>(
+    (mut ws_tx, mut ws_rx): (Tx, Rx),
+    addr: SocketAddr,
+    target: Target,
+    audit: InputAudit,
+    ws_settings: WsSettings,
+    rtc: Option<Arc<RtcSettings>>,
+    mut seat: Seat,
+) where
+    Tx: Sink<Message, Error = axum::Error> + Unpin,
+    Rx: Stream<Item = Result<Message, axum::Error>> + Unpin,
+{
+    let resume_grace = target.arbiter.resume_grace();
+    let Target { hub, hid_manager, vnc, power, .. } = target;
+
+    let session = vnc.sessions().register("kvm-rs", addr);
+    if let Some(user) = &seat.user {
+        vnc.sessions().set_user(session.id(), user);
+    }
+    vnc.sessions().set_role(session.id(), seat.role.clone());
+    let counters = session.counters();
+    let mut rx = hub.tx.subscribe();
+    let budget = hub.budget().clone();
+    let mut clipboard = vnc.clipboard().subscribe();
+    let mut input_lock = hid_manager.subscribe_input_lock();
+    let mut host_off = hid_manager.subscribe_host_state();
+    let mut settings = StreamSettings::new(ws_settings.jpeg_quality);
+    let raw_reports = ws_settings.raw_reports;
+    let keepalive = Keepalive::new(ws_settings);
+    let mut ticker = keepalive.ticker();
+
+    // Only the newest frame waits to be sent; frames captured while a send is
+    // in progress replace it, so a slow client skips frames instead of lagging
+    let (frame_tx, mut frame_rx) = watch::channel::<Option<Arc<Frame>>>(None);
+    let (settings_tx, settings_rx) = watch::channel(settings);
+    let (control_tx, mut control_rx) = mpsc::channel::<Message>(CONTROL_QUEUE);
+    // Frames go over the WebRTC video channel once the client has opened one
+    let (peer_tx, peer_rx) = watch::channel::<Option<Arc<Peer>>>(None);
+    let (peer_events_tx, mut peer_events) = mpsc::channel::<PeerEvent>(CONTROL_QUEUE);
+
+    // Tell the client whether its input will reach the host
+    let locked = *input_lock.borrow_and_update();
+    let role = *seat.role.borrow_and_update();
+    if ws_tx.send(role_message(role)).await.is_err() || ws_tx.send(input_lock_message(locked)).await.is_err() {
+        return;
+    }
+    let off = *host_off.borrow_and_update();
+    if off && ws_tx.send(host_power_message(off)).await.is_err() {
+        return;
+    }
+    if let (Some(token), Some(grace)) = (&seat.resume_token, resume_grace) {
+        if ws_tx.send(resume_message(token, grace)).await.is_err() {
+            return;
+        }
+    }
+    // Offer what was copied before the client connected
+    let text = clipboard.borrow_and_update().text.clone();
+    if !text.is_empty() && ws_tx.send(clipboard_message(&text)).await.is_err() {
+        return;
+    }
+    // Set when the connection is lost rather than closed, so the seat is kept for a reconnect
+    let dropped = AtomicBool::new(false);
+    // Recent average frame message size, for fitting the frame rate to a client's link
+    let frame_bytes = AtomicUsize::new(0);
+
+    let outgoing = async {
+        loop {
+            tokio::select! {
+                // Control messages go first, they are small and answer the client
+                biased;
+                message = control_rx.recv() => {
+                    let Some(message) = message else { break };
+                    let close = matches!(message, Message::Close(_));
+                    if ws_tx.send(message).await.is_err() {
+                        dropped.store(true, Ordering::Relaxed);
+                        break;
+                    }
+                    if close {
+                        break;
+                    }
+                }
+                changed = frame_rx.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                    // Over a bandwidth cap, wait for it; the newest frame is picked up afterwards
+                    if let Some(until) = counters.throttled_until() {
+                        tokio::time::sleep_until(until.into()).await;
+                    }
+                    let Some(frame_data) = frame_rx.borrow_and_update().clone() else { continue };
+                    let settings = *settings_rx.borrow();
+                    let sent_at = Instant::now();
+                    let Some(payload) = frame_payload(&frame_data, &settings, &vnc).await else { continue };
+                    // Held until the frame is sent; a client that does not fit skips it
+                    let Some(_reservation) = budget.try_reserve(payload.len()) else { continue };
+                    let average = frame_bytes.load(Ordering::Relaxed);
+                    frame_bytes.store(if average == 0 { payload.len() } else { (average * 7 + payload.len()) / 8 }, Ordering::Relaxed);
+                    let peer = peer_rx.borrow().clone().filter(|peer| peer.video_open());
+                    let size = payload.len();
+                    if let Some(peer) = peer {
+                        match peer.send_frame(&payload).await {
+                            Ok(()) => counters.frame_sent(size),
+                            Err(e) => warn!("WebRTC frame error for {}: {}", addr, e),
+                        }
+                    } else if ws_tx.send(Message::Binary(payload.into())).await.is_err() {
+                        dropped.store(true, Ordering::Relaxed);
+                        break;
+                    } else {
+                        counters.frame_sent(size);
+                    }
+                    // Frame rate limit: wait out the rest of the interval, the
+                    // newest frame is picked up afterwards
+                    if let Some(interval) = settings.frame_interval() {
+                        tokio::time::sleep_until((sent_at + interval).into()).await;
+                    }
+                }
+            }
+        }
+    };
+
+    let incoming = async {
+        let mut input = InputState::default();
+        let stopping = vnc.shutdown().wait();
+        tokio::pin!(stopping);
+        let mut lifetime = vnc.sessions().lifetime(session.id());
         loop {
             tokio::select! {
-                // Send framebuffer data to client
+                _ = &mut stopping => break,
+
+                // Warn before the maximum session duration, then close
+                event = lifetime.next() => {
+                    let message = match event {
+                        LifetimeEvent::Warning(remaining) => expiring_message(remaining),
+                        LifetimeEvent::Expired => {
+                            info!("Closing WebSocket session of {}: maximum session duration reached", addr);
+                            // The sender stops after the close frame
+                            expired_message()
+                        }
+                        LifetimeEvent::Disconnected => {
+                            info!("Closing WebSocket session of {}: disconnected through the control interface", addr);
+                            disconnected_message()
+                        }
+                    };
+                    if control_tx.send(message).await.is_err() {
+                        break;
+                    }
+                }
+
+                // Reflect input lock changes to the client
+                changed = input_lock.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                    let locked = *input_lock.borrow_and_update();
+                    if control_tx.send(input_lock_message(locked)).await.is_err() {
+                        break;
+                    }
+                }
+
+                // Tell the client whether the host is off, and its input refused or queued
+                changed = host_off.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                    let off = *host_off.borrow_and_update();
+                    if control_tx.send(host_power_message(off)).await.is_err() {
+                        break;
+                    }
+                }
+
+                // Text copied in another client
+                changed = clipboard.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                    let copied = clipboard.borrow_and_update().clone();
+                    if copied.source != session.id() && control_tx.send(clipboard_message(&copied.text)).await.is_err() {
+                        break;
+                    }
+                }
+
+                // Report promotion to controller, or close after preemption
+                changed = seat.role.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                    let role = *seat.role.borrow_and_update();
+                    vnc.sessions().security_audit().log(SecurityEvent::RoleChange {
+                        client: addr,
+                        session: session.id(),
+                        role: role.as_str(),
+                    });
+                    if control_tx.send(role_message(role)).await.is_err() {
+                        break;
+                    }
+                    if role == Role::Preempted {
+                        info!("WebSocket client {} preempted by a newer client", addr);
+                        // The sender stops after the close frame
+                        if control_tx.send(Message::Close(None)).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+
+                // Signaling and input of the WebRTC connection
+                Some(event) = peer_events.recv() => {
+                    match event {
+                        PeerEvent::Candidate(init) => {
+                            let message = serde_json::json!({
+                                "type": "webrtc-candidate",
+                                "candidate": init.candidate,
+                                "sdpMid": init.sdp_mid,
+                                "sdpMLineIndex": init.sdp_mline_index,
+                            });
+                            if control_tx.send(Message::Text(message.to_string().into())).await.is_err() {
+                                break;
+                            }
+                        }
+                        PeerEvent::Input(data) => {
+                            if settings.view_only || *seat.role.borrow() != Role::Controller {
+                                continue;
+                            }
+                            match InputMessage::parse(&data) {
+                                Ok(message) => {
+                                    handle_input(message, &mut input, raw_reports, addr, &hid_manager, &audit, &vnc).await;
+                                    counters.input_event();
+                                }
+                                Err(e) => debug!("Invalid input message from {}: {}", addr, e),
+                            }
+                        }
+                        // Frames fall back to the WebSocket
+                        PeerEvent::Closed => {
+                            if let Some(peer) = peer_tx.send_replace(None) {
+                                peer.close().await;
+                            }
+                        }
+                    }
+                }
+
+                // Ping the client, closing the connection if it is gone or idle
+                _ = ticker.tick() => {
+                    if let Err(reason) = keepalive.check() {
+                        info!("Closing WebSocket connection from {}: {}", addr, reason);
+                        dropped.store(true, Ordering::Relaxed);
+                        break;
+                    }
+                    if control_tx.send(Message::Ping(Default::default())).await.is_err() {
+                        break;
+                    }
+                }
+
+                // Hand the newest frame to the sender
                 frame = rx.recv() => {
                     match frame {
                         Ok(frame_data) => {
-                            if socket.send(Message::Binary(frame_data.into())).await.is_err() {
-                                break;
-                            }
+                            frame_tx.send_replace(Some(frame_data));
                         }
+                        Err(broadcast::error::RecvError::Lagged(_)) => {}
                         Err(_) => break,
                     }
                 }
-                
+
                 // Receive input from client
-                msg = socket.recv() => {
+                msg = ws_rx.next() => {
                     match msg {
                         Some(Ok(Message::Binary(data))) => {
-                            // TODO: Parse input data and determine if it's keyboard or mouse
-                            // For now, just show how the HID devices would be used
-                            if !data.is_empty() {
-                                match data[0] {
-                                    0x01 => { // Example: keyboard input
-                                        if let Err(e) = hid_manager.send_keyboard_input(&data[1..]).await {
-                                            eprintln!("Keyboard input error: {}", e);
+                            keepalive.activity();
+                            if settings.view_only || *seat.role.borrow() != Role::Controller {
+                                continue;
+                            }
+                            match InputMessage::parse(&data) {
+                                Ok(message) => {
+                                    handle_input(message, &mut input, raw_reports, addr, &hid_manager, &audit, &vnc).await;
+                                    counters.input_event();
+                                }
+                                Err(e) => debug!("Invalid input message from {}: {}", addr, e),
+                            }
+                        }
+                        Some(Ok(Message::Text(text))) => {
+                            keepalive.activity();
+                            let reply = match ControlMessage::parse(&text) {
+                                Ok(ControlMessage::RequestKeyframe) => {
+                                    // Resend the newest frame, even if it was already sent
+                                    frame_tx.send_modify(|_| {});
+                                    continue;
+                                }
+                                Ok(ControlMessage::SetStream { fps, quality }) => {
+                                    match settings.update(fps, quality) {
+                                        Ok(()) => stream_message(&settings),
+                                        Err(e) => error_message(&e),
+                                    }
+                                }
+                                Ok(ControlMessage::LinkReport { throughput, latency_ms }) => {
+                                    if !settings.adapt(throughput, latency_ms, frame_bytes.load(Ordering::Relaxed)) {
+                                        continue;
+                                    }
+                                    stream_message(&settings)
+                                }
+                                Ok(ControlMessage::SetViewOnly { enabled }) => {
+                                    if enabled && !settings.view_only {
+                                        // Nothing this client holds may stay pressed on the host
+                                        if let Err(e) = vnc.release_input(&mut input).await {
+                                            warn!("Failed to release input for {}: {}", addr, e);
                                         }
                                     }
-                                    0x02 => { // Example: mouse input
-                                        if let Err(e) = hid_manager.send_mouse_input(&data[1..]).await {
-                                            eprintln!("Mouse input error: {}", e);
+                                    if enabled != settings.view_only {
+                                        vnc.sessions().security_audit().log(SecurityEvent::ViewOnly {
+                                            client: addr,
+                                            session: session.id(),
+                                            enabled,
+                                        });
+                                    }
+                                    settings.view_only = enabled;
+                                    stream_message(&settings)
+                                }
+                                Ok(ControlMessage::QueryResolution) => {
+                                    let (width, height) = vnc.resolution().await;
+                                    let message = serde_json::json!({ "type": "resolution", "width": width, "height": height });
+                                    Message::Text(message.to_string().into())
+                                }
+                                Ok(ControlMessage::Clipboard { text }) => {
+                                    if text.len() > MAX_CLIPBOARD_TEXT {
+                                        error_message(&anyhow::anyhow!("Clipboard text of {} bytes is too long", text.len()))
+                                    } else {
+                                        vnc.clipboard().set(&text, session.id());
+                                        continue;
+                                    }
+                                }
+                                Ok(ControlMessage::TypeClipboard) => {
+                                    match type_clipboard(&vnc, &hid_manager, &audit, addr, settings.view_only, &seat).await {
+                                        Ok(()) => continue,
+                                        Err(e) => error_message(&e),
+                                    }
+                                }
+                                Ok(ControlMessage::Power { action }) => {
+                                    match request_power(power.as_ref(), action, addr, &seat, settings.view_only).await {
+                                        Ok(()) => power_message(action),
+                                        Err(e) => error_message(&e),
+                                    }
+                                }
+                                Ok(ControlMessage::WebrtcOffer { sdp }) => {
+                                    match rtc.as_deref() {
+                                        Some(rtc) => {
+                                            // A new offer replaces the previous connection
+                                            if let Some(peer) = peer_tx.send_replace(None) {
+                                                peer.close().await;
+                                            }
+                                            match Peer::answer(rtc, sdp, peer_events_tx.clone()).await {
+                                                Ok((peer, answer)) => {
+                                                    info!("WebRTC connection offered by {}", addr);
+                                                    peer_tx.send_replace(Some(Arc::new(peer)));
+                                                    let message = serde_json::json!({ "type": "webrtc-answer", "sdp": answer });
+                                                    Message::Text(message.to_string().into())
+                                                }
+                                                Err(e) => error_message(&e),
+                                            }
                                         }
+                                        None => error_message(&anyhow::anyhow!("WebRTC is not enabled")),
                                     }
-                                    _ => {
-                                        println!("Unknown input type: {}", data[0]);
+                                }
+                                Ok(ControlMessage::WebrtcCandidate { candidate, sdp_mid, sdp_mline_index }) => {
+                                    let peer = peer_tx.borrow().clone();
+                                    let Some(peer) = peer else { continue };
+                                    let init = IceCandidate { candidate, sdp_mid, sdp_mline_index, ..Default::default() };
+                                    match peer.add_candidate(init).await {
+                                        Ok(()) => continue,
+                                        Err(e) => error_message(&e),
                                     }
                                 }
+                                Err(e) => error_message(&e),
+                            };
+                            settings_tx.send_replace(settings);
+                            if control_tx.send(reply).await.is_err() {
+                                break;
                             }
                         }
-                        Some(Ok(Message::Close(_))) | None => break,
+                        Some(Ok(Message::Pong(_))) => keepalive.pong(),
+                        Some(Ok(Message::Close(_))) => break,
+                        None => {
+                            dropped.store(true, Ordering::Relaxed);
+                            break;
+                        }
                         Some(Err(e)) => {
-                            eprintln!("WebSocket error: {}", e);
+                            warn!("WebSocket error: {}", e);
+                            dropped.store(true, Ordering::Relaxed);
                             break;
                         }
                         _ => {} // Ignore other message types
@@ -67,5 +679,202 @@ pub async fn kvm_ws(
                 }
             }
         }
-    })
+    };
+
+    tokio::select! {
+        _ = outgoing => {}
+        _ = incoming => {}
+    }
+    if vnc.shutdown().is_triggered() {
+        let _ = ws_tx.send(going_away_message()).await;
+    }
+    let peer = peer_tx.send_replace(None);
+    if let Some(peer) = peer {
+        peer.close().await;
+    }
+    if dropped.load(Ordering::Relaxed) && !vnc.shutdown().is_triggered() {
+        seat.park();
+    }
+}
+
+/// Power operation requested by a client; like input, only controllers may
+async fn request_power(
+    power: Option<&HostPower>,
+    action: PowerAction,
+    addr: SocketAddr,
+    seat: &Seat,
+    view_only: bool,
+) -> anyhow::Result<()> {
+    let Some(power) = power else {
+        return Err(anyhow::anyhow!("Power control is not enabled for this console"));
+    };
+    if view_only || *seat.role.borrow() != Role::Controller {
+        return Err(anyhow::anyhow!("This session may not control the host power"));
+    }
+    power.request(action, addr, seat.user.as_deref()).await
+}
+
+/// Type the shared clipboard on the host, for controllers that may send input
+async fn type_clipboard(
+    vnc: &VncHandler,
+    hid_manager: &HidManager,
+    audit: &InputAudit,
+    addr: SocketAddr,
+    view_only: bool,
+    seat: &Seat,
+) -> anyhow::Result<()> {
+    if view_only || *seat.role.borrow() != Role::Controller {
+        return Err(anyhow::anyhow!("This session may not send input"));
+    }
+    let text = vnc.clipboard().text();
+    if text.chars().count() > MAX_TYPED_CLIPBOARD {
+        return Err(anyhow::anyhow!("Clipboard text is too long to type, at most {} characters", MAX_TYPED_CLIPBOARD));
+    }
+    if let Some(c) = text.chars().find(|c| keyboard::usage_for_char(*c).is_none()) {
+        return Err(anyhow::anyhow!("Cannot type character {:?}", c));
+    }
+    audit.record("websocket", &addr.to_string(), InputClass::Key, || format!("text={:?}", text));
+    Ok(hid_manager.type_text(&text).await?)
+}
+
+/// Translate one input message and forward it to the HID gadgets
+async fn handle_input(
+    message: InputMessage<'_>,
+    input: &mut InputState,
+    raw_reports: bool,
+    addr: SocketAddr,
+    hid_manager: &HidManager,
+    audit: &InputAudit,
+    vnc: &VncHandler,
+) {
+    let raw = |class: InputClass, report: &[u8]| {
+        audit.record("websocket", &addr.to_string(), class, || {
+            let report: Vec<String> = report.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("report={}", report.join(""))
+        });
+    };
+
+    let result = match message {
+        InputMessage::Key { keysym, down } => {
+            vnc.key_event(input, keysym, down, addr, "websocket").await;
+            Ok(())
+        }
+        InputMessage::Pointer { buttons, x, y, wheel } => {
+            vnc.pointer_event(input, ws_protocol::rfb_button_mask(buttons, wheel), x, y, addr, "websocket").await;
+            Ok(())
+        }
+        InputMessage::ReleaseAll => vnc.release_all(input).await,
+        // Client report bytes bypass the keymap, blocked keys and pointer settings
+        InputMessage::RawKeyboard(_) | InputMessage::RawMouse(_) | InputMessage::RawTouch(_) if !raw_reports => {
+            debug!("Raw HID report from {} ignored, see --ws-raw-reports", addr);
+            Ok(())
+        }
+        InputMessage::RawKeyboard(report) => {
+            raw(InputClass::RawKeyboard, report);
+            hid_manager.send_raw_keyboard_input(report).await
+        }
+        InputMessage::RawMouse(report) => {
+            raw(InputClass::RawMouse, report);
+            hid_manager.send_raw_mouse_input(report).await
+        }
+        InputMessage::RawTouch(report) => {
+            raw(InputClass::RawTouch, report);
+            hid_manager.send_raw_touch_input(report).await
+        }
+    };
+    match result {
+        Ok(()) => {}
+        // The client was told with a host-power message
+        Err(HidError::HostOff) => debug!("Input from {} refused, the host is off", addr),
+        Err(e) => warn!("Input error from {}: {}", addr, e),
+    }
+}
+
+/// Frame message carrying a frame as JPEG, None if the frame can't be encoded
+async fn frame_payload(frame: &Arc<Frame>, settings: &StreamSettings, vnc: &VncHandler) -> Option<Vec<u8>> {
+    let jpeg = vnc.frame_jpeg(frame, settings.quality, settings.reencode_jpeg).await?;
+    Some(ws_protocol::jpeg_frame(jpeg.width, jpeg.height, &jpeg.data))
+}
+
+/// Control message with the session's current stream settings
+fn stream_message(settings: &StreamSettings) -> Message {
+    let message = serde_json::json!({
+        "type": "stream",
+        "fps": settings.fps,
+        "quality": settings.quality,
+        "view_only": settings.view_only,
+    });
+    Message::Text(message.to_string().into())
+}
+
+/// Control message confirming a power operation was requested
+fn power_message(action: PowerAction) -> Message {
+    let message = serde_json::json!({ "type": "power", "action": action });
+    Message::Text(message.to_string().into())
+}
+
+/// Control message reporting a rejected control message
+fn error_message(error: &anyhow::Error) -> Message {
+    let message = serde_json::json!({ "type": "error", "message": error.to_string() });
+    Message::Text(message.to_string().into())
+}
+
+/// Close frame telling the client the server is shutting down
+pub fn going_away_message() -> Message {
+    Message::Close(Some(CloseFrame {
+        code: close_code::AWAY,
+        reason: "server shutting down".into(),
+    }))
+}
+
+/// Control message warning that the session reaches its maximum duration soon
+fn expiring_message(remaining: Duration) -> Message {
+    let message = serde_json::json!({ "type": "session-expiring", "remaining_secs": remaining.as_secs() });
+    Message::Text(message.to_string().into())
+}
+
+/// Close frame ending a session at its maximum duration
+pub fn expired_message() -> Message {
+    Message::Close(Some(CloseFrame {
+        code: close_code::POLICY,
+        reason: "maximum session duration reached".into(),
+    }))
+}
+
+/// Close frame for a session disconnected through the control interface
+pub fn disconnected_message() -> Message {
+    Message::Close(Some(CloseFrame {
+        code: close_code::POLICY,
+        reason: "disconnected by an administrator".into(),
+    }))
+}
+
+/// Control message announcing the client's role
+fn role_message(role: Role) -> Message {
+    let message = serde_json::json!({ "type": "role", "role": role });
+    Message::Text(message.to_string().into())
+}
+
+/// Control message with the token that resumes this session after a dropped connection
+fn resume_message(token: &str, grace: Duration) -> Message {
+    let message = serde_json::json!({ "type": "resume", "token": token, "grace_secs": grace.as_secs() });
+    Message::Text(message.to_string().into())
+}
+
+/// Control message with text copied in another client
+fn clipboard_message(text: &str) -> Message {
+    let message = serde_json::json!({ "type": "clipboard", "text": text });
+    Message::Text(message.to_string().into())
+}
+
+/// Control message announcing the input lock state
+fn input_lock_message(locked: bool) -> Message {
+    let message = serde_json::json!({ "type": "input-lock", "locked": locked });
+    Message::Text(message.to_string().into())
+}
+
+/// Control message telling whether the host is powered off
+fn host_power_message(off: bool) -> Message {
+    let message = serde_json::json!({ "type": "host-power", "off": off });
+    Message::Text(message.to_string().into())
 }
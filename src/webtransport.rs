@@ -0,0 +1,311 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// WebTransport (HTTP/3) console endpoint for kvm-rs
+
+use std::net::SocketAddr;
+use std::time::Duration;
+use anyhow::{anyhow, Context, Result};
+use axum::{
+    extract::ws::Message,
+    http::{HeaderMap, HeaderName, HeaderValue, Uri},
+};
+use futures_util::future::BoxFuture;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use wtransport::{config::Ipv6DualStackConfig, endpoint::{endpoint_side::Server, IncomingSession}, Connection, Endpoint, ServerConfig, VarInt};
+use tracing::{field, info, info_span, warn, Instrument, Span};
+use crate::{
+    auth::{AuthError, Authenticator},
+    ip_filter::IpFilter,
+    origin::OriginPolicy,
+    security_audit::{SecurityAudit, SecurityEvent},
+    shutdown::Shutdown,
+    transport::ConsoleTransport,
+    websocket::{self, WsState},
+};
+
+/// Messages queued between a session and its QUIC streams
+const MESSAGE_QUEUE: usize = 16;
+
+/// Largest message accepted on the control stream
+const MAX_CONTROL_MESSAGE: usize = 64 * 1024;
+
+/// Control stream message kinds: an input message or a JSON control message
+const KIND_INPUT: u8 = 0x00;
+const KIND_CONTROL: u8 = 0x01;
+
+/// QUIC keepalive, so NAT mappings of idle viewers do not expire
+const KEEP_ALIVE: Duration = Duration::from_secs(10);
+
+/// Who may open sessions, checked as for /kvm/{id} WebSockets
+#[derive(Clone)]
+pub struct Admission {
+    pub origin_policy: OriginPolicy,
+    pub authenticator: Authenticator,
+    pub ip_filter: IpFilter,
+    pub security_audit: SecurityAudit,
+}
+
+impl Admission {
+    fn refuse(&self, client: SocketAddr, reason: &str) {
+        self.security_audit.log(SecurityEvent::Connection {
+            transport: "webtransport",
+            client,
+            refused: Some(reason),
+        });
+    }
+}
+
+/// HTTP/3 endpoint for WebTransport sessions on `bind_addr`; an IPv6 address
+/// takes `only_v6` IPv6 clients, so an IPv4 endpoint on the port can be bound too
+pub fn bind(bind_addr: SocketAddr, only_v6: bool, tls: rustls::ServerConfig) -> Result<Endpoint<Server>> {
+    let builder = match bind_addr {
+        SocketAddr::V6(addr) if only_v6 => ServerConfig::builder().with_bind_address_v6(addr, Ipv6DualStackConfig::Deny),
+        _ => ServerConfig::builder().with_bind_address(bind_addr),
+    };
+    let config = builder
+        .with_custom_tls(tls)
+        .keep_alive_interval(Some(KEEP_ALIVE))
+        .build();
+    let endpoint = Endpoint::server(config)
+        .with_context(|| format!("Failed to bind WebTransport endpoint to {}", bind_addr))?;
+    info!("WebTransport listening on {} (UDP)", bind_addr);
+    Ok(endpoint)
+}
+
+/// WebTransport endpoint as a console transport
+pub struct WebTransportServer {
+    endpoint: Endpoint<Server>,
+    state: WsState,
+    admission: Admission,
+}
+
+impl WebTransportServer {
+    pub fn new(endpoint: Endpoint<Server>, state: WsState, admission: Admission) -> Self {
+        Self { endpoint, state, admission }
+    }
+}
+
+impl ConsoleTransport for WebTransportServer {
+    fn name(&self) -> &str {
+        "WebTransport server"
+    }
+
+    fn serve(self: Box<Self>, shutdown: Shutdown) -> BoxFuture<'static, Result<()>> {
+        Box::pin(serve(self.endpoint, self.state, self.admission, shutdown))
+    }
+}
+
+/// Accept WebTransport sessions on `/kvm/{id}` until shutdown
+async fn serve(
+    endpoint: Endpoint<Server>,
+    state: WsState,
+    admission: Admission,
+    shutdown: Shutdown,
+) -> Result<()> {
+
+    let admission = std::sync::Arc::new(admission);
+    let stopping = shutdown.wait();
+    tokio::pin!(stopping);
+    loop {
+        let incoming = tokio::select! {
+            incoming = endpoint.accept() => incoming,
+            _ = &mut stopping => break,
+        };
+        let state = state.clone();
+        let admission = admission.clone();
+        let span = info_span!("webtransport", client = field::Empty, session = field::Empty);
+        tokio::spawn(async move {
+            if let Err(e) = handle_session(incoming, state, &admission).await {
+                warn!("WebTransport session error: {:#}", e);
+            }
+        }.instrument(span));
+    }
+    endpoint.close(VarInt::from_u32(0), b"server shutting down");
+    Ok(())
+}
+
+async fn handle_session(incoming: IncomingSession, state: WsState, admission: &Admission) -> Result<()> {
+    let request = incoming.await.context("WebTransport handshake failed")?;
+    let client = request.remote_address();
+    Span::current().record("client", field::display(client));
+    if !admission.ip_filter.allows(client.ip()) {
+        warn!("Refused WebTransport session from {}: address not allowed", client);
+        admission.refuse(client, "address not allowed");
+        request.forbidden().await;
+        return Ok(());
+    }
+
+    // Browsers send no cookies with WebTransport, the session token may come in the query
+    let uri: Uri = format!("https://{}{}", request.authority(), request.path()).parse()
+        .context("Invalid WebTransport request")?;
+    let mut headers = HeaderMap::new();
+    for (name, value) in request.headers() {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(name.as_str()), HeaderValue::from_str(value)) {
+            headers.insert(name, value);
+        }
+    }
+    if let Some(token) = query_param(&uri, "token") {
+        if let Ok(token) = HeaderValue::from_str(token) {
+            headers.insert("x-auth-token", token);
+        }
+    }
+
+    if let Err(reason) = admission.origin_policy.check(&uri, &headers) {
+        warn!("Refused WebTransport session: {}", reason);
+        admission.refuse(client, &reason);
+        request.forbidden().await;
+        return Ok(());
+    }
+    // A handoff token keeps the session token out of the URL
+    let access = match query_param(&uri, "handoff") {
+        Some(handoff) => admission.authenticator.redeem(handoff).ok_or(AuthError::InvalidHandoff),
+        None => admission.authenticator.check(&headers).await,
+    };
+    let access = match access {
+        Ok(access) => access,
+        Err(e) => {
+            warn!("Refused WebTransport session: {}", e);
+            admission.security_audit.log(SecurityEvent::TokenRefused {
+                client,
+                reason: &e.to_string(),
+            });
+            // Tell clients whose credential may still work to come back later
+            if e.is_recoverable() {
+                request.too_many_requests().await;
+            } else {
+                request.forbidden().await;
+            }
+            return Ok(());
+        }
+    };
+
+    let Some(target) = uri.path().strip_prefix("/kvm/")
+        .and_then(|id| id.parse::<u32>().ok())
+        .and_then(|id| state.targets.get(id).cloned())
+    else {
+        request.not_found().await;
+        return Ok(());
+    };
+    if !target.hub.budget().admit_client() {
+        warn!("Refused WebTransport client: frame memory budget used up");
+        request.too_many_requests().await;
+        return Ok(());
+    }
+    let Some(mut seat) = target.arbiter.admit(query_param(&uri, "resume"), access.role.may_send_input()) else {
+        request.too_many_requests().await;
+        return Ok(());
+    };
+    seat.user = access.principal.map(|principal| principal.0);
+
+    let connection = request.accept().await.context("Failed to accept WebTransport session")?;
+    let addr = connection.remote_address();
+    info!("WebTransport client connected from: {}", addr);
+    admission.security_audit.log(SecurityEvent::Connection {
+        transport: "webtransport",
+        client: addr,
+        refused: None,
+    });
+
+    // The session speaks kvm-rs messages; the pump maps them onto QUIC streams
+    let (to_client_tx, to_client_rx) = mpsc::channel::<Message>(MESSAGE_QUEUE);
+    let (from_client_tx, from_client_rx) = mpsc::channel::<Result<Message, axum::Error>>(MESSAGE_QUEUE);
+    let sink = Box::pin(futures_util::sink::unfold(to_client_tx, |tx, message: Message| async move {
+        tx.send(message).await.map_err(axum::Error::new)?;
+        Ok::<_, axum::Error>(tx)
+    }));
+    let stream = Box::pin(futures_util::stream::unfold(from_client_rx, |mut rx| async move {
+        rx.recv().await.map(|message| (message, rx))
+    }));
+
+    let settings = state.settings();
+    // Each side ends the other by dropping its channel ends, so the session
+    // sees a lost connection as such and keeps its seat for a resume
+    let session = websocket::native_session((sink, stream), addr, target, state.audit, settings, state.rtc, seat);
+    let token = access.token;
+    let (_, result) = tokio::join!(
+        websocket::until_session_ends(session, admission.authenticator.clone(), token, addr),
+        pump(&connection, to_client_rx, from_client_tx),
+    );
+    if let Err(e) = result {
+        warn!("WebTransport error for {}: {:#}", addr, e);
+    }
+    connection.close(VarInt::from_u32(0), b"");
+    info!("WebTransport client disconnected: {}", addr);
+    Ok(())
+}
+
+/// Carry session messages over the connection: every frame on its own
+/// unidirectional stream, so a lost packet only delays that frame, and
+/// control and input messages on the client's bidirectional stream
+async fn pump(
+    connection: &Connection,
+    mut to_client: mpsc::Receiver<Message>,
+    from_client: mpsc::Sender<Result<Message, axum::Error>>,
+) -> Result<()> {
+    let (mut control_tx, mut control_rx) = connection.accept_bi().await
+        .context("Client did not open the control stream")?;
+
+    let outgoing = async {
+        while let Some(message) = to_client.recv().await {
+            match message {
+                Message::Binary(frame) => {
+                    let mut stream = connection.open_uni().await?.await?;
+                    stream.write_all(&frame).await?;
+                    stream.finish().await?;
+                }
+                Message::Text(text) => {
+                    write_message(&mut control_tx, KIND_CONTROL, text.as_bytes()).await?;
+                }
+                // QUIC has its own keepalive, answer for the client
+                Message::Ping(data) => {
+                    from_client.send(Ok(Message::Pong(data))).await?;
+                }
+                Message::Close(_) => break,
+                Message::Pong(_) => {}
+            }
+        }
+        anyhow::Ok(())
+    };
+
+    let incoming = async {
+        loop {
+            let kind = control_rx.read_u8().await?;
+            let len = control_rx.read_u32().await? as usize;
+            if len > MAX_CONTROL_MESSAGE {
+                return Err(anyhow!("Control message of {} bytes is too long", len));
+            }
+            let mut data = vec![0u8; len];
+            control_rx.read_exact(&mut data).await?;
+            let message = match kind {
+                KIND_INPUT => Message::Binary(data.into()),
+                KIND_CONTROL => Message::Text(String::from_utf8(data)?.into()),
+                kind => return Err(anyhow!("Unknown control stream message kind {}", kind)),
+            };
+            from_client.send(Ok(message)).await?;
+        }
+    };
+
+    tokio::select! {
+        result = outgoing => result,
+        result = incoming => result,
+    }
+}
+
+/// Length-prefixed message on the control stream
+async fn write_message<W: tokio::io::AsyncWrite + Unpin>(stream: &mut W, kind: u8, data: &[u8]) -> Result<()> {
+    let mut message = Vec::with_capacity(data.len() + 5);
+    message.push(kind);
+    message.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    message.extend_from_slice(data);
+    stream.write_all(&message).await?;
+    Ok(())
+}
+
+fn query_param<'a>(uri: &'a Uri, name: &str) -> Option<&'a str> {
+    uri.query()?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
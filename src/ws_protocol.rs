@@ -0,0 +1,259 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Binary input protocol of the kvm-rs WebSocket subprotocol
+
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use crate::power::PowerAction;
+
+/// WebSocket subprotocol name; the version changes with incompatible message changes
+pub const PROTOCOL: &str = "kvm-rs.v2";
+
+/// Video frame from the server: format, width, height (big endian), image data
+const MSG_FRAME: u8 = 0x10;
+/// Frame format: baseline JPEG
+const FRAME_JPEG: u8 = 0x01;
+/// Part of a frame message on the WebRTC video channel: frame id (u32),
+/// chunk index and chunk count (u16, big endian), data
+#[cfg(feature = "webrtc")]
+const MSG_FRAME_CHUNK: u8 = 0x11;
+/// Data per frame chunk, small enough for any browser's data channel messages
+#[cfg(feature = "webrtc")]
+const FRAME_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Frame latency in milliseconds above which adaptive quality backs off
+const ADAPTIVE_LATENCY_TARGET_MS: u32 = 200;
+/// Lowest JPEG quality adaptive quality goes down to
+const ADAPTIVE_MIN_QUALITY: u8 = 20;
+/// Lowest frame rate adaptive quality goes down to
+const ADAPTIVE_MIN_FPS: u32 = 2;
+/// Frame rate limit of adaptive sessions whose client set none
+const ADAPTIVE_MAX_FPS: u32 = 30;
+/// Share of the measured throughput frames may take on a congested link
+const ADAPTIVE_THROUGHPUT_SHARE: f64 = 0.8;
+
+/// Key event: down flag, X11 keysym (big endian)
+const MSG_KEY: u8 = 0x01;
+/// Absolute pointer: buttons, x, y (big endian, framebuffer pixels), wheel
+const MSG_POINTER: u8 = 0x02;
+/// Release every key and button
+const MSG_RELEASE_ALL: u8 = 0x03;
+/// HID report for the keyboard gadget, accepted with --ws-raw-reports
+const MSG_RAW_KEYBOARD: u8 = 0x81;
+/// HID report for the mouse gadget, accepted with --ws-raw-reports
+const MSG_RAW_MOUSE: u8 = 0x82;
+/// HID report for the touchscreen gadget, accepted with --ws-raw-reports
+const MSG_RAW_TOUCH: u8 = 0x83;
+
+/// Input message from a WebSocket client
+#[derive(Debug)]
+pub enum InputMessage<'a> {
+    Key { keysym: u32, down: bool },
+    /// Buttons: bit 0 left, bit 1 middle, bit 2 right; wheel > 0 scrolls up
+    Pointer { buttons: u8, x: u16, y: u16, wheel: i8 },
+    ReleaseAll,
+    RawKeyboard(&'a [u8]),
+    RawMouse(&'a [u8]),
+    RawTouch(&'a [u8]),
+}
+
+impl<'a> InputMessage<'a> {
+    /// Parse one binary message; the first byte is the message type
+    pub fn parse(data: &'a [u8]) -> Result<Self> {
+        let (&message_type, body) = data.split_first()
+            .ok_or_else(|| anyhow!("Empty input message"))?;
+        let expect = |len: usize| {
+            if body.len() == len {
+                Ok(())
+            } else {
+                Err(anyhow!("Input message 0x{:02x} needs {} payload bytes, got {}", message_type, len, body.len()))
+            }
+        };
+
+        match message_type {
+            MSG_KEY => {
+                expect(5)?;
+                Ok(InputMessage::Key {
+                    down: body[0] != 0,
+                    keysym: u32::from_be_bytes([body[1], body[2], body[3], body[4]]),
+                })
+            }
+            MSG_POINTER => {
+                expect(6)?;
+                Ok(InputMessage::Pointer {
+                    buttons: body[0] & 0x07,
+                    x: u16::from_be_bytes([body[1], body[2]]),
+                    y: u16::from_be_bytes([body[3], body[4]]),
+                    wheel: body[5] as i8,
+                })
+            }
+            MSG_RELEASE_ALL => {
+                expect(0)?;
+                Ok(InputMessage::ReleaseAll)
+            }
+            MSG_RAW_KEYBOARD => Ok(InputMessage::RawKeyboard(body)),
+            MSG_RAW_MOUSE => Ok(InputMessage::RawMouse(body)),
+            MSG_RAW_TOUCH => Ok(InputMessage::RawTouch(body)),
+            _ => Err(anyhow!("Unknown input message type 0x{:02x}", message_type)),
+        }
+    }
+}
+
+/// Pointer buttons and wheel direction as an RFB button mask (wheel = buttons 4/5)
+pub fn rfb_button_mask(buttons: u8, wheel: i8) -> u8 {
+    let wheel_bits = match wheel.signum() {
+        1 => 0x08,
+        -1 => 0x10,
+        _ => 0,
+    };
+    (buttons & 0x07) | wheel_bits
+}
+
+/// JSON control message from a WebSocket client, sent as a text message
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ControlMessage {
+    /// Send the latest frame now, even if it would be skipped by the frame rate limit
+    RequestKeyframe,
+    /// Change the stream settings; omitted fields keep their value
+    SetStream {
+        fps: Option<u32>,
+        quality: Option<u8>,
+    },
+    /// Link measurements for adaptive quality: bytes per second received and
+    /// frame latency in milliseconds, as measured by the client
+    LinkReport { throughput: u64, latency_ms: u32 },
+    /// Stop or resume forwarding this client's input
+    SetViewOnly { enabled: bool },
+    QueryResolution,
+    /// Text copied in the browser, offered to the other console clients
+    Clipboard { text: String },
+    /// Type the shared clipboard text on the host keyboard
+    TypeClipboard,
+    /// Turn the host on or off, power cycle or reset it; the server answers with power
+    Power { action: PowerAction },
+    /// Start a WebRTC connection; the server answers with webrtc-answer
+    WebrtcOffer { sdp: String },
+    /// ICE candidate of the browser, as in RTCIceCandidate.toJSON()
+    WebrtcCandidate {
+        candidate: String,
+        #[serde(rename = "sdpMid")]
+        sdp_mid: Option<String>,
+        #[serde(rename = "sdpMLineIndex")]
+        sdp_mline_index: Option<u16>,
+    },
+}
+
+impl ControlMessage {
+    pub fn parse(text: &str) -> Result<Self> {
+        serde_json::from_str(text).map_err(|e| anyhow!("Invalid control message: {}", e))
+    }
+}
+
+/// Frame message carrying a JPEG image
+pub fn jpeg_frame(width: u16, height: u16, jpeg: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(jpeg.len() + 6);
+    message.push(MSG_FRAME);
+    message.push(FRAME_JPEG);
+    message.extend_from_slice(&width.to_be_bytes());
+    message.extend_from_slice(&height.to_be_bytes());
+    message.extend_from_slice(jpeg);
+    message
+}
+
+/// Frame message split into chunk messages for the WebRTC video channel
+#[cfg(feature = "webrtc")]
+pub fn frame_chunks(frame_id: u32, frame: &[u8]) -> impl Iterator<Item = Vec<u8>> + '_ {
+    let count = frame.len().div_ceil(FRAME_CHUNK_SIZE) as u16;
+    frame.chunks(FRAME_CHUNK_SIZE).enumerate().map(move |(index, data)| {
+        let mut chunk = Vec::with_capacity(data.len() + 9);
+        chunk.push(MSG_FRAME_CHUNK);
+        chunk.extend_from_slice(&frame_id.to_be_bytes());
+        chunk.extend_from_slice(&(index as u16).to_be_bytes());
+        chunk.extend_from_slice(&count.to_be_bytes());
+        chunk.extend_from_slice(data);
+        chunk
+    })
+}
+
+/// Per-session stream settings changed by control messages
+#[derive(Debug, Clone, Copy)]
+pub struct StreamSettings {
+    /// Maximum frames per second, 0 for every captured frame
+    pub fps: u32,
+    /// JPEG quality (1-100) frames are encoded with
+    pub quality: u8,
+    /// Re-encode frames captured as JPEG instead of forwarding them, set once
+    /// the client picks a quality or adaptive quality changes it
+    pub reencode_jpeg: bool,
+    /// Limits chosen with SetStream, which adaptive quality stays within
+    max_fps: u32,
+    max_quality: u8,
+    pub view_only: bool,
+}
+
+impl StreamSettings {
+    pub fn new(quality: u8) -> Self {
+        Self {
+            fps: 0,
+            quality,
+            reencode_jpeg: false,
+            max_fps: 0,
+            max_quality: quality,
+            view_only: false,
+        }
+    }
+
+    /// Apply a SetStream message
+    pub fn update(&mut self, fps: Option<u32>, quality: Option<u8>) -> Result<()> {
+        if let Some(quality) = quality {
+            if !(1..=100).contains(&quality) {
+                return Err(anyhow!("Quality must be between 1 and 100, got {}", quality));
+            }
+            self.quality = quality;
+            self.max_quality = quality;
+            self.reencode_jpeg = true;
+        }
+        if let Some(fps) = fps {
+            self.fps = fps;
+            self.max_fps = fps;
+        }
+        Ok(())
+    }
+
+    /// Apply a LinkReport: while frames arrive late, lower the quality and fit
+    /// the frame rate to the throughput; on a fast link raise both again, up
+    /// to the SetStream limits. `frame_bytes` is the recent average frame
+    /// size. Returns whether the settings changed
+    pub fn adapt(&mut self, throughput: u64, latency_ms: u32, frame_bytes: usize) -> bool {
+        let before = (self.fps, self.quality);
+        let max_fps = if self.max_fps > 0 { self.max_fps } else { ADAPTIVE_MAX_FPS };
+        let fps = if self.fps > 0 { self.fps.min(max_fps) } else { max_fps };
+        if latency_ms > ADAPTIVE_LATENCY_TARGET_MS {
+            self.quality = (self.quality - self.quality / 4).max(ADAPTIVE_MIN_QUALITY.min(self.max_quality));
+            // The throughput only says what the link can carry while it is saturated
+            self.fps = fps;
+            if throughput > 0 && frame_bytes > 0 {
+                let affordable = (throughput as f64 * ADAPTIVE_THROUGHPUT_SHARE / frame_bytes as f64) as u32;
+                self.fps = affordable.clamp(ADAPTIVE_MIN_FPS.min(max_fps), fps);
+            }
+        } else if latency_ms < ADAPTIVE_LATENCY_TARGET_MS / 2 {
+            self.quality = (self.quality + 5).min(self.max_quality);
+            // Back at the limit, a session without one is unlimited again
+            if self.fps > 0 {
+                let raised = fps + fps / 4 + 1;
+                self.fps = if raised >= max_fps { self.max_fps } else { raised };
+            }
+        }
+        if self.quality != before.1 {
+            self.reencode_jpeg = true;
+        }
+        (self.fps, self.quality) != before
+    }
+
+    /// Minimum time between two frames sent to the client
+    pub fn frame_interval(&self) -> Option<Duration> {
+        (self.fps > 0).then(|| Duration::from_secs(1) / self.fps)
+    }
+}